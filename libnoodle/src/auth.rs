@@ -1,8 +1,13 @@
-use std::{error, fmt::Display};
+use std::{error, fmt::Display, net::SocketAddr};
 
 use async_trait::async_trait;
-use axum::{Json, http::StatusCode};
-use axum_login::{AuthSession, AuthUser, AuthnBackend, UserId};
+use axum::{
+    Json,
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, StatusCode, header},
+};
+use axum_login::{AuthSession, AuthUser, AuthnBackend, UserId, tower_sessions::Session};
+use const_format::concatcp;
 use sqlx::{PgPool, Postgres, postgres::PgRow};
 use tokio::task;
 
@@ -11,8 +16,15 @@ use crate::{
     user::{self, User},
 };
 
+pub mod cache;
+pub mod email;
+pub mod expiry;
+pub mod password;
 pub mod permission;
-use permission::Operations;
+pub mod session;
+pub mod token;
+pub mod totp;
+use permission::{EFFECTIVE_OPERATIONS, Operations};
 
 impl AuthUser for user::User {
     type Id = i64;
@@ -35,6 +47,18 @@ impl Backend {
     pub async fn new(db: PgPool) -> Self {
         Backend { db }
     }
+
+    /// The user of a personal access token, limited to the scope of the token.
+    pub async fn authenticate_token(&self, token: &str) -> Result<Option<User>, Error> {
+        let Some((user_id, scope)) = token::resolve(&self.db, token).await.map_err(Error::Sqlx)?
+        else {
+            return Ok(None);
+        };
+        Ok(self.get_user(&user_id).await?.map(|user| User {
+            scope: Some(scope),
+            ..user
+        }))
+    }
 }
 
 #[derive(Debug)]
@@ -69,45 +93,76 @@ impl AuthnBackend for Backend {
         &self,
         credentials: Self::Credentials,
     ) -> Result<Option<Self::User>, Self::Error> {
-        let user: Result<Option<(i64, String, String, String, String, String)>, _> =
-            sqlx::query_as("SELECT * FROM \"user\" WHERE email = $1")
-                .bind(credentials.email)
-                .fetch_optional(&self.db)
-                .await;
+        let user: Result<Option<(i64, String, String, String, String, String, bool)>, _> =
+            sqlx::query_as(concatcp!(
+                "SELECT u.id, u.firstname, u.lastname, u.title, u.email, u.password, ",
+                totp::MISSING,
+                " FROM \"user\" u WHERE u.email = $1"
+            ))
+            .bind(credentials.email)
+            .fetch_optional(&self.db)
+            .await;
         if let Err(e) = user {
             return Err(Self::Error::Sqlx(e));
         }
-        let user = user.unwrap();
-        match task::spawn_blocking(|| {
-            user.filter(|u| {
-                if let Ok(true) = bcrypt::verify(credentials.password, &u.5) {
-                    true
-                } else {
-                    false
-                }
-            })
-        })
-        .await
-        {
-            Err(e) => return Err(Self::Error::TaskJoin(e)),
-            Ok(Some(u)) => Ok(Some(Self::User {
-                user_id: u.0,
-                firstname: u.1,
-                lastname: u.2,
-                title: u.3,
-                email: u.4,
-                password: u.5.into(),
-            })),
-            _ => Ok(None),
+        let Some(u) = user.unwrap() else {
+            return Ok(None);
+        };
+        // a hash with an outdated algorithm or cost is replaced while the password is at hand, which
+        // ends the other sessions of the user once as they are bound to the hash
+        let stored = u.5.clone();
+        let checked =
+            task::spawn_blocking(
+                move || match password::verify(&credentials.password, &stored) {
+                    password::Verification::Wrong => None,
+                    password::Verification::Valid => Some(None),
+                    password::Verification::Outdated => {
+                        Some(password::hash(&credentials.password).ok())
+                    }
+                },
+            )
+            .await
+            .map_err(Self::Error::TaskJoin)?;
+        let Some(rehashed) = checked else {
+            return Ok(None);
+        };
+        let mut password_hash = u.5;
+        if let Some(rehashed) = rehashed {
+            // the login works with the old hash as well, so a failed update is not fatal
+            match sqlx::query("UPDATE \"user\" SET password = $1 WHERE id = $2 AND password = $3")
+                .bind(&rehashed)
+                .bind(u.0)
+                .bind(&password_hash)
+                .execute(&self.db)
+                .await
+            {
+                Ok(r) if r.rows_affected() > 0 => password_hash = rehashed,
+                Ok(_) => {}
+                Err(e) => tracing::warn!("rehashing the password of user {} failed: {e}", u.0),
+            }
         }
+        Ok(Some(Self::User {
+            user_id: u.0,
+            firstname: u.1,
+            lastname: u.2,
+            title: u.3,
+            email: u.4,
+            password: password_hash.into(),
+            scope: None,
+            totp_missing: u.6,
+        }))
     }
 
     async fn get_user(&self, user_id: &UserId<Self>) -> Result<Option<Self::User>, Self::Error> {
-        let user: Result<Option<(i64, String, String, String, String, String)>, _> =
-            sqlx::query_as("SELECT * FROM \"user\" WHERE id = $1")
-                .bind(user_id)
-                .fetch_optional(&self.db)
-                .await;
+        let user: Result<Option<(i64, String, String, String, String, String, bool)>, _> =
+            sqlx::query_as(concatcp!(
+                "SELECT u.id, u.firstname, u.lastname, u.title, u.email, u.password, ",
+                totp::MISSING,
+                " FROM \"user\" u WHERE u.id = $1"
+            ))
+            .bind(user_id)
+            .fetch_optional(&self.db)
+            .await;
         if let Err(e) = user {
             return Err(Self::Error::Sqlx(e));
         }
@@ -120,6 +175,8 @@ impl AuthnBackend for Backend {
                 title: u.3,
                 email: u.4,
                 password: u.5.into(),
+                scope: None,
+                totp_missing: u.6,
             }))
         } else {
             Ok(None)
@@ -129,18 +186,89 @@ impl AuthnBackend for Backend {
 
 pub async fn create_session_handler(
     mut auth_session: AuthSession<Backend>,
+    session: Session,
+    State(state): State<crate::AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(credentials): Json<user::Credentials>,
 ) -> StatusCode {
     if let Ok(Some(u)) = auth_session.authenticate(credentials).await {
-        if let Err(_) = auth_session.login(&u).await {
-            return StatusCode::UNAUTHORIZED;
-        };
-        StatusCode::CREATED
+        if email::verification_required() {
+            match email::verified(&state.db, u.user_id).await {
+                Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
+                Ok(Some(true)) => {}
+                Ok(_) => return StatusCode::FORBIDDEN,
+            }
+        }
+        // with two-factor authentication the login is finished by a code, see totp::http::login
+        match totp::enabled(&state.db, u.user_id).await {
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
+            Ok(true) => {
+                return match totp::http::await_code(&session, u.user_id).await {
+                    Ok(_) => StatusCode::ACCEPTED,
+                    Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                };
+            }
+            Ok(false) => {}
+        }
+        start_session(&mut auth_session, &session, &state.db, &u, addr, &headers).await
     } else {
         StatusCode::UNAUTHORIZED
     }
 }
 
+/// Logs `user` in and keeps track of the session, see [`session::record`].
+pub(crate) async fn start_session(
+    auth_session: &mut AuthSession<Backend>,
+    session: &Session,
+    db: &PgPool,
+    user: &User,
+    addr: SocketAddr,
+    headers: &HeaderMap,
+) -> StatusCode {
+    if let Err(_) = auth_session.login(user).await {
+        return StatusCode::UNAUTHORIZED;
+    };
+    // the login gave the session a new id, which it only gets once it is stored
+    if session.save().await.is_err() {
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    }
+    let Some(session_id) = session.id() else {
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    };
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned);
+    if session::record(
+        db,
+        &session_id.to_string(),
+        user.user_id,
+        Some(addr.ip().to_string()),
+        user_agent,
+    )
+    .await
+    .is_err()
+    {
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    }
+    StatusCode::CREATED
+}
+
+/// Selects the operations user `$1` holds on every resource with a grant of their own or of one of
+/// their roles, as `resource_id` and `permission` columns. The type-wide ones have no resource id.
+pub(crate) fn effective_operations_query(resource_type: ResourceType) -> String {
+    let table_name = resource_type.table_name();
+    format!(
+        "WITH g AS (SELECT p.* FROM {table_name}_permissions p \
+LEFT JOIN current_user_has_role ur ON ur.role_id = p.role_id \
+WHERE (p.user_id = $1 OR ur.user_id = $1)) \
+SELECT r.resource_id, {EFFECTIVE_OPERATIONS} AS permission FROM (SELECT DISTINCT resource_id FROM g) r \
+JOIN g p ON p.resource_id IS NULL OR p.resource_id = r.resource_id GROUP BY r.resource_id",
+    )
+}
+
+/// Ids of the resources whose own grants, applied over the type-wide ones, match `operations`.
 pub async fn get_permitted_ids<'a, T>(
     resource_type: ResourceType,
     operations: Operations,
@@ -152,57 +280,59 @@ where
     for<'r> T: sqlx::Decode<'r, Postgres> + sqlx::Type<Postgres>,
     T: Send + Unpin,
 {
-    let table_name = resource_type.table_name();
     sqlx::query_scalar::<_, T>(&format!(
-        "SELECT DISTINCT resource_id FROM {table_name}_permissions \
-LEFT JOIN user_has_role on user_has_role.role_id = {table_name}_permissions.role_id \
-WHERE (user_has_role.user_id = $1 OR {table_name}_permissions.user_id = $1) \
-AND ($2::bit(16) & permission) <> B'0'::bit(16)",
+        "SELECT e.resource_id FROM ({}) e WHERE e.resource_id IS NOT NULL \
+AND ($2::int::bit(16) & e.permission) <> B'0'::bit(16)",
+        effective_operations_query(resource_type),
     ))
     .bind(user.user_id)
-    .bind(operations)
+    .bind(operations & user.allowed_operations(resource_type))
     .fetch_all(db)
     .await
 }
 
 pub async fn can_create(
     resource_type: ResourceType,
-    user_id: i64,
+    user: &User,
     db: &PgPool,
 ) -> Result<bool, sqlx::Error> {
-    user_has_permissions_all(resource_type, Operations::CREATE, user_id, db).await
+    user_has_permissions_all(resource_type, Operations::CREATE, user, db).await
 }
 
 pub async fn can_delete(
     resource_type: ResourceType,
-    user_id: i64,
+    user: &User,
     db: &PgPool,
 ) -> Result<bool, sqlx::Error> {
-    user_has_permissions_all(resource_type, Operations::DELETE, user_id, db).await
+    user_has_permissions_all(resource_type, Operations::DELETE, user, db).await
 }
 
 pub async fn can_update(
     resource_type: ResourceType,
-    user_id: i64,
+    user: &User,
     db: &PgPool,
 ) -> Result<bool, sqlx::Error> {
-    user_has_permissions_all(resource_type, Operations::UPDATE, user_id, db).await
+    user_has_permissions_all(resource_type, Operations::UPDATE, user, db).await
 }
 
 pub async fn user_has_permissions_all(
     resource_type: ResourceType,
     operations: Operations,
-    user_id: i64,
+    user: &User,
     db: &PgPool,
 ) -> Result<bool, sqlx::Error> {
+    let operations = operations & user.allowed_operations(resource_type);
+    if operations == Operations::NONE {
+        return Ok(false);
+    }
     let table_name = resource_type.table_name();
     match sqlx::query_scalar::<_, i32>(&format!(
-        "SELECT 1 FROM {table_name}_permissions \
-LEFT JOIN user_has_role ON user_has_role.role_id = {table_name}_permissions.role_id \
-WHERE (user_has_role.user_id = $1 OR {table_name}_permissions.user_id = $1) \
-AND ($2::int::bit(16) & permission) <> B'0'::bit(16) AND resource_id IS NULL",
+        "SELECT 1 FROM {table_name}_permissions p \
+LEFT JOIN current_user_has_role ur ON ur.role_id = p.role_id \
+WHERE (ur.user_id = $1 OR p.user_id = $1) AND p.resource_id IS NULL \
+HAVING ($2::int::bit(16) & {EFFECTIVE_OPERATIONS}) <> B'0'::bit(16)",
     ))
-    .bind(user_id)
+    .bind(user.user_id)
     .bind(operations)
     .fetch_optional(db)
     .await
@@ -220,11 +350,15 @@ pub async fn user_has_permissions_id<
     resource_type: ResourceType,
     resource_id: &'a T,
     operations: Operations,
-    user_id: i64,
+    user: &User,
     db: &PgPool,
 ) -> Result<bool, sqlx::Error> {
+    let operations = operations & user.allowed_operations(resource_type);
+    if operations == Operations::NONE {
+        return Ok(false);
+    }
     match sqlx::query_scalar::<_, i32>(resource_type.permission_id_query())
-        .bind(user_id)
+        .bind(user.user_id)
         .bind(operations)
         .bind(resource_id)
         .fetch_optional(db)
@@ -235,3 +369,26 @@ pub async fn user_has_permissions_id<
         Err(e) => Err(e),
     }
 }
+
+/// Operations `user_id` holds on `resource_id`, or type-wide if it is `None`, combined from all
+/// grants of the user and their roles as [`EFFECTIVE_OPERATIONS`] describes. `resource_id` is the
+/// text form of the id.
+pub async fn held_operations(
+    resource_type: ResourceType,
+    resource_id: Option<&str>,
+    user_id: i64,
+    db: &PgPool,
+) -> Result<Operations, sqlx::Error> {
+    let table_name = resource_type.table_name();
+    let id_type = resource_type.id_type();
+    sqlx::query_scalar::<_, Operations>(&format!(
+        "SELECT {EFFECTIVE_OPERATIONS}::int::int2 FROM {table_name}_permissions p \
+LEFT JOIN current_user_has_role ur ON ur.role_id = p.role_id \
+WHERE (ur.user_id = $1 OR p.user_id = $1) \
+AND (p.resource_id IS NULL OR p.resource_id = $2::text::{id_type})",
+    ))
+    .bind(user_id)
+    .bind(resource_id)
+    .fetch_one(db)
+    .await
+}
@@ -0,0 +1,325 @@
+use std::{
+    collections::HashMap,
+    env,
+    fmt::Display,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::FromRequestParts,
+    http::{StatusCode, request::Parts},
+};
+use axum_login::AuthSession;
+use sqlx::PgPool;
+use tokio::sync::OnceCell;
+
+use super::{permission::Operations, token::Scope};
+use crate::{resources::Type as ResourceType, user::User};
+
+/// Operations a user holds on the resources of one type, combined from their own grants and
+/// those of their roles. Resources with grants of their own keep what results from applying
+/// them over the type-wide ones, which may deny some of those.
+#[derive(Debug)]
+pub struct Effective {
+    type_wide: Operations,
+    by_id: HashMap<String, Operations>,
+}
+
+impl Effective {
+    async fn load(
+        resource_type: ResourceType,
+        user_id: i64,
+        db: &PgPool,
+    ) -> Result<Self, sqlx::Error> {
+        let rows = sqlx::query_as::<_, (Option<String>, Operations)>(&format!(
+            "SELECT e.resource_id::text, e.permission::int::int2 FROM ({}) e",
+            super::effective_operations_query(resource_type),
+        ))
+        .bind(user_id)
+        .fetch_all(db)
+        .await?;
+
+        let mut effective = Self {
+            type_wide: Operations::NONE,
+            by_id: HashMap::with_capacity(rows.len()),
+        };
+        for (resource_id, ops) in rows {
+            match resource_id {
+                None => effective.type_wide = ops,
+                Some(id) => {
+                    let id = resource_type.normalize_id(&id).unwrap_or(id);
+                    effective.by_id.insert(id, ops);
+                }
+            }
+        }
+        Ok(effective)
+    }
+
+    /// Whether a type-wide grant matches `operations`, like [`super::user_has_permissions_all`].
+    pub fn allows_all(&self, operations: Operations) -> bool {
+        self.type_wide.intersects(operations)
+    }
+
+    /// Whether a grant on `resource_id` or a type-wide one matches `operations`, like
+    /// [`super::user_has_permissions_id`]. `resource_id` has to be normalized.
+    pub fn allows(&self, resource_id: &str, operations: Operations) -> bool {
+        self.operations(resource_id).intersects(operations)
+    }
+
+    /// Operations granted on `resource_id`, including type-wide ones. `resource_id` has to be
+    /// normalized.
+    pub fn operations(&self, resource_id: &str) -> Operations {
+        self.by_id
+            .get(resource_id)
+            .copied()
+            .unwrap_or(self.type_wide)
+    }
+
+    /// Ids of the resources with a grant that matches `operations`, not counting type-wide ones.
+    pub fn permitted_ids(&self, operations: Operations) -> impl Iterator<Item = &str> {
+        self.by_id
+            .iter()
+            .filter(move |(_, ops)| ops.intersects(operations))
+            .map(|(id, _)| id.as_str())
+    }
+
+    /// Ids of the resources whose grants leave none of `operations`, which excludes them from
+    /// what a type-wide grant allows.
+    pub fn denied_ids(&self, operations: Operations) -> impl Iterator<Item = &str> {
+        self.by_id
+            .iter()
+            .filter(move |(_, ops)| !ops.intersects(operations))
+            .map(|(id, _)| id.as_str())
+    }
+}
+
+struct Entry {
+    loaded_at: Instant,
+    generation: i64,
+    effective: Arc<Effective>,
+}
+
+/// Effective permissions shared between requests. Entries are dropped after the TTL and whenever
+/// the `permission_generation` counter moved, which triggers bump on every change of a grant or
+/// a role membership.
+/// Grants and memberships that start or run out do not move it, so with a TTL their
+/// [`Validity`](super::expiry::Validity) is applied up to the TTL late.
+pub struct PermissionCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<(i64, ResourceType), Entry>>,
+}
+
+impl PermissionCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Keeps permissions for `PERMISSION_CACHE_TTL` seconds, by default `0` which only caches them
+    /// for a single request.
+    pub fn from_env() -> Self {
+        let ttl = match env::var("PERMISSION_CACHE_TTL") {
+            Ok(secs) => Duration::from_secs(
+                secs.parse()
+                    .expect("PERMISSION_CACHE_TTL has to be a number of seconds"),
+            ),
+            Err(_) => Duration::ZERO,
+        };
+        Self::new(ttl)
+    }
+
+    fn get(&self, key: (i64, ResourceType), generation: i64) -> Option<Arc<Effective>> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(&key)
+            .filter(|e| e.generation == generation && e.loaded_at.elapsed() < self.ttl)
+            .map(|e| e.effective.clone())
+    }
+
+    fn insert(&self, key: (i64, ResourceType), generation: i64, effective: Arc<Effective>) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, e| e.generation == generation && e.loaded_at.elapsed() < self.ttl);
+        entries.insert(
+            key,
+            Entry {
+                loaded_at: Instant::now(),
+                generation,
+                effective,
+            },
+        );
+    }
+}
+
+/// Permissions of the logged in user, each resource type loaded once per request and limited to
+/// the scope of their token. Extracting it rejects requests without a user with 401.
+pub struct Permissions {
+    user_id: i64,
+    scope: Option<Scope>,
+    db: PgPool,
+    shared: Arc<PermissionCache>,
+    generation: OnceCell<i64>,
+    loaded: Mutex<HashMap<ResourceType, Arc<Effective>>>,
+}
+
+impl Permissions {
+    pub fn new(user: &User, state: &crate::AppState) -> Self {
+        Self {
+            user_id: user.user_id,
+            scope: user.scope.clone(),
+            db: state.db.clone(),
+            shared: state.permissions.clone(),
+            generation: OnceCell::new(),
+            loaded: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn user_id(&self) -> i64 {
+        self.user_id
+    }
+
+    /// Same as [`User::allowed_operations`], the checks below are limited to them.
+    pub fn allowed_operations(&self, resource_type: ResourceType) -> Operations {
+        match &self.scope {
+            Some(scope) => scope.operations_on(resource_type),
+            None => Operations::ALL,
+        }
+    }
+
+    pub async fn effective(
+        &self,
+        resource_type: ResourceType,
+    ) -> Result<Arc<Effective>, sqlx::Error> {
+        if let Some(effective) = self.loaded.lock().unwrap().get(&resource_type) {
+            return Ok(effective.clone());
+        }
+
+        let effective = if self.shared.ttl.is_zero() {
+            Arc::new(Effective::load(resource_type, self.user_id, &self.db).await?)
+        } else {
+            // read before loading, so a change that commits in between invalidates the entry
+            let generation = *self
+                .generation
+                .get_or_try_init(|| {
+                    sqlx::query_scalar::<_, i64>("SELECT \"value\" FROM permission_generation")
+                        .fetch_one(&self.db)
+                })
+                .await?;
+            let key = (self.user_id, resource_type);
+            match self.shared.get(key, generation) {
+                Some(effective) => effective,
+                None => {
+                    let effective =
+                        Arc::new(Effective::load(resource_type, self.user_id, &self.db).await?);
+                    self.shared.insert(key, generation, effective.clone());
+                    effective
+                }
+            }
+        };
+
+        self.loaded
+            .lock()
+            .unwrap()
+            .insert(resource_type, effective.clone());
+        Ok(effective)
+    }
+
+    /// Same as [`super::user_has_permissions_all`].
+    pub async fn has_all(
+        &self,
+        resource_type: ResourceType,
+        operations: Operations,
+    ) -> Result<bool, sqlx::Error> {
+        let operations = operations & self.allowed_operations(resource_type);
+        Ok(self.effective(resource_type).await?.allows_all(operations))
+    }
+
+    /// Same as [`super::user_has_permissions_id`].
+    pub async fn has_id(
+        &self,
+        resource_type: ResourceType,
+        resource_id: impl Display,
+        operations: Operations,
+    ) -> Result<bool, sqlx::Error> {
+        let Some(id) = resource_type.normalize_id(&resource_id.to_string()) else {
+            return Ok(false);
+        };
+        let operations = operations & self.allowed_operations(resource_type);
+        Ok(self.effective(resource_type).await?.allows(&id, operations))
+    }
+
+    /// Operations granted on `resource_id`, including type-wide ones.
+    pub async fn operations(
+        &self,
+        resource_type: ResourceType,
+        resource_id: impl Display,
+    ) -> Result<Operations, sqlx::Error> {
+        let Some(id) = resource_type.normalize_id(&resource_id.to_string()) else {
+            return Ok(Operations::NONE);
+        };
+        Ok(self.effective(resource_type).await?.operations(&id)
+            & self.allowed_operations(resource_type))
+    }
+
+    /// Ids of the resources of `resource_type` with a grant that matches `operations`, not counting
+    /// type-wide ones.
+    pub async fn permitted_ids<T: FromStr>(
+        &self,
+        resource_type: ResourceType,
+        operations: Operations,
+    ) -> Result<Vec<T>, sqlx::Error> {
+        let operations = operations & self.allowed_operations(resource_type);
+        Ok(self
+            .effective(resource_type)
+            .await?
+            .permitted_ids(operations)
+            .filter_map(|id| id.parse().ok())
+            .collect())
+    }
+
+    /// Ids of the resources of `resource_type` that grants on them exclude from a type-wide grant
+    /// of `operations`.
+    pub async fn denied_ids<T: FromStr>(
+        &self,
+        resource_type: ResourceType,
+        operations: Operations,
+    ) -> Result<Vec<T>, sqlx::Error> {
+        let operations = operations & self.allowed_operations(resource_type);
+        Ok(self
+            .effective(resource_type)
+            .await?
+            .denied_ids(operations)
+            .filter_map(|id| id.parse().ok())
+            .collect())
+    }
+
+    /// Same as [`super::can_create`].
+    pub async fn can_create(&self, resource_type: ResourceType) -> Result<bool, sqlx::Error> {
+        self.has_all(resource_type, Operations::CREATE).await
+    }
+
+    /// Same as [`super::can_delete`].
+    pub async fn can_delete(&self, resource_type: ResourceType) -> Result<bool, sqlx::Error> {
+        self.has_all(resource_type, Operations::DELETE).await
+    }
+}
+
+impl FromRequestParts<crate::AppState> for Permissions {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &crate::AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let auth_session = AuthSession::<super::Backend>::from_request_parts(parts, state)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        match auth_session.user {
+            Some(user) => Ok(Self::new(&user, state)),
+            None => Err(StatusCode::UNAUTHORIZED),
+        }
+    }
+}
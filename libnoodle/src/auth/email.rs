@@ -0,0 +1,306 @@
+use std::env;
+
+use sqlx::PgPool;
+use thiserror::Error;
+use uuid::Uuid;
+
+use super::token::hash;
+use crate::mail::{self, Mailer, Message};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("SQLX Error")]
+    Sqlx(#[from] sqlx::Error),
+    #[error("mail could not be sent")]
+    Mail(#[from] mail::Error),
+}
+
+/// What a token sent by mail is good for, the `email_token_purpose` enum.
+#[derive(sqlx::Type, Clone, Copy, Debug, PartialEq, Eq)]
+#[sqlx(type_name = "email_token_purpose", rename_all = "snake_case")]
+pub enum Purpose {
+    PasswordReset,
+    EmailVerification,
+}
+
+impl Purpose {
+    /// Seconds the token can be used for after it was sent.
+    fn validity(self) -> i64 {
+        match self {
+            Self::PasswordReset => 60 * 60,
+            Self::EmailVerification => 48 * 60 * 60,
+        }
+    }
+}
+
+/// Whether users have to verify their address before they can log in, `REQUIRE_EMAIL_VERIFICATION`.
+pub fn verification_required() -> bool {
+    env::var("REQUIRE_EMAIL_VERIFICATION").is_ok_and(|r| r == "true")
+}
+
+/// Link to the page of the frontend at `APP_URL` that takes the token.
+fn link(page: &str, token: &str) -> String {
+    let base = env::var("APP_URL").unwrap_or_else(|_| "http://localhost:3000".to_owned());
+    format!("{}/{page}?token={token}", base.trim_end_matches('/'))
+}
+
+/// Creates a token for `purpose`, which replaces those `user_id` got for it before.
+async fn issue(db: &PgPool, user_id: i64, purpose: Purpose) -> Result<String, sqlx::Error> {
+    let token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    let mut tx = db.begin().await?;
+    sqlx::query("DELETE FROM email_token WHERE user_id = $1 AND purpose = $2")
+        .bind(user_id)
+        .bind(purpose)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(
+        "INSERT INTO email_token(user_id, purpose, token_hash, expires_at) \
+VALUES ($1, $2, $3, now() + make_interval(secs => $4))",
+    )
+    .bind(user_id)
+    .bind(purpose)
+    .bind(hash(&token))
+    .bind(purpose.validity() as f64)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(token)
+}
+
+/// Uses up `token` and returns its user, `None` if it is unknown, for something else or expired.
+async fn redeem(
+    db: impl sqlx::PgExecutor<'_>,
+    token: &str,
+    purpose: Purpose,
+) -> Result<Option<i64>, sqlx::Error> {
+    let redeemed = sqlx::query_as::<_, (i64, bool)>(
+        "DELETE FROM email_token WHERE token_hash = $1 AND purpose = $2 \
+RETURNING user_id, expires_at > now()",
+    )
+    .bind(hash(token))
+    .bind(purpose)
+    .fetch_optional(db)
+    .await?;
+    Ok(redeemed.and_then(|(user_id, valid)| valid.then_some(user_id)))
+}
+
+/// Mails `email`, the address of `user_id`, a link to confirm it is theirs.
+pub async fn send_verification(
+    db: &PgPool,
+    mailer: &dyn Mailer,
+    user_id: i64,
+    email: &str,
+) -> Result<(), Error> {
+    let token = issue(db, user_id, Purpose::EmailVerification).await?;
+    mailer
+        .send(&Message {
+            to: email.to_owned(),
+            subject: "Confirm your email address".to_owned(),
+            body: format!(
+                "An account was created for you. Please confirm that this is your address by opening\n\n{}\n\nThe link is valid for 48 hours.\n",
+                link("verify-email", &token)
+            ),
+        })
+        .await?;
+    Ok(())
+}
+
+/// Marks the address of the user of `token` as verified, `false` if the token is not valid.
+pub async fn verify(db: &PgPool, token: &str) -> Result<bool, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    let Some(user_id) = redeem(&mut *tx, token, Purpose::EmailVerification).await? else {
+        return Ok(false);
+    };
+    sqlx::query("UPDATE \"user\" SET email_verified_at = now() WHERE id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Whether the address of `user_id` was verified, `None` if there is no such user.
+pub async fn verified(db: &PgPool, user_id: i64) -> Result<Option<bool>, sqlx::Error> {
+    sqlx::query_scalar::<_, bool>(
+        "SELECT email_verified_at IS NOT NULL FROM \"user\" WHERE id = $1",
+    )
+    .bind(user_id)
+    .fetch_optional(db)
+    .await
+}
+
+/// Mails the user with the address `email` a link to choose a new password. Nothing is sent if
+/// there is no such user, without telling so.
+pub async fn send_password_reset(
+    db: &PgPool,
+    mailer: &dyn Mailer,
+    email: &str,
+) -> Result<(), Error> {
+    let Some(user_id) = sqlx::query_scalar::<_, i64>("SELECT id FROM \"user\" WHERE email = $1")
+        .bind(email)
+        .fetch_optional(db)
+        .await?
+    else {
+        return Ok(());
+    };
+    let token = issue(db, user_id, Purpose::PasswordReset).await?;
+    mailer
+        .send(&Message {
+            to: email.to_owned(),
+            subject: "Reset your password".to_owned(),
+            body: format!(
+                "A new password was requested for your account. You can choose one at\n\n{}\n\nThe link is valid for an hour. If you did not ask for this, you can ignore this mail.\n",
+                link("reset-password", &token)
+            ),
+        })
+        .await?;
+    Ok(())
+}
+
+/// Sets the password of the user of `token` to `password_hash` and returns them, `None` if the
+/// token is not valid. Their address counts as verified as they got the mail.
+pub async fn reset_password(
+    db: &PgPool,
+    token: &str,
+    password_hash: &str,
+) -> Result<Option<i64>, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    let Some(user_id) = redeem(&mut *tx, token, Purpose::PasswordReset).await? else {
+        return Ok(None);
+    };
+    sqlx::query(
+        "UPDATE \"user\" SET password = $1, email_verified_at = COALESCE(email_verified_at, now()) \
+WHERE id = $2",
+    )
+    .bind(password_hash)
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(Some(user_id))
+}
+
+/// Removes the tokens that expired unused.
+pub(crate) async fn cleanup(db: impl sqlx::PgExecutor<'_>) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar::<_, String>(
+        "DELETE FROM email_token WHERE expires_at <= now() \
+RETURNING format('%s token of user %s', purpose, user_id)",
+    )
+    .fetch_all(db)
+    .await
+}
+
+pub mod http {
+    use axum::{
+        Json,
+        extract::{Path, State},
+        http::StatusCode,
+        response::{IntoResponse, Response},
+    };
+    use serde::Deserialize;
+
+    use crate::{
+        auth::{cache::Permissions, password, permission::Operations, session},
+        resources,
+        user::validation,
+    };
+
+    #[derive(Deserialize)]
+    pub struct ResetRequest {
+        email: String,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Reset {
+        token: String,
+        password: String,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Verification {
+        token: String,
+    }
+
+    /// Sends a password reset link if there is a user with the address. The answer is the same
+    /// either way, and comes before the mail is sent, to not give away who has an account.
+    pub async fn request_password_reset(
+        State(state): State<crate::AppState>,
+        Json(request): Json<ResetRequest>,
+    ) -> StatusCode {
+        tokio::spawn(async move {
+            if let Err(e) =
+                super::send_password_reset(&state.db, &*state.mailer, &request.email).await
+            {
+                tracing::warn!("password reset mail failed: {e}");
+            }
+        });
+        StatusCode::ACCEPTED
+    }
+
+    /// Sets a new password with the token of a reset link, and ends the sessions of the user.
+    pub async fn reset_password(
+        State(state): State<crate::AppState>,
+        Json(reset): Json<Reset>,
+    ) -> Response {
+        let errors = validation::password(&reset.password);
+        if !errors.is_valid() {
+            return (StatusCode::BAD_REQUEST, Json(errors)).into_response();
+        }
+        let Ok(Ok(password_hash)) =
+            tokio::task::spawn_blocking(move || password::hash(&reset.password)).await
+        else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        match super::reset_password(&state.db, &reset.token, &password_hash).await {
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            Ok(None) => StatusCode::UNAUTHORIZED.into_response(),
+            Ok(Some(user_id)) => match session::revoke(&state.db, user_id, None, None).await {
+                Ok(_) => StatusCode::OK.into_response(),
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            },
+        }
+    }
+
+    pub async fn verify(
+        State(state): State<crate::AppState>,
+        Json(verification): Json<Verification>,
+    ) -> StatusCode {
+        match super::verify(&state.db, &verification.token).await {
+            Ok(true) => StatusCode::OK,
+            Ok(false) => StatusCode::UNAUTHORIZED,
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Sends the verification mail again, which takes the permission to update the user.
+    pub async fn resend_verification(
+        permissions: Permissions,
+        Path(user_id): Path<i64>,
+        State(state): State<crate::AppState>,
+    ) -> StatusCode {
+        match permissions
+            .has_id(resources::Type::User, user_id, Operations::UPDATE)
+            .await
+        {
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
+            Ok(false) => return StatusCode::UNAUTHORIZED,
+            Ok(true) => {}
+        }
+        let email = match sqlx::query_as::<_, (String, bool)>(
+            "SELECT email, email_verified_at IS NOT NULL FROM \"user\" WHERE id = $1",
+        )
+        .bind(user_id)
+        .fetch_optional(&state.db)
+        .await
+        {
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
+            Ok(None) => return StatusCode::NOT_FOUND,
+            Ok(Some((_, true))) => return StatusCode::CONFLICT,
+            Ok(Some((email, false))) => email,
+        };
+        match super::send_verification(&state.db, &*state.mailer, user_id, &email).await {
+            Ok(_) => StatusCode::OK,
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
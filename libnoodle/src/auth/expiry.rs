@@ -0,0 +1,141 @@
+use std::{env, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use super::permission::{GRANTABLE_TYPES, Operations, RoleRow};
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// When a grant or a membership applies: from `valid_from` on and before `valid_until`, without
+/// a bound on the sides that are `None`. Outside of it the row stays in place but the permission
+/// checks ignore it, until [`cleanup`] removes it once it ran out.
+#[derive(
+    Serialize, Deserialize, sqlx::FromRow, Hash, PartialEq, Eq, Clone, Copy, Default, Debug,
+)]
+#[serde(rename_all = "camelCase")]
+pub struct Validity {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub valid_from: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub valid_until: Option<DateTime<Utc>>,
+}
+
+impl Validity {
+    pub const ALWAYS: Validity = Validity {
+        valid_from: None,
+        valid_until: None,
+    };
+
+    /// Whether the window is not empty, which the tables check as well.
+    pub fn is_valid(&self) -> bool {
+        match (self.valid_from, self.valid_until) {
+            (Some(from), Some(until)) => from < until,
+            _ => true,
+        }
+    }
+}
+
+/// Removes the grants and memberships whose `valid_until` has passed, along with the records of
+/// sessions that ended and the access tokens and mailed tokens that expired, and describes each
+/// of them.
+pub async fn cleanup(db: &PgPool) -> Result<Vec<String>, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    let mut removed = sqlx::query_scalar::<_, String>(
+        "DELETE FROM user_has_role WHERE valid_until <= now() \
+RETURNING format('role %s of user %s', role_id, user_id)",
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    removed.extend(
+        sqlx::query_scalar::<_, String>(
+            "DELETE FROM user_in_group WHERE valid_until <= now() \
+RETURNING format('group %s of user %s', group_id, user_id)",
+        )
+        .fetch_all(&mut *tx)
+        .await?,
+    );
+    removed.extend(
+        sqlx::query_scalar::<_, String>(
+            "DELETE FROM course_user WHERE valid_until <= now() \
+RETURNING format('user %s as %s of course %s', user_id, role, course_id)",
+        )
+        .fetch_all(&mut *tx)
+        .await?,
+    );
+
+    let mut expired_roles = Vec::new();
+    for resource_type in GRANTABLE_TYPES {
+        let table_name = resource_type.table_name();
+        let grants = sqlx::query_as::<
+            _,
+            (Option<i64>, Option<i64>, Option<String>, Operations, bool),
+        >(&format!(
+            "DELETE FROM {table_name}_permissions WHERE valid_until <= now() \
+RETURNING user_id, role_id, resource_id::text, permission::int::int2, deny",
+        ))
+        .fetch_all(&mut *tx)
+        .await?;
+        expired_roles.extend(grants.iter().filter(|g| g.0.is_none()).filter_map(|g| g.1));
+        removed.extend(
+            grants
+                .into_iter()
+                .map(|(user_id, role_id, resource_id, ops, deny)| {
+                    format!(
+                        "{} of {} on {table_name} {} to {}",
+                        if deny { "deny" } else { "grant" },
+                        ops.names().collect::<Vec<_>>().join(","),
+                        resource_id.as_deref().unwrap_or("*"),
+                        match (user_id, role_id) {
+                            (Some(id), _) => format!("user {id}"),
+                            (None, Some(id)) => format!("role {id}"),
+                            (None, None) => "nobody".to_owned(),
+                        },
+                    )
+                }),
+        );
+    }
+    removed.extend(super::session::cleanup(&mut *tx).await?);
+    removed.extend(super::token::cleanup(&mut *tx).await?);
+    removed.extend(super::email::cleanup(&mut *tx).await?);
+
+    // keep the permissions of the roles in line with their remaining grants
+    expired_roles.sort_unstable();
+    expired_roles.dedup();
+    for role_id in expired_roles {
+        RoleRow::refresh_permissions(&mut tx, role_id).await?;
+    }
+    tx.commit().await?;
+    Ok(removed)
+}
+
+/// Runs [`cleanup`] every `EXPIRY_CLEANUP_INTERVAL` seconds, by default once an hour, `0` disables
+/// it. Each removed entry is logged at info level, a failed run as a warning.
+pub fn spawn_from_env(db: PgPool) {
+    let interval = match env::var("EXPIRY_CLEANUP_INTERVAL") {
+        Ok(secs) => Duration::from_secs(
+            secs.parse()
+                .expect("EXPIRY_CLEANUP_INTERVAL has to be a number of seconds"),
+        ),
+        Err(_) => DEFAULT_INTERVAL,
+    };
+    if interval.is_zero() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            match cleanup(&db).await {
+                Ok(removed) => {
+                    for entry in removed {
+                        tracing::info!("expired: {entry}");
+                    }
+                }
+                Err(e) => tracing::warn!("cleanup of expired records failed: {e}"),
+            }
+        }
+    });
+}
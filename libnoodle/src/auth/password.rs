@@ -0,0 +1,166 @@
+use std::env;
+
+use argon2::{
+    Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version,
+    password_hash::{SaltString, rand_core::OsRng},
+};
+
+/// How a password compares to the hash stored for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    Wrong,
+    Valid,
+    /// The password is right, but its hash should be replaced by one from [`hash`] as it uses an
+    /// older algorithm or other parameters.
+    Outdated,
+}
+
+fn cost(var: &str, default: u32) -> u32 {
+    match env::var(var) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("{var} has to be a positive number")),
+        Err(_) => default,
+    }
+}
+
+/// Argon2id parameters for new hashes, `ARGON2_MEMORY_KIB`, `ARGON2_ITERATIONS` and
+/// `ARGON2_PARALLELISM`, by default those recommended by the argon2 crate.
+fn params() -> Params {
+    Params::new(
+        cost("ARGON2_MEMORY_KIB", Params::DEFAULT_M_COST),
+        cost("ARGON2_ITERATIONS", Params::DEFAULT_T_COST),
+        cost("ARGON2_PARALLELISM", Params::DEFAULT_P_COST),
+        None,
+    )
+    .expect("the ARGON2_ variables are out of range")
+}
+
+/// Hashes `password` with argon2id. This takes a while, so it belongs in a blocking task.
+pub fn hash(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params())
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+/// Checks `password` against `stored`, an argon2 hash or a bcrypt one from before argon2 was
+/// used. This takes a while, so it belongs in a blocking task.
+pub fn verify(password: &str, stored: &str) -> Verification {
+    if stored.starts_with("$2") {
+        return match bcrypt::verify(password, stored) {
+            Ok(true) => Verification::Outdated,
+            _ => Verification::Wrong,
+        };
+    }
+    let Ok(parsed) = PasswordHash::new(stored) else {
+        return Verification::Wrong;
+    };
+    if Argon2::default()
+        .verify_password(password.as_bytes(), &parsed)
+        .is_err()
+    {
+        return Verification::Wrong;
+    }
+    let current = params();
+    let up_to_date = parsed.algorithm == Algorithm::Argon2id.ident()
+        && parsed.version == Some(Version::V0x13.into())
+        && Params::try_from(&parsed).is_ok_and(|p| {
+            p.m_cost() == current.m_cost()
+                && p.t_cost() == current.t_cost()
+                && p.p_cost() == current.p_cost()
+        });
+    if up_to_date {
+        Verification::Valid
+    } else {
+        Verification::Outdated
+    }
+}
+
+pub mod http {
+    use axum::{
+        Json,
+        extract::State,
+        http::StatusCode,
+        response::{IntoResponse, Response},
+    };
+    use axum_login::{AuthSession, tower_sessions::Session};
+    use serde::Deserialize;
+
+    use super::Verification;
+    use crate::{
+        auth::{Backend, session},
+        user::{User, validation},
+    };
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Change {
+        current_password: String,
+        new_password: String,
+    }
+
+    /// Sets a new password in exchange for the current one. The other sessions of the user end,
+    /// the one of the request goes on. Only with a login, not with an access token.
+    pub async fn change(
+        mut auth_session: AuthSession<Backend>,
+        session: Session,
+        State(state): State<crate::AppState>,
+        Json(change): Json<Change>,
+    ) -> Response {
+        let user = auth_session.user.clone().unwrap();
+        if user.scope.is_some() {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+        let errors = validation::password(&change.new_password);
+        if !errors.is_valid() {
+            return (StatusCode::BAD_REQUEST, Json(errors)).into_response();
+        }
+        let stored = String::from_utf8_lossy(&user.password).into_owned();
+        let hashed = tokio::task::spawn_blocking(move || {
+            match super::verify(&change.current_password, &stored) {
+                Verification::Wrong => Ok(None),
+                _ => super::hash(&change.new_password).map(Some),
+            }
+        })
+        .await;
+        let password_hash = match hashed {
+            Ok(Ok(Some(password_hash))) => password_hash,
+            Ok(Ok(None)) => return StatusCode::FORBIDDEN.into_response(),
+            _ => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+        if sqlx::query("UPDATE \"user\" SET password = $1 WHERE id = $2")
+            .bind(&password_hash)
+            .bind(user.user_id)
+            .execute(&state.db)
+            .await
+            .is_err()
+        {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+        let Some(current) = session.id().map(|id| id.to_string()) else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        if session::revoke(&state.db, user.user_id, None, Some(&current))
+            .await
+            .is_err()
+        {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+        // the session is bound to the password hash, so it has to be logged in with the new one
+        let user = User {
+            password: password_hash.into(),
+            ..user
+        };
+        if auth_session.login(&user).await.is_err() || session.save().await.is_err() {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+        let Some(renewed) = session.id() else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        match session::rename(&state.db, &current, &renewed.to_string()).await {
+            Ok(_) => StatusCode::OK.into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+}
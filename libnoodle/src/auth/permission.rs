@@ -1,27 +1,85 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use const_format::concatcp;
 use serde::{Deserialize, Serialize};
 use sqlx::{Decode, PgPool, Postgres, error::ErrorKind};
 use thiserror::Error;
 
-use crate::resources;
+use super::expiry::Validity;
+use crate::{
+    resources::{self, course::CourseRole},
+    user::{self, User},
+};
 
 #[derive(Serialize, Deserialize, sqlx::Type, Hash, PartialEq, Eq)]
 pub struct Permission {
     pub(super) subject: resources::Type,
     pub(super) ops: Operations,
     pub(super) ids: Option<Vec<String>>,
+    /// Takes the operations away instead of granting them
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(super) deny: bool,
+    /// When the grants apply, see [`Validity`]
+    #[serde(rename = "validFrom", default, skip_serializing_if = "Option::is_none")]
+    pub(super) valid_from: Option<DateTime<Utc>>,
+    #[serde(
+        rename = "validUntil",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(super) valid_until: Option<DateTime<Utc>>,
+}
+
+impl Permission {
+    fn validity(&self) -> Validity {
+        Validity {
+            valid_from: self.valid_from,
+            valid_until: self.valid_until,
+        }
+    }
 }
 
+/// Aggregates the grant rows `p` that apply to one user into the operations they hold.
+///
+/// Grants are applied from the most general to the most specific one: grants of a role before the
+/// user's own, type-wide grants before those on a single resource, and allows before denies on the
+/// same level. Each grant overrides what the ones before it said about its operations, so a user's
+/// own grant beats every grant of their roles, even one on the resource, and a deny on a resource
+/// beats a type-wide allow. Grants outside of their [`Validity`] window are skipped.
+pub const EFFECTIVE_OPERATIONS: &str = "effective_permission(p.permission, p.deny \
+ORDER BY p.user_id IS NOT NULL, p.resource_id IS NOT NULL, p.deny) \
+FILTER (WHERE is_current(p.valid_from, p.valid_until))";
+
 #[derive(Deserialize)]
 pub struct PermissionQueryParam {
     pub edit: Option<bool>,
     pub view: Option<bool>,
 }
 
-#[derive(Serialize, Deserialize, sqlx::Type, Hash, PartialEq, Eq, Clone, Copy)]
+/// Operations on a resource as the bits of the `BIT(16)` permission columns. Serialized as the
+/// list of their names, e.g. `["read", "update"]`; a comma-separated string of names and the raw
+/// integer are accepted as well.
+#[derive(sqlx::Type, Hash, PartialEq, Eq, Clone, Copy, Debug)]
 #[sqlx(transparent)]
-#[serde(transparent)]
 pub struct Operations(i16);
 
+impl std::ops::BitOr for Operations {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl std::ops::BitAnd for Operations {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
 impl From<i16> for Operations {
     fn from(value: i16) -> Self {
         Self(value)
@@ -34,7 +92,32 @@ impl Operations {
     pub const READ: Operations = Self(0b00000010);
     pub const UPDATE: Operations = Self(0b00000100);
     pub const DELETE: Operations = Self(0b00001000);
+    /// Hide and reveal content
+    pub const PUBLISH: Operations = Self(0b00010000);
+    pub const GRADE: Operations = Self(0b00100000);
+    /// Add and remove the members of a course
+    pub const ENROLL: Operations = Self(0b01000000);
+    /// Grant and revoke operations on the resource, or hand out roles that come with them
+    pub const MANAGE_PERMISSIONS: Operations = Self(0b10000000);
+    /// Take content out of the platform as a copy
+    pub const EXPORT: Operations = Self(0b1_00000000);
+    pub const NONE: Operations = Self(0);
+    pub const ALL: Operations = Self(0b1_11111111);
+
+    /// Every operation with its name in the serialized form.
+    pub const NAMED: [(&'static str, Operations); 9] = [
+        ("create", Self::CREATE),
+        ("read", Self::READ),
+        ("update", Self::UPDATE),
+        ("delete", Self::DELETE),
+        ("publish", Self::PUBLISH),
+        ("grade", Self::GRADE),
+        ("enroll", Self::ENROLL),
+        ("managePermissions", Self::MANAGE_PERMISSIONS),
+        ("export", Self::EXPORT),
+    ];
 
+    /// CRUD operations, combine them with the others through `|`.
     pub fn new(create: bool, read: bool, update: bool, delete: bool) -> Self {
         Self(create as i16 | (read as i16) << 1 | (update as i16) << 2 | (delete as i16) << 3)
     }
@@ -51,6 +134,531 @@ impl Operations {
     pub fn can_delete(&self) -> bool {
         (Self::DELETE.0 & self.0) != 0
     }
+    pub fn can_publish(&self) -> bool {
+        (Self::PUBLISH.0 & self.0) != 0
+    }
+    pub fn can_grade(&self) -> bool {
+        (Self::GRADE.0 & self.0) != 0
+    }
+    pub fn can_enroll(&self) -> bool {
+        (Self::ENROLL.0 & self.0) != 0
+    }
+    pub fn can_manage_permissions(&self) -> bool {
+        (Self::MANAGE_PERMISSIONS.0 & self.0) != 0
+    }
+    pub fn can_export(&self) -> bool {
+        (Self::EXPORT.0 & self.0) != 0
+    }
+
+    /// Whether all of `other` are part of these operations.
+    pub fn contains(&self, other: Operations) -> bool {
+        (self.0 & other.0) == other.0
+    }
+
+    /// Whether any of `other` is part of these operations, which is how grants are matched.
+    pub fn intersects(&self, other: Operations) -> bool {
+        (self.0 & other.0) != 0
+    }
+
+    /// Whether only the known operation bits are set.
+    pub fn is_valid(&self) -> bool {
+        Self::ALL.contains(*self)
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::NAMED
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, ops)| *ops)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &'static str> {
+        Self::NAMED
+            .into_iter()
+            .filter(|(_, ops)| self.contains(*ops))
+            .map(|(name, _)| name)
+    }
+}
+
+impl std::str::FromStr for Operations {
+    type Err = String;
+
+    /// Parses a comma-separated list of names or an integer.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(bits) = s.parse::<i16>() {
+            return Ok(Self(bits));
+        }
+        s.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .try_fold(Self::NONE, |ops, name| {
+                Self::from_name(name)
+                    .map(|o| ops | o)
+                    .ok_or_else(|| format!("unknown operation `{name}`"))
+            })
+    }
+}
+
+impl Serialize for Operations {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.names())
+    }
+}
+
+impl<'de> Deserialize<'de> for Operations {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = Operations;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a list of operation names")
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Operations, E> {
+                i16::try_from(v)
+                    .map(Operations)
+                    .map_err(|_| E::custom("operations out of range"))
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Operations, E> {
+                i16::try_from(v)
+                    .map(Operations)
+                    .map_err(|_| E::custom("operations out of range"))
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Operations, E> {
+                v.parse().map_err(E::custom)
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<Operations, A::Error> {
+                let mut ops = Operations::NONE;
+                while let Some(name) = seq.next_element::<std::borrow::Cow<str>>()? {
+                    ops = ops
+                        | Operations::from_name(&name).ok_or_else(|| {
+                            serde::de::Error::custom(format!("unknown operation `{name}`"))
+                        })?;
+                }
+                Ok(ops)
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+/// Operations granted to a user or a role on one resource, or on all resources of a type if
+/// `resource_id` is `None`. A row of one of the `*_permissions` tables. Deny grants take the
+/// operations away again, see [`EFFECTIVE_OPERATIONS`] for which grant wins.
+#[derive(Serialize, Deserialize, sqlx::FromRow, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Grant {
+    pub user_id: Option<i64>,
+    pub role_id: Option<i64>,
+    /// Id of the resource as text, file ids in the simple uuid format
+    pub resource_id: Option<String>,
+    pub operations: Operations,
+    #[serde(default)]
+    pub deny: bool,
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub validity: Validity,
+}
+
+/// Selects the grants of a user or a role, on a resource or type-wide. `deny` picks the deny
+/// grant instead of the allowing one when setting or revoking, listing returns both.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GrantTarget {
+    pub user_id: Option<i64>,
+    pub role_id: Option<i64>,
+    pub resource_id: Option<String>,
+    #[serde(default)]
+    pub deny: bool,
+}
+
+#[derive(Debug, Error)]
+pub enum GrantError {
+    #[error("SQLX Error")]
+    Sqlx(#[from] sqlx::Error),
+    #[error("malformed parameters")]
+    BadParam,
+    #[error("grantee or resource does not exist")]
+    NotFound,
+    #[error("access denied")]
+    AccessDenied,
+}
+
+impl GrantTarget {
+    /// Checks that exactly one grantee is given and normalizes the resource id.
+    pub fn validate(mut self, resource_type: resources::Type) -> Result<Self, GrantError> {
+        if self.user_id.is_some() == self.role_id.is_some() {
+            return Err(GrantError::BadParam);
+        }
+        if let Some(id) = &self.resource_id {
+            self.resource_id = Some(resource_type.normalize_id(id).ok_or(GrantError::BadParam)?);
+        }
+        Ok(self)
+    }
+
+    /// Operations currently granted to the target.
+    async fn granted(
+        &self,
+        resource_type: resources::Type,
+        conn: &mut sqlx::PgConnection,
+    ) -> Result<Operations, sqlx::Error> {
+        let table_name = resource_type.table_name();
+        let id_type = resource_type.id_type();
+        sqlx::query_scalar::<_, Operations>(&format!(
+            "SELECT COALESCE(BIT_OR(permission), B'0'::bit(16))::int::int2 FROM {table_name}_permissions \
+WHERE user_id IS NOT DISTINCT FROM $1 AND role_id IS NOT DISTINCT FROM $2 \
+AND resource_id IS NOT DISTINCT FROM $3::text::{id_type} AND deny = $4",
+        ))
+        .bind(self.user_id)
+        .bind(self.role_id)
+        .bind(&self.resource_id)
+        .bind(self.deny)
+        .fetch_one(conn)
+        .await
+    }
+
+    /// Replaces the operations granted to the target with `operations` for `validity`, removing
+    /// the grant if they are empty. `granter` has to be allowed to manage the permissions of the
+    /// resource and hold both the operations granted so far and the new ones, so nobody hands out
+    /// or takes away rights they do not hold themselves. The same holds for deny grants. Returns
+    /// the grant as it was before.
+    pub async fn set(
+        &self,
+        resource_type: resources::Type,
+        operations: Operations,
+        validity: Validity,
+        granter: &User,
+        db: &PgPool,
+    ) -> Result<Operations, GrantError> {
+        if !operations.is_valid() || !validity.is_valid() {
+            return Err(GrantError::BadParam);
+        }
+        // a deny only takes rights away, so whoever manages the permissions of the whole type may
+        // lift one on themselves even if it covers operations they no longer hold
+        let own_deny_revoke =
+            self.deny && self.user_id == Some(granter.user_id) && operations == Operations::NONE;
+        let held = super::held_operations(
+            resource_type,
+            self.resource_id.as_deref().filter(|_| !own_deny_revoke),
+            granter.user_id,
+            db,
+        )
+        .await?
+            & granter.allowed_operations(resource_type);
+
+        let table_name = resource_type.table_name();
+        let id_type = resource_type.id_type();
+        let mut tx = db.begin().await?;
+        // serialises changes of the grant, the table has no key to conflict on
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1))")
+            .bind(format!("{table_name}_permissions"))
+            .execute(&mut *tx)
+            .await?;
+        let granted = self.granted(resource_type, &mut tx).await?;
+        if !held.can_manage_permissions()
+            || (!own_deny_revoke && !held.contains(granted | operations))
+        {
+            return Err(GrantError::AccessDenied);
+        }
+
+        sqlx::query(&format!(
+            "DELETE FROM {table_name}_permissions \
+WHERE user_id IS NOT DISTINCT FROM $1 AND role_id IS NOT DISTINCT FROM $2 \
+AND resource_id IS NOT DISTINCT FROM $3::text::{id_type} AND deny = $4",
+        ))
+        .bind(self.user_id)
+        .bind(self.role_id)
+        .bind(&self.resource_id)
+        .bind(self.deny)
+        .execute(&mut *tx)
+        .await?;
+        if operations != Operations::NONE {
+            let inserted = sqlx::query(&format!(
+                "INSERT INTO {table_name}_permissions\
+(user_id, role_id, resource_id, permission, deny, valid_from, valid_until) \
+VALUES ($1, $2, $3::text::{id_type}, $4::int::bit(16), $5, $6, $7)",
+            ))
+            .bind(self.user_id)
+            .bind(self.role_id)
+            .bind(&self.resource_id)
+            .bind(operations)
+            .bind(self.deny)
+            .bind(validity.valid_from)
+            .bind(validity.valid_until)
+            .execute(&mut *tx)
+            .await;
+            match inserted {
+                Err(sqlx::Error::Database(e)) if e.kind() == ErrorKind::ForeignKeyViolation => {
+                    return Err(GrantError::NotFound);
+                }
+                Err(e) => return Err(e.into()),
+                Ok(_) => {}
+            }
+        }
+        if let Some(role_id) = self.role_id {
+            RoleRow::refresh_permissions(&mut tx, role_id).await?;
+        }
+        tx.commit().await?;
+        Ok(granted)
+    }
+}
+
+impl Grant {
+    /// Grants on resources of `resource_type` whose permissions `user` may manage, optionally
+    /// only those matching `filter`.
+    pub async fn list(
+        resource_type: resources::Type,
+        filter: &GrantTarget,
+        user: &User,
+        db: &PgPool,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let table_name = resource_type.table_name();
+        let id_type = resource_type.id_type();
+        let mut grants = sqlx::query_as::<_, Grant>(&format!(
+            "SELECT g.user_id, g.role_id, g.resource_id::text AS resource_id, \
+g.permission::int::int2 AS operations, g.deny, g.valid_from, g.valid_until \
+FROM {table_name}_permissions g \
+WHERE ($2::int8 IS NULL OR g.user_id = $2) \
+AND ($3::int8 IS NULL OR g.role_id = $3) \
+AND ($4::text IS NULL OR g.resource_id = $4::text::{id_type}) \
+AND ($5::int::bit(16) & (SELECT {EFFECTIVE_OPERATIONS} FROM {table_name}_permissions p \
+LEFT JOIN current_user_has_role ur ON ur.role_id = p.role_id \
+WHERE (p.user_id = $1 OR ur.user_id = $1) \
+AND (p.resource_id IS NULL OR p.resource_id = g.resource_id))) <> B'0'::bit(16) \
+ORDER BY g.resource_id NULLS FIRST, g.role_id, g.user_id, g.deny",
+        ))
+        .bind(user.user_id)
+        .bind(filter.user_id)
+        .bind(filter.role_id)
+        .bind(&filter.resource_id)
+        .bind(Operations::MANAGE_PERMISSIONS & user.allowed_operations(resource_type))
+        .fetch_all(db)
+        .await?;
+        for grant in &mut grants {
+            grant.resource_id = grant
+                .resource_id
+                .as_deref()
+                .and_then(|id| resource_type.normalize_id(id));
+        }
+        Ok(grants)
+    }
+}
+
+/// Whether a grant applies to a user directly or through one of their roles.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum GrantSource {
+    User,
+    Role,
+}
+
+/// Whether a grant covers all resources of a type or a single one.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum GrantScope {
+    Type,
+    Resource,
+}
+
+/// A grant that applies to the user asked about in an [`Explanation`].
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ExplainedGrant {
+    pub source: GrantSource,
+    pub scope: GrantScope,
+    pub role_id: Option<i64>,
+    pub role_name: Option<String>,
+    pub resource_id: Option<String>,
+    pub operations: Operations,
+    pub deny: bool,
+    #[serde(flatten)]
+    pub validity: Validity,
+    /// Whether the grant shares an operation with the ones asked about, which is what the
+    /// permission checks require.
+    pub matches: bool,
+}
+
+/// Read access a user has without a grant, through the courses they take part in.
+#[derive(Serialize, Debug)]
+#[serde(
+    tag = "kind",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum Fallback {
+    /// The user is a lecturer of the course, which lets them edit its content.
+    CourseLecturer { course_id: i64 },
+    /// The user was added to the course with `role`.
+    CourseUser { course_id: i64, role: CourseRole },
+    /// The user is in a group that was added to the course, or in one of its sub-groups, and
+    /// studies it.
+    CourseGroup {
+        course_id: i64,
+        group_id: i64,
+        group_name: String,
+    },
+    /// The file is attached to content of a course the user may read.
+    EmbeddedInCourse { course_id: i64, content_id: i64 },
+}
+
+/// Why a user may or may not perform operations on a resource. The grants are listed in the
+/// order they apply, later ones override earlier ones.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Explanation {
+    pub granted: bool,
+    pub grants: Vec<ExplainedGrant>,
+    pub fallbacks: Vec<Fallback>,
+}
+
+impl Explanation {
+    /// Explains the access of `user_id` to `resource_id` of `resource_type`, or type-wide if it is
+    /// `None`, the same way the permission checks decide it. `resource_id` must be normalized.
+    pub async fn build(
+        resource_type: resources::Type,
+        resource_id: Option<&str>,
+        operations: Operations,
+        user_id: i64,
+        db: &PgPool,
+    ) -> Result<Self, sqlx::Error> {
+        let table_name = resource_type.table_name();
+        let id_type = resource_type.id_type();
+        let rows = sqlx::query_as::<
+            _,
+            (
+                Option<i64>,
+                Option<String>,
+                Option<String>,
+                Operations,
+                bool,
+                Option<DateTime<Utc>>,
+                Option<DateTime<Utc>>,
+            ),
+        >(&format!(
+            "SELECT p.role_id, r.name AS role_name, p.resource_id::text AS resource_id, \
+p.permission::int::int2 AS operations, p.deny, p.valid_from, p.valid_until \
+FROM {table_name}_permissions p \
+LEFT JOIN \"role\" r ON r.id = p.role_id \
+WHERE (p.user_id = $1 OR p.role_id IN (SELECT role_id FROM current_user_has_role WHERE user_id = $1)) \
+AND (p.resource_id IS NULL OR p.resource_id = $2::text::{id_type}) \
+AND is_current(p.valid_from, p.valid_until) \
+ORDER BY p.user_id IS NOT NULL, p.resource_id IS NOT NULL, p.deny, p.role_id",
+        ))
+        .bind(user_id)
+        .bind(resource_id)
+        .fetch_all(db)
+        .await?;
+        let grants: Vec<_> = rows
+            .into_iter()
+            .map(
+                |(role_id, role_name, resource_id, ops, deny, valid_from, valid_until)| {
+                    ExplainedGrant {
+                        source: match role_id {
+                            Some(_) => GrantSource::Role,
+                            None => GrantSource::User,
+                        },
+                        scope: match resource_id {
+                            Some(_) => GrantScope::Resource,
+                            None => GrantScope::Type,
+                        },
+                        role_id,
+                        role_name,
+                        resource_id: resource_id.and_then(|id| resource_type.normalize_id(&id)),
+                        operations: ops,
+                        deny,
+                        validity: Validity {
+                            valid_from,
+                            valid_until,
+                        },
+                        matches: ops.intersects(operations),
+                    }
+                },
+            )
+            .collect();
+        let held = super::held_operations(resource_type, resource_id, user_id, db).await?;
+
+        let mut fallbacks = Vec::new();
+        // memberships give what their course role stands for on a single course, and read access
+        // to what it embeds
+        if let Some(id) = resource_id {
+            match resource_type {
+                resources::Type::Course => {
+                    let course_id: i64 = id.parse().unwrap_or_default();
+                    if CourseRole::Lecturer.operations().intersects(operations)
+                        && sqlx::query_scalar::<_, i32>(
+                            "SELECT 1 FROM course_lecturer WHERE course_id = $2 AND user_id = $1",
+                        )
+                        .bind(user_id)
+                        .bind(course_id)
+                        .fetch_optional(db)
+                        .await?
+                        .is_some()
+                    {
+                        fallbacks.push(Fallback::CourseLecturer { course_id });
+                    }
+                    if let Some(role) = sqlx::query_scalar::<_, CourseRole>(
+                        "SELECT role FROM course_user WHERE course_id = $2 AND user_id = $1 \
+AND is_current(valid_from, valid_until)",
+                    )
+                    .bind(user_id)
+                    .bind(course_id)
+                    .fetch_optional(db)
+                    .await?
+                        && role.operations().intersects(operations)
+                    {
+                        fallbacks.push(Fallback::CourseUser { course_id, role });
+                    }
+                    let groups = if CourseRole::Student.operations().intersects(operations) {
+                        sqlx::query_as::<_, (i64, String)>(
+                            "SELECT g.id, g.name FROM course_group cg \
+JOIN effective_user_in_group uig ON uig.group_id = cg.group_id \
+JOIN \"group\" g ON g.id = cg.group_id \
+WHERE cg.course_id = $2 AND uig.user_id = $1 ORDER BY g.id",
+                        )
+                        .bind(user_id)
+                        .bind(course_id)
+                        .fetch_all(db)
+                        .await?
+                    } else {
+                        Vec::new()
+                    };
+                    fallbacks.extend(groups.into_iter().map(|(group_id, group_name)| {
+                        Fallback::CourseGroup {
+                            course_id,
+                            group_id,
+                            group_name,
+                        }
+                    }));
+                }
+                resources::Type::File if operations.contains(Operations::READ) => {
+                    let uid = uuid::Uuid::parse_str(id).unwrap_or_default();
+                    let embeddings = resources::file::readable_embeddings(db, uid, user_id).await?;
+                    fallbacks.extend(embeddings.into_iter().map(|(course_id, content_id)| {
+                        Fallback::EmbeddedInCourse {
+                            course_id,
+                            content_id,
+                        }
+                    }));
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            granted: held.intersects(operations) || !fallbacks.is_empty(),
+            grants,
+            fallbacks,
+        })
+    }
 }
 
 #[derive(Debug, Error)]
@@ -85,24 +693,254 @@ pub struct RoleRow {
     group: Option<i64>,
 }
 
+/// Every resource type with a `*_permissions` table.
+pub(crate) const GRANTABLE_TYPES: [resources::Type; 6] = [
+    resources::Type::User,
+    resources::Type::Role,
+    resources::Type::Group,
+    resources::Type::File,
+    resources::Type::Course,
+    resources::Type::Template,
+];
+
+/// Operations per type, resource, whether they are denied and when they apply, `None` for
+/// type-wide ones.
+type GrantMap = HashMap<(resources::Type, Option<String>, bool, Validity), Operations>;
+
+/// Flattens `permissions` into the grants they stand for, normalizing the ids.
+fn grant_map(permissions: &[Permission]) -> Result<GrantMap, GrantError> {
+    let mut grants = GrantMap::new();
+    for p in permissions {
+        if !p.ops.is_valid() || !p.validity().is_valid() {
+            return Err(GrantError::BadParam);
+        }
+        let ids = match &p.ids {
+            None => vec![None],
+            Some(ids) => ids
+                .iter()
+                .map(|id| p.subject.normalize_id(id).map(Some))
+                .collect::<Option<Vec<_>>>()
+                .ok_or(GrantError::BadParam)?,
+        };
+        for id in ids {
+            let ops = grants
+                .entry((p.subject, id, p.deny, p.validity()))
+                .or_insert(Operations::NONE);
+            *ops = *ops | p.ops;
+        }
+    }
+    grants.retain(|_, ops| *ops != Operations::NONE);
+    Ok(grants)
+}
+
 impl RoleRow {
+    /// Checks that `editor` may change the permissions of a role from `old` to `new`: like a
+    /// [`GrantTarget::set`], every changed grant needs the right to manage permissions and all
+    /// operations granted before and after.
+    pub async fn check_permission_change(
+        old: &[Permission],
+        new: &[Permission],
+        editor: &User,
+        db: &PgPool,
+    ) -> Result<(), GrantError> {
+        let old = grant_map(old)?;
+        let new = grant_map(new)?;
+        for key in old.keys().chain(new.keys()) {
+            let before = old.get(key).copied().unwrap_or(Operations::NONE);
+            let after = new.get(key).copied().unwrap_or(Operations::NONE);
+            if before == after {
+                continue;
+            }
+            let held = super::held_operations(key.0, key.1.as_deref(), editor.user_id, db).await?
+                & editor.allowed_operations(key.0);
+            if !held.can_manage_permissions() || !held.contains(before | after) {
+                return Err(GrantError::AccessDenied);
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that `editor` may add users to `role_id` or remove them from it: that takes the right
+    /// to update the role and, like a [`GrantTarget::set`], all operations the role grants, so
+    /// nobody hands out rights they do not hold themselves through a membership.
+    pub async fn check_membership_change(
+        role_id: i64,
+        editor: &User,
+        db: &PgPool,
+    ) -> Result<(), GrantError> {
+        if !super::user_has_permissions_id(
+            resources::Type::Role,
+            &role_id,
+            Operations::UPDATE,
+            editor,
+            db,
+        )
+        .await?
+        {
+            return Err(GrantError::AccessDenied);
+        }
+        let Some(permissions) = sqlx::query_scalar::<_, sqlx::types::Json<Vec<Permission>>>(
+            "SELECT permissions FROM \"role\" WHERE id = $1",
+        )
+        .bind(role_id)
+        .fetch_optional(db)
+        .await?
+        else {
+            return Err(GrantError::NotFound);
+        };
+        for ((resource_type, resource_id, _, _), ops) in grant_map(&permissions)? {
+            let held =
+                super::held_operations(resource_type, resource_id.as_deref(), editor.user_id, db)
+                    .await?
+                    & editor.allowed_operations(resource_type);
+            if !held.contains(ops) {
+                return Err(GrantError::AccessDenied);
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the grants of `role_id` in the `*_permissions` tables with `permissions`, so the
+    /// permissions of a role decide what its users may do.
+    pub async fn sync_grants(
+        conn: &mut sqlx::PgConnection,
+        role_id: i64,
+        permissions: &[Permission],
+    ) -> Result<(), GrantError> {
+        let grants = grant_map(permissions)?;
+        for resource_type in GRANTABLE_TYPES {
+            let table_name = resource_type.table_name();
+            sqlx::query(&format!(
+                "DELETE FROM {table_name}_permissions WHERE role_id = $1 AND user_id IS NULL",
+            ))
+            .bind(role_id)
+            .execute(&mut *conn)
+            .await?;
+        }
+        for ((resource_type, resource_id, deny, validity), ops) in grants {
+            let table_name = resource_type.table_name();
+            let id_type = resource_type.id_type();
+            let inserted = sqlx::query(&format!(
+                "INSERT INTO {table_name}_permissions\
+(role_id, resource_id, permission, deny, valid_from, valid_until) \
+VALUES ($1, $2::text::{id_type}, $3::int::bit(16), $4, $5, $6)",
+            ))
+            .bind(role_id)
+            .bind(resource_id)
+            .bind(ops)
+            .bind(deny)
+            .bind(validity.valid_from)
+            .bind(validity.valid_until)
+            .execute(&mut *conn)
+            .await;
+            match inserted {
+                // the permissions name a resource that does not exist
+                Err(sqlx::Error::Database(e)) if e.kind() == ErrorKind::ForeignKeyViolation => {
+                    return Err(GrantError::BadParam);
+                }
+                Err(e) => return Err(e.into()),
+                Ok(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrites the permissions of `role_id` from its grants in the `*_permissions` tables, after
+    /// they were changed through the grants API.
+    pub async fn refresh_permissions(
+        conn: &mut sqlx::PgConnection,
+        role_id: i64,
+    ) -> Result<(), sqlx::Error> {
+        let mut permissions = Vec::new();
+        for resource_type in GRANTABLE_TYPES {
+            let table_name = resource_type.table_name();
+            let grants = sqlx::query_as::<
+                _,
+                (
+                    Option<String>,
+                    bool,
+                    Option<DateTime<Utc>>,
+                    Option<DateTime<Utc>>,
+                    Operations,
+                ),
+            >(&format!(
+                "SELECT resource_id::text, deny, valid_from, valid_until, \
+BIT_OR(permission)::int::int2 FROM {table_name}_permissions \
+WHERE role_id = $1 AND user_id IS NULL GROUP BY resource_id, deny, valid_from, valid_until \
+ORDER BY resource_id NULLS FIRST, deny, valid_from NULLS FIRST, valid_until NULLS FIRST",
+            ))
+            .bind(role_id)
+            .fetch_all(&mut *conn)
+            .await?;
+
+            let mut by_ops: Vec<(Operations, bool, Validity, Vec<String>)> = Vec::new();
+            for (resource_id, deny, valid_from, valid_until, ops) in grants {
+                let validity = Validity {
+                    valid_from,
+                    valid_until,
+                };
+                match resource_id {
+                    None => permissions.push(Permission {
+                        subject: resource_type,
+                        ops,
+                        ids: None,
+                        deny,
+                        valid_from,
+                        valid_until,
+                    }),
+                    Some(id) => {
+                        let id = resource_type.normalize_id(&id).unwrap_or(id);
+                        match by_ops
+                            .iter_mut()
+                            .find(|(o, d, v, _)| *o == ops && *d == deny && *v == validity)
+                        {
+                            Some((_, _, _, ids)) => ids.push(id),
+                            None => by_ops.push((ops, deny, validity, vec![id])),
+                        }
+                    }
+                }
+            }
+            permissions.extend(
+                by_ops
+                    .into_iter()
+                    .map(|(ops, deny, validity, ids)| Permission {
+                        subject: resource_type,
+                        ops,
+                        ids: Some(ids),
+                        deny,
+                        valid_from: validity.valid_from,
+                        valid_until: validity.valid_until,
+                    }),
+            );
+        }
+
+        sqlx::query("UPDATE \"role\" SET permissions = $1 WHERE id = $2")
+            .bind(sqlx::types::Json(&permissions))
+            .bind(role_id)
+            .execute(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    /// The roles `target_user_id` holds right now, as far as `requesting_user` may read them.
     pub async fn from_user_id(
         db: &PgPool,
-        requesting_user_id: i64,
+        requesting_user: &User,
         target_user_id: i64,
     ) -> Result<Vec<Self>, sqlx::Error> {
-        match sqlx::query_as::<_, RoleRow>(
+        match sqlx::query_as::<_, RoleRow>(concatcp!(
             "SELECT id, \"name\", permissions, \"group\" FROM \"role\" r \
-JOIN user_has_role ON role_id = r.id \
-WHERE EXISTS (\
-SELECT 1 FROM role_permissions rp \
-WHERE (rp.resource_id = r.id OR rp.resource_id IS NULL) \
-AND (rp.permission & $3::int::bit(16)) <> B'0'::bit(16) \
-AND (rp.user_id = $2 OR rp.role_id IN (SELECT role_id FROM user_has_role WHERE user_id = $2)))",
-        )
+JOIN current_user_has_role ur ON ur.role_id = r.id \
+WHERE ur.user_id = $1 AND ($3::int::bit(16) & (SELECT ",
+            EFFECTIVE_OPERATIONS,
+            " FROM role_permissions p \
+WHERE (p.resource_id = r.id OR p.resource_id IS NULL) \
+AND (p.user_id = $2 OR p.role_id IN (SELECT role_id FROM current_user_has_role WHERE user_id = $2)))) \
+<> B'0'::bit(16)",
+        ))
         .bind(target_user_id)
-        .bind(requesting_user_id)
-        .bind(Operations::READ)
+        .bind(requesting_user.user_id)
+        .bind(Operations::READ & requesting_user.allowed_operations(resources::Type::Role))
         .fetch_all(db)
         .await
         {
@@ -116,9 +954,9 @@ AND (rp.user_id = $2 OR rp.role_id IN (SELECT role_id FROM user_has_role WHERE u
         name: &str,
         permissions: &[Permission],
         group: Option<i64>,
-        user_id: i64,
+        user: &User,
     ) -> Result<i64, ResourceCreateError> {
-        match super::can_create(resources::Type::Role, user_id, db).await {
+        match super::can_create(resources::Type::Role, user, db).await {
             Err(e) => return Err(ResourceCreateError::Sqlx(e)),
             Ok(false) => return Err(ResourceCreateError::AccessDenied),
             Ok(true) => {}
@@ -196,23 +1034,26 @@ pub struct GroupRow {
 }
 
 impl GroupRow {
+    /// The groups `target_user_id` is a current direct member of, as far as `requesting_user` may
+    /// read them.
     pub async fn from_user_id(
         db: &PgPool,
-        requesting_user_id: i64,
+        requesting_user: &User,
         target_user_id: i64,
     ) -> Result<Vec<Self>, sqlx::Error> {
-        match sqlx::query_as::<_, GroupRow>(
+        match sqlx::query_as::<_, GroupRow>(concatcp!(
             "SELECT g.id, g.\"name\", g.shortname, g.kind, g.parent FROM \"group\" g \
 JOIN user_in_group uig ON uig.group_id = g.id \
-WHERE EXISTS (\
-SELECT 1 FROM group_permissions gp \
-WHERE (gp.resource_id = g.id OR gp.resource_id IS NULL) \
-AND (gp.permission & $3::int::bit(16)) <> B'0'::bit(16) \
-AND (gp.user_id = $2 OR gp.role_id IN (SELECT role_id FROM user_has_role WHERE user_id = $2)))",
-        )
+WHERE uig.user_id = $1 AND is_current(uig.valid_from, uig.valid_until) AND ($3::int::bit(16) & (SELECT ",
+            EFFECTIVE_OPERATIONS,
+            " FROM group_permissions p \
+WHERE (p.resource_id = g.id OR p.resource_id IS NULL) \
+AND (p.user_id = $2 OR p.role_id IN (SELECT role_id FROM current_user_has_role WHERE user_id = $2)))) \
+<> B'0'::bit(16)",
+        ))
         .bind(target_user_id)
-        .bind(requesting_user_id)
-        .bind(Operations::READ)
+        .bind(requesting_user.user_id)
+        .bind(Operations::READ & requesting_user.allowed_operations(resources::Type::Group))
         .fetch_all(db)
         .await
         {
@@ -227,9 +1068,9 @@ AND (gp.user_id = $2 OR gp.role_id IN (SELECT role_id FROM user_has_role WHERE u
         shortname: &str,
         kind: &GroupKind,
         parent: Option<i64>,
-        user_id: i64,
+        user: &User,
     ) -> Result<i64, ResourceCreateError> {
-        match super::can_create(resources::Type::Group, user_id, db).await {
+        match super::can_create(resources::Type::Group, user, db).await {
             Err(e) => return Err(ResourceCreateError::Sqlx(e)),
             Ok(false) => return Err(ResourceCreateError::AccessDenied),
             Ok(true) => {}
@@ -260,11 +1101,91 @@ AND (gp.user_id = $2 OR gp.role_id IN (SELECT role_id FROM user_has_role WHERE u
     }
 }
 
-pub fn add_users_to_groups_query<'a>()
--> sqlx::query::Query<'a, Postgres, <Postgres as sqlx::Database>::Arguments<'a>> {
+/// A group and all groups below it.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupTree {
+    #[serde(flatten)]
+    group: GroupRow,
+    children: Vec<GroupTree>,
+}
+
+impl GroupTree {
+    /// Builds the tree below `group` from `below`, the groups of its subtree by their parent.
+    fn build(group: GroupRow, below: &mut HashMap<i64, Vec<GroupRow>>) -> Self {
+        // removing the children makes sure every group is only used once, even for cycles
+        let children = below
+            .remove(&group.group_id)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|child| Self::build(child, below))
+            .collect();
+        Self { group, children }
+    }
+}
+
+/// The groups below a group and its effective members, everyone in any group of the subtree.
+#[derive(Serialize)]
+pub struct GroupSubtree {
+    group: GroupTree,
+    members: Vec<user::Profile>,
+}
+
+impl GroupRow {
+    /// `group_id` and every group below it, `group_id` first. Empty if the group does not exist.
+    pub async fn subtree(db: &PgPool, group_id: i64) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as::<_, GroupRow>(
+            "WITH RECURSIVE subtree AS (\
+SELECT * FROM \"group\" WHERE id = $1 UNION \
+SELECT g.* FROM \"group\" g JOIN subtree s ON g.parent = s.id) \
+SELECT id, \"name\", shortname, kind, parent FROM subtree ORDER BY id = $1 DESC, id",
+        )
+        .bind(group_id)
+        .fetch_all(db)
+        .await
+    }
+}
+
+impl GroupSubtree {
+    /// `None` if the group does not exist.
+    pub async fn load(db: &PgPool, group_id: i64) -> Result<Option<Self>, sqlx::Error> {
+        let mut groups = GroupRow::subtree(db, group_id).await?.into_iter();
+        let Some(root) = groups.next() else {
+            return Ok(None);
+        };
+
+        // the effective members of the root are the current members of every group below it
+        let members = sqlx::query_as::<_, user::Profile>(
+            "SELECT u.id, u.firstname, u.lastname, u.title, u.email FROM \"user\" u \
+JOIN effective_user_in_group uig ON uig.user_id = u.id WHERE uig.group_id = $1 ORDER BY u.id",
+        )
+        .bind(group_id)
+        .fetch_all(db)
+        .await?;
+
+        let mut below: HashMap<i64, Vec<GroupRow>> = HashMap::new();
+        for group in groups {
+            if let Some(parent) = group.parent {
+                below.entry(parent).or_default().push(group);
+            }
+        }
+        Ok(Some(Self {
+            group: GroupTree::build(root, &mut below),
+            members,
+        }))
+    }
+}
+
+/// Adds the users in the first bound array to the groups in the second one, for `validity`.
+pub fn add_users_to_groups_query<'a>(
+    validity: Validity,
+) -> sqlx::query::Query<'a, Postgres, <Postgres as sqlx::Database>::Arguments<'a>> {
     sqlx::query(
-        "INSERT INTO \"user_in_group\"(user_id, group_id) SELECT * FROM UNNEST($1::int8[], $2::int8[])",
+        "INSERT INTO \"user_in_group\"(user_id, group_id, valid_from, valid_until) \
+SELECT u, g, $1, $2 FROM UNNEST($3::int8[], $4::int8[]) AS m(u, g)",
     )
+    .bind(validity.valid_from)
+    .bind(validity.valid_until)
 }
 
 pub fn remove_user_from_role_groups_query<'a>()
@@ -276,27 +1197,41 @@ WHERE user_id = $1 AND group_id IN \
     )
 }
 
-pub fn add_users_to_role_group_query<'a>()
--> sqlx::query::Query<'a, Postgres, <Postgres as sqlx::Database>::Arguments<'a>> {
+/// Adds the users in the bound array to the group of the bound role, for `validity`.
+pub fn add_users_to_role_group_query<'a>(
+    validity: Validity,
+) -> sqlx::query::Query<'a, Postgres, <Postgres as sqlx::Database>::Arguments<'a>> {
     sqlx::query(
-        "INSERT INTO \"user_in_group\"(user_id, group_id) \
-SELECT uid, \"group\"::int8 FROM UNNEST($1::int8[]) AS u(uid) CROSS JOIN (SELECT \"group\" FROM \"role\" WHERE id = $2)",
+        "INSERT INTO \"user_in_group\"(user_id, group_id, valid_from, valid_until) \
+SELECT uid, \"group\"::int8, $1, $2 FROM UNNEST($3::int8[]) AS u(uid) \
+CROSS JOIN (SELECT \"group\" FROM \"role\" WHERE id = $4)",
     )
+    .bind(validity.valid_from)
+    .bind(validity.valid_until)
 }
 
-pub fn add_user_to_role_groups_query<'a>()
--> sqlx::query::Query<'a, Postgres, <Postgres as sqlx::Database>::Arguments<'a>> {
+/// Adds the bound user to the groups of the roles in the bound array, for `validity`.
+pub fn add_user_to_role_groups_query<'a>(
+    validity: Validity,
+) -> sqlx::query::Query<'a, Postgres, <Postgres as sqlx::Database>::Arguments<'a>> {
     sqlx::query(
-        "INSERT INTO \"user_in_group\"(user_id, group_id) \
-SELECT $1::int8, \"role\".\"group\"::int8 FROM \"role\" WHERE id = ANY($2)",
+        "INSERT INTO \"user_in_group\"(user_id, group_id, valid_from, valid_until) \
+SELECT $3::int8, \"role\".\"group\"::int8, $1, $2 FROM \"role\" WHERE id = ANY($4)",
     )
+    .bind(validity.valid_from)
+    .bind(validity.valid_until)
 }
 
-pub fn add_users_to_roles_query<'a>()
--> sqlx::query::Query<'a, Postgres, <Postgres as sqlx::Database>::Arguments<'a>> {
+/// Adds the users in the first bound array to the roles in the second one, for `validity`.
+pub fn add_users_to_roles_query<'a>(
+    validity: Validity,
+) -> sqlx::query::Query<'a, Postgres, <Postgres as sqlx::Database>::Arguments<'a>> {
     sqlx::query(
-        "INSERT INTO \"user_has_role\"(user_id, role_id) SELECT * FROM UNNEST($1::int8[], $2::int8[])",
+        "INSERT INTO \"user_has_role\"(user_id, role_id, valid_from, valid_until) \
+SELECT u, r, $1, $2 FROM UNNEST($3::int8[], $4::int8[]) AS m(u, r)",
     )
+    .bind(validity.valid_from)
+    .bind(validity.valid_until)
 }
 
 #[derive(Serialize, Deserialize)]
@@ -6,12 +6,14 @@ use axum::{
 };
 
 pub mod role {
-    use axum::extract::Path;
+    use axum::extract::{Path, Query};
     use axum_login::AuthSession;
     use sqlx::error::ErrorKind;
 
-    use crate::auth::permission::{Role, RoleDescription, RoleRow, add_users_to_role_group_query};
-    use crate::auth::permission::{add_users_to_groups_query, add_users_to_roles_query};
+    use crate::auth::permission::{
+        Operations, Permission, Role, RoleDescription, RoleRow, add_users_to_role_group_query,
+    };
+    use crate::auth::{expiry::Validity, permission::add_users_to_roles_query};
     use crate::{auth, resources, user};
 
     use super::*;
@@ -33,7 +35,7 @@ pub mod role {
         Json(role): Json<RoleDescription>,
     ) -> Response {
         let s_user = auth_session.user.unwrap();
-        match auth::can_create(resources::Type::Role, s_user.user_id, &state.db).await {
+        match auth::can_create(resources::Type::Role, &s_user, &state.db).await {
             Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
             Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
             Ok(true) => {}
@@ -54,7 +56,17 @@ pub mod role {
             _ => {}
         }
 
-        match sqlx::query_scalar::<_, i64>(
+        if let Err(e) =
+            RoleRow::check_permission_change(&[], &role.permissions, &s_user, &state.db).await
+        {
+            return super::grant::error_response(e);
+        }
+
+        let Ok(mut tx) = state.db.begin().await else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+
+        let role_id = match sqlx::query_scalar::<_, i64>(
             "WITH inserted_group AS (INSERT INTO \"group\" (\"name\", kind, parent) VALUES ($1, 'role', $2) RETURNING id) \
 INSERT INTO \"role\"(name, permissions, \"group\") \
 VALUES ($3, $4, (SELECT id FROM inserted_group))\
@@ -64,16 +76,11 @@ RETURNING id",
         .bind::<Option<i64>>(None)
         .bind(&role.name)
         .bind(sqlx::types::Json(&role.permissions))
-        .fetch_one(&state.db)
+        .fetch_one(&mut *tx)
         .await
         {
-            Ok(id) => (StatusCode::CREATED, Json(Role {
-                role_id: id,
-                name: role.name,
-                permissions: role.permissions,
-            }))
-            .into_response(),
-            Err(e) => match e {
+            Ok(id) => id,
+            Err(e) => return match e {
                 sqlx::Error::Database(e) => {
                     if e.kind() == ErrorKind::UniqueViolation {
                         StatusCode::CONFLICT.into_response()
@@ -84,8 +91,25 @@ RETURNING id",
                 _ => StatusCode::INTERNAL_SERVER_ERROR.into_response()
 
             },
+        };
+
+        if let Err(e) = RoleRow::sync_grants(&mut tx, role_id, &role.permissions).await {
+            return super::grant::error_response(e);
+        }
+        if tx.commit().await.is_err() {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
         }
 
+        (
+            StatusCode::CREATED,
+            Json(Role {
+                role_id,
+                name: role.name,
+                permissions: role.permissions,
+            }),
+        )
+            .into_response()
+
         // let group_id =
         //     match GroupRow::create_in_db(&state.db, &role.name, &GroupKind::Organization, None)
         //         .await
@@ -129,52 +153,109 @@ RETURNING id",
     }
 
     pub async fn update(
+        auth_session: AuthSession<crate::auth::Backend>,
         Path(role_id): Path<i64>,
         State(state): State<crate::AppState>,
         Json(role): Json<RoleDescription>,
     ) -> Response {
-        match sqlx::query_scalar::<_, i32>("SELECT 1 FROM \"role\" WHERE id = $1")
-            .bind(role_id)
-            .fetch_optional(&state.db)
-            .await
+        let s_user = auth_session.user.unwrap();
+        match auth::user_has_permissions_id(
+            resources::Type::Role,
+            &role_id,
+            Operations::UPDATE,
+            &s_user,
+            &state.db,
+        )
+        .await
+        {
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
+            Ok(true) => {}
+        }
+
+        let old_permissions = match sqlx::query_scalar::<_, sqlx::types::Json<Vec<Permission>>>(
+            "SELECT permissions FROM \"role\" WHERE id = $1",
+        )
+        .bind(role_id)
+        .fetch_optional(&state.db)
+        .await
         {
             Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
             Ok(None) => return StatusCode::NOT_FOUND.into_response(),
-            Ok(Some(_)) => {}
+            Ok(Some(p)) => p.0,
+        };
+
+        if let Err(e) = RoleRow::check_permission_change(
+            &old_permissions,
+            &role.permissions,
+            &s_user,
+            &state.db,
+        )
+        .await
+        {
+            return super::grant::error_response(e);
         }
 
+        let Ok(mut tx) = state.db.begin().await else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+
         //TODO: Update role group aswell
-        match sqlx::query_scalar::<_, i64>(
+        let group = match sqlx::query_scalar::<_, i64>(
             "WITH updated_role AS (UPDATE \"role\" SET \"name\" = $1, permissions = $2 WHERE id = $3 RETURNING \"group\") \
 UPDATE \"group\" SET \"name\" = $1 WHERE id = (SELECT \"group\" FROM updated_role)",
         )
         .bind(&role.name)
         .bind(sqlx::types::Json(&role.permissions))
         .bind(role_id)
-        .fetch_optional(&state.db)
+        .fetch_optional(&mut *tx)
         .await
         {
-            Ok(g) => Json(RoleRow {
-                role_id,
-                name: role.name,
-                permissions: sqlx::types::Json(role.permissions),
-                group: g,
-            })
-            .into_response(),
-            Err(e) => match e {
+            Ok(g) => g,
+            Err(e) => return match e {
                 sqlx::Error::Database(f) => match f.kind() {
                     ErrorKind::UniqueViolation => StatusCode::CONFLICT.into_response(),
                     _ => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
                 },
                 _ => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
             },
+        };
+
+        if let Err(e) = RoleRow::sync_grants(&mut tx, role_id, &role.permissions).await {
+            return super::grant::error_response(e);
+        }
+        if tx.commit().await.is_err() {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
         }
+
+        Json(RoleRow {
+            role_id,
+            name: role.name,
+            permissions: sqlx::types::Json(role.permissions),
+            group,
+        })
+        .into_response()
     }
 
     pub async fn delete(
+        auth_session: AuthSession<crate::auth::Backend>,
         Path(role_id): Path<i64>,
         State(state): State<crate::AppState>,
     ) -> StatusCode {
+        match auth::user_has_permissions_id(
+            resources::Type::Role,
+            &role_id,
+            Operations::DELETE,
+            &auth_session.user.unwrap(),
+            &state.db,
+        )
+        .await
+        {
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
+            Ok(false) => return StatusCode::UNAUTHORIZED,
+            Ok(true) => {}
+        }
+
         let group_to_delete = match sqlx::query_as::<_, (i64,)>(
             "DELETE FROM \"role\" WHERE id = $1 RETURNING \"group\"",
         )
@@ -198,12 +279,27 @@ UPDATE \"group\" SET \"name\" = $1 WHERE id = (SELECT \"group\" FROM updated_rol
     }
 
     pub async fn get_users(
+        auth_session: AuthSession<crate::auth::Backend>,
         Path(role_id): Path<i64>,
         State(state): State<crate::AppState>,
     ) -> Response {
+        match auth::user_has_permissions_id(
+            resources::Type::Role,
+            &role_id,
+            Operations::READ,
+            &auth_session.user.unwrap(),
+            &state.db,
+        )
+        .await
+        {
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
+            Ok(true) => {}
+        }
+
         match sqlx::query_as::<_, user::Profile>(
             "SELECT \"user\".id, firstname, lastname, title, email FROM \"user\" \
-LEFT JOIN \"user_has_role\" ON id = user_id \
+JOIN \"current_user_has_role\" ON id = user_id \
 WHERE role_id = $1",
         )
         .bind(role_id)
@@ -216,10 +312,16 @@ WHERE role_id = $1",
     }
 
     pub async fn replace_users(
+        auth_session: AuthSession<crate::auth::Backend>,
         Path(role_id): Path<i64>,
         State(state): State<crate::AppState>,
         Json(user_ids): Json<Vec<i64>>,
     ) -> Response {
+        if let Err(e) =
+            RoleRow::check_membership_change(role_id, &auth_session.user.unwrap(), &state.db).await
+        {
+            return super::grant::error_response(e);
+        }
         match sqlx::query_scalar::<_, i32>("SELECT 1 FROM \"role\" WHERE id = $1")
             .bind(role_id)
             .fetch_optional(&state.db)
@@ -271,7 +373,7 @@ WHERE role_id = $1",
                     } else {
                         let role_ids = vec![role_id; user_ids.len()];
 
-                        if let Err(_) = add_users_to_roles_query()
+                        if let Err(_) = add_users_to_roles_query(Validity::ALWAYS)
                             .bind(&user_ids)
                             .bind(&role_ids)
                             .execute(&mut *transaction)
@@ -280,7 +382,7 @@ WHERE role_id = $1",
                             return StatusCode::INTERNAL_SERVER_ERROR.into_response();
                         }
 
-                        match add_users_to_role_group_query()
+                        match add_users_to_role_group_query(Validity::ALWAYS)
                             .bind(&user_ids)
                             .bind(role_id)
                             .execute(&mut *transaction)
@@ -305,13 +407,20 @@ WHERE role_id = $1",
     }
 
     pub async fn add_users(
+        auth_session: AuthSession<crate::auth::Backend>,
         Path(role_id): Path<i64>,
+        Query(validity): Query<Validity>,
         State(state): State<crate::AppState>,
         Json(user_ids): Json<Vec<i64>>,
     ) -> StatusCode {
-        if user_ids.len() < 1 {
+        if user_ids.len() < 1 || !validity.is_valid() {
             return StatusCode::BAD_REQUEST;
         }
+        if let Err(e) =
+            RoleRow::check_membership_change(role_id, &auth_session.user.unwrap(), &state.db).await
+        {
+            return super::grant::error_response(e).status();
+        }
         match sqlx::query_scalar::<_, i32>("SELECT 1 FROM \"role\" WHERE id = $1")
             .bind(role_id)
             .fetch_optional(&state.db)
@@ -323,10 +432,13 @@ WHERE role_id = $1",
                 };
                 if user_ids.len() == 1 {
                     if sqlx::query(
-                        "INSERT INTO \"user_has_role\"(user_id, role_id) VALUES ($1, $2)",
+                        "INSERT INTO \"user_has_role\"(user_id, role_id, valid_from, valid_until) \
+VALUES ($1, $2, $3, $4)",
                     )
                     .bind(user_ids[0])
                     .bind(role_id)
+                    .bind(validity.valid_from)
+                    .bind(validity.valid_until)
                     .execute(&mut *transaction)
                     .await
                     .is_err()
@@ -335,10 +447,12 @@ WHERE role_id = $1",
                     }
 
                     if sqlx::query(
-                        "INSERT INTO \"user_in_group\"(user_id, group_id) VALUES ($1, (SELECT \"group\" FROM \"role\" WHERE id = $2))",
+                        "INSERT INTO \"user_in_group\"(user_id, group_id, valid_from, valid_until) VALUES ($1, (SELECT \"group\" FROM \"role\" WHERE id = $2), $3, $4)",
                     )
                     .bind(user_ids[0])
                     .bind(role_id)
+                    .bind(validity.valid_from)
+                    .bind(validity.valid_until)
                     .execute(&mut *transaction)
                     .await.is_err()
                     {
@@ -353,7 +467,7 @@ WHERE role_id = $1",
 
                 let role_ids = vec![role_id; user_ids.len()];
 
-                if add_users_to_groups_query()
+                if add_users_to_roles_query(validity)
                     .bind(&user_ids)
                     .bind(&role_ids)
                     .execute(&mut *transaction)
@@ -363,7 +477,7 @@ WHERE role_id = $1",
                     return StatusCode::INTERNAL_SERVER_ERROR;
                 }
 
-                if add_users_to_role_group_query()
+                if add_users_to_role_group_query(validity)
                     .bind(user_ids)
                     .bind(role_id)
                     .execute(&mut *transaction)
@@ -384,10 +498,16 @@ WHERE role_id = $1",
     }
 
     pub async fn delete_users(
+        auth_session: AuthSession<crate::auth::Backend>,
         Path(role_id): Path<i64>,
         State(state): State<crate::AppState>,
         Json(user_ids): Json<Vec<i64>>,
     ) -> StatusCode {
+        if let Err(e) =
+            RoleRow::check_membership_change(role_id, &auth_session.user.unwrap(), &state.db).await
+        {
+            return super::grant::error_response(e).status();
+        }
         let Ok(mut transaction) = state.db.begin().await else {
             return StatusCode::INTERNAL_SERVER_ERROR;
         };
@@ -418,14 +538,17 @@ WHERE role_id = $1",
 }
 
 pub mod group {
-    use axum::extract::Path;
+    use axum::extract::{Path, Query};
     use axum_login::AuthSession;
 
     use super::*;
     use crate::{
         auth::{
             self,
-            permission::{GroupDescription, GroupRow, Operations, add_users_to_groups_query},
+            expiry::Validity,
+            permission::{
+                GroupDescription, GroupRow, GroupSubtree, Operations, add_users_to_groups_query,
+            },
         },
         resources, user,
     };
@@ -449,7 +572,7 @@ pub mod group {
         Json(group): Json<GroupDescription>,
     ) -> Response {
         let s_user = auth_session.user.unwrap();
-        match auth::can_create(resources::Type::Group, s_user.user_id, &state.db).await {
+        match auth::can_create(resources::Type::Group, &s_user, &state.db).await {
             Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
             Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
             Ok(true) => {}
@@ -511,7 +634,7 @@ pub mod group {
             resources::Type::Group,
             &id,
             Operations::READ,
-            s_user.user_id,
+            &s_user,
             &state.db,
         )
         .await
@@ -552,7 +675,7 @@ pub mod group {
             resources::Type::Group,
             &group_id,
             Operations::UPDATE,
-            s_user.user_id,
+            &s_user,
             &state.db,
         )
         .await
@@ -561,6 +684,14 @@ pub mod group {
             Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
             Ok(true) => {}
         }
+        // a group can't be moved below itself
+        match GroupRow::subtree(&state.db, group_id).await {
+            Ok(subtree) if subtree.iter().any(|g| Some(g.group_id) == group.parent) => {
+                return StatusCode::BAD_REQUEST.into_response();
+            }
+            Ok(_) => {}
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
 
         match sqlx::query(
             "UPDATE \"group\" SET \"name\" = $1, kind = $2, parent = $3 WHERE id = $4",
@@ -587,7 +718,21 @@ pub mod group {
         State(state): State<crate::AppState>,
     ) -> StatusCode {
         let s_user = auth_session.user.unwrap();
-        match auth::can_delete(resources::Type::Group, s_user.user_id, &state.db).await {
+        match auth::can_delete(resources::Type::Group, &s_user, &state.db).await {
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
+            Ok(false) => return StatusCode::UNAUTHORIZED,
+            Ok(true) => {}
+        }
+        // a deny grant on the group itself still protects it
+        match auth::user_has_permissions_id(
+            resources::Type::Group,
+            &group_id,
+            Operations::DELETE,
+            &s_user,
+            &state.db,
+        )
+        .await
+        {
             Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
             Ok(false) => return StatusCode::UNAUTHORIZED,
             Ok(true) => {}
@@ -603,11 +748,39 @@ pub mod group {
         }
     }
 
+    //GET /groups/{id}/subtree
+    pub async fn get_subtree(
+        auth_session: AuthSession<auth::Backend>,
+        Path(id): Path<i64>,
+        State(state): State<crate::AppState>,
+    ) -> Response {
+        let s_user = auth_session.user.unwrap();
+        match auth::user_has_permissions_id(
+            resources::Type::Group,
+            &id,
+            Operations::READ,
+            &s_user,
+            &state.db,
+        )
+        .await
+        {
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
+            Ok(true) => {}
+        }
+
+        match GroupSubtree::load(&state.db, id).await {
+            Ok(Some(subtree)) => Json(subtree).into_response(),
+            Ok(None) => StatusCode::NOT_FOUND.into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
     pub async fn get_users(Path(id): Path<i64>, State(state): State<crate::AppState>) -> Response {
         match sqlx::query_as::<_, user::Profile>(
             "SELECT \"user\".id, firstname, lastname, title, email \
-FROM \"user\" LEFT JOIN \"user_in_group\" ON id = user_id
-WHERE group_id = $1",
+FROM \"user\" JOIN \"user_in_group\" ON id = user_id \
+WHERE group_id = $1 AND is_current(valid_from, valid_until)",
         )
         .bind(id)
         .fetch_all(&state.db)
@@ -638,7 +811,7 @@ WHERE group_id = $1",
                         resources::Type::Group,
                         &group_id,
                         Operations::UPDATE,
-                        s_user.user_id,
+                        &s_user,
                         &state.db,
                     )
                     .await
@@ -671,9 +844,8 @@ WHERE group_id = $1",
                             return StatusCode::INTERNAL_SERVER_ERROR.into_response();
                         }
                     } else {
-                        let mut group_ids = Vec::with_capacity(user_ids.len());
-                        group_ids.fill(group_id);
-                        if let Err(_) = add_users_to_groups_query()
+                        let group_ids = vec![group_id; user_ids.len()];
+                        if let Err(_) = add_users_to_groups_query(Validity::ALWAYS)
                             .bind(&user_ids)
                             .bind(&group_ids)
                             .execute(&mut *transaction)
@@ -697,10 +869,11 @@ WHERE group_id = $1",
     pub async fn add_users(
         auth_session: AuthSession<auth::Backend>,
         Path(group_id): Path<i64>,
+        Query(validity): Query<Validity>,
         State(state): State<crate::AppState>,
         Json(user_ids): Json<Vec<i64>>,
     ) -> StatusCode {
-        if user_ids.len() < 1 {
+        if user_ids.len() < 1 || !validity.is_valid() {
             return StatusCode::BAD_REQUEST;
         }
 
@@ -709,7 +882,7 @@ WHERE group_id = $1",
             resources::Type::Group,
             &group_id,
             Operations::UPDATE,
-            s_user.user_id,
+            &s_user,
             &state.db,
         )
         .await
@@ -729,10 +902,13 @@ WHERE group_id = $1",
             Ok(Some(_)) => {
                 if user_ids.len() == 1 {
                     if let Err(_) = sqlx::query(
-                        "INSERT INTO \"user_in_group\"(user_id, group_id) VALUES ($1, $2)",
+                        "INSERT INTO \"user_in_group\"(user_id, group_id, valid_from, valid_until) \
+VALUES ($1, $2, $3, $4)",
                     )
                     .bind(user_ids[0])
                     .bind(group_id)
+                    .bind(validity.valid_from)
+                    .bind(validity.valid_until)
                     .execute(&state.db)
                     .await
                     {
@@ -742,7 +918,7 @@ WHERE group_id = $1",
                 }
 
                 let group_ids = vec![group_id; user_ids.len()];
-                if let Err(_) = add_users_to_groups_query()
+                if let Err(_) = add_users_to_groups_query(validity)
                     .bind(&user_ids)
                     .bind(group_ids)
                     .execute(&state.db)
@@ -773,3 +949,202 @@ WHERE group_id = $1",
         }
     }
 }
+
+pub mod grant {
+    use axum::extract::{Path, Query};
+    use axum_login::AuthSession;
+    use serde::Deserialize;
+
+    use crate::{
+        auth::{
+            self,
+            expiry::Validity,
+            permission::{Explanation, Grant, GrantError, GrantTarget, Operations},
+        },
+        resources,
+    };
+
+    use super::*;
+
+    pub(crate) fn error_response(e: GrantError) -> Response {
+        match e {
+            GrantError::BadParam => StatusCode::BAD_REQUEST.into_response(),
+            GrantError::NotFound => StatusCode::NOT_FOUND.into_response(),
+            GrantError::AccessDenied => StatusCode::UNAUTHORIZED.into_response(),
+            GrantError::Sqlx(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    //GET /grants/{type}
+    pub async fn get_all(
+        auth_session: AuthSession<auth::Backend>,
+        Path(resource_type): Path<resources::Type>,
+        Query(mut filter): Query<GrantTarget>,
+        State(state): State<crate::AppState>,
+    ) -> Response {
+        let s_user = auth_session.user.unwrap();
+        if let Some(id) = &filter.resource_id {
+            match resource_type.normalize_id(id) {
+                Some(id) => filter.resource_id = Some(id),
+                None => return StatusCode::BAD_REQUEST.into_response(),
+            }
+        }
+
+        match Grant::list(resource_type, &filter, &s_user, &state.db).await {
+            Ok(grants) => Json(grants).into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    //PUT /grants/{type}
+    pub async fn set(
+        auth_session: AuthSession<auth::Backend>,
+        Path(resource_type): Path<resources::Type>,
+        State(state): State<crate::AppState>,
+        Json(grant): Json<Grant>,
+    ) -> Response {
+        let s_user = auth_session.user.unwrap();
+        if grant.operations == Operations::NONE {
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+        let target = match (GrantTarget {
+            user_id: grant.user_id,
+            role_id: grant.role_id,
+            resource_id: grant.resource_id,
+            deny: grant.deny,
+        })
+        .validate(resource_type)
+        {
+            Ok(t) => t,
+            Err(e) => return error_response(e),
+        };
+
+        match target
+            .set(
+                resource_type,
+                grant.operations,
+                grant.validity,
+                &s_user,
+                &state.db,
+            )
+            .await
+        {
+            Ok(previous) => (
+                if previous == Operations::NONE {
+                    StatusCode::CREATED
+                } else {
+                    StatusCode::OK
+                },
+                Json(Grant {
+                    user_id: target.user_id,
+                    role_id: target.role_id,
+                    resource_id: target.resource_id,
+                    operations: grant.operations,
+                    deny: target.deny,
+                    validity: grant.validity,
+                }),
+            )
+                .into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    //DELETE /grants/{type}
+    pub async fn revoke(
+        auth_session: AuthSession<auth::Backend>,
+        Path(resource_type): Path<resources::Type>,
+        Query(target): Query<GrantTarget>,
+        State(state): State<crate::AppState>,
+    ) -> Response {
+        let s_user = auth_session.user.unwrap();
+        let target = match target.validate(resource_type) {
+            Ok(t) => t,
+            Err(e) => return error_response(e),
+        };
+
+        match target
+            .set(
+                resource_type,
+                Operations::NONE,
+                Validity::ALWAYS,
+                &s_user,
+                &state.db,
+            )
+            .await
+        {
+            Ok(Operations::NONE) => StatusCode::NOT_FOUND.into_response(),
+            Ok(_) => StatusCode::OK.into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ExplainQuery {
+        user_id: i64,
+        resource_id: Option<String>,
+        operations: Operations,
+    }
+
+    //GET /grants/{type}/explain
+    pub async fn explain(
+        auth_session: AuthSession<auth::Backend>,
+        Path(resource_type): Path<resources::Type>,
+        Query(query): Query<ExplainQuery>,
+        State(state): State<crate::AppState>,
+    ) -> Response {
+        let s_user = auth_session.user.unwrap();
+        if query.operations == Operations::NONE || !query.operations.is_valid() {
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+        let resource_id = match query
+            .resource_id
+            .as_deref()
+            .map(|id| resource_type.normalize_id(id))
+        {
+            Some(None) => return StatusCode::BAD_REQUEST.into_response(),
+            Some(id) => id,
+            None => None,
+        };
+
+        // only those who may manage the grants of a resource may see why someone can access it
+        match auth::held_operations(
+            resource_type,
+            resource_id.as_deref(),
+            s_user.user_id,
+            &state.db,
+        )
+        .await
+        {
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            Ok(held)
+                if !(held & s_user.allowed_operations(resource_type)).can_manage_permissions() =>
+            {
+                return StatusCode::UNAUTHORIZED.into_response();
+            }
+            Ok(_) => {}
+        }
+
+        match sqlx::query_scalar::<_, i32>("SELECT 1 FROM \"user\" WHERE id = $1")
+            .bind(query.user_id)
+            .fetch_optional(&state.db)
+            .await
+        {
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+            Ok(Some(_)) => {}
+        }
+
+        match Explanation::build(
+            resource_type,
+            resource_id.as_deref(),
+            query.operations,
+            query.user_id,
+            &state.db,
+        )
+        .await
+        {
+            Ok(explanation) => Json(explanation).into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{FromRow, PgPool};
+
+/// A login session of a user, without the id the session store keeps it under as that is the
+/// value of its cookie.
+#[derive(Serialize, FromRow, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionInfo {
+    pub id: i64,
+    pub created_at: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    /// Whether this is the session of the request
+    pub current: bool,
+}
+
+/// Keeps track of a session the user just logged in with.
+pub async fn record(
+    db: &PgPool,
+    session_id: &str,
+    user_id: i64,
+    ip: Option<String>,
+    user_agent: Option<String>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO user_session(session_id, user_id, ip, user_agent) VALUES ($1, $2, $3, $4)",
+    )
+    .bind(session_id)
+    .bind(user_id)
+    .bind(ip)
+    .bind(user_agent)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Notes activity on a session, at most once a minute to spare the database.
+pub async fn touch(db: &PgPool, session_id: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE user_session SET last_seen = now() \
+WHERE session_id = $1 AND last_seen < now() - interval '1 minute'",
+    )
+    .bind(session_id)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Keeps track of a session under the new id it got by logging in again.
+pub async fn rename(db: &PgPool, from: &str, to: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE user_session SET session_id = $2 WHERE session_id = $1")
+        .bind(from)
+        .bind(to)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// The sessions of `user_id` that have not expired yet, the most recently used first.
+pub async fn list(
+    db: &PgPool,
+    user_id: i64,
+    current: Option<&str>,
+) -> Result<Vec<SessionInfo>, sqlx::Error> {
+    sqlx::query_as::<_, SessionInfo>(
+        "SELECT us.id, us.created_at, us.last_seen, us.ip, us.user_agent, \
+us.session_id IS NOT DISTINCT FROM $2 AS current FROM user_session us \
+JOIN tower_sessions.session s ON s.id = us.session_id AND s.expiry_date > now() \
+WHERE us.user_id = $1 ORDER BY us.last_seen DESC, us.id DESC",
+    )
+    .bind(user_id)
+    .bind(current)
+    .fetch_all(db)
+    .await
+}
+
+/// Ends the sessions of `user_id`, only the one with `id` if given and all but `except`, and
+/// returns how many there were.
+pub async fn revoke(
+    db: &PgPool,
+    user_id: i64,
+    id: Option<i64>,
+    except: Option<&str>,
+) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar::<_, i64>(
+        "WITH r AS (DELETE FROM user_session WHERE user_id = $1 AND ($2::bigint IS NULL OR id = $2) \
+AND session_id IS DISTINCT FROM $3 RETURNING session_id), \
+s AS (DELETE FROM tower_sessions.session WHERE id IN (SELECT session_id FROM r)) \
+SELECT count(*) FROM r",
+    )
+    .bind(user_id)
+    .bind(id)
+    .bind(except)
+    .fetch_one(db)
+    .await
+}
+
+/// Forgets the sessions the store no longer has, because they expired or were logged out.
+pub(crate) async fn cleanup(db: impl sqlx::PgExecutor<'_>) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar::<_, String>(
+        "DELETE FROM user_session us WHERE NOT EXISTS(SELECT 1 FROM tower_sessions.session s \
+WHERE s.id = us.session_id AND s.expiry_date > now()) \
+RETURNING format('session %s of user %s', us.id, us.user_id)",
+    )
+    .fetch_all(db)
+    .await
+}
+
+pub mod http {
+    use axum::{
+        Json,
+        extract::{Path, Request, State},
+        http::StatusCode,
+        middleware::Next,
+        response::{IntoResponse, Response},
+    };
+    use axum_login::{AuthSession, tower_sessions::Session};
+
+    use crate::{
+        auth::{Backend, cache::Permissions, permission::Operations},
+        resources,
+    };
+
+    /// Keeps the last activity of the session of each request up to date.
+    pub async fn track(
+        State(state): State<crate::AppState>,
+        auth_session: AuthSession<Backend>,
+        session: Session,
+        request: Request,
+        next: Next,
+    ) -> Response {
+        if let (Some(_), Some(id)) = (&auth_session.user, session.id())
+            && let Err(e) = super::touch(&state.db, &id.to_string()).await
+        {
+            tracing::warn!("session activity update failed: {e}");
+        }
+        next.run(request).await
+    }
+
+    pub async fn logout(
+        mut auth_session: AuthSession<Backend>,
+        session: Session,
+        State(state): State<crate::AppState>,
+    ) -> StatusCode {
+        if let Some(id) = session.id()
+            && sqlx::query("DELETE FROM user_session WHERE session_id = $1")
+                .bind(id.to_string())
+                .execute(&state.db)
+                .await
+                .is_err()
+        {
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+        match auth_session.logout().await {
+            Ok(_) => StatusCode::OK,
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // the sessions of a user are only managed with a login, not with an access token
+
+    pub async fn get_own(
+        auth_session: AuthSession<Backend>,
+        session: Session,
+        State(state): State<crate::AppState>,
+    ) -> Response {
+        let user = auth_session.user.unwrap();
+        if user.scope.is_some() {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+        let current = session.id().map(|id| id.to_string());
+        match super::list(&state.db, user.user_id, current.as_deref()).await {
+            Ok(sessions) => Json(sessions).into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    /// Ends all sessions of the user but the one of the request.
+    pub async fn revoke_own_others(
+        auth_session: AuthSession<Backend>,
+        session: Session,
+        State(state): State<crate::AppState>,
+    ) -> StatusCode {
+        let user = auth_session.user.unwrap();
+        if user.scope.is_some() {
+            return StatusCode::UNAUTHORIZED;
+        }
+        let current = session.id().map(|id| id.to_string());
+        match super::revoke(&state.db, user.user_id, None, current.as_deref()).await {
+            Ok(_) => StatusCode::OK,
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub async fn revoke_own(
+        auth_session: AuthSession<Backend>,
+        Path(id): Path<i64>,
+        State(state): State<crate::AppState>,
+    ) -> StatusCode {
+        let user = auth_session.user.unwrap();
+        if user.scope.is_some() {
+            return StatusCode::UNAUTHORIZED;
+        }
+        match super::revoke(&state.db, user.user_id, Some(id), None).await {
+            Ok(0) => StatusCode::NOT_FOUND,
+            Ok(_) => StatusCode::OK,
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Logs a user out everywhere, which takes the permission to update them.
+    pub async fn revoke_user(
+        permissions: Permissions,
+        Path(user_id): Path<i64>,
+        State(state): State<crate::AppState>,
+    ) -> StatusCode {
+        match permissions
+            .has_id(resources::Type::User, user_id, Operations::UPDATE)
+            .await
+        {
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
+            Ok(false) => return StatusCode::UNAUTHORIZED,
+            Ok(true) => {}
+        }
+        match super::revoke(&state.db, user_id, None, None).await {
+            Ok(_) => StatusCode::OK,
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, PgPool, types::Json};
+use uuid::Uuid;
+
+use super::permission::Operations;
+use crate::resources::Type;
+
+/// Start of every token, so they are easy to recognise in scripts and logs.
+const PREFIX: &str = "noodle_";
+
+/// What a personal access token may be used for: `operations` on the resource types in `types`,
+/// on all of them without it. It only narrows down what the grants of the user allow.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Scope {
+    #[serde(default)]
+    pub types: Option<Vec<Type>>,
+    #[serde(default = "all_operations")]
+    pub operations: Operations,
+}
+
+fn all_operations() -> Operations {
+    Operations::ALL
+}
+
+impl Scope {
+    /// Operations the token may be used for on resources of `resource_type`.
+    pub fn operations_on(&self, resource_type: Type) -> Operations {
+        if self
+            .types
+            .as_ref()
+            .is_none_or(|types| types.contains(&resource_type))
+        {
+            self.operations
+        } else {
+            Operations::NONE
+        }
+    }
+}
+
+/// A token as it is listed, the token itself is only stored hashed.
+#[derive(Serialize, FromRow, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AccessToken {
+    pub id: i64,
+    pub name: String,
+    #[serde(flatten)]
+    pub scope: Json<Scope>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct NewToken {
+    pub name: String,
+    #[serde(flatten)]
+    pub scope: Scope,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl NewToken {
+    pub fn is_valid(&self) -> bool {
+        !self.name.trim().is_empty()
+            && self.scope.operations.is_valid()
+            && self.expires_at.is_none_or(|e| e > Utc::now())
+    }
+}
+
+/// A token that was just created, the only time the token itself is handed out.
+#[derive(Serialize, Debug)]
+pub struct CreatedToken {
+    #[serde(flatten)]
+    pub info: AccessToken,
+    pub token: String,
+}
+
+pub(super) fn hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+pub async fn create(db: &PgPool, user_id: i64, new: NewToken) -> Result<CreatedToken, sqlx::Error> {
+    let token = format!(
+        "{PREFIX}{}{}",
+        Uuid::new_v4().simple(),
+        Uuid::new_v4().simple()
+    );
+    let info = sqlx::query_as::<_, AccessToken>(
+        "INSERT INTO access_token(user_id, name, token_hash, scope, expires_at) \
+VALUES ($1, $2, $3, $4, $5) RETURNING id, name, scope, created_at, expires_at, last_used",
+    )
+    .bind(user_id)
+    .bind(new.name.trim())
+    .bind(hash(&token))
+    .bind(Json(&new.scope))
+    .bind(new.expires_at)
+    .fetch_one(db)
+    .await?;
+    Ok(CreatedToken { info, token })
+}
+
+pub async fn list(db: &PgPool, user_id: i64) -> Result<Vec<AccessToken>, sqlx::Error> {
+    sqlx::query_as::<_, AccessToken>(
+        "SELECT id, name, scope, created_at, expires_at, last_used FROM access_token \
+WHERE user_id = $1 ORDER BY id",
+    )
+    .bind(user_id)
+    .fetch_all(db)
+    .await
+}
+
+/// `false` if the user has no token `id`.
+pub async fn revoke(db: &PgPool, user_id: i64, id: i64) -> Result<bool, sqlx::Error> {
+    Ok(
+        sqlx::query("DELETE FROM access_token WHERE id = $1 AND user_id = $2")
+            .bind(id)
+            .bind(user_id)
+            .execute(db)
+            .await?
+            .rows_affected()
+            > 0,
+    )
+}
+
+/// The user and scope of `token` if it exists and has not expired, noting its use.
+pub(crate) async fn resolve(db: &PgPool, token: &str) -> Result<Option<(i64, Scope)>, sqlx::Error> {
+    let resolved = sqlx::query_as::<_, (i64, Json<Scope>)>(
+        "UPDATE access_token SET last_used = now() \
+WHERE token_hash = $1 AND (expires_at IS NULL OR expires_at > now()) RETURNING user_id, scope",
+    )
+    .bind(hash(token))
+    .fetch_optional(db)
+    .await?;
+    Ok(resolved.map(|(user_id, scope)| (user_id, scope.0)))
+}
+
+/// Removes the tokens that expired.
+pub(crate) async fn cleanup(db: impl sqlx::PgExecutor<'_>) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar::<_, String>(
+        "DELETE FROM access_token WHERE expires_at <= now() \
+RETURNING format('token %s of user %s', id, user_id)",
+    )
+    .fetch_all(db)
+    .await
+}
+
+pub mod http {
+    use axum::{
+        Json,
+        extract::{Path, Request, State},
+        http::{StatusCode, header},
+        middleware::Next,
+        response::{IntoResponse, Response},
+    };
+    use axum_login::AuthSession;
+
+    use super::NewToken;
+    use crate::auth::Backend;
+
+    /// Logs in the user of an `Authorization: Bearer` token for this request only, with the
+    /// scope of the token. Requests with an unknown or expired token are rejected.
+    pub async fn bearer(mut request: Request, next: Next) -> Response {
+        let Some(value) = request.headers().get(header::AUTHORIZATION) else {
+            return next.run(request).await;
+        };
+        let Some(token) = value
+            .to_str()
+            .ok()
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(str::to_owned)
+        else {
+            return StatusCode::UNAUTHORIZED.into_response();
+        };
+        let Some(auth_session) = request.extensions_mut().get_mut::<AuthSession<Backend>>() else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        match auth_session.backend.authenticate_token(&token).await {
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            Ok(None) => return StatusCode::UNAUTHORIZED.into_response(),
+            Ok(Some(user)) => auth_session.user = Some(user),
+        }
+        next.run(request).await
+    }
+
+    // tokens are managed with a login only, so that a token cannot hand out a wider one
+
+    pub async fn get_own(
+        auth_session: AuthSession<Backend>,
+        State(state): State<crate::AppState>,
+    ) -> Response {
+        let user = auth_session.user.unwrap();
+        if user.scope.is_some() {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+        match super::list(&state.db, user.user_id).await {
+            Ok(tokens) => Json(tokens).into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    pub async fn create(
+        auth_session: AuthSession<Backend>,
+        State(state): State<crate::AppState>,
+        Json(new): Json<NewToken>,
+    ) -> Response {
+        let user = auth_session.user.unwrap();
+        if user.scope.is_some() {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+        if !new.is_valid() {
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+        match super::create(&state.db, user.user_id, new).await {
+            Ok(created) => (StatusCode::CREATED, Json(created)).into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    pub async fn revoke(
+        auth_session: AuthSession<Backend>,
+        Path(id): Path<i64>,
+        State(state): State<crate::AppState>,
+    ) -> StatusCode {
+        let user = auth_session.user.unwrap();
+        if user.scope.is_some() {
+            return StatusCode::UNAUTHORIZED;
+        }
+        match super::revoke(&state.db, user.user_id, id).await {
+            Ok(true) => StatusCode::OK,
+            Ok(false) => StatusCode::NOT_FOUND,
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
@@ -0,0 +1,552 @@
+use std::env;
+
+use chrono::Utc;
+use const_format::concatcp;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use totp_rs::{Algorithm, Secret, TOTP};
+use uuid::Uuid;
+
+use super::token::hash;
+
+/// Seconds a code of the authenticator app is valid for, the default of the apps.
+const STEP: u64 = 30;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Holds if a role of the user `u` requires two-factor authentication.
+const REQUIRED: &str = "EXISTS(SELECT 1 FROM current_user_has_role ur \
+JOIN \"role\" r ON r.id = ur.role_id WHERE ur.user_id = u.id AND r.require_totp)";
+
+/// Holds if a role of the user `u` requires two-factor authentication they did not set up yet,
+/// selected along with the user on every request.
+pub(crate) const MISSING: &str = concatcp!(
+    REQUIRED,
+    " AND NOT EXISTS(SELECT 1 FROM user_totp t WHERE t.user_id = u.id AND t.enabled)"
+);
+
+/// What the authenticator app is set up with to start the enrollment, shown as QR code of `uri`
+/// or entered as `secret`.
+#[derive(Serialize, Debug)]
+pub struct Enrollment {
+    pub secret: String,
+    pub uri: String,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Status {
+    pub enabled: bool,
+    /// Whether one of the roles of the user requires it
+    pub required: bool,
+    pub recovery_codes_left: i32,
+}
+
+/// A code of the authenticator app, or a recovery code where it says so.
+#[derive(Deserialize, Debug)]
+pub struct Code {
+    pub code: String,
+}
+
+/// The authenticator with the parameters apps support, SHA-1 codes of 6 digits every 30 seconds.
+fn authenticator(secret: Vec<u8>, account: String) -> TOTP {
+    let issuer = env::var("TOTP_ISSUER").unwrap_or_else(|_| "noodle".to_owned());
+    // the secrets are generated with the 160 bits the RFC recommends, and email addresses have no
+    // ':' to break the URI apart
+    TOTP::new_unchecked(Algorithm::SHA1, 6, 0, STEP, secret, Some(issuer), account)
+}
+
+/// Recovery codes are compared without dashes and case, as they may be typed in.
+fn normalize(code: &str) -> String {
+    code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .collect::<String>()
+        .to_ascii_lowercase()
+}
+
+/// How recovery codes are stored.
+fn hashed(codes: &[String]) -> Vec<String> {
+    codes.iter().map(|c| hash(&normalize(c))).collect()
+}
+
+fn new_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code = Uuid::new_v4().simple().to_string();
+            format!("{}-{}", &code[..5], &code[5..10])
+        })
+        .collect()
+}
+
+pub async fn status(db: &PgPool, user_id: i64) -> Result<Status, sqlx::Error> {
+    let (enabled, required, recovery_codes_left) = sqlx::query_as::<_, (bool, bool, i32)>(&format!(
+        "SELECT COALESCE(t.enabled, false), {REQUIRED}, COALESCE(cardinality(t.recovery_codes), 0) \
+FROM (SELECT $1::bigint AS id) u LEFT JOIN user_totp t ON t.user_id = u.id"
+    ))
+    .bind(user_id)
+    .fetch_one(db)
+    .await?;
+    Ok(Status {
+        enabled,
+        required,
+        recovery_codes_left,
+    })
+}
+
+/// Whether logging in as `user_id` takes a code.
+pub async fn enabled(db: &PgPool, user_id: i64) -> Result<bool, sqlx::Error> {
+    Ok(
+        sqlx::query("SELECT 1 FROM user_totp WHERE user_id = $1 AND enabled")
+            .bind(user_id)
+            .fetch_optional(db)
+            .await?
+            .is_some(),
+    )
+}
+
+/// Starts over the enrollment of `user_id` with a new secret, which is only enabled once a code
+/// of it is [`confirm`]ed. `None` if two-factor authentication is enabled already.
+pub async fn enroll(
+    db: &PgPool,
+    user_id: i64,
+    email: &str,
+) -> Result<Option<Enrollment>, sqlx::Error> {
+    let Secret::Raw(secret) = Secret::generate_secret() else {
+        unreachable!("generated secrets are raw")
+    };
+    let started = sqlx::query(
+        "INSERT INTO user_totp(user_id, secret) VALUES ($1, $2) \
+ON CONFLICT (user_id) DO UPDATE SET secret = EXCLUDED.secret, last_step = NULL \
+WHERE NOT user_totp.enabled",
+    )
+    .bind(user_id)
+    .bind(&secret)
+    .execute(db)
+    .await?
+    .rows_affected()
+        > 0;
+    if !started {
+        return Ok(None);
+    }
+    let totp = authenticator(secret, email.to_owned());
+    Ok(Some(Enrollment {
+        secret: totp.get_secret_base32(),
+        uri: totp.get_url(),
+    }))
+}
+
+/// Whether `code` is one of the authenticator of `user_id`, enabled or still enrolling. Each code
+/// is only accepted once, and those of the steps right before and after the current one as well
+/// to allow for clocks that are a bit off.
+async fn check_code(
+    db: &PgPool,
+    user_id: i64,
+    code: &str,
+    enabled: bool,
+) -> Result<bool, sqlx::Error> {
+    let Some(secret) = sqlx::query_scalar::<_, Vec<u8>>(
+        "SELECT secret FROM user_totp WHERE user_id = $1 AND enabled = $2",
+    )
+    .bind(user_id)
+    .bind(enabled)
+    .fetch_optional(db)
+    .await?
+    else {
+        return Ok(false);
+    };
+    let totp = authenticator(secret, String::new());
+    let now = Utc::now().timestamp() as u64 / STEP;
+    let Some(step) = (now - 1..=now + 1).find(|step| totp.check(code.trim(), step * STEP)) else {
+        return Ok(false);
+    };
+    Ok(sqlx::query(
+        "UPDATE user_totp SET last_step = $2 WHERE user_id = $1 \
+AND (last_step IS NULL OR last_step < $2)",
+    )
+    .bind(user_id)
+    .bind(step as i64)
+    .execute(db)
+    .await?
+    .rows_affected()
+        > 0)
+}
+
+/// Enables two-factor authentication for `user_id` if `code` is one of the enrolling
+/// authenticator, and returns the recovery codes for when it is lost.
+pub async fn confirm(
+    db: &PgPool,
+    user_id: i64,
+    code: &str,
+) -> Result<Option<Vec<String>>, sqlx::Error> {
+    if !check_code(db, user_id, code, false).await? {
+        return Ok(None);
+    }
+    let codes = new_recovery_codes();
+    let enabled = sqlx::query(
+        "UPDATE user_totp SET enabled = true, recovery_codes = $2 WHERE user_id = $1 AND NOT enabled",
+    )
+    .bind(user_id)
+    .bind(hashed(&codes))
+    .execute(db)
+    .await?
+    .rows_affected()
+        > 0;
+    Ok(enabled.then_some(codes))
+}
+
+/// Whether `code` is a code of the authenticator of `user_id` or one of their recovery codes,
+/// which is used up by this.
+pub async fn verify(db: &PgPool, user_id: i64, code: &str) -> Result<bool, sqlx::Error> {
+    let code = code.trim();
+    if code.len() == 6 && code.bytes().all(|b| b.is_ascii_digit()) {
+        return check_code(db, user_id, code, true).await;
+    }
+    Ok(sqlx::query(
+        "UPDATE user_totp SET recovery_codes = array_remove(recovery_codes, $2) \
+WHERE user_id = $1 AND enabled AND $2 = ANY(recovery_codes)",
+    )
+    .bind(user_id)
+    .bind(hash(&normalize(code)))
+    .execute(db)
+    .await?
+    .rows_affected()
+        > 0)
+}
+
+/// Replaces the recovery codes of `user_id`, `None` without two-factor authentication.
+pub async fn renew_recovery_codes(
+    db: &PgPool,
+    user_id: i64,
+) -> Result<Option<Vec<String>>, sqlx::Error> {
+    let codes = new_recovery_codes();
+    let renewed =
+        sqlx::query("UPDATE user_totp SET recovery_codes = $2 WHERE user_id = $1 AND enabled")
+            .bind(user_id)
+            .bind(hashed(&codes))
+            .execute(db)
+            .await?
+            .rows_affected()
+            > 0;
+    Ok(renewed.then_some(codes))
+}
+
+/// Turns two-factor authentication off for `user_id`, `false` if it was not set up.
+pub async fn disable(db: &PgPool, user_id: i64) -> Result<bool, sqlx::Error> {
+    Ok(sqlx::query("DELETE FROM user_totp WHERE user_id = $1")
+        .bind(user_id)
+        .execute(db)
+        .await?
+        .rows_affected()
+        > 0)
+}
+
+pub mod http {
+    use std::net::SocketAddr;
+
+    use axum::{
+        Json,
+        extract::{ConnectInfo, Path, Request, State},
+        http::{HeaderMap, Method, StatusCode},
+        middleware::Next,
+        response::{IntoResponse, Response},
+    };
+    use axum_login::{AuthSession, AuthnBackend, tower_sessions::Session};
+    use chrono::Utc;
+    use serde::{Deserialize, Serialize};
+
+    use super::Code;
+    use crate::{
+        auth::{Backend, cache::Permissions, permission::Operations},
+        resources,
+    };
+
+    /// Session key of a login that still waits for its code.
+    const PENDING: &str = "totp.pending";
+    /// Seconds there are to enter the code after the password.
+    const PENDING_TIMEOUT: i64 = 300;
+    /// Wrong codes after which the password has to be entered again.
+    const MAX_ATTEMPTS: u8 = 5;
+
+    #[derive(Serialize, Deserialize)]
+    struct Pending {
+        user_id: i64,
+        since: i64,
+        attempts: u8,
+    }
+
+    /// Keeps in the session that `user_id` entered their password and the login continues with
+    /// [`login`].
+    pub async fn await_code(
+        session: &Session,
+        user_id: i64,
+    ) -> Result<(), axum_login::tower_sessions::session::Error> {
+        session
+            .insert(
+                PENDING,
+                Pending {
+                    user_id,
+                    since: Utc::now().timestamp(),
+                    attempts: 0,
+                },
+            )
+            .await
+    }
+
+    /// Second step of a login with two-factor authentication, after `/login` answered `202`.
+    pub async fn login(
+        mut auth_session: AuthSession<Backend>,
+        session: Session,
+        State(state): State<crate::AppState>,
+        ConnectInfo(addr): ConnectInfo<SocketAddr>,
+        headers: HeaderMap,
+        Json(code): Json<Code>,
+    ) -> StatusCode {
+        let mut pending = match session.get::<Pending>(PENDING).await {
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
+            Ok(None) => return StatusCode::UNAUTHORIZED,
+            Ok(Some(p)) => p,
+        };
+        if pending.since + PENDING_TIMEOUT < Utc::now().timestamp() {
+            return match session.remove::<Pending>(PENDING).await {
+                Ok(_) => StatusCode::UNAUTHORIZED,
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+        }
+        match super::verify(&state.db, pending.user_id, &code.code).await {
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
+            Ok(false) => {
+                pending.attempts += 1;
+                let stored = if pending.attempts >= MAX_ATTEMPTS {
+                    session.remove::<Pending>(PENDING).await.map(|_| ())
+                } else {
+                    session.insert(PENDING, pending).await
+                };
+                return match stored {
+                    Ok(_) => StatusCode::UNAUTHORIZED,
+                    Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                };
+            }
+            Ok(true) => {}
+        }
+        if session.remove::<Pending>(PENDING).await.is_err() {
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+        match auth_session.backend.get_user(&pending.user_id).await {
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Ok(None) => StatusCode::UNAUTHORIZED,
+            Ok(Some(user)) => {
+                crate::auth::start_session(
+                    &mut auth_session,
+                    &session,
+                    &state.db,
+                    &user,
+                    addr,
+                    &headers,
+                )
+                .await
+            }
+        }
+    }
+
+    /// Holds users a role requires two-factor authentication of to setting it up, everything
+    /// else is answered with `403` until they did.
+    pub async fn enforce(
+        auth_session: AuthSession<Backend>,
+        request: Request,
+        next: Next,
+    ) -> Response {
+        let path = request.uri().path();
+        let exempt = path.starts_with("/user/totp")
+            || path == "/logout"
+            || path == "/user" && request.method() == Method::GET;
+        if let Some(user) = &auth_session.user
+            && user.totp_missing
+            && !exempt
+        {
+            return StatusCode::FORBIDDEN.into_response();
+        }
+        next.run(request).await
+    }
+
+    // two-factor authentication is only managed with a login, not with an access token
+
+    pub async fn get_own(
+        auth_session: AuthSession<Backend>,
+        State(state): State<crate::AppState>,
+    ) -> Response {
+        let user = auth_session.user.unwrap();
+        if user.scope.is_some() {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+        match super::status(&state.db, user.user_id).await {
+            Ok(status) => Json(status).into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    pub async fn enroll(
+        auth_session: AuthSession<Backend>,
+        State(state): State<crate::AppState>,
+    ) -> Response {
+        let user = auth_session.user.unwrap();
+        if user.scope.is_some() {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+        match super::enroll(&state.db, user.user_id, &user.email).await {
+            Ok(Some(enrollment)) => (StatusCode::CREATED, Json(enrollment)).into_response(),
+            Ok(None) => StatusCode::CONFLICT.into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct RecoveryCodes {
+        recovery_codes: Vec<String>,
+    }
+
+    pub async fn confirm(
+        auth_session: AuthSession<Backend>,
+        State(state): State<crate::AppState>,
+        Json(code): Json<Code>,
+    ) -> Response {
+        let user = auth_session.user.unwrap();
+        if user.scope.is_some() {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+        match super::confirm(&state.db, user.user_id, &code.code).await {
+            Ok(Some(recovery_codes)) => Json(RecoveryCodes { recovery_codes }).into_response(),
+            Ok(None) => StatusCode::BAD_REQUEST.into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    /// New recovery codes in exchange for a code, the old ones no longer work.
+    pub async fn renew_recovery_codes(
+        auth_session: AuthSession<Backend>,
+        State(state): State<crate::AppState>,
+        Json(code): Json<Code>,
+    ) -> Response {
+        let user = auth_session.user.unwrap();
+        if user.scope.is_some() {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+        match super::verify(&state.db, user.user_id, &code.code).await {
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            Ok(false) => return StatusCode::BAD_REQUEST.into_response(),
+            Ok(true) => {}
+        }
+        match super::renew_recovery_codes(&state.db, user.user_id).await {
+            Ok(Some(recovery_codes)) => Json(RecoveryCodes { recovery_codes }).into_response(),
+            Ok(None) => StatusCode::NOT_FOUND.into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    /// Turns two-factor authentication off in exchange for a code, unless a role requires it.
+    pub async fn disable(
+        auth_session: AuthSession<Backend>,
+        State(state): State<crate::AppState>,
+        Json(code): Json<Code>,
+    ) -> StatusCode {
+        let user = auth_session.user.unwrap();
+        if user.scope.is_some() {
+            return StatusCode::UNAUTHORIZED;
+        }
+        match super::status(&state.db, user.user_id).await {
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
+            Ok(status) if !status.enabled => return StatusCode::NOT_FOUND,
+            Ok(status) if status.required => return StatusCode::CONFLICT,
+            Ok(_) => {}
+        }
+        match super::verify(&state.db, user.user_id, &code.code).await {
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
+            Ok(false) => return StatusCode::BAD_REQUEST,
+            Ok(true) => {}
+        }
+        match super::disable(&state.db, user.user_id).await {
+            Ok(_) => StatusCode::OK,
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Resets the two-factor authentication of a user who lost their authenticator and recovery
+    /// codes, which takes the permission to update them.
+    pub async fn reset_user(
+        permissions: Permissions,
+        Path(user_id): Path<i64>,
+        State(state): State<crate::AppState>,
+    ) -> StatusCode {
+        match permissions
+            .has_id(resources::Type::User, user_id, Operations::UPDATE)
+            .await
+        {
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
+            Ok(false) => return StatusCode::UNAUTHORIZED,
+            Ok(true) => {}
+        }
+        match super::disable(&state.db, user_id).await {
+            Ok(true) => StatusCode::OK,
+            Ok(false) => StatusCode::NOT_FOUND,
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct RoleRequirement {
+        required: bool,
+    }
+
+    /// Whether the members of a role have to use two-factor authentication, which takes the
+    /// permission to read it.
+    pub async fn get_role_requirement(
+        permissions: Permissions,
+        Path(role_id): Path<i64>,
+        State(state): State<crate::AppState>,
+    ) -> Response {
+        match permissions
+            .has_id(resources::Type::Role, role_id, Operations::READ)
+            .await
+        {
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
+            Ok(true) => {}
+        }
+        match sqlx::query_scalar::<_, bool>("SELECT require_totp FROM \"role\" WHERE id = $1")
+            .bind(role_id)
+            .fetch_optional(&state.db)
+            .await
+        {
+            Ok(Some(required)) => Json(RoleRequirement { required }).into_response(),
+            Ok(None) => StatusCode::NOT_FOUND.into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    /// Requires two-factor authentication of the members of a role, which takes the permission to
+    /// update it. Members without it are held to setting it up on their next request.
+    pub async fn set_role_requirement(
+        permissions: Permissions,
+        Path(role_id): Path<i64>,
+        State(state): State<crate::AppState>,
+        Json(requirement): Json<RoleRequirement>,
+    ) -> StatusCode {
+        match permissions
+            .has_id(resources::Type::Role, role_id, Operations::UPDATE)
+            .await
+        {
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
+            Ok(false) => return StatusCode::UNAUTHORIZED,
+            Ok(true) => {}
+        }
+        match sqlx::query("UPDATE \"role\" SET require_totp = $1 WHERE id = $2")
+            .bind(requirement.required)
+            .bind(role_id)
+            .execute(&state.db)
+            .await
+        {
+            Ok(r) if r.rows_affected() == 0 => StatusCode::NOT_FOUND,
+            Ok(_) => StatusCode::OK,
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
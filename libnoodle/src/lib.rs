@@ -1,12 +1,20 @@
 #![allow(dead_code)]
+use std::sync::Arc;
+
 use sqlx::PgPool;
 
 pub mod auth;
+pub mod mail;
+pub mod media;
 pub mod resources;
 pub mod user;
 
 #[derive(Clone)]
 pub struct AppState {
     pub db: PgPool,
-    pub media_path: String,
+    pub permissions: Arc<auth::cache::PermissionCache>,
+    pub media: media::Store,
+    pub mailer: mail::Sender,
+    pub upload_policy: Arc<media::policy::UploadPolicy>,
+    pub variants: media::variant::Pipeline,
 }
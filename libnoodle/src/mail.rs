@@ -0,0 +1,68 @@
+use std::{env, sync::Arc};
+
+use async_trait::async_trait;
+use lettre::message::{Mailbox, header::ContentType};
+
+pub mod file;
+pub mod smtp;
+pub use file::FileMailer;
+pub use smtp::SmtpMailer;
+
+/// Shared handle to the configured backend, see [`crate::AppState::mailer`].
+pub type Sender = Arc<dyn Mailer>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid mail address")]
+    Address(#[from] lettre::address::AddressError),
+    #[error("message could not be built")]
+    Message(#[from] lettre::error::Error),
+    #[error("I/O operation failed")]
+    Io(#[from] std::io::Error),
+    #[error("SMTP delivery failed")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+}
+
+/// A plain text mail to a single recipient.
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Message {
+    /// The mail as sent from `from`, with all headers.
+    fn build(&self, from: &Mailbox) -> Result<lettre::Message, Error> {
+        Ok(lettre::Message::builder()
+            .from(from.clone())
+            .to(self.to.parse()?)
+            .subject(&self.subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(self.body.clone())?)
+    }
+}
+
+/// Delivery of the mails the server sends, like password reset links.
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, message: &Message) -> Result<(), Error>;
+}
+
+/// Builds the mailer configured by the environment.
+///
+/// `MAIL_BACKEND` is either `file` (default) or `smtp`, both send from `MAIL_FROM`. The file
+/// backend writes each mail to `MAIL_PATH`, or logs it with the `mail` target without it, see
+/// [`FileMailer`]. The SMTP backend delivers to the server of `SMTP_URL`, see
+/// [`SmtpMailer::from_env`].
+pub fn from_env() -> Sender {
+    let from = env::var("MAIL_FROM")
+        .unwrap_or_else(|_| "noodle <noreply@localhost>".to_owned())
+        .parse::<Mailbox>()
+        .expect("MAIL_FROM has to be a mail address");
+    match env::var("MAIL_BACKEND").as_deref() {
+        Ok("smtp") => Arc::new(SmtpMailer::from_env(from)),
+        Ok("file") | Err(_) => Arc::new(FileMailer::new(from, env::var("MAIL_PATH").ok())),
+        Ok(other) => panic!("unknown MAIL_BACKEND {other}"),
+    }
+}
@@ -0,0 +1,44 @@
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use lettre::message::Mailbox;
+use uuid::Uuid;
+
+use super::{Error, Mailer, Message};
+
+/// Keeps mails instead of sending them, for development and tests: each one is written to
+/// `<time>-<uuid>.eml` in its directory, or logged with the `mail` target without one.
+pub struct FileMailer {
+    from: Mailbox,
+    dir: Option<PathBuf>,
+}
+
+impl FileMailer {
+    pub fn new(from: Mailbox, dir: Option<String>) -> Self {
+        Self {
+            from,
+            dir: dir.map(PathBuf::from),
+        }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, message: &Message) -> Result<(), Error> {
+        let formatted = message.build(&self.from)?.formatted();
+        match &self.dir {
+            Some(dir) => {
+                tokio::fs::create_dir_all(dir).await?;
+                let name = format!(
+                    "{}-{}.eml",
+                    Utc::now().format("%Y%m%dT%H%M%S"),
+                    Uuid::new_v4().simple()
+                );
+                tokio::fs::write(dir.join(name), formatted).await?;
+            }
+            None => tracing::info!(target: "mail", "{}", String::from_utf8_lossy(&formatted)),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,233 @@
+use std::{env, sync::Arc, time::SystemTime};
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures_util::{StreamExt, stream::BoxStream};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+
+use crate::resources::file::ByteRange;
+
+pub mod integrity;
+pub mod local;
+pub mod policy;
+pub mod s3;
+pub mod variant;
+pub use local::LocalStore;
+pub use s3::S3Store;
+
+/// Shared handle to the configured backend, see [`crate::AppState::media`].
+pub type Store = Arc<dyn MediaStore>;
+
+pub type ByteStream<'a> = BoxStream<'a, Result<Bytes, Error>>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O operation failed")]
+    Io(#[from] std::io::Error),
+    #[error("source stream could not be read")]
+    Body,
+    #[error("blob does not exist")]
+    NotFound,
+    #[error("source stream exceeds the size limit")]
+    TooLarge,
+    #[error("object storage request failed")]
+    Remote(#[source] Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// A blob that was written to a [`MediaStore`].
+pub struct Blob {
+    /// Key to retrieve the blob with. This is what `file.location` stores.
+    pub key: String,
+    pub size: u64,
+    /// SHA-256 of the contents.
+    pub checksum: [u8; 32],
+}
+
+/// An object held by a [`MediaStore`], see [`MediaStore::list`].
+pub struct Entry {
+    pub key: String,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+/// Storage for uploaded file contents.
+///
+/// Blobs are content addressed: storing the same bytes twice yields the same key and only one copy.
+/// Stores do not count references themselves, `resources::file` only deletes a blob once no `file`
+/// row points at it anymore.
+#[async_trait]
+pub trait MediaStore: Send + Sync {
+    /// Streams `body` into the store.
+    async fn put(&self, body: ByteStream<'_>) -> Result<Blob, Error>;
+
+    /// Stores `body` under a key of the caller's choosing, replacing what was stored there.
+    /// Meant for contents derived from a blob, like the [`variant`]s of an image.
+    async fn put_at(&self, key: &str, body: ByteStream<'_>) -> Result<(), Error>;
+
+    /// Streams the blob stored under `key`, or only `range` of it.
+    async fn get(&self, key: &str, range: Option<ByteRange>) -> Result<ByteStream<'static>, Error>;
+
+    /// Size of the blob in bytes, `None` if it does not exist.
+    async fn size(&self, key: &str) -> Result<Option<u64>, Error>;
+
+    async fn exists(&self, key: &str) -> Result<bool, Error> {
+        Ok(self.size(key).await?.is_some())
+    }
+
+    /// Removes the blob. Deleting a blob that does not exist is not an error.
+    async fn delete(&self, key: &str) -> Result<(), Error>;
+
+    /// Everything the store holds: blobs, their variants and temporary files of unfinished uploads.
+    async fn list(&self) -> Result<Vec<Entry>, Error>;
+
+    /// Time limited URL clients can download the blob from directly, if the backend supports it.
+    /// `content_type` and `content_disposition` are sent along with the download.
+    async fn presigned_url(
+        &self,
+        _key: &str,
+        _content_type: &str,
+        _content_disposition: &str,
+    ) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+}
+
+/// Dispatches to the backend that holds a blob, based on the scheme of its key.
+/// New blobs go to S3 if it is configured. Keys without a scheme belong to the [`LocalStore`],
+/// which keeps blobs uploaded before switching to S3 readable.
+pub struct Routed {
+    local: Option<LocalStore>,
+    s3: Option<S3Store>,
+}
+
+impl Routed {
+    pub fn new(local: Option<LocalStore>, s3: Option<S3Store>) -> Self {
+        Self { local, s3 }
+    }
+
+    fn backend(&self, key: &str) -> Result<&dyn MediaStore, Error> {
+        let backend: Option<&dyn MediaStore> = if key.starts_with(s3::SCHEME) {
+            self.s3.as_ref().map(|s| s as _)
+        } else {
+            self.local.as_ref().map(|l| l as _)
+        };
+        backend.ok_or(Error::NotFound)
+    }
+}
+
+#[async_trait]
+impl MediaStore for Routed {
+    async fn put(&self, body: ByteStream<'_>) -> Result<Blob, Error> {
+        match (&self.s3, &self.local) {
+            (Some(s3), _) => s3.put(body).await,
+            (None, Some(local)) => local.put(body).await,
+            (None, None) => Err(Error::NotFound),
+        }
+    }
+
+    async fn put_at(&self, key: &str, body: ByteStream<'_>) -> Result<(), Error> {
+        self.backend(key)?.put_at(key, body).await
+    }
+
+    async fn get(&self, key: &str, range: Option<ByteRange>) -> Result<ByteStream<'static>, Error> {
+        self.backend(key)?.get(key, range).await
+    }
+
+    async fn size(&self, key: &str) -> Result<Option<u64>, Error> {
+        match self.backend(key) {
+            Ok(backend) => backend.size(key).await,
+            Err(_) => Ok(None),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        match self.backend(key) {
+            Ok(backend) => backend.delete(key).await,
+            Err(_) => Ok(()),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<Entry>, Error> {
+        let mut entries = Vec::new();
+        if let Some(local) = &self.local {
+            entries.extend(local.list().await?);
+        }
+        if let Some(s3) = &self.s3 {
+            entries.extend(s3.list().await?);
+        }
+        Ok(entries)
+    }
+
+    async fn presigned_url(
+        &self,
+        key: &str,
+        content_type: &str,
+        content_disposition: &str,
+    ) -> Result<Option<String>, Error> {
+        self.backend(key)?
+            .presigned_url(key, content_type, content_disposition)
+            .await
+    }
+}
+
+/// Builds the store configured by the environment.
+///
+/// `MEDIA_BACKEND` is either `local` (default) or `s3`. The local backend stores blobs below
+/// `MEDIA_PATH`. The S3 backend is configured by `S3_BUCKET`, `S3_REGION`, `S3_ENDPOINT`,
+/// `S3_ACCESS_KEY_ID`, `S3_SECRET_ACCESS_KEY` and `S3_PRESIGN_EXPIRY` (seconds, `0` disables
+/// pre-signed downloads), see [`S3Store::from_env`]. If `MEDIA_PATH` is set as well, blobs stored
+/// locally before stay available.
+pub fn from_env() -> Store {
+    let local = env::var("MEDIA_PATH").ok().map(LocalStore::new);
+    match env::var("MEDIA_BACKEND").as_deref() {
+        Ok("s3") => Arc::new(Routed::new(local, Some(S3Store::from_env()))),
+        Ok("local") | Err(_) => Arc::new(local.expect("MEDIA_PATH has to be set")),
+        Ok(other) => panic!("unknown MEDIA_BACKEND {other}"),
+    }
+}
+
+/// Wraps an in-memory buffer for [`MediaStore::put`].
+pub fn bytes_stream<'a>(bytes: Vec<u8>) -> ByteStream<'a> {
+    Box::pin(futures_util::stream::once(
+        async move { Ok(Bytes::from(bytes)) },
+    ))
+}
+
+/// Reads a whole blob into memory. Only meant for small files.
+pub async fn read_to_vec(store: &dyn MediaStore, key: &str) -> Result<Vec<u8>, Error> {
+    let mut stream = store.get(key, None).await?;
+    let mut contents = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        contents.extend_from_slice(&chunk?);
+    }
+    Ok(contents)
+}
+
+/// Writes `body` to the file at `path` and returns its size and SHA-256.
+/// The file is removed again if the body can't be read completely.
+async fn spool(
+    body: &mut ByteStream<'_>,
+    path: impl AsRef<std::path::Path>,
+) -> Result<(u64, [u8; 32]), Error> {
+    let mut file = tokio::fs::File::create(&path).await?;
+    let mut hasher = Sha256::new();
+    let mut size = 0u64;
+    let written: Result<(), Error> = async {
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk?;
+            hasher.update(&chunk);
+            file.write_all(&chunk).await?;
+            size += chunk.len() as u64;
+        }
+        file.flush().await?;
+        Ok(())
+    }
+    .await;
+    drop(file);
+    if let Err(e) = written {
+        let _ = tokio::fs::remove_file(&path).await;
+        return Err(e);
+    }
+    Ok((size, hasher.finalize().into()))
+}
@@ -0,0 +1,162 @@
+use std::{
+    collections::HashSet,
+    env,
+    time::{Duration, SystemTime},
+};
+
+use serde::Serialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::{MediaStore, Store, local, variant};
+use crate::resources::file::{self, BlobRefError};
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+const DEFAULT_GRACE: Duration = Duration::from_secs(60 * 60);
+
+/// Inconsistencies between the `file` table and the media store.
+#[derive(Serialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Report {
+    /// Keys of blobs and variants no `file` row references
+    pub orphaned_blobs: Vec<String>,
+    /// Uids of `file` rows whose blob does not exist
+    pub missing_blobs: Vec<String>,
+    /// Keys of temporary files of uploads that never completed
+    pub temp_files: Vec<String>,
+    /// Bytes taken up by orphaned blobs and temporary files
+    pub reclaimable: u64,
+    /// Whether orphans and temporary files were deleted and rows without blob removed
+    pub repaired: bool,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.orphaned_blobs.is_empty()
+            && self.missing_blobs.is_empty()
+            && self.temp_files.is_empty()
+    }
+}
+
+/// Compares the `file` table with the contents of the media store.
+///
+/// Blobs and temporary files younger than `grace` are left alone, as they may belong to an upload
+/// that is still in progress. With `repair`, orphaned blobs and temporary files are deleted and
+/// `file` rows whose blob is missing are removed.
+pub async fn check(
+    db: &PgPool,
+    store: &dyn MediaStore,
+    grace: Duration,
+    repair: bool,
+) -> Result<Report, BlobRefError> {
+    // rows are read before the store is listed, so a blob stored in between is either too young
+    // to be considered orphaned or still released under the blob lock
+    let rows = sqlx::query_as::<_, (Uuid, String)>("SELECT uid, location FROM \"file\"")
+        .fetch_all(db)
+        .await?;
+    let entries = store.list().await?;
+
+    let referenced: HashSet<&str> = rows.iter().map(|(_, location)| location.as_str()).collect();
+    let stored: HashSet<&str> = entries.iter().map(|e| e.key.as_str()).collect();
+    let now = SystemTime::now();
+
+    let mut report = Report::default();
+    let mut orphaned_variants = Vec::new();
+    for entry in &entries {
+        let expired = entry
+            .modified
+            .is_none_or(|m| now.duration_since(m).unwrap_or_default() >= grace);
+        if !expired {
+            continue;
+        }
+        // before anything else, a temporary file in a checksum directory is no blob either
+        if local::is_temp(&entry.key) {
+            report.temp_files.push(entry.key.clone());
+        } else if let Some(source) = variant::source_key(&entry.key) {
+            if referenced.contains(source) {
+                continue;
+            }
+            orphaned_variants.push(entry.key.clone());
+        } else {
+            if referenced.contains(entry.key.as_str()) {
+                continue;
+            }
+            report.orphaned_blobs.push(entry.key.clone());
+        }
+        report.reclaimable += entry.size;
+    }
+
+    let mut missing = Vec::new();
+    for (uid, location) in &rows {
+        // the row may be younger than the listing
+        if !stored.contains(location.as_str()) && !store.exists(location).await? {
+            missing.push((*uid, location.as_str()));
+        }
+    }
+    report.missing_blobs = missing
+        .iter()
+        .map(|(uid, _)| uid.simple().to_string())
+        .collect();
+
+    if repair {
+        for key in &report.orphaned_blobs {
+            // checks again that no row references the blob
+            file::release_blob(db, store, key).await?;
+        }
+        for key in orphaned_variants.iter().chain(&report.temp_files) {
+            store.delete(key).await?;
+        }
+        for (uid, location) in &missing {
+            file::remove_if_missing(db, store, *uid, location).await?;
+        }
+        report.repaired = true;
+    }
+    report.orphaned_blobs.extend(orphaned_variants);
+    Ok(report)
+}
+
+fn seconds_from_env(var: &str, default: Duration) -> Duration {
+    match env::var(var) {
+        Ok(secs) => Duration::from_secs(
+            secs.parse()
+                .unwrap_or_else(|_| panic!("{var} has to be a number of seconds")),
+        ),
+        Err(_) => default,
+    }
+}
+
+/// Age below which blobs and temporary files are not considered orphaned, `MEDIA_CHECK_GRACE`
+/// seconds or one hour.
+pub fn grace_from_env() -> Duration {
+    seconds_from_env("MEDIA_CHECK_GRACE", DEFAULT_GRACE)
+}
+
+/// Runs [`check`] periodically, configured by `MEDIA_CHECK_INTERVAL` (seconds, default one day,
+/// `0` disables it), [`grace_from_env`] and `MEDIA_CHECK_REPAIR` (`true` to repair what is found,
+/// default `false`). Findings and failed runs are logged as warnings.
+pub fn spawn_from_env(db: PgPool, store: Store) {
+    let interval = seconds_from_env("MEDIA_CHECK_INTERVAL", DEFAULT_INTERVAL);
+    let grace = grace_from_env();
+    let repair = env::var("MEDIA_CHECK_REPAIR").is_ok_and(|r| r == "true");
+    if interval.is_zero() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            match check(&db, &*store, grace, repair).await {
+                Ok(report) if report.is_clean() => {}
+                Ok(report) => tracing::warn!(
+                    "media check: {} orphaned blobs, {} missing blobs, {} temporary files{}",
+                    report.orphaned_blobs.len(),
+                    report.missing_blobs.len(),
+                    report.temp_files.len(),
+                    if report.repaired { ", repaired" } else { "" }
+                ),
+                Err(e) => tracing::warn!("media check failed: {e}"),
+            }
+        }
+    });
+}
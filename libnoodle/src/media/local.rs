@@ -0,0 +1,162 @@
+use std::io::{ErrorKind, SeekFrom};
+
+use async_trait::async_trait;
+use futures_util::{StreamExt, TryStreamExt};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+use super::{Blob, ByteStream, Entry, Error, MediaStore};
+use crate::resources::file::{ByteRange, Path};
+
+pub const TEMP_SUFFIX: &str = "tmp";
+
+/// Whether the entry stored under `key` is a temporary file of an upload, in the base directory or
+/// left in a checksum directory by an older version.
+pub fn is_temp(key: &str) -> bool {
+    key.rsplit('/')
+        .next()
+        .and_then(|name| name.strip_suffix(TEMP_SUFFIX))
+        .is_some_and(|stem| stem.ends_with('.'))
+}
+
+/// Stores blobs on the local file system below `MEDIA_PATH`, using the layout of
+/// [`Path::from_hash`]. Uploads are written to a `<uuid>.tmp` file in the base directory first and
+/// renamed once complete.
+pub struct LocalStore {
+    base_path: String,
+}
+
+impl LocalStore {
+    pub fn new(base_path: String) -> Self {
+        Self { base_path }
+    }
+
+    pub fn base_path(&self) -> &str {
+        &self.base_path
+    }
+
+    fn path(&self, key: &str) -> Path {
+        Path::from_relative_path(&self.base_path, key)
+    }
+}
+
+#[async_trait]
+impl MediaStore for LocalStore {
+    async fn put(&self, mut body: ByteStream<'_>) -> Result<Blob, Error> {
+        let temp_path = self.path(&format!("{}.{}", Uuid::new_v4().simple(), TEMP_SUFFIX));
+        let (size, checksum) = super::spool(&mut body, &temp_path).await?;
+
+        let path = Path::from_hash(&self.base_path, &checksum);
+        let key = path
+            .0
+            .strip_prefix(&self.base_path)
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+
+        let moved = async {
+            tokio::fs::create_dir_all(path.0.parent().unwrap()).await?;
+            if tokio::fs::try_exists(&path).await? {
+                // same contents are already stored
+                tokio::fs::remove_file(&temp_path).await
+            } else {
+                tokio::fs::rename(&temp_path, &path).await
+            }
+        }
+        .await;
+        if let Err(e) = moved {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(e.into());
+        }
+
+        Ok(Blob {
+            key,
+            size,
+            checksum,
+        })
+    }
+
+    async fn put_at(&self, key: &str, mut body: ByteStream<'_>) -> Result<(), Error> {
+        let temp_path = self.path(&format!("{}.{}", Uuid::new_v4().simple(), TEMP_SUFFIX));
+        super::spool(&mut body, &temp_path).await?;
+
+        let path = self.path(key);
+        let moved = async {
+            tokio::fs::create_dir_all(path.0.parent().unwrap()).await?;
+            tokio::fs::rename(&temp_path, &path).await
+        }
+        .await;
+        if let Err(e) = moved {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    async fn get(&self, key: &str, range: Option<ByteRange>) -> Result<ByteStream<'static>, Error> {
+        let mut file = match tokio::fs::File::open(self.path(key)).await {
+            Ok(f) => f,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Err(Error::NotFound),
+            Err(e) => return Err(e.into()),
+        };
+        match range {
+            None => Ok(ReaderStream::new(file).map_err(Error::Io).boxed()),
+            Some(range) => {
+                file.seek(SeekFrom::Start(range.start)).await?;
+                Ok(ReaderStream::new(file.take(range.len()))
+                    .map_err(Error::Io)
+                    .boxed())
+            }
+        }
+    }
+
+    async fn size(&self, key: &str) -> Result<Option<u64>, Error> {
+        match tokio::fs::metadata(self.path(key)).await {
+            Ok(m) => Ok(Some(m.len())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        match tokio::fs::remove_file(self.path(key)).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<Entry>, Error> {
+        fn entry(key: String, metadata: std::fs::Metadata) -> Entry {
+            Entry {
+                key,
+                size: metadata.len(),
+                modified: metadata.modified().ok(),
+            }
+        }
+
+        let mut entries = Vec::new();
+        let mut base_dir = tokio::fs::read_dir(&self.base_path).await?;
+        while let Some(item) = base_dir.next_entry().await? {
+            let name = item.file_name().to_string_lossy().into_owned();
+            let metadata = item.metadata().await?;
+            if metadata.is_file() && is_temp(&name) {
+                entries.push(entry(name, metadata));
+            } else if metadata.is_dir()
+                && name.len() == 2
+                && name.bytes().all(|b| b.is_ascii_hexdigit())
+            {
+                // first byte of the checksum, see `Path::from_hash`
+                let mut hash_dir = tokio::fs::read_dir(item.path()).await?;
+                while let Some(blob) = hash_dir.next_entry().await? {
+                    let metadata = blob.metadata().await?;
+                    if metadata.is_file() {
+                        let key = format!("{name}/{}", blob.file_name().to_string_lossy());
+                        entries.push(entry(key, metadata));
+                    }
+                }
+            }
+        }
+        Ok(entries)
+    }
+}
@@ -0,0 +1,358 @@
+use std::env;
+
+use axum::{
+    Json,
+    body::Bytes,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use super::{ByteStream, Error};
+
+/// Number of leading bytes the content type is detected from.
+const SNIFF_LEN: usize = 8192;
+
+const MIB: u64 = 1024 * 1024;
+
+/// What an upload is meant for. Each context has its own allowed types and size limit.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Context {
+    #[default]
+    CourseMaterial,
+    BrandingLogo,
+    Avatar,
+}
+
+impl Context {
+    fn env_suffix(&self) -> &'static str {
+        match self {
+            Self::CourseMaterial => "COURSE_MATERIAL",
+            Self::BrandingLogo => "BRANDING_LOGO",
+            Self::Avatar => "AVATAR",
+        }
+    }
+
+    fn default_allowed_types(&self) -> &'static str {
+        match self {
+            Self::CourseMaterial => {
+                "application/pdf,image/*,video/*,audio/*,text/plain,text/csv,text/markdown,\
+application/zip,application/msword,application/vnd.ms-excel,application/vnd.ms-powerpoint,\
+application/vnd.openxmlformats-officedocument.*,application/vnd.oasis.opendocument.*"
+            }
+            Self::BrandingLogo => "image/png,image/jpeg,image/svg+xml",
+            Self::Avatar => "image/png,image/jpeg,image/webp,image/gif",
+        }
+    }
+
+    fn default_max_size(&self) -> u64 {
+        match self {
+            Self::CourseMaterial => 512 * MIB,
+            Self::BrandingLogo | Self::Avatar => 5 * MIB,
+        }
+    }
+}
+
+/// Whose storage a [`Quota`] limits.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum QuotaScope {
+    User,
+    Course,
+}
+
+/// Why an upload was refused. Serialized as the body of the error response.
+#[derive(Serialize, Debug)]
+#[serde(
+    tag = "error",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum Rejection {
+    TypeNotAllowed {
+        #[serde(rename = "type")]
+        mime_type: String,
+        allowed_types: Vec<String>,
+    },
+    TypeMismatch {
+        declared_type: String,
+        detected_type: String,
+    },
+    FileTooLarge {
+        max_size: u64,
+    },
+    QuotaExceeded {
+        scope: QuotaScope,
+        quota: u64,
+        used: u64,
+    },
+}
+
+impl IntoResponse for Rejection {
+    fn into_response(self) -> Response {
+        let status = match self {
+            Self::TypeNotAllowed { .. } | Self::TypeMismatch { .. } => {
+                StatusCode::UNSUPPORTED_MEDIA_TYPE
+            }
+            Self::FileTooLarge { .. } | Self::QuotaExceeded { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+        };
+        (status, Json(self)).into_response()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ScreenError {
+    #[error("upload violates the policy")]
+    Rejected(Rejection),
+    #[error("upload could not be read")]
+    Store(#[from] Error),
+}
+
+/// Storage used of a quota, `used` already excludes the file that is replaced.
+#[derive(Clone, Copy, Debug)]
+pub struct Quota {
+    pub scope: QuotaScope,
+    pub quota: u64,
+    pub used: u64,
+}
+
+impl Quota {
+    pub fn remaining(&self) -> u64 {
+        self.quota.saturating_sub(self.used)
+    }
+}
+
+/// Upper bound of an upload's size, from its context and the uploader's quota.
+#[derive(Clone, Copy, Debug)]
+pub struct Limit {
+    max_size: u64,
+    quota: Option<Quota>,
+}
+
+impl Limit {
+    fn bytes(&self) -> u64 {
+        match self.quota {
+            Some(q) => self.max_size.min(q.remaining()),
+            None => self.max_size,
+        }
+    }
+
+    /// The rejection to answer with once the body exceeded [`Limit::bytes`].
+    pub fn rejection(&self) -> Rejection {
+        match self.quota {
+            Some(q) if q.remaining() < self.max_size => Rejection::QuotaExceeded {
+                scope: q.scope,
+                quota: q.quota,
+                used: q.used,
+            },
+            _ => Rejection::FileTooLarge {
+                max_size: self.max_size,
+            },
+        }
+    }
+}
+
+/// An upload that passed the type checks. `body` fails with [`Error::TooLarge`] once it
+/// exceeds `limit`.
+pub struct Screened<'a> {
+    pub mime_type: String,
+    pub body: ByteStream<'a>,
+    pub limit: Limit,
+}
+
+struct Rules {
+    allowed_types: Vec<String>,
+    max_size: u64,
+}
+
+/// Allowed types and size limits for uploads, configured by `UPLOAD_ALLOWED_TYPES_<CONTEXT>`
+/// (comma separated, `image/*` allows all image types), `UPLOAD_MAX_SIZE_<CONTEXT>` (bytes),
+/// `UPLOAD_USER_QUOTA` (bytes per uploader) and `UPLOAD_COURSE_QUOTA` (bytes per course).
+/// Quotas are unlimited if unset.
+pub struct UploadPolicy {
+    course_material: Rules,
+    branding_logo: Rules,
+    avatar: Rules,
+    user_quota: Option<u64>,
+    course_quota: Option<u64>,
+}
+
+impl UploadPolicy {
+    pub fn from_env() -> Self {
+        let rules = |context: Context| {
+            let allowed_types = env::var(format!("UPLOAD_ALLOWED_TYPES_{}", context.env_suffix()))
+                .unwrap_or_else(|_| context.default_allowed_types().to_owned());
+            Rules {
+                allowed_types: allowed_types
+                    .split(',')
+                    .map(|t| t.trim().to_ascii_lowercase())
+                    .filter(|t| !t.is_empty())
+                    .collect(),
+                max_size: bytes_from_env(&format!("UPLOAD_MAX_SIZE_{}", context.env_suffix()))
+                    .unwrap_or(context.default_max_size()),
+            }
+        };
+        Self {
+            course_material: rules(Context::CourseMaterial),
+            branding_logo: rules(Context::BrandingLogo),
+            avatar: rules(Context::Avatar),
+            user_quota: bytes_from_env("UPLOAD_USER_QUOTA"),
+            course_quota: bytes_from_env("UPLOAD_COURSE_QUOTA"),
+        }
+    }
+
+    fn rules(&self, context: Context) -> &Rules {
+        match context {
+            Context::CourseMaterial => &self.course_material,
+            Context::BrandingLogo => &self.branding_logo,
+            Context::Avatar => &self.avatar,
+        }
+    }
+
+    pub fn user_quota(&self) -> Option<u64> {
+        self.user_quota
+    }
+
+    pub fn course_quota(&self) -> Option<u64> {
+        self.course_quota
+    }
+
+    /// Detects the content type of `body` from its first bytes and checks it against the
+    /// declared type and the types allowed in `context`. Returns the type to store the file with.
+    pub async fn screen<'a>(
+        &self,
+        context: Context,
+        declared_type: Option<&str>,
+        mut body: ByteStream<'a>,
+        quota: Option<Quota>,
+    ) -> Result<Screened<'a>, ScreenError> {
+        let mut head = Vec::with_capacity(SNIFF_LEN);
+        while head.len() < SNIFF_LEN {
+            match body.next().await {
+                Some(chunk) => head.extend_from_slice(&chunk?),
+                None => break,
+            }
+        }
+
+        let detected_type = detect(&head[..head.len().min(SNIFF_LEN)]);
+        let declared_type = declared_type
+            .map(normalize)
+            .filter(|t| t != mime::APPLICATION_OCTET_STREAM.as_ref());
+        let mime_type = match declared_type {
+            None => detected_type,
+            Some(declared) if matches_detected(&declared, &detected_type) => declared,
+            Some(declared) => {
+                return Err(ScreenError::Rejected(Rejection::TypeMismatch {
+                    declared_type: declared,
+                    detected_type,
+                }));
+            }
+        };
+
+        let rules = self.rules(context);
+        if !rules
+            .allowed_types
+            .iter()
+            .any(|pattern| type_matches(pattern, &mime_type))
+        {
+            return Err(ScreenError::Rejected(Rejection::TypeNotAllowed {
+                mime_type,
+                allowed_types: rules.allowed_types.clone(),
+            }));
+        }
+
+        let limit = Limit {
+            max_size: rules.max_size,
+            quota,
+        };
+        let max_bytes = limit.bytes();
+        let mut seen = 0u64;
+        let body = futures_util::stream::once(async move { Ok(Bytes::from(head)) })
+            .chain(body)
+            .map(move |chunk| {
+                let chunk = chunk?;
+                seen += chunk.len() as u64;
+                if seen > max_bytes {
+                    Err(Error::TooLarge)
+                } else {
+                    Ok(chunk)
+                }
+            })
+            .boxed();
+
+        Ok(Screened {
+            mime_type,
+            body,
+            limit,
+        })
+    }
+}
+
+fn bytes_from_env(var: &str) -> Option<u64> {
+    env::var(var).ok().map(|v| {
+        v.parse()
+            .unwrap_or_else(|_| panic!("{var} has to be a number of bytes"))
+    })
+}
+
+/// Lowercase type without parameters like `charset`.
+fn normalize(mime_type: &str) -> String {
+    let essence = mime_type.split(';').next().unwrap_or_default().trim();
+    match essence.to_ascii_lowercase().as_str() {
+        "image/jpg" => mime::IMAGE_JPEG.to_string(),
+        other => other.to_owned(),
+    }
+}
+
+fn detect(head: &[u8]) -> String {
+    if let Some(kind) = infer::get(head) {
+        return kind.mime_type().to_owned();
+    }
+    // the head may end in the middle of a multi byte character
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&head[..e.valid_up_to()]).unwrap(),
+        Err(_) => return mime::APPLICATION_OCTET_STREAM.to_string(),
+    };
+    if text.contains("<svg") {
+        mime::IMAGE_SVG.to_string()
+    } else {
+        mime::TEXT_PLAIN.to_string()
+    }
+}
+
+fn is_textual(mime_type: &str) -> bool {
+    mime_type.starts_with("text/")
+        || mime_type.ends_with("+xml")
+        || mime_type.ends_with("+json")
+        || matches!(
+            mime_type,
+            "application/json" | "application/xml" | "application/javascript"
+        )
+}
+
+/// Whether browsers run scripts in documents of `mime_type` when they open them, like SVG images.
+pub(crate) fn is_scriptable(mime_type: &str) -> bool {
+    let essence = normalize(mime_type);
+    matches!(
+        essence.as_str(),
+        "image/svg+xml" | "text/html" | "application/xhtml+xml" | "text/xml" | "application/xml"
+    )
+}
+
+/// Text formats can't be told apart reliably, so any textual declared type is accepted for them.
+fn matches_detected(declared: &str, detected: &str) -> bool {
+    declared == detected || (is_textual(detected) && is_textual(declared))
+}
+
+fn type_matches(pattern: &str, mime_type: &str) -> bool {
+    match pattern {
+        "*" | "*/*" => true,
+        _ => match pattern.strip_suffix('*') {
+            Some(prefix) => mime_type.starts_with(prefix),
+            None => pattern == mime_type,
+        },
+    }
+}
@@ -0,0 +1,238 @@
+use std::{
+    env,
+    time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use aws_sdk_s3::{
+    Client,
+    config::{Credentials, Region},
+    presigning::PresigningConfig,
+    primitives::ByteStream as S3ByteStream,
+};
+use futures_util::{StreamExt, TryStreamExt};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+use super::{Blob, ByteStream, Entry, Error, MediaStore, local::TEMP_SUFFIX};
+use crate::resources::file::ByteRange;
+
+/// Prefix of the keys of blobs held by an [`S3Store`].
+pub const SCHEME: &str = "s3:";
+
+const DEFAULT_REGION: &str = "us-east-1";
+const DEFAULT_PRESIGN_EXPIRY: Duration = Duration::from_secs(300);
+
+fn remote<E: std::error::Error + Send + Sync + 'static>(e: E) -> Error {
+    Error::Remote(Box::new(e))
+}
+
+/// Stores blobs in an S3 compatible bucket, using the same content addressed layout as
+/// [`super::LocalStore`]. Uploads are spooled to a temporary file first, as the checksum and
+/// therefore the object key is only known once the whole body was read.
+pub struct S3Store {
+    client: Client,
+    bucket: String,
+    presign_expiry: Option<Duration>,
+}
+
+impl S3Store {
+    pub fn new(client: Client, bucket: String, presign_expiry: Option<Duration>) -> Self {
+        Self {
+            client,
+            bucket,
+            presign_expiry,
+        }
+    }
+
+    /// Configures the store from `S3_*` environment variables. Setting `S3_ENDPOINT` switches to
+    /// path style requests, which S3 compatible servers like MinIO expect.
+    pub fn from_env() -> Self {
+        let credentials = Credentials::new(
+            env::var("S3_ACCESS_KEY_ID").expect("S3_ACCESS_KEY_ID has to be set"),
+            env::var("S3_SECRET_ACCESS_KEY").expect("S3_SECRET_ACCESS_KEY has to be set"),
+            None,
+            None,
+            "environment",
+        );
+        let mut config = aws_sdk_s3::Config::builder()
+            .credentials_provider(credentials)
+            .region(Region::new(
+                env::var("S3_REGION").unwrap_or_else(|_| DEFAULT_REGION.to_owned()),
+            ));
+        if let Ok(endpoint) = env::var("S3_ENDPOINT") {
+            config = config.endpoint_url(endpoint).force_path_style(true);
+        }
+
+        let presign_expiry = match env::var("S3_PRESIGN_EXPIRY").map(|secs| secs.parse::<u64>()) {
+            Err(_) => Some(DEFAULT_PRESIGN_EXPIRY),
+            Ok(Ok(0)) => None,
+            Ok(Ok(secs)) => Some(Duration::from_secs(secs)),
+            Ok(Err(_)) => panic!("S3_PRESIGN_EXPIRY has to be a number of seconds"),
+        };
+
+        Self::new(
+            Client::from_conf(config.build()),
+            env::var("S3_BUCKET").expect("S3_BUCKET has to be set"),
+            presign_expiry,
+        )
+    }
+
+    fn object_key(key: &str) -> Result<&str, Error> {
+        key.strip_prefix(SCHEME).ok_or(Error::NotFound)
+    }
+}
+
+#[async_trait]
+impl MediaStore for S3Store {
+    async fn put(&self, mut body: ByteStream<'_>) -> Result<Blob, Error> {
+        let temp_path = env::temp_dir().join(format!(
+            "noodle-{}.{}",
+            Uuid::new_v4().simple(),
+            TEMP_SUFFIX
+        ));
+        let (size, checksum) = super::spool(&mut body, &temp_path).await?;
+
+        let object_key = format!(
+            "{}/{}",
+            hex::encode(&checksum[..1]),
+            hex::encode(&checksum[1..])
+        );
+        let key = format!("{SCHEME}{object_key}");
+        let uploaded: Result<(), Error> = async {
+            if self.size(&key).await?.is_some() {
+                // same contents are already stored
+                return Ok(());
+            }
+            let contents = S3ByteStream::from_path(&temp_path).await.map_err(remote)?;
+            self.client
+                .put_object()
+                .bucket(&self.bucket)
+                .key(&object_key)
+                .content_length(size as i64)
+                .body(contents)
+                .send()
+                .await
+                .map_err(remote)?;
+            Ok(())
+        }
+        .await;
+        let _ = tokio::fs::remove_file(&temp_path).await;
+        uploaded?;
+
+        Ok(Blob {
+            key,
+            size,
+            checksum,
+        })
+    }
+
+    async fn put_at(&self, key: &str, mut body: ByteStream<'_>) -> Result<(), Error> {
+        let object_key = Self::object_key(key)?;
+        // derived contents are small, so they are buffered instead of spooled
+        let mut contents = Vec::new();
+        while let Some(chunk) = body.next().await {
+            contents.extend_from_slice(&chunk?);
+        }
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(object_key)
+            .body(S3ByteStream::from(contents))
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(remote)
+    }
+
+    async fn get(&self, key: &str, range: Option<ByteRange>) -> Result<ByteStream<'static>, Error> {
+        let request = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(Self::object_key(key)?)
+            .set_range(range.map(|r| format!("bytes={}-{}", r.start, r.end)));
+        match request.send().await {
+            Ok(object) => Ok(ReaderStream::new(object.body.into_async_read())
+                .map_err(Error::Io)
+                .boxed()),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => {
+                Err(Error::NotFound)
+            }
+            Err(e) => Err(remote(e)),
+        }
+    }
+
+    async fn size(&self, key: &str) -> Result<Option<u64>, Error> {
+        let request = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(Self::object_key(key)?);
+        match request.send().await {
+            Ok(head) => Ok(Some(head.content_length().unwrap_or(0) as u64)),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => Ok(None),
+            Err(e) => Err(remote(e)),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(Self::object_key(key)?)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(remote)
+    }
+
+    /// Lists the bucket. Uploads are spooled to the temporary directory of the host, so leftovers of
+    /// interrupted uploads are not part of the listing.
+    async fn list(&self) -> Result<Vec<Entry>, Error> {
+        let mut entries = Vec::new();
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .into_paginator()
+            .send();
+        while let Some(page) = pages.next().await {
+            for object in page.map_err(remote)?.contents() {
+                let Some(object_key) = object.key() else {
+                    continue;
+                };
+                entries.push(Entry {
+                    key: format!("{SCHEME}{object_key}"),
+                    size: object.size().unwrap_or(0) as u64,
+                    modified: object
+                        .last_modified()
+                        .and_then(|t| SystemTime::try_from(*t).ok()),
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    async fn presigned_url(
+        &self,
+        key: &str,
+        content_type: &str,
+        content_disposition: &str,
+    ) -> Result<Option<String>, Error> {
+        let Some(expiry) = self.presign_expiry else {
+            return Ok(None);
+        };
+        let request = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(Self::object_key(key)?)
+            .response_content_type(content_type)
+            .response_content_disposition(content_disposition)
+            .presigned(PresigningConfig::expires_in(expiry).map_err(remote)?)
+            .await
+            .map_err(remote)?;
+        Ok(Some(request.uri().to_owned()))
+    }
+}
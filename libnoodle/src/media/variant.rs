@@ -0,0 +1,223 @@
+use std::io::Cursor;
+
+use image::{
+    DynamicImage, ImageFormat, ImageReader, Limits, codecs::jpeg::JpegEncoder, imageops::FilterType,
+};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+
+use super::{Error, MediaStore, Store};
+
+/// Images stored in larger blobs are not decoded.
+const MAX_SOURCE_SIZE: u64 = 64 * 1024 * 1024;
+/// Largest width and height of an image that is decoded.
+const MAX_DIMENSION: u32 = 16384;
+const JPEG_QUALITY: u8 = 80;
+/// Uploads waiting for their variants to be rendered.
+const QUEUE_LEN: usize = 256;
+
+/// Downscaled rendition of an image, stored next to the original blob under [`Variant::key`].
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Variant {
+    Thumb,
+    Preview,
+}
+
+impl Variant {
+    pub const ALL: [Self; 2] = [Self::Thumb, Self::Preview];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Thumb => "thumb",
+            Self::Preview => "preview",
+        }
+    }
+
+    /// Longest edge in pixels. Smaller images keep their size.
+    fn max_edge(&self) -> u32 {
+        match self {
+            Self::Thumb => 256,
+            Self::Preview => 1280,
+        }
+    }
+
+    /// Key this variant of the blob stored under `key` is stored under.
+    pub fn key(&self, key: &str) -> String {
+        format!("{key}.{}", self.name())
+    }
+}
+
+/// Key of the blob the variant stored under `key` was rendered from, `None` if it is no variant.
+pub fn source_key(key: &str) -> Option<&str> {
+    Variant::ALL
+        .iter()
+        .find_map(|variant| key.strip_suffix(variant.name())?.strip_suffix('.'))
+}
+
+/// Query parameter selecting a [`Variant`] instead of the original contents.
+#[derive(Deserialize, Default)]
+pub struct VariantQueryParam {
+    pub variant: Option<Variant>,
+}
+
+/// Type the variants of an image of `mime_type` are encoded as, `None` if there are no variants
+/// for it. Formats that may be transparent get PNG variants, JPEGs stay JPEGs.
+pub fn variant_type(mime_type: &str) -> Option<&'static str> {
+    match mime_type {
+        "image/jpeg" => Some("image/jpeg"),
+        "image/png" | "image/gif" | "image/webp" => Some("image/png"),
+        _ => None,
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RenderError {
+    #[error("media store operation failed")]
+    Store(#[from] Error),
+    #[error("image could not be decoded or encoded")]
+    Image(#[from] image::ImageError),
+    #[error("image is too large to be rendered")]
+    TooLarge,
+    /// The decoder or encoder panicked, on a crafted image for example
+    #[error("rendering the image panicked")]
+    Panicked(#[from] tokio::task::JoinError),
+}
+
+fn render(
+    source: &[u8],
+    variant_type: &str,
+    variants: &[Variant],
+) -> Result<Vec<Vec<u8>>, image::ImageError> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    let mut reader = ImageReader::new(Cursor::new(source)).with_guessed_format()?;
+    reader.limits(limits);
+    let image = reader.decode()?;
+
+    variants
+        .iter()
+        .map(|variant| {
+            let edge = variant.max_edge();
+            let resized;
+            let scaled = if image.width() <= edge && image.height() <= edge {
+                &image
+            } else {
+                resized = image.resize(edge, edge, FilterType::Lanczos3);
+                &resized
+            };
+            let mut encoded = Cursor::new(Vec::new());
+            if variant_type == mime::IMAGE_JPEG.as_ref() {
+                // JPEG has no alpha channel
+                DynamicImage::ImageRgb8(scaled.to_rgb8()).write_with_encoder(
+                    JpegEncoder::new_with_quality(&mut encoded, JPEG_QUALITY),
+                )?;
+            } else {
+                scaled.write_to(&mut encoded, ImageFormat::Png)?;
+            }
+            Ok(encoded.into_inner())
+        })
+        .collect()
+}
+
+/// Renders `variants` of the image of `mime_type` stored under `key` and stores them next to it.
+pub async fn generate(
+    store: &dyn MediaStore,
+    key: &str,
+    mime_type: &str,
+    variants: &[Variant],
+) -> Result<(), RenderError> {
+    let Some(variant_type) = variant_type(mime_type) else {
+        return Ok(());
+    };
+    match store.size(key).await? {
+        None => return Err(Error::NotFound.into()),
+        Some(size) if size > MAX_SOURCE_SIZE => return Err(RenderError::TooLarge),
+        Some(_) => {}
+    }
+
+    let source = super::read_to_vec(store, key).await?;
+    let to_render = variants.to_vec();
+    let rendered =
+        tokio::task::spawn_blocking(move || render(&source, variant_type, &to_render)).await??;
+    for (variant, contents) in variants.iter().zip(rendered) {
+        store
+            .put_at(&variant.key(key), super::bytes_stream(contents))
+            .await?;
+    }
+
+    // the blob may have been released while its variants were rendered
+    if !store.exists(key).await? {
+        for variant in variants {
+            store.delete(&variant.key(key)).await?;
+        }
+        return Err(Error::NotFound.into());
+    }
+    Ok(())
+}
+
+/// Key and type of `variant` of the image of `mime_type` stored under `key`, rendering it first if
+/// the background [`Pipeline`] did not get to it yet. `None` if there are no variants for the type.
+pub async fn ensure(
+    store: &dyn MediaStore,
+    key: &str,
+    mime_type: &str,
+    variant: Variant,
+) -> Result<Option<(String, &'static str)>, RenderError> {
+    let Some(variant_type) = variant_type(mime_type) else {
+        return Ok(None);
+    };
+    let variant_key = variant.key(key);
+    if !store.exists(&variant_key).await? {
+        generate(store, key, mime_type, &[variant]).await?;
+    }
+    Ok(Some((variant_key, variant_type)))
+}
+
+struct Job {
+    key: String,
+    mime_type: String,
+}
+
+/// Renders the variants of uploaded images in the background, so they are ready by the time a
+/// course page asks for them.
+#[derive(Clone)]
+pub struct Pipeline {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl Pipeline {
+    /// Starts the worker rendering the variants of the blobs passed to [`Pipeline::enqueue`].
+    pub fn spawn(store: Store) -> Self {
+        let (jobs, mut queue) = mpsc::channel::<Job>(QUEUE_LEN);
+        tokio::spawn(async move {
+            while let Some(job) = queue.recv().await {
+                let mut missing = Vec::with_capacity(Variant::ALL.len());
+                for variant in Variant::ALL {
+                    if let Ok(false) = store.exists(&variant.key(&job.key)).await {
+                        missing.push(variant);
+                    }
+                }
+                if missing.is_empty() {
+                    continue;
+                }
+                if let Err(e) = generate(&*store, &job.key, &job.mime_type, &missing).await {
+                    tracing::warn!("rendering the variants of {} failed: {e}", job.key);
+                }
+            }
+        });
+        Self { jobs }
+    }
+
+    /// Schedules rendering the variants of the blob stored under `key`. Types without variants are
+    /// skipped. If the queue is full the job is dropped and the variants are rendered on request.
+    pub fn enqueue(&self, key: &str, mime_type: &str) {
+        if variant_type(mime_type).is_some() {
+            let _ = self.jobs.try_send(Job {
+                key: key.to_owned(),
+                mime_type: mime_type.to_owned(),
+            });
+        }
+    }
+}
@@ -3,10 +3,11 @@ use serde::{Deserialize, Serialize};
 pub mod branding;
 pub mod content_section;
 pub mod course;
+pub mod crud;
 pub mod file;
 pub mod template;
 
-#[derive(Serialize, Deserialize, sqlx::Type, Hash, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, sqlx::Type, Hash, PartialEq, Eq, Clone, Copy, Debug)]
 #[serde(rename_all = "camelCase")]
 pub enum Type {
     User,
@@ -17,6 +18,7 @@ pub enum Type {
     Template,
 }
 
+use crate::auth::permission::EFFECTIVE_OPERATIONS;
 use const_format::concatcp;
 const USER_TABLE: &str = "user";
 const ROLE_TABLE: &str = "role";
@@ -36,65 +38,48 @@ impl Type {
         }
     }
 
+    /// SQL type of the `resource_id` column of the permission table.
+    pub fn id_type(&self) -> &'static str {
+        match self {
+            Self::File => "uuid",
+            _ => "bigint",
+        }
+    }
+
+    /// Canonical form of a resource id given as text, `None` if it is no valid id of this type.
+    /// File ids use the simple uuid format.
+    pub fn normalize_id(&self, id: &str) -> Option<String> {
+        match self {
+            Self::File => uuid::Uuid::parse_str(id)
+                .ok()
+                .map(|u| u.simple().to_string()),
+            _ => id.parse::<i64>().ok().map(|i| i.to_string()),
+        }
+    }
+
+    /// Holds a row if user `$1` may perform one of the `$2` operations on resource `$3`, either
+    /// through a grant on it or a type-wide one, see [`EFFECTIVE_OPERATIONS`] for denies.
     pub fn permission_id_query(&self) -> &'static str {
-        // "SELECT 1 FROM {table_name}_permissions \
-        // LEFT JOIN user_has_role on user_has_role.role_id = {table_name}_permissions.role_id \
-        // WHERE (user_has_role.user_id = $1 OR {table_name}_permissions.user_id = $1) \
-        // AND ($2::bit(16) & permission) <> B'0'::bit(16) AND resource_id = $3",
+        // "SELECT 1 FROM {table_name}_permissions p \
+        // LEFT JOIN current_user_has_role ur ON ur.role_id = p.role_id \
+        // WHERE (ur.user_id = $1 OR p.user_id = $1) AND (p.resource_id = $3 OR p.resource_id IS NULL) \
+        // HAVING ($2::int::bit(16) & {EFFECTIVE_OPERATIONS}) <> B'0'::bit(16)",
         const START: &str = "SELECT 1 FROM ";
-        const SECOND: &str = "_permissions LEFT JOIN user_has_role ON user_has_role.role_id = ";
-        const THIRD: &str = "_permissions.role_id WHERE (user_has_role.user_id = $1 OR ";
-        const END: &str = "_permissions.user_id = $1) AND ($2::int::bit(16) & permission) <> B'0'::bit(16) AND (resource_id = $3 OR resource_id IS NULL)";
+        const END: &str = concatcp!(
+            "_permissions p LEFT JOIN current_user_has_role ur ON ur.role_id = p.role_id \
+WHERE (ur.user_id = $1 OR p.user_id = $1) AND (p.resource_id = $3 OR p.resource_id IS NULL) \
+HAVING ($2::int::bit(16) & ",
+            EFFECTIVE_OPERATIONS,
+            ") <> B'0'::bit(16)"
+        );
 
         match self {
-            Self::User => {
-                concatcp!(
-                    START, USER_TABLE, SECOND, USER_TABLE, THIRD, USER_TABLE, END
-                )
-            }
-            Self::Role => {
-                concatcp!(
-                    START, ROLE_TABLE, SECOND, ROLE_TABLE, THIRD, ROLE_TABLE, END
-                )
-            }
-            Self::Group => {
-                concatcp!(
-                    START,
-                    GROUP_TABLE,
-                    SECOND,
-                    GROUP_TABLE,
-                    THIRD,
-                    GROUP_TABLE,
-                    END
-                )
-            }
-            Self::File => {
-                concatcp!(
-                    START, FILE_TABLE, SECOND, FILE_TABLE, THIRD, FILE_TABLE, END
-                )
-            }
-            Self::Course => {
-                concatcp!(
-                    START,
-                    COURSE_TABLE,
-                    SECOND,
-                    COURSE_TABLE,
-                    THIRD,
-                    COURSE_TABLE,
-                    END
-                )
-            }
-            Self::Template => {
-                concatcp!(
-                    START,
-                    TEMPLATE_TABLE,
-                    SECOND,
-                    TEMPLATE_TABLE,
-                    THIRD,
-                    TEMPLATE_TABLE,
-                    END
-                )
-            }
+            Self::User => concatcp!(START, USER_TABLE, END),
+            Self::Role => concatcp!(START, ROLE_TABLE, END),
+            Self::Group => concatcp!(START, GROUP_TABLE, END),
+            Self::File => concatcp!(START, FILE_TABLE, END),
+            Self::Course => concatcp!(START, COURSE_TABLE, END),
+            Self::Template => concatcp!(START, TEMPLATE_TABLE, END),
         }
     }
 }
@@ -1,12 +1,17 @@
 // use std::os::unix::ffi::OsStrExt;
 
-use chrono::Utc;
+use futures_util::{StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
-use sha1::{Digest, Sha1};
-use sqlx::PgPool;
+use tokio_util::io::ReaderStream;
 use uuid::Uuid;
 
-use crate::resources::file::Path;
+use crate::{
+    media::{
+        self,
+        policy::{Context, Rejection, ScreenError},
+    },
+    resources::file::{self, BlobRefError, FileRow},
+};
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -23,10 +28,16 @@ pub enum BrandingCreateError {
     Sqlx(#[from] sqlx::Error),
     #[error("I/O operation failed!")]
     Io(#[from] std::io::Error),
+    #[error("Storing the logo failed!")]
+    Media(#[from] media::Error),
+    #[error("Referencing the logo failed!")]
+    BlobRef(#[from] BlobRefError),
     #[error("Serialization invalid")]
     Serde(serde_json::Error),
     #[error("File type is invalid")]
     InvalidFileType,
+    #[error("Logo violates the upload policy")]
+    Rejected(Rejection),
 }
 
 const CONFIG_FILE_NAME: &'static str = "branding.json";
@@ -39,57 +50,62 @@ impl Branding {
         blue: u8,
         alpha: u8,
         logo: std::path::PathBuf,
-        dest_base_path: &str,
-        db: &PgPool,
+        state: &crate::AppState,
     ) -> Result<Self, BrandingCreateError> {
+        let (store, db) = (&*state.media, &state.db);
         let file_name = logo.file_name();
         let Some(filename) = file_name else {
             return Err(BrandingCreateError::InvalidFileType);
         };
 
-        let mime_type = match logo.extension() {
-            None => return Err(BrandingCreateError::InvalidFileType),
-            Some(ext) => match ext.to_string_lossy().as_ref() {
-                "jpg" | "JPG" | "jpeg" | "JPEG" => mime::IMAGE_JPEG,
-                "png" | "PNG" => mime::IMAGE_PNG,
-                "svg" | "SVG" => mime::IMAGE_SVG,
-                _ => return Err(BrandingCreateError::InvalidFileType),
-            },
-        };
-
         let color = 0u32
             | ((red as u32) << 24)
             | ((green as u32) << 16)
             | ((blue as u32) << 8)
             | alpha as u32;
 
-        let current_time = Utc::now();
-        let mut hasher = Sha1::new();
-        hasher.update(&logo.file_name().unwrap().as_encoded_bytes());
-        hasher.update(current_time.to_rfc2822());
-        let dir_hash = hasher.finalize();
-        let dest_path = Path::from_hash(dest_base_path, &dir_hash);
-
-        tokio::fs::create_dir(&dest_path.0.parent().unwrap())
+        let logo_file = tokio::fs::File::open(&logo).await?;
+        let screened = match state
+            .upload_policy
+            .screen(
+                Context::BrandingLogo,
+                None,
+                ReaderStream::new(logo_file)
+                    .map_err(media::Error::Io)
+                    .boxed(),
+                None,
+            )
             .await
-            .unwrap();
-        tokio::fs::copy(&logo, &dest_path).await?;
+        {
+            Ok(s) => s,
+            Err(ScreenError::Rejected(rejection)) => {
+                return Err(BrandingCreateError::Rejected(rejection));
+            }
+            Err(ScreenError::Store(e)) => return Err(e.into()),
+        };
+        let blob = match store.put(screened.body).await {
+            Ok(b) => b,
+            Err(media::Error::TooLarge) => {
+                return Err(BrandingCreateError::Rejected(screened.limit.rejection()));
+            }
+            Err(e) => return Err(e.into()),
+        };
 
         let file_uuid = Uuid::new_v4();
-        let dest_path_str = dest_path.0.to_string_lossy();
-        match sqlx::query(
-            "INSERT INTO \"file\" (uid, filename, \"type\", location) VALUES ($1, $2, $3, $4)",
+        if let Err(e) = FileRow::insert(
+            db,
+            store,
+            file_uuid,
+            &filename.to_string_lossy(),
+            &screened.mime_type,
+            None,
+            &blob,
         )
-        .bind(file_uuid)
-        .bind(filename.to_string_lossy())
-        .bind(mime_type.to_string())
-        .bind(&dest_path_str[dest_path_str.len() - dir_hash.len() * 2 - 1..])
-        .execute(db)
         .await
         {
-            Err(e) => return Err(BrandingCreateError::Sqlx(e)),
-            _ => {}
-        };
+            let _ = file::release_blob(db, store, &blob.key).await;
+            return Err(e.into());
+        }
 
         let branding_dir = std::env::var("BRANDING_PATH").unwrap();
         let mut branding_conf_path =
@@ -145,8 +161,7 @@ pub mod http {
             std::path::PathBuf::with_capacity(branding_path.len() + DEFAULT_LOGO_NAME.len() + 2);
         logo_path.push(branding_path);
         logo_path.push(DEFAULT_LOGO_NAME);
-        match Branding::create_in_db(255, 0, 0, 255, logo_path, &state.media_path, &state.db).await
-        {
+        match Branding::create_in_db(255, 0, 0, 255, logo_path, &state).await {
             Ok(b) => (StatusCode::CREATED, Json(b)).into_response(),
             Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
         }
@@ -1,5 +1,19 @@
+use axum::{
+    Json,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
 use serde::{Deserialize, Serialize};
-use sqlx::FromRow;
+use sqlx::{FromRow, PgPool, Row, postgres::PgRow};
+use uuid::Uuid;
+
+use crate::resources::file::{FileMetadata, FileRow};
+
+/// Longest markdown text of an element in bytes.
+const MAX_MARKDOWN_LEN: usize = 512 * 1024;
+/// Longest title, caption, question or quiz option.
+const MAX_LABEL_LEN: usize = 1024;
+const MIN_QUIZ_OPTIONS: usize = 2;
 
 #[derive(Debug, Serialize, Deserialize, Clone, FromRow)]
 #[serde(rename_all = "camelCase")]
@@ -11,6 +25,9 @@ pub struct ContentSection {
     pub template_id: Option<i64>,
     pub headline: String,
     pub order_index: i32,
+    /// Only shown to course members who may see hidden sections. Creating visible sections and
+    /// changing the flag needs the right to publish.
+    pub hidden: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -18,37 +35,315 @@ pub struct ContentSection {
 pub struct ContentSectionCreationRequest {
     pub headline: String,
     pub order_index: Option<i32>,
+    #[serde(default)]
+    pub hidden: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, FromRow)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct QuizOption {
+    pub text: String,
+    /// Left out for course members who may not edit the course
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correct: Option<bool>,
+}
+
+/// What a content element shows, serialized as its `type` and the kind specific `content`.
+/// Stored in the `type` and `content` columns, markdown as is and the other kinds as JSON.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(
+    tag = "type",
+    content = "content",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum ElementKind {
+    Markdown(String),
+    /// The files attached to the element
+    File {
+        caption: Option<String>,
+    },
+    Video {
+        url: String,
+        title: Option<String>,
+    },
+    Link {
+        url: String,
+        title: Option<String>,
+    },
+    Quiz {
+        question: String,
+        options: Vec<QuizOption>,
+        /// Whether more than one option may be correct
+        #[serde(default)]
+        multiple_choice: bool,
+    },
+    Divider,
+}
+
+/// Why the content of an element was refused. Serialized as the body of the error response.
+#[derive(Serialize, Debug)]
+#[serde(
+    tag = "error",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum InvalidContent {
+    EmptyField {
+        field: &'static str,
+    },
+    TooLong {
+        field: &'static str,
+        max_length: usize,
+    },
+    InvalidUrl {
+        field: &'static str,
+    },
+    TooFewOptions {
+        min: usize,
+    },
+    NoCorrectOption,
+    SeveralCorrectOptions,
+}
+
+impl IntoResponse for InvalidContent {
+    fn into_response(self) -> Response {
+        (StatusCode::BAD_REQUEST, Json(self)).into_response()
+    }
+}
+
+fn check_text(field: &'static str, text: &str, max_length: usize) -> Result<(), InvalidContent> {
+    if text.trim().is_empty() {
+        Err(InvalidContent::EmptyField { field })
+    } else if text.len() > max_length {
+        Err(InvalidContent::TooLong { field, max_length })
+    } else {
+        Ok(())
+    }
+}
+
+fn check_label(field: &'static str, label: &Option<String>) -> Result<(), InvalidContent> {
+    match label {
+        Some(l) if l.len() > MAX_LABEL_LEN => Err(InvalidContent::TooLong {
+            field,
+            max_length: MAX_LABEL_LEN,
+        }),
+        _ => Ok(()),
+    }
+}
+
+fn check_url(field: &'static str, url: &str) -> Result<(), InvalidContent> {
+    match url::Url::parse(url) {
+        Ok(u) if matches!(u.scheme(), "http" | "https") && u.has_host() => Ok(()),
+        _ => Err(InvalidContent::InvalidUrl { field }),
+    }
+}
+
+impl ElementKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Markdown(_) => "markdown",
+            Self::File { .. } => "file",
+            Self::Video { .. } => "video",
+            Self::Link { .. } => "link",
+            Self::Quiz { .. } => "quiz",
+            Self::Divider => "divider",
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), InvalidContent> {
+        match self {
+            Self::Markdown(text) => check_text("content", text, MAX_MARKDOWN_LEN),
+            Self::File { caption } => check_label("caption", caption),
+            Self::Video { url, title } | Self::Link { url, title } => {
+                check_url("url", url)?;
+                check_label("title", title)
+            }
+            Self::Quiz {
+                question,
+                options,
+                multiple_choice,
+            } => {
+                check_text("question", question, MAX_LABEL_LEN)?;
+                if options.len() < MIN_QUIZ_OPTIONS {
+                    return Err(InvalidContent::TooFewOptions {
+                        min: MIN_QUIZ_OPTIONS,
+                    });
+                }
+                for option in options {
+                    check_text("options", &option.text, MAX_LABEL_LEN)?;
+                }
+                match options.iter().filter(|o| o.correct == Some(true)).count() {
+                    0 => Err(InvalidContent::NoCorrectOption),
+                    1 => Ok(()),
+                    _ if *multiple_choice => Ok(()),
+                    _ => Err(InvalidContent::SeveralCorrectOptions),
+                }
+            }
+            Self::Divider => Ok(()),
+        }
+    }
+
+    /// Leaves out which quiz options are correct.
+    pub fn hide_solutions(&mut self) {
+        if let Self::Quiz { options, .. } = self {
+            for option in options {
+                option.correct = None;
+            }
+        }
+    }
+
+    /// Value of the `content` column.
+    pub fn content_column(&self) -> Option<String> {
+        match self {
+            Self::Markdown(text) => Some(text.clone()),
+            Self::Divider => None,
+            _ => serde_json::to_value(self)
+                .ok()
+                .and_then(|mut v| v.get_mut("content").map(serde_json::Value::take))
+                .map(|content| content.to_string()),
+        }
+    }
+
+    /// Decodes the `type` and `content` columns. Rows written before the kinds were introduced
+    /// hold free-form strings: plain URLs of videos and links are taken over, references to files
+    /// become file elements, and anything else is shown as markdown so no content gets lost.
+    pub fn from_columns(element_type: &str, content: Option<String>) -> Self {
+        match element_type {
+            "markdown" | "text" => return Self::Markdown(content.unwrap_or_default()),
+            "divider" => return Self::Divider,
+            _ => {}
+        }
+        let Some(content) = content else {
+            return match element_type {
+                "file" | "image" | "pdf" => Self::File { caption: None },
+                _ => Self::Markdown(String::new()),
+            };
+        };
+        if let Ok(value) = serde_json::from_str::<serde_json::Value>(&content)
+            && let Ok(kind) = serde_json::from_value(
+                serde_json::json!({ "type": element_type, "content": value }),
+            )
+        {
+            return kind;
+        }
+        match element_type {
+            "video" if check_url("url", content.trim()).is_ok() => Self::Video {
+                url: content.trim().to_owned(),
+                title: None,
+            },
+            "link" if check_url("url", content.trim()).is_ok() => Self::Link {
+                url: content.trim().to_owned(),
+                title: None,
+            },
+            "file" | "image" | "pdf" => Self::File { caption: None },
+            _ => Self::Markdown(content),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct ContentElement {
-    #[sqlx(rename = "uid")]
     pub content_id: i64,
-    #[sqlx(rename = "section_id")]
     #[serde(rename = "parentSectionId")]
     pub section_id: i64,
     pub order_index: i32,
-    #[sqlx(rename = "type")]
-    #[serde(rename = "type")]
-    pub element_type: String,
-    pub content: Option<String>,
+    #[serde(flatten)]
+    pub kind: ElementKind,
+    /// Attached files in their order, managed through `/content/{contentId}/files`
+    #[serde(default, skip_deserializing)]
+    pub files: Vec<FileMetadata>,
+}
+
+impl<'r> FromRow<'r, PgRow> for ContentElement {
+    fn from_row(row: &'r PgRow) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            content_id: row.try_get("uid")?,
+            section_id: row.try_get("section_id")?,
+            order_index: row.try_get("order_index")?,
+            kind: ElementKind::from_columns(row.try_get("type")?, row.try_get("content")?),
+            files: Vec::new(),
+        })
+    }
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ContentElementCreationRequest {
-    #[serde(rename = "type")]
-    pub element_type: String,
-    pub content: Option<String>,
+    #[serde(flatten)]
+    pub kind: ElementKind,
     pub order_index: Option<i32>,
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileAttachmentRequest {
+    pub file_uid: Uuid,
+    /// Position among the attached files, appended if missing
+    pub order_index: Option<i32>,
+}
+
+#[derive(FromRow)]
+struct AttachedFile {
+    content_id: i64,
+    #[sqlx(flatten)]
+    file: FileRow,
+}
+
+impl ContentElement {
+    /// Files attached to the element `content_id`, in their order.
+    pub async fn files(db: &PgPool, content_id: i64) -> Result<Vec<FileMetadata>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, FileRow>(
+            "SELECT f.* FROM file_in_content_element fc \
+JOIN \"file\" f ON f.uid = fc.file_id \
+WHERE fc.content_id = $1 ORDER BY fc.order_index, fc.created_at",
+        )
+        .bind(content_id)
+        .fetch_all(db)
+        .await?;
+        Ok(rows.into_iter().map(FileMetadata::from).collect())
+    }
+
+    /// Fills in the attached files of `elements`.
+    pub async fn load_files(
+        db: &PgPool,
+        elements: &mut [ContentElement],
+    ) -> Result<(), sqlx::Error> {
+        let ids: Vec<i64> = elements.iter().map(|e| e.content_id).collect();
+        let attached = sqlx::query_as::<_, AttachedFile>(
+            "SELECT fc.content_id, f.* FROM file_in_content_element fc \
+JOIN \"file\" f ON f.uid = fc.file_id \
+WHERE fc.content_id = ANY($1) ORDER BY fc.order_index, fc.created_at",
+        )
+        .bind(&ids)
+        .fetch_all(db)
+        .await?;
+        for a in attached {
+            if let Some(e) = elements.iter_mut().find(|e| e.content_id == a.content_id) {
+                e.files.push(a.file.into());
+            }
+        }
+        Ok(())
+    }
+}
+
 pub mod http {
-    //NOTE: evtl. einzelne Content Sections -/ Elements versteckbar machen -> Permissions anpassen
+    //NOTE: evtl. einzelne Content Elements versteckbar machen -> Permissions anpassen
+    use std::collections::HashSet;
+
     use crate::{
-        auth::{self, permission::Operations},
-        resources,
+        auth::{cache::Permissions, permission::Operations},
+        media::policy::{QuotaScope, Rejection},
+        resources::{
+            self,
+            course::{
+                Capability, CourseAccess,
+                http::{check_capability, check_read},
+            },
+            crud::{ResourceError, require},
+            file::{self, StorageUsage},
+        },
     };
 
     use super::*;
@@ -58,48 +353,24 @@ pub mod http {
         http::StatusCode,
         response::{IntoResponse, Response},
     };
-    use axum_login::AuthSession;
 
     /* -------- Sections ---------- */
 
     //GET /course/{courseId}/sections
     pub async fn get_all_for_course(
-        auth_session: AuthSession<auth::Backend>,
+        permissions: Permissions,
         UrlPath(course_id): UrlPath<i64>,
         State(state): State<crate::AppState>,
     ) -> Response {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Course,
-            &course_id,
-            Operations::READ,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
-        {
+        let access = match CourseAccess::load(&permissions, &state.db, course_id).await {
             Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            Ok(false) => {
-                match sqlx::query_as::<_, ContentSection>(
-                    "SELECT uid, course_id, template_id, headline, order_index FROM content_section cs \
-WHERE course_id = $2 \
-AND (EXISTS(SELECT 1 FROM course_user cu WHERE cu.course_id = $2 AND cu.user_id = $1) \
-OR EXISTS(SELECT 1 FROM course_group cg JOIN user_in_group uig ON uig.group_id = cg.group_id \
-WHERE cg.course_id = $2 \
-AND uig.user_id = $1))",
-                )
-                .bind(s_user.user_id)
-                .bind(course_id)
-                .fetch_all(&state.db).await {
-                    Ok(c) => return Json(c).into_response(),
-                    Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-                }
-            }
-            Ok(true) => {}
+            Ok(access) if !access.can_read() => return StatusCode::UNAUTHORIZED.into_response(),
+            Ok(access) => access,
         };
 
-        match sqlx::query_as::<_, ContentSection>("SELECT uid, course_id, template_id, headline, order_index FROM content_section WHERE course_id = $1 ORDER BY order_index")
+        match sqlx::query_as::<_, ContentSection>("SELECT uid, course_id, template_id, headline, order_index, hidden FROM content_section WHERE course_id = $1 AND (NOT hidden OR $2) ORDER BY order_index")
             .bind(course_id)
+            .bind(access.can(Capability::SeeHidden))
             .fetch_all(&state.db)
             .await
         {
@@ -110,39 +381,37 @@ AND uig.user_id = $1))",
 
     //POST /course/{courseId}/sections
     pub async fn create_for_course(
-        auth_session: AuthSession<auth::Backend>,
+        permissions: Permissions,
         UrlPath(course_id): UrlPath<i64>,
         State(state): State<crate::AppState>,
         Json(req): Json<ContentSectionCreationRequest>,
     ) -> Response {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Course,
-            &course_id,
-            Operations::UPDATE,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
-        {
+        let access = match CourseAccess::load(&permissions, &state.db, course_id).await {
             Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
-            Ok(true) => {}
+            Ok(access) if !access.can(Capability::EditContent) => {
+                return StatusCode::UNAUTHORIZED.into_response();
+            }
+            Ok(access) => access,
         };
+        // only those who may publish sections create visible ones
+        if !req.hidden && !access.can(Capability::Publish) {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
 
         if req.headline.trim().is_empty() {
             return StatusCode::BAD_REQUEST.into_response();
         }
         let idx = req.order_index.unwrap_or(0);
-        match sqlx::query_scalar::<_, i64>("INSERT INTO content_section(course_id, headline, order_index) VALUES ($1,$2,$3) RETURNING uid")
+        match sqlx::query_scalar::<_, i64>("INSERT INTO content_section(course_id, headline, order_index, hidden) VALUES ($1,$2,$3,$4) RETURNING uid")
             .bind(course_id)
             .bind(&req.headline)
             .bind(idx)
+            .bind(req.hidden)
             .fetch_one(&state.db)
             .await
         {
             Ok(uid) => Json(
-                    ContentSection { section_id: uid, course_id: Some(course_id), template_id: None, headline: req.headline, order_index: idx
+                    ContentSection { section_id: uid, course_id: Some(course_id), template_id: None, headline: req.headline, order_index: idx, hidden: req.hidden
                 }).into_response(),
             Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
         }
@@ -150,46 +419,20 @@ AND uig.user_id = $1))",
 
     //GET /course/{courseId}/section/{sectionId}
     pub async fn get_for_course(
-        auth_session: AuthSession<auth::Backend>,
+        permissions: Permissions,
         UrlPath((course_id, section_id)): UrlPath<(i64, i64)>,
         State(state): State<crate::AppState>,
     ) -> Response {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Course,
-            &course_id,
-            Operations::READ,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
-        {
+        let access = match CourseAccess::load(&permissions, &state.db, course_id).await {
             Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            Ok(false) => {
-                match sqlx::query_as::<_, ContentSection>(
-                    "SELECT uid, course_id, template_id, headline, order_index FROM content_section cs \
-WHERE course_id = $2 AND uid = $3 \
-AND (EXISTS(SELECT 1 FROM course_user cu WHERE cu.course_id = $2 AND cu.user_id = $1) \
-OR EXISTS(SELECT 1 FROM course_group cg JOIN user_in_group uig ON uig.group_id = cg.group_id \
-WHERE cg.course_id = $2 \
-AND uig.user_id = $1)) \
-ORDER BY order_index",
-                )
-                .bind(s_user.user_id)
-                .bind(course_id)
-                .bind(section_id)
-                .fetch_optional(&state.db).await {
-                    Ok(Some(c)) => return Json(c).into_response(),
-                    Ok(None) => return StatusCode::NOT_FOUND.into_response(),
-                    Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-                }
-            }
-            Ok(true) => {}
+            Ok(access) if !access.can_read() => return StatusCode::UNAUTHORIZED.into_response(),
+            Ok(access) => access,
         };
 
-        match sqlx::query_as::<_, ContentSection>("SELECT uid, course_id, headline, order_index FROM content_section WHERE uid = $1 AND course_id = $2")
+        match sqlx::query_as::<_, ContentSection>("SELECT uid, course_id, template_id, headline, order_index, hidden FROM content_section WHERE uid = $1 AND course_id = $2 AND (NOT hidden OR $3)")
             .bind(section_id)
             .bind(course_id)
+            .bind(access.can(Capability::SeeHidden))
             .fetch_optional(&state.db)
             .await
         {
@@ -201,40 +444,49 @@ ORDER BY order_index",
 
     //PUT /course/{courseId}/section/{sectionId}
     pub async fn update_for_course(
-        auth_session: AuthSession<auth::Backend>,
+        permissions: Permissions,
         UrlPath((course_id, section_id)): UrlPath<(i64, i64)>,
         State(state): State<crate::AppState>,
         Json(req): Json<ContentSectionCreationRequest>,
     ) -> Response {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Course,
-            &course_id,
-            Operations::UPDATE,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
-        {
+        let access = match CourseAccess::load(&permissions, &state.db, course_id).await {
             Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
-            Ok(true) => {}
+            Ok(access) if !access.can(Capability::EditContent) => {
+                return StatusCode::UNAUTHORIZED.into_response();
+            }
+            Ok(access) => access,
         };
 
         if req.headline.trim().is_empty() {
             return StatusCode::BAD_REQUEST.into_response();
         }
+        match sqlx::query_scalar::<_, bool>(
+            "SELECT hidden FROM content_section WHERE uid = $1 AND course_id = $2",
+        )
+        .bind(section_id)
+        .bind(course_id)
+        .fetch_optional(&state.db)
+        .await
+        {
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+            Ok(Some(hidden)) if hidden != req.hidden && !access.can(Capability::Publish) => {
+                return StatusCode::UNAUTHORIZED.into_response();
+            }
+            Ok(Some(_)) => {}
+        }
         let idx = req.order_index.unwrap_or(0);
-        match sqlx::query("UPDATE content_section SET headline = $1, order_index = $2 WHERE uid = $3 AND course_id = $4")
+        match sqlx::query("UPDATE content_section SET headline = $1, order_index = $2, hidden = $5 WHERE uid = $3 AND course_id = $4")
             .bind(&req.headline)
             .bind(idx)
             .bind(section_id)
             .bind(course_id)
+            .bind(req.hidden)
             .execute(&state.db)
             .await
         {
             Ok(res) => {
-                if res.rows_affected()==0 { StatusCode::NOT_FOUND.into_response() } else { Json(ContentSection{section_id, course_id: Some(course_id), template_id: None, headline:req.headline,order_index:idx}).into_response() }
+                if res.rows_affected()==0 { StatusCode::NOT_FOUND.into_response() } else { Json(ContentSection{section_id, course_id: Some(course_id), template_id: None, headline:req.headline,order_index:idx,hidden:req.hidden}).into_response() }
             }
             Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
         }
@@ -242,24 +494,15 @@ ORDER BY order_index",
 
     //DELETE /course/{courseId}/section/{sectionId}
     pub async fn delete_for_course(
-        auth_session: AuthSession<auth::Backend>,
+        permissions: Permissions,
         UrlPath((course_id, section_id)): UrlPath<(i64, i64)>,
         State(state): State<crate::AppState>,
     ) -> StatusCode {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Course,
-            &course_id,
-            Operations::UPDATE,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
+        if let Err(code) =
+            check_capability(&state, &permissions, course_id, Capability::EditContent).await
         {
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
-            Ok(false) => return StatusCode::UNAUTHORIZED,
-            Ok(true) => {}
-        };
+            return code;
+        }
 
         match sqlx::query("DELETE FROM content_section WHERE uid = $1 AND course_id = $2")
             .bind(section_id)
@@ -282,141 +525,126 @@ ORDER BY order_index",
 
     //GET /course/{courseId}/section/{sectionId}/content
     pub async fn get_course_content(
-        auth_session: AuthSession<auth::Backend>,
+        permissions: Permissions,
         UrlPath((course_id, section_id)): UrlPath<(i64, i64)>,
         State(state): State<crate::AppState>,
     ) -> Response {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Course,
-            &course_id,
-            Operations::READ,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
-        {
+        let access = match CourseAccess::load(&permissions, &state.db, course_id).await {
             Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            Ok(false) => {
-                match sqlx::query_as::<_, ContentElement>(
-                    "SELECT uid, section_id, order_index, type, content FROM content_element WHERE section_id = $3 AND section_id IN (SELECT uid FROM content_section WHERE course_id = $2)\
-AND (EXISTS(SELECT 1 FROM course_user cu WHERE cu.course_id = $2 AND cu.user_id = $1) \
-OR EXISTS(SELECT 1 FROM course_group cg JOIN user_in_group uig ON uig.group_id = cg.group_id \
-WHERE cg.course_id = $2 \
-AND uig.user_id = $1)) \
-ORDER BY order_index",
-                )
-                .bind(s_user.user_id)
-                .bind(course_id)
-                .bind(section_id)
-                .fetch_all(&state.db).await {
-                    Ok(c) => return Json(c).into_response(),
-                    Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-                }
-            },
-            Ok(true) => {}
+            Ok(access) if !access.can_read() => return StatusCode::UNAUTHORIZED.into_response(),
+            Ok(access) => access,
         };
+        let see_hidden = access.can(Capability::SeeHidden);
 
-        let query = "SELECT uid, section_id, order_index, type, content FROM content_element WHERE section_id = $1 AND section_id IN (SELECT uid FROM content_section WHERE course_id = $2) ORDER BY order_index";
+        let query = "SELECT uid, section_id, order_index, type, content FROM content_element WHERE section_id = $1 AND section_id IN (SELECT uid FROM content_section WHERE course_id = $2 AND (NOT hidden OR $3)) ORDER BY order_index";
         match sqlx::query_as::<_, ContentElement>(query)
             .bind(section_id)
             .bind(course_id)
+            .bind(see_hidden)
             .fetch_all(&state.db)
             .await
         {
-            Ok(elements) => Json(elements).into_response(),
+            Ok(mut elements) => match ContentElement::load_files(&state.db, &mut elements).await {
+                Ok(()) => {
+                    // members who may not see hidden content only take the quizzes
+                    if !see_hidden {
+                        for e in &mut elements {
+                            e.kind.hide_solutions();
+                        }
+                    }
+                    Json(elements).into_response()
+                }
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            },
             Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
         }
     }
 
+    /// What a section belongs to.
+    #[derive(Clone, Copy)]
+    enum SectionParent {
+        Course(i64),
+        Template(i64),
+    }
+
+    /// `Err(BadParam)` unless section `section_id` belongs to `parent`.
+    async fn check_section(
+        state: &crate::AppState,
+        parent: SectionParent,
+        section_id: i64,
+    ) -> Result<(), ResourceError> {
+        let (column, parent_id) = match parent {
+            SectionParent::Course(id) => ("course_id", id),
+            SectionParent::Template(id) => ("template_id", id),
+        };
+        sqlx::query_scalar::<_, i32>(&format!(
+            "SELECT 1 FROM content_section WHERE uid=$1 AND {column}=$2"
+        ))
+        .bind(section_id)
+        .bind(parent_id)
+        .fetch_optional(&state.db)
+        .await?
+        .map(|_| ())
+        .ok_or(ResourceError::BadParam)
+    }
+
     //POST /course/{courseId}/section/{sectionId}/content
     pub async fn create_course_content(
-        auth_session: AuthSession<auth::Backend>,
+        permissions: Permissions,
         UrlPath((course_id, section_id)): UrlPath<(i64, i64)>,
         State(state): State<crate::AppState>,
         Json(req): Json<ContentElementCreationRequest>,
     ) -> Response {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Course,
-            &course_id,
-            Operations::UPDATE,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
+        if let Err(code) =
+            check_capability(&state, &permissions, course_id, Capability::EditContent).await
         {
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
-            Ok(true) => {}
-        };
+            return code.into_response();
+        }
 
-        // verify section
-        match sqlx::query_scalar::<_, i32>(
-            "SELECT 1 FROM content_section WHERE uid=$1 AND course_id=$2",
-        )
-        .bind(section_id)
-        .bind(course_id)
-        .fetch_optional(&state.db)
-        .await
-        {
-            Ok(None) => return StatusCode::BAD_REQUEST.into_response(),
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            _ => {}
+        if let Err(e) = req.kind.validate() {
+            return e.into_response();
+        }
+        if let Err(e) = check_section(&state, SectionParent::Course(course_id), section_id).await {
+            return e.into_response();
         }
         let idx = req.order_index.unwrap_or(0);
         match sqlx::query_scalar::<_, i64>("INSERT INTO content_element(section_id, order_index, type, content) VALUES ($1,$2,$3,$4) RETURNING uid")
             .bind(section_id)
             .bind(idx)
-            .bind(&req.element_type)
-            .bind(&req.content)
+            .bind(req.kind.name())
+            .bind(req.kind.content_column())
             .fetch_one(&state.db)
             .await
         {
-            Ok(uid) => Json(ContentElement{content_id:uid,section_id,order_index:idx,element_type:req.element_type,content:req.content}).into_response(),
+            Ok(uid) => Json(ContentElement{content_id:uid,section_id,order_index:idx,kind:req.kind,files:Vec::new()}).into_response(),
             Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
         }
     }
 
     //PUT /course/{courseId}/section/{sectionId}/content
     pub async fn update_course_content(
-        auth_session: AuthSession<auth::Backend>,
+        permissions: Permissions,
         UrlPath((course_id, section_id)): UrlPath<(i64, i64)>,
         State(state): State<crate::AppState>,
-        Json(elem): Json<ContentElement>,
+        Json(mut elem): Json<ContentElement>,
     ) -> Response {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Course,
-            &course_id,
-            Operations::UPDATE,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
+        if let Err(code) =
+            check_capability(&state, &permissions, course_id, Capability::EditContent).await
         {
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
-            Ok(true) => {}
-        };
+            return code.into_response();
+        }
 
-        match sqlx::query_scalar::<_, i32>(
-            "SELECT 1 FROM content_section WHERE uid=$1 AND course_id=$2",
-        )
-        .bind(section_id)
-        .bind(course_id)
-        .fetch_optional(&state.db)
-        .await
-        {
-            Ok(None) => return StatusCode::BAD_REQUEST.into_response(),
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            _ => {}
+        if let Err(e) = elem.kind.validate() {
+            return e.into_response();
+        }
+        if let Err(e) = check_section(&state, SectionParent::Course(course_id), section_id).await {
+            return e.into_response();
         }
         let query = "UPDATE content_element SET order_index=$1, type=$2, content=$3, updated_at=CURRENT_TIMESTAMP WHERE uid=$4 AND section_id=$5";
         match sqlx::query(query)
             .bind(elem.order_index)
-            .bind(&elem.element_type)
-            .bind(&elem.content)
+            .bind(elem.kind.name())
+            .bind(elem.kind.content_column())
             .bind(elem.content_id)
             .bind(section_id)
             .execute(&state.db)
@@ -424,9 +652,14 @@ ORDER BY order_index",
         {
             Ok(r) => {
                 if r.rows_affected() == 0 {
-                    StatusCode::NOT_FOUND.into_response()
-                } else {
-                    Json(elem).into_response()
+                    return StatusCode::NOT_FOUND.into_response();
+                }
+                match ContentElement::files(&state.db, elem.content_id).await {
+                    Ok(files) => {
+                        elem.files = files;
+                        Json(elem).into_response()
+                    }
+                    Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
                 }
             }
             Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
@@ -435,24 +668,15 @@ ORDER BY order_index",
 
     //DELETE /course/{courseId}/section/{sectionId}/content
     pub async fn delete_course_content(
-        auth_session: AuthSession<auth::Backend>,
+        permissions: Permissions,
         UrlPath((course_id, section_id)): UrlPath<(i64, i64)>,
         State(state): State<crate::AppState>,
     ) -> StatusCode {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Course,
-            &course_id,
-            Operations::UPDATE,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
+        if let Err(code) =
+            check_capability(&state, &permissions, course_id, Capability::EditContent).await
         {
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
-            Ok(false) => return StatusCode::UNAUTHORIZED,
-            Ok(true) => {}
-        };
+            return code;
+        }
 
         let query = "DELETE FROM content_element WHERE section_id=$1 AND section_id IN (SELECT uid FROM content_section WHERE course_id=$2)";
         match sqlx::query(query)
@@ -466,358 +690,483 @@ ORDER BY order_index",
         }
     }
 
-    //GET /template/{templateId}/sections
-    pub async fn get_all_for_template(
-        auth_session: AuthSession<auth::Backend>,
-        UrlPath(template_id): UrlPath<i64>,
+    /* -------- Attached Files ---------- */
+
+    /// Checks that the content element `content_id` belongs to section `section_id` of `course_id`,
+    /// which has to be visible with `access`.
+    async fn check_content(
+        state: &crate::AppState,
+        access: &CourseAccess,
+        course_id: i64,
+        section_id: i64,
+        content_id: i64,
+    ) -> Result<(), StatusCode> {
+        match sqlx::query_scalar::<_, i32>(
+            "SELECT 1 FROM content_element ce JOIN content_section cs ON cs.uid = ce.section_id \
+WHERE ce.uid = $1 AND ce.section_id = $2 AND cs.course_id = $3 AND (NOT cs.hidden OR $4)",
+        )
+        .bind(content_id)
+        .bind(section_id)
+        .bind(course_id)
+        .bind(access.can(Capability::SeeHidden))
+        .fetch_optional(&state.db)
+        .await
+        {
+            Ok(Some(_)) => Ok(()),
+            Ok(None) => Err(StatusCode::NOT_FOUND),
+            Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+
+    fn files_response(result: Result<Vec<file::FileMetadata>, sqlx::Error>) -> Response {
+        match result {
+            Ok(files) => Json(files).into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    //GET /course/{courseId}/section/{sectionId}/content/{contentId}/files
+    pub async fn get_content_files(
+        permissions: Permissions,
+        UrlPath((course_id, section_id, content_id)): UrlPath<(i64, i64, i64)>,
         State(state): State<crate::AppState>,
     ) -> Response {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Template,
-            &template_id,
-            Operations::READ,
-            s_user.user_id,
-            &state.db,
+        let access = match check_read(&state, &permissions, course_id).await {
+            Ok(access) => access,
+            Err(code) => return code.into_response(),
+        };
+        if let Err(code) = check_content(&state, &access, course_id, section_id, content_id).await {
+            return code.into_response();
+        }
+
+        files_response(ContentElement::files(&state.db, content_id).await)
+    }
+
+    //POST /course/{courseId}/section/{sectionId}/content/{contentId}/files
+    pub async fn attach_file(
+        permissions: Permissions,
+        UrlPath((course_id, section_id, content_id)): UrlPath<(i64, i64, i64)>,
+        State(state): State<crate::AppState>,
+        Json(req): Json<FileAttachmentRequest>,
+    ) -> Response {
+        let access = match check_capability(
+            &state,
+            &permissions,
+            course_id,
+            Capability::EditContent,
         )
         .await
         {
+            Ok(access) => access,
+            Err(code) => return code.into_response(),
+        };
+        if let Err(code) = check_content(&state, &access, course_id, section_id, content_id).await {
+            return code.into_response();
+        }
+        // attaching shares the file with everyone who can read the course
+        match file::user_can_read(&permissions, &state.db, req.file_uid).await {
             Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
             Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
             Ok(true) => {}
         };
-
-        match sqlx::query_as::<_, ContentSection>("SELECT uid, course_id, template_id, headline, order_index FROM content_section WHERE template_id = $1 ORDER BY order_index")
-            .bind(template_id)
-            .fetch_all(&state.db)
+        let size = match sqlx::query_scalar::<_, i64>("SELECT size FROM \"file\" WHERE uid = $1")
+            .bind(req.file_uid)
+            .fetch_optional(&state.db)
             .await
         {
-            Ok(sections) => Json(sections).into_response(),
-            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            Ok(Some(size)) => size,
+            Ok(None) => return StatusCode::BAD_REQUEST.into_response(),
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+
+        // files already in the course don't count against its quota a second time
+        if let Some(quota) = state.upload_policy.course_quota() {
+            let linked = match file::FileRow::linked_courses(&state.db, req.file_uid).await {
+                Ok(l) => l.contains(&course_id),
+                Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            };
+            if !linked {
+                let used = match StorageUsage::of_course(&state.db, course_id).await {
+                    Ok(u) => u.used as u64,
+                    Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+                };
+                if used + size as u64 > quota {
+                    return Rejection::QuotaExceeded {
+                        scope: QuotaScope::Course,
+                        quota,
+                        used,
+                    }
+                    .into_response();
+                }
+            }
         }
+
+        match sqlx::query(
+            "INSERT INTO file_in_content_element(content_id, file_id, order_index) \
+VALUES ($1, $2, COALESCE($3, (SELECT COALESCE(MAX(order_index) + 1, 0) \
+FROM file_in_content_element WHERE content_id = $1))) \
+ON CONFLICT DO NOTHING",
+        )
+        .bind(content_id)
+        .bind(req.file_uid)
+        .bind(req.order_index)
+        .execute(&state.db)
+        .await
+        {
+            Ok(r) if r.rows_affected() == 0 => return StatusCode::CONFLICT.into_response(),
+            Ok(_) => {}
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+
+        files_response(ContentElement::files(&state.db, content_id).await)
     }
 
-    //POST /template/{templateId}/sections
-    pub async fn create_for_template(
-        auth_session: AuthSession<auth::Backend>,
-        UrlPath(template_id): UrlPath<i64>,
+    //PUT /course/{courseId}/section/{sectionId}/content/{contentId}/files
+    /// Takes the uids of all attached files in their new order.
+    pub async fn reorder_files(
+        permissions: Permissions,
+        UrlPath((course_id, section_id, content_id)): UrlPath<(i64, i64, i64)>,
         State(state): State<crate::AppState>,
-        Json(req): Json<ContentSectionCreationRequest>,
+        Json(order): Json<Vec<Uuid>>,
     ) -> Response {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Template,
-            &template_id,
-            Operations::UPDATE,
-            s_user.user_id,
-            &state.db,
+        let access = match check_capability(
+            &state,
+            &permissions,
+            course_id,
+            Capability::EditContent,
         )
         .await
         {
+            Ok(access) => access,
+            Err(code) => return code.into_response(),
+        };
+        if let Err(code) = check_content(&state, &access, course_id, section_id, content_id).await {
+            return code.into_response();
+        }
+
+        let mut tx = match state.db.begin().await {
+            Ok(tx) => tx,
             Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
-            Ok(true) => {}
         };
+        let attached = match sqlx::query_scalar::<_, Uuid>(
+            "SELECT file_id FROM file_in_content_element WHERE content_id = $1 FOR UPDATE",
+        )
+        .bind(content_id)
+        .fetch_all(&mut *tx)
+        .await
+        {
+            Ok(a) => a.into_iter().collect::<HashSet<_>>(),
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+        let requested: HashSet<_> = order.iter().copied().collect();
+        if requested.len() != order.len() || requested != attached {
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+
+        match sqlx::query(
+            "UPDATE file_in_content_element fc SET order_index = o.idx - 1, updated_at = CURRENT_TIMESTAMP \
+FROM UNNEST($2::uuid[]) WITH ORDINALITY AS o(file_id, idx) \
+WHERE fc.content_id = $1 AND fc.file_id = o.file_id",
+        )
+        .bind(content_id)
+        .bind(&order)
+        .execute(&mut *tx)
+        .await
+        {
+            Ok(_) => {}
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+        if tx.commit().await.is_err() {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+
+        files_response(ContentElement::files(&state.db, content_id).await)
+    }
+
+    //DELETE /course/{courseId}/section/{sectionId}/content/{contentId}/files/{fileUid}
+    pub async fn detach_file(
+        permissions: Permissions,
+        UrlPath((course_id, section_id, content_id, file_uid)): UrlPath<(i64, i64, i64, Uuid)>,
+        State(state): State<crate::AppState>,
+    ) -> StatusCode {
+        let access = match check_capability(
+            &state,
+            &permissions,
+            course_id,
+            Capability::EditContent,
+        )
+        .await
+        {
+            Ok(access) => access,
+            Err(code) => return code,
+        };
+        if let Err(code) = check_content(&state, &access, course_id, section_id, content_id).await {
+            return code;
+        }
+
+        match sqlx::query(
+            "DELETE FROM file_in_content_element WHERE content_id = $1 AND file_id = $2",
+        )
+        .bind(content_id)
+        .bind(file_uid)
+        .execute(&state.db)
+        .await
+        {
+            Ok(r) => {
+                if r.rows_affected() == 0 {
+                    StatusCode::NOT_FOUND
+                } else {
+                    StatusCode::OK
+                }
+            }
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /* -------- Templates ---------- */
+
+    /// `Err(AccessDenied)` unless the user may do `operations` on template `template_id`.
+    async fn check_template(
+        permissions: &Permissions,
+        template_id: i64,
+        operations: Operations,
+    ) -> Result<(), ResourceError> {
+        require(
+            permissions
+                .has_id(resources::Type::Template, &template_id, operations)
+                .await?,
+        )
+    }
+
+    /// Operations held on template `template_id`, `Err(AccessDenied)` unless they allow updating it.
+    async fn template_editor(
+        permissions: &Permissions,
+        template_id: i64,
+    ) -> Result<Operations, ResourceError> {
+        let held = permissions
+            .operations(resources::Type::Template, template_id)
+            .await?;
+        require(held.can_update())?;
+        Ok(held)
+    }
+
+    //GET /template/{templateId}/sections
+    pub async fn get_all_for_template(
+        permissions: Permissions,
+        UrlPath(template_id): UrlPath<i64>,
+        State(state): State<crate::AppState>,
+    ) -> Result<Json<Vec<ContentSection>>, ResourceError> {
+        check_template(&permissions, template_id, Operations::READ).await?;
+        Ok(Json(sqlx::query_as::<_, ContentSection>("SELECT uid, course_id, template_id, headline, order_index, hidden FROM content_section WHERE template_id = $1 ORDER BY order_index")
+            .bind(template_id)
+            .fetch_all(&state.db)
+            .await?))
+    }
+
+    //POST /template/{templateId}/sections
+    pub async fn create_for_template(
+        permissions: Permissions,
+        UrlPath(template_id): UrlPath<i64>,
+        State(state): State<crate::AppState>,
+        Json(req): Json<ContentSectionCreationRequest>,
+    ) -> Result<Json<ContentSection>, ResourceError> {
+        let held = template_editor(&permissions, template_id).await?;
+        // only those who may publish sections create visible ones
+        require(req.hidden || held.can_publish())?;
 
         if req.headline.trim().is_empty() {
-            return StatusCode::BAD_REQUEST.into_response();
+            return Err(ResourceError::BadParam);
         }
         let idx = req.order_index.unwrap_or(0);
-        match sqlx::query_scalar::<_, i64>("INSERT INTO content_section(template_id, headline, order_index) VALUES ($1,$2,$3) RETURNING uid")
+        let uid = sqlx::query_scalar::<_, i64>("INSERT INTO content_section(template_id, headline, order_index, hidden) VALUES ($1,$2,$3,$4) RETURNING uid")
             .bind(template_id)
             .bind(&req.headline)
             .bind(idx)
+            .bind(req.hidden)
             .fetch_one(&state.db)
-            .await
-        {
-            Ok(uid) => Json(
-                    ContentSection { section_id: uid, course_id: None, template_id: Some(template_id), headline: req.headline, order_index: idx
-                }).into_response(),
-            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-        }
+            .await?;
+        Ok(Json(ContentSection {
+            section_id: uid,
+            course_id: None,
+            template_id: Some(template_id),
+            headline: req.headline,
+            order_index: idx,
+            hidden: req.hidden,
+        }))
     }
 
     //GET /template/{templateId}/section/{sectionId}
     pub async fn get_for_template(
-        auth_session: AuthSession<auth::Backend>,
+        permissions: Permissions,
         UrlPath((template_id, section_id)): UrlPath<(i64, i64)>,
         State(state): State<crate::AppState>,
-    ) -> Response {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Template,
-            &template_id,
-            Operations::READ,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
-        {
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
-            Ok(true) => {}
-        };
-
-        match sqlx::query_as::<_, ContentSection>("SELECT uid, course_id, template_id, headline, order_index FROM content_section WHERE uid = $1 AND template_id = $2")
+    ) -> Result<Json<ContentSection>, ResourceError> {
+        check_template(&permissions, template_id, Operations::READ).await?;
+        sqlx::query_as::<_, ContentSection>("SELECT uid, course_id, template_id, headline, order_index, hidden FROM content_section WHERE uid = $1 AND template_id = $2")
             .bind(section_id)
             .bind(template_id)
             .fetch_optional(&state.db)
-            .await
-        {
-            Ok(Some(sec)) => Json(sec).into_response(),
-            Ok(None) => StatusCode::NOT_FOUND.into_response(),
-            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-        }
+            .await?
+            .map(Json)
+            .ok_or(ResourceError::NotFound)
     }
 
     //PUT /template/{templateId}/section/{sectionId}
     pub async fn update_for_template(
-        auth_session: AuthSession<auth::Backend>,
+        permissions: Permissions,
         UrlPath((template_id, section_id)): UrlPath<(i64, i64)>,
         State(state): State<crate::AppState>,
         Json(req): Json<ContentSectionCreationRequest>,
-    ) -> Response {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Template,
-            &template_id,
-            Operations::UPDATE,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
-        {
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
-            Ok(true) => {}
-        };
+    ) -> Result<Json<ContentSection>, ResourceError> {
+        let held = template_editor(&permissions, template_id).await?;
 
         if req.headline.trim().is_empty() {
-            return StatusCode::BAD_REQUEST.into_response();
+            return Err(ResourceError::BadParam);
         }
+        let hidden = sqlx::query_scalar::<_, bool>(
+            "SELECT hidden FROM content_section WHERE uid = $1 AND template_id = $2",
+        )
+        .bind(section_id)
+        .bind(template_id)
+        .fetch_optional(&state.db)
+        .await?
+        .ok_or(ResourceError::NotFound)?;
+        require(hidden == req.hidden || held.can_publish())?;
         let idx = req.order_index.unwrap_or(0);
-        match sqlx::query("UPDATE content_section SET headline = $1, order_index = $2 WHERE uid = $3 AND template_id = $4")
+        let updated = sqlx::query("UPDATE content_section SET headline = $1, order_index = $2, hidden = $5 WHERE uid = $3 AND template_id = $4")
             .bind(&req.headline)
             .bind(idx)
             .bind(section_id)
             .bind(template_id)
+            .bind(req.hidden)
             .execute(&state.db)
-            .await
-        {
-            Ok(res) => {
-                if res.rows_affected()==0 { StatusCode::NOT_FOUND.into_response() } else { Json(ContentSection{section_id, course_id: None, template_id: Some(template_id), headline:req.headline,order_index:idx}).into_response() }
-            }
-            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            .await?;
+        if updated.rows_affected() == 0 {
+            return Err(ResourceError::NotFound);
         }
+        Ok(Json(ContentSection {
+            section_id,
+            course_id: None,
+            template_id: Some(template_id),
+            headline: req.headline,
+            order_index: idx,
+            hidden: req.hidden,
+        }))
     }
 
     //DELETE /template/{templateId}/section/{sectionId}
     pub async fn delete_for_template(
-        auth_session: AuthSession<auth::Backend>,
+        permissions: Permissions,
         UrlPath((template_id, section_id)): UrlPath<(i64, i64)>,
         State(state): State<crate::AppState>,
-    ) -> StatusCode {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Template,
-            &template_id,
-            Operations::UPDATE,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
-        {
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
-            Ok(false) => return StatusCode::UNAUTHORIZED,
-            Ok(true) => {}
-        };
-
-        match sqlx::query("DELETE FROM content_section WHERE uid = $1 AND template_id = $2")
-            .bind(section_id)
-            .bind(template_id)
-            .execute(&state.db)
-            .await
-        {
-            Ok(r) => {
-                if r.rows_affected() == 0 {
-                    StatusCode::NOT_FOUND
-                } else {
-                    StatusCode::OK
-                }
-            }
-            Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    ) -> Result<StatusCode, ResourceError> {
+        check_template(&permissions, template_id, Operations::UPDATE).await?;
+        let deleted =
+            sqlx::query("DELETE FROM content_section WHERE uid = $1 AND template_id = $2")
+                .bind(section_id)
+                .bind(template_id)
+                .execute(&state.db)
+                .await?;
+        if deleted.rows_affected() == 0 {
+            Err(ResourceError::NotFound)
+        } else {
+            Ok(StatusCode::OK)
         }
     }
+
     //GET /template/{templateId}/section/{sectionId}/content
     pub async fn get_template_content(
-        auth_session: AuthSession<auth::Backend>,
+        permissions: Permissions,
         UrlPath((template_id, section_id)): UrlPath<(i64, i64)>,
         State(state): State<crate::AppState>,
-    ) -> Response {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Template,
-            &template_id,
-            Operations::READ,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
-        {
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
-            Ok(true) => {}
-        };
-
+    ) -> Result<Json<Vec<ContentElement>>, ResourceError> {
+        check_template(&permissions, template_id, Operations::READ).await?;
         let query = "SELECT uid, section_id, order_index, type, content FROM content_element WHERE section_id = $1 AND section_id IN (SELECT uid FROM content_section WHERE template_id = $2) ORDER BY order_index";
-        match sqlx::query_as::<_, ContentElement>(query)
+        let mut elements = sqlx::query_as::<_, ContentElement>(query)
             .bind(section_id)
             .bind(template_id)
             .fetch_all(&state.db)
-            .await
-        {
-            Ok(elements) => Json(elements).into_response(),
-            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-        }
+            .await?;
+        ContentElement::load_files(&state.db, &mut elements).await?;
+        Ok(Json(elements))
     }
 
     //POST /template/{templateId}/section/{sectionId}/content
     pub async fn create_template_content(
-        auth_session: AuthSession<auth::Backend>,
+        permissions: Permissions,
         UrlPath((template_id, section_id)): UrlPath<(i64, i64)>,
         State(state): State<crate::AppState>,
         Json(req): Json<ContentElementCreationRequest>,
-    ) -> Response {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Template,
-            &template_id,
-            Operations::UPDATE,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
-        {
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
-            Ok(true) => {}
-        };
+    ) -> Result<Response, ResourceError> {
+        check_template(&permissions, template_id, Operations::UPDATE).await?;
 
-        // verify section
-        match sqlx::query_scalar::<_, i32>(
-            "SELECT 1 FROM content_section WHERE uid=$1 AND template_id=$2",
-        )
-        .bind(section_id)
-        .bind(template_id)
-        .fetch_optional(&state.db)
-        .await
-        {
-            Ok(None) => return StatusCode::BAD_REQUEST.into_response(),
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            _ => {}
+        if let Err(e) = req.kind.validate() {
+            return Ok(e.into_response());
         }
+        check_section(&state, SectionParent::Template(template_id), section_id).await?;
         let idx = req.order_index.unwrap_or(0);
-        match sqlx::query_scalar::<_, i64>("INSERT INTO content_element(section_id, order_index, type, content) VALUES ($1,$2,$3,$4) RETURNING uid")
+        let uid = sqlx::query_scalar::<_, i64>("INSERT INTO content_element(section_id, order_index, type, content) VALUES ($1,$2,$3,$4) RETURNING uid")
             .bind(section_id)
             .bind(idx)
-            .bind(&req.element_type)
-            .bind(&req.content)
+            .bind(req.kind.name())
+            .bind(req.kind.content_column())
             .fetch_one(&state.db)
-            .await
-        {
-            Ok(uid) => Json(ContentElement{content_id:uid,section_id,order_index:idx,element_type:req.element_type,content:req.content}).into_response(),
-            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-        }
+            .await?;
+        Ok(Json(ContentElement {
+            content_id: uid,
+            section_id,
+            order_index: idx,
+            kind: req.kind,
+            files: Vec::new(),
+        })
+        .into_response())
     }
 
     //PUT /template/{templateId}/section/{sectionId}/content
     pub async fn update_template_content(
-        auth_session: AuthSession<auth::Backend>,
+        permissions: Permissions,
         UrlPath((template_id, section_id)): UrlPath<(i64, i64)>,
         State(state): State<crate::AppState>,
-        Json(elem): Json<ContentElement>,
-    ) -> Response {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Template,
-            &template_id,
-            Operations::UPDATE,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
-        {
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
-            Ok(true) => {}
-        };
+        Json(mut elem): Json<ContentElement>,
+    ) -> Result<Response, ResourceError> {
+        check_template(&permissions, template_id, Operations::UPDATE).await?;
         // sanity checks
         if elem.section_id != section_id {
-            return StatusCode::BAD_REQUEST.into_response();
+            return Err(ResourceError::BadParam);
         }
-        match sqlx::query_scalar::<_, i32>(
-            "SELECT 1 FROM content_section WHERE uid=$1 AND template_id=$2",
-        )
-        .bind(section_id)
-        .bind(template_id)
-        .fetch_optional(&state.db)
-        .await
-        {
-            Ok(None) => return StatusCode::BAD_REQUEST.into_response(),
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            _ => {}
+        if let Err(e) = elem.kind.validate() {
+            return Ok(e.into_response());
         }
+        check_section(&state, SectionParent::Template(template_id), section_id).await?;
         let query = "UPDATE content_element SET order_index=$1, type=$2, content=$3, updated_at=CURRENT_TIMESTAMP WHERE uid=$4 AND section_id=$5";
-        match sqlx::query(query)
+        let updated = sqlx::query(query)
             .bind(elem.order_index)
-            .bind(&elem.element_type)
-            .bind(&elem.content)
+            .bind(elem.kind.name())
+            .bind(elem.kind.content_column())
             .bind(elem.content_id)
             .bind(section_id)
             .execute(&state.db)
-            .await
-        {
-            Ok(r) => {
-                if r.rows_affected() == 0 {
-                    StatusCode::NOT_FOUND.into_response()
-                } else {
-                    Json(elem).into_response()
-                }
-            }
-            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            .await?;
+        if updated.rows_affected() == 0 {
+            return Err(ResourceError::NotFound);
         }
+        elem.files = ContentElement::files(&state.db, elem.content_id).await?;
+        Ok(Json(elem).into_response())
     }
 
     //DELETE /template/{templateId}/section/{sectionId}/content
     pub async fn delete_template_content(
-        auth_session: AuthSession<auth::Backend>,
+        permissions: Permissions,
         UrlPath((template_id, section_id)): UrlPath<(i64, i64)>,
         State(state): State<crate::AppState>,
-    ) -> StatusCode {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Template,
-            &template_id,
-            Operations::UPDATE,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
-        {
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
-            Ok(false) => return StatusCode::UNAUTHORIZED,
-            Ok(true) => {}
-        };
-
+    ) -> Result<StatusCode, ResourceError> {
+        check_template(&permissions, template_id, Operations::UPDATE).await?;
         let query = "DELETE FROM content_element WHERE section_id=$1 AND section_id IN (SELECT uid FROM content_section WHERE template_id=$2)";
-        match sqlx::query(query)
+        sqlx::query(query)
             .bind(section_id)
             .bind(template_id)
             .execute(&state.db)
-            .await
-        {
-            Ok(_) => StatusCode::OK,
-            Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
-        }
+            .await?;
+        Ok(StatusCode::OK)
     }
 }
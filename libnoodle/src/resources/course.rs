@@ -1,5 +1,16 @@
+use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use sqlx::FromRow;
+use sqlx::{FromRow, PgPool};
+
+use crate::{
+    AppState,
+    auth::{cache::Permissions, expiry::Validity, permission::Operations},
+    resources::{
+        self,
+        crud::{Create, NoFilter, Page, Resource, ResourceError, Selection},
+    },
+    user::Profile,
+};
 
 #[derive(Debug, Serialize, Deserialize, Clone, FromRow)]
 #[serde(rename_all = "camelCase")]
@@ -18,307 +29,416 @@ pub struct CourseDescription {
     pub shortname: String,
 }
 
+/// Role of a member inside a course, ordered from the most to the least privileged one like the
+/// `course_role` enum.
+#[derive(
+    Debug, Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[sqlx(type_name = "course_role", rename_all = "lowercase")]
+#[serde(rename_all = "camelCase")]
+pub enum CourseRole {
+    Lecturer,
+    Tutor,
+    Student,
+    Guest,
+}
+
+/// Something a member may do inside a course on top of reading it.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Capability {
+    /// Change sections, content elements and their files
+    EditContent,
+    /// Hide and reveal sections
+    Publish,
+    /// See hidden sections and the solutions of quizzes
+    SeeHidden,
+    Grade,
+    /// Enroll users and groups and change their course roles
+    ManageEnrollment,
+    /// List the members of the course
+    ViewMembers,
+}
+
+/// Condition on a `course_member` row `cm` and a `content_section` row `cs`, whether the member may
+/// see the section. Has to match the roles with [`Capability::SeeHidden`].
+pub const MEMBER_SEES_SECTION: &str = "(NOT cs.hidden OR cm.role IN ('lecturer', 'tutor'))";
+
+impl Capability {
+    /// Operations of a course grant that give the capability without a course role.
+    pub fn operations(&self) -> Operations {
+        match self {
+            Self::SeeHidden | Self::ViewMembers => Operations::READ,
+            Self::EditContent => Operations::UPDATE,
+            Self::Publish => Operations::PUBLISH,
+            Self::Grade => Operations::GRADE,
+            Self::ManageEnrollment => Operations::ENROLL,
+        }
+    }
+}
+
+impl CourseRole {
+    pub fn capabilities(&self) -> &'static [Capability] {
+        use Capability::*;
+        match self {
+            Self::Lecturer => &[EditContent, SeeHidden, Grade, ManageEnrollment, ViewMembers],
+            Self::Tutor => &[SeeHidden, Grade, ViewMembers],
+            Self::Student => &[ViewMembers],
+            Self::Guest => &[],
+        }
+    }
+
+    pub fn has(&self, capability: Capability) -> bool {
+        self.capabilities().contains(&capability)
+    }
+
+    /// Course operations the role stands in for.
+    pub fn operations(&self) -> Operations {
+        self.capabilities()
+            .iter()
+            .fold(Operations::READ, |ops, c| ops | c.operations())
+    }
+
+    /// Role of `user_id` in `course_id`, `None` if they are no member.
+    pub async fn of(
+        db: &PgPool,
+        course_id: i64,
+        user_id: i64,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_scalar::<_, Self>(
+            "SELECT role FROM course_member WHERE course_id = $1 AND user_id = $2",
+        )
+        .bind(course_id)
+        .bind(user_id)
+        .fetch_optional(db)
+        .await
+    }
+}
+
+/// What the logged in user may do in a course, through their course role or a course grant.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CourseAccess {
+    pub role: Option<CourseRole>,
+    pub capabilities: Vec<Capability>,
+    #[serde(skip)]
+    granted: Operations,
+    /// What the login may be used for on courses, which also limits what the role allows
+    #[serde(skip)]
+    allowed: Operations,
+}
+
+impl CourseAccess {
+    pub async fn load(
+        permissions: &Permissions,
+        db: &PgPool,
+        course_id: i64,
+    ) -> Result<Self, sqlx::Error> {
+        let granted = permissions
+            .operations(resources::Type::Course, course_id)
+            .await?;
+        let allowed = permissions.allowed_operations(resources::Type::Course);
+        let role = CourseRole::of(db, course_id, permissions.user_id()).await?;
+        let capabilities = [
+            Capability::EditContent,
+            Capability::Publish,
+            Capability::SeeHidden,
+            Capability::Grade,
+            Capability::ManageEnrollment,
+            Capability::ViewMembers,
+        ]
+        .into_iter()
+        .filter(|c| {
+            granted.intersects(c.operations())
+                || allowed.intersects(c.operations()) && role.is_some_and(|r| r.has(*c))
+        })
+        .collect();
+        Ok(Self {
+            role,
+            capabilities,
+            granted,
+            allowed,
+        })
+    }
+
+    /// Whether the user may read the course, as a member or through a grant.
+    pub fn can_read(&self) -> bool {
+        self.role.is_some() && self.allowed.intersects(Operations::READ)
+            || self.granted.intersects(Operations::READ)
+    }
+
+    pub fn can(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+}
+
+#[derive(Serialize, FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct CourseMember {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub profile: Profile,
+    pub role: CourseRole,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CourseRoleRequest {
+    pub role: CourseRole,
+    #[serde(flatten)]
+    pub validity: Validity,
+}
+
+#[async_trait]
+impl Resource for Course {
+    const TYPE: resources::Type = resources::Type::Course;
+    type Id = i64;
+    type Description = CourseDescription;
+    type Filter = NoFilter;
+
+    fn validate(desc: &CourseDescription) -> Result<(), ResourceError> {
+        if desc.name.trim().is_empty() {
+            return Err(ResourceError::BadParam);
+        }
+        Ok(())
+    }
+
+    async fn list(
+        state: &AppState,
+        selection: Selection<i64>,
+        page: Page,
+        _filter: &NoFilter,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as::<_, Course>(&format!(
+            "SELECT uid, name, shortname FROM \"course\" WHERE uid {} \
+ORDER BY uid LIMIT $2 OFFSET $3",
+            selection.condition()
+        ))
+        .bind(selection.into_ids())
+        .bind(page.limit)
+        .bind(page.offset)
+        .fetch_all(&state.db)
+        .await
+    }
+
+    async fn fetch(state: &AppState, id: &i64) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as::<_, Course>("SELECT uid, name, shortname FROM \"course\" WHERE uid = $1")
+            .bind(id)
+            .fetch_optional(&state.db)
+            .await
+    }
+
+    async fn update(
+        state: &AppState,
+        id: &i64,
+        desc: CourseDescription,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as::<_, Course>(
+            "UPDATE \"course\" SET \"name\" = $1, shortname = $2 WHERE uid = $3 \
+RETURNING uid, name, shortname",
+        )
+        .bind(desc.name)
+        .bind(desc.shortname)
+        .bind(id)
+        .fetch_optional(&state.db)
+        .await
+    }
+
+    async fn delete(state: &AppState, id: &i64) -> Result<bool, sqlx::Error> {
+        Ok(sqlx::query("DELETE FROM \"course\" WHERE uid = $1")
+            .bind(id)
+            .execute(&state.db)
+            .await?
+            .rows_affected()
+            > 0)
+    }
+
+    /// The courses the user is a member of, with a type-wide `READ` grant also all others that are
+    /// not denied to them.
+    async fn readable(
+        permissions: &Permissions,
+        state: &AppState,
+        page: Page,
+        _filter: &NoFilter,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        if !permissions
+            .allowed_operations(resources::Type::Course)
+            .intersects(Operations::READ)
+        {
+            return Ok(Vec::new());
+        }
+        // members still see the courses denied to them
+        let denied = if permissions
+            .has_all(resources::Type::Course, Operations::READ)
+            .await?
+        {
+            Some(
+                permissions
+                    .denied_ids::<i64>(resources::Type::Course, Operations::READ)
+                    .await?,
+            )
+        } else {
+            None
+        };
+        sqlx::query_as::<_, Course>(
+            "SELECT c.uid, c.name, c.shortname FROM \"course\" c \
+WHERE ($1::bigint[] IS NOT NULL AND c.uid <> ALL($1)) \
+OR EXISTS(SELECT 1 FROM course_member cm WHERE cm.course_id = c.uid AND cm.user_id = $2) \
+ORDER BY c.uid LIMIT $3 OFFSET $4",
+        )
+        .bind(denied)
+        .bind(permissions.user_id())
+        .bind(page.limit)
+        .bind(page.offset)
+        .fetch_all(&state.db)
+        .await
+    }
+
+    async fn can_read(
+        permissions: &Permissions,
+        state: &AppState,
+        id: &i64,
+    ) -> Result<bool, sqlx::Error> {
+        Ok(CourseAccess::load(permissions, &state.db, *id)
+            .await?
+            .can_read())
+    }
+}
+
+#[async_trait]
+impl Create for Course {
+    async fn insert(state: &AppState, desc: CourseDescription) -> Result<Self, sqlx::Error> {
+        sqlx::query_as::<_, Course>(
+            "INSERT INTO \"course\"(name, shortname) VALUES ($1, $2) RETURNING uid, name, shortname",
+        )
+        .bind(desc.name)
+        .bind(desc.shortname)
+        .fetch_one(&state.db)
+        .await
+    }
+}
+
 pub mod http {
     use crate::{
         auth::{
-            self,
+            cache::Permissions,
+            expiry::Validity,
             permission::{Operations, PermissionQueryParam},
         },
-        resources,
-        user::Profile,
+        resources::{
+            self,
+            crud::{NoFilter, Page, ResourceError, list_permitted},
+            file::{StorageReport, StorageUsage},
+        },
     };
 
-    use super::{Course, CourseDescription};
+    use super::{Capability, Course, CourseAccess, CourseMember, CourseRole, CourseRoleRequest};
     use axum::{
         Json,
         extract::{Path as UrlPath, Query, State},
         http::StatusCode,
         response::{IntoResponse, Response},
     };
-    use axum_login::AuthSession;
 
-    pub async fn get_all(
-        auth_session: AuthSession<auth::Backend>,
-        State(state): State<crate::AppState>,
-    ) -> Response {
-        let s_user = auth_session.user.unwrap();
-        let courses = match auth::user_has_permissions_all(
-            resources::Type::Course,
-            Operations::READ,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
-        {
-            Ok(true) => {
-                match sqlx::query_as::<_, Course>("SELECT uid, name, shortname FROM \"course\"")
-                    .fetch_all(&state.db)
-                    .await
-                {
-                    Ok(c) => c,
-                    Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-                }
-            }
-            Ok(false) => match sqlx::query_as::<_, Course>(
-                "WITH user_courses AS (\
-SELECT course_id FROM course_user WHERE user_id = $1 UNION \
-SELECT cg.course_id FROM course_group cg \
-JOIN user_in_group uig ON uig.group_id = cg.group_id \
-WHERE uig.user_id = $1) \
-SELECT c.uid, c.name, c.shortname FROM course c \
-JOIN user_courses uc ON c.uid = uc.course_id"
-            )
-            .bind(s_user.user_id)
-            .fetch_all(&state.db)
-            .await
-            {
-                Ok(c) => c,
-                Err(e) => {println!("{e}");return StatusCode::INTERNAL_SERVER_ERROR.into_response()},
-            },
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-        };
+    /// Checks that the user may read `course_id`, as a member or through a grant, and returns what
+    /// else they may do in it.
+    pub(crate) async fn check_read(
+        state: &crate::AppState,
+        permissions: &Permissions,
+        course_id: i64,
+    ) -> Result<CourseAccess, StatusCode> {
+        match CourseAccess::load(permissions, &state.db, course_id).await {
+            Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+            Ok(access) if !access.can_read() => Err(StatusCode::UNAUTHORIZED),
+            Ok(access) => Ok(access),
+        }
+    }
 
-        Json(courses).into_response()
+    /// Checks that the user has `capability` in `course_id`, through their course role or a grant,
+    /// and returns what else they may do in it.
+    pub(crate) async fn check_capability(
+        state: &crate::AppState,
+        permissions: &Permissions,
+        course_id: i64,
+        capability: Capability,
+    ) -> Result<CourseAccess, StatusCode> {
+        match CourseAccess::load(permissions, &state.db, course_id).await {
+            Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+            Ok(access) if !access.can(capability) => Err(StatusCode::UNAUTHORIZED),
+            Ok(access) => Ok(access),
+        }
     }
 
     pub async fn get_all_management(
-        auth_session: AuthSession<auth::Backend>,
+        permissions: Permissions,
         State(state): State<crate::AppState>,
         Query(perm): Query<PermissionQueryParam>,
-    ) -> Response {
-        let s_user = auth_session.user.unwrap();
+        Query(page): Query<Page>,
+    ) -> Result<Json<Vec<Course>>, ResourceError> {
         let ops = if let Some(true) = perm.edit {
             Operations::UPDATE
         } else {
             Operations::READ
         };
-        let courses = match auth::user_has_permissions_all(
-            resources::Type::Course,
-            ops,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
-        {
-            Ok(true) => {
-                match sqlx::query_as::<_, Course>("SELECT uid, name, shortname FROM \"course\"")
-                    .fetch_all(&state.db)
-                    .await
-                {
-                    Ok(c) => c,
-                    Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-                }
-            }
-            Ok(false) => match sqlx::query_as::<_, Course>(
-                "SELECT c.uid, c.name, c.shortname \
-FROM course c \
-JOIN course_permissions cp ON c.uid = cp.resource_id \
-WHERE (cp.user_id = $1 OR EXISTS(\
-SELECT 1 FROM user_has_role ur \
-WHERE ur.user_id = $1 AND ur.role_id = cp.role_id)) \
-AND (cp.permission & $2::int::bit(16)) <> B'0'::bit(16)",
-            )
-            .bind(s_user.user_id)
-            .bind(ops)
-            .fetch_all(&state.db)
-            .await
-            {
-                Ok(c) => c,
-                Err(e) => {println!("{e}");return StatusCode::INTERNAL_SERVER_ERROR.into_response()},
-            },
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-        };
-
-        Json(courses).into_response()
-    }    
-
-    pub async fn get_by_uid(
-        auth_session: AuthSession<auth::Backend>,
-        UrlPath(id): UrlPath<i64>,
-        State(state): State<crate::AppState>,
-    ) -> Response {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Course,
-            &id,
-            Operations::READ,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
-        {
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            Ok(false) => {
-                match sqlx::query_as::<_, Course>(
-                    "SELECT c.uid, c.name, c.shortname FROM course c WHERE c.uid = $2 \
-AND (EXISTS(SELECT 1 FROM course_user cu WHERE cu.course_id = c.uid AND cu.user_id = $1) \
-OR EXISTS(SELECT 1 FROM course_group cg JOIN user_in_group uig ON uig.group_id = cg.group_id \
-WHERE cg.course_id = c.uid \
-AND uig.user_id = $1))",
-                )
-                .bind(s_user.user_id)
-                .bind(id).fetch_optional(&state.db).await {
-                    Ok(Some(c)) => return Json(c).into_response(),
-                    Ok(None) => return StatusCode::UNAUTHORIZED.into_response(),
-                    Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-                }
-                
-            },
-            Ok(true) => {}
-        };
-
-        match sqlx::query_as::<_, Course>(
-            "SELECT uid, name, shortname FROM \"course\" WHERE uid = $1",
-        )
-        .bind(id)
-        .fetch_optional(&state.db)
-        .await
-        {
-            Ok(Some(course)) => Json(course).into_response(),
-            Ok(None) => StatusCode::NOT_FOUND.into_response(),
-            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-        }
+        let page = page.validate()?;
+        Ok(Json(
+            list_permitted::<Course>(&permissions, &state, ops, page, &NoFilter {}).await?,
+        ))
     }
 
-    pub async fn create(
-        auth_session: AuthSession<auth::Backend>,
+    //GET /course/{courseId}/access
+    pub async fn get_access(
+        permissions: Permissions,
+        UrlPath(id): UrlPath<i64>,
         State(state): State<crate::AppState>,
-        Json(desc): Json<CourseDescription>,
     ) -> Response {
-        let s_user = auth_session.user.unwrap();
-        match auth::can_create(resources::Type::Course, s_user.user_id, &state.db).await {
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
-            Ok(true) => {}
-        }
-
-        if desc.name.trim().is_empty() {
-            return StatusCode::BAD_REQUEST.into_response();
-        }
-        match sqlx::query_scalar::<_, i64>(
-            "INSERT INTO \"course\"(name, shortname) VALUES ($1, $2) RETURNING uid",
-        )
-        .bind(&desc.name)
-        .bind(&desc.shortname)
-        .fetch_one(&state.db)
-        .await
-        {
-            Ok(id) => Json(Course {
-                course_id: id,
-                name: desc.name,
-                shortname: desc.shortname,
-            })
-            .into_response(),
+        match CourseAccess::load(&permissions, &state.db, id).await {
             Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            Ok(access) if !access.can_read() => StatusCode::UNAUTHORIZED.into_response(),
+            Ok(access) => Json(access).into_response(),
         }
     }
 
-    pub async fn update(
-        auth_session: AuthSession<auth::Backend>,
+    pub async fn get_storage(
+        permissions: Permissions,
         UrlPath(id): UrlPath<i64>,
         State(state): State<crate::AppState>,
-        Json(desc): Json<CourseDescription>,
     ) -> Response {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Course,
-            &id,
-            Operations::UPDATE,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
+        match permissions
+            .has_id(resources::Type::Course, &id, Operations::READ)
+            .await
         {
             Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
             Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
             Ok(true) => {}
-        }
+        };
 
-        if desc.name.trim().is_empty() {
-            return StatusCode::BAD_REQUEST.into_response();
-        }
-        match sqlx::query("UPDATE \"course\" SET \"name\" = $1, shortname = $2 WHERE uid = $3")
-            .bind(&desc.name)
-            .bind(&desc.shortname)
+        match sqlx::query_scalar::<_, i32>("SELECT 1 FROM \"course\" WHERE uid = $1")
             .bind(id)
-            .execute(&state.db)
+            .fetch_optional(&state.db)
             .await
         {
-            Ok(result) => {
-                if result.rows_affected() == 0 {
-                    return StatusCode::NOT_FOUND.into_response();
-                }
-                Json(Course {
-                    course_id: id,
-                    name: desc.name,
-                    shortname: desc.shortname,
-                })
-                .into_response()
-            }
-            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-        }
-    }
-
-    pub async fn delete(
-        auth_session: AuthSession<auth::Backend>,
-        UrlPath(id): UrlPath<i64>,
-        State(state): State<crate::AppState>,
-    ) -> StatusCode {
-        let s_user = auth_session.user.unwrap();
-        match auth::can_delete(resources::Type::Course, s_user.user_id, &state.db).await {
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
-            Ok(false) => return StatusCode::UNAUTHORIZED,
-            Ok(true) => {}
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+            Ok(Some(_)) => {}
         }
 
-        match sqlx::query("DELETE FROM \"course\" WHERE uid = $1")
-            .bind(id)
-            .execute(&state.db)
-            .await
-        {
-            Ok(r) => {
-                if r.rows_affected() == 0 {
-                    StatusCode::NOT_FOUND
-                } else {
-                    StatusCode::OK
-                }
-            }
-            Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        match StorageUsage::of_course(&state.db, id).await {
+            Ok(usage) => Json(StorageReport {
+                usage,
+                quota: state.upload_policy.course_quota(),
+            })
+            .into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
         }
     }
 
     pub async fn get_lecturers(
-        auth_session: AuthSession<auth::Backend>,
+        permissions: Permissions,
         UrlPath(id): UrlPath<i64>,
         State(state): State<crate::AppState>,
     ) -> Response {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Course,
-            &id,
-            Operations::READ,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
-        {
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            Ok(false) => {
-                match sqlx::query_as::<_, (String, String)>(
-                    "SELECT u.firstname, u.lastname FROM \"user\" AS u JOIN course_lecturer cl ON cl.user_id = u.id \
-WHERE cl.course_id = $2 \
-AND (EXISTS(SELECT 1 FROM course_user cu WHERE cu.course_id = $1 AND cu.user_id = $1) \
-OR EXISTS(SELECT 1 FROM course_group cg JOIN user_in_group uig ON uig.group_id = cg.group_id \
-WHERE cg.course_id = $2 \
-AND uig.user_id = $1))",
-                )
-                .bind(s_user.user_id)
-                .bind(id).fetch_all(&state.db).await {
-                    Ok(n) => return Json(n.iter().map(|v| {format!("{} {}", v.0, v.1)}).collect::<Vec<String>>()).into_response(),
-                    Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-                }
-            },
-            Ok(true) => {}
+        if let Err(code) = check_read(&state, &permissions, id).await {
+            return code.into_response();
         }
 
         match sqlx::query_as::<_, (String, String)>("SELECT u.firstname, u.lastname FROM \"user\" AS u JOIN course_lecturer cl ON cl.user_id = u.id WHERE cl.course_id = $1")
@@ -331,21 +451,21 @@ AND uig.user_id = $1))",
         }
     }
 
+    /// Lecturers get every capability in the course, so naming them needs the right to manage its
+    /// permissions.
     pub async fn add_lecturers(
-        auth_session: AuthSession<auth::Backend>,
+        permissions: Permissions,
         UrlPath(course_id): UrlPath<i64>,
         State(state): State<crate::AppState>,
         Json(user_ids): Json<Vec<i64>>,
     ) -> StatusCode {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Course,
-            &course_id,
-            Operations::UPDATE,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
+        match permissions
+            .has_id(
+                resources::Type::Course,
+                &course_id,
+                Operations::MANAGE_PERMISSIONS,
+            )
+            .await
         {
             Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
             Ok(false) => return StatusCode::UNAUTHORIZED,
@@ -364,20 +484,18 @@ AND uig.user_id = $1))",
     }
 
     pub async fn set_lecturers(
-        auth_session: AuthSession<auth::Backend>,
+        permissions: Permissions,
         UrlPath(course_id): UrlPath<i64>,
         State(state): State<crate::AppState>,
         Json(user_ids): Json<Vec<i64>>,
     ) -> StatusCode {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Course,
-            &course_id,
-            Operations::UPDATE,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
+        match permissions
+            .has_id(
+                resources::Type::Course,
+                &course_id,
+                Operations::MANAGE_PERMISSIONS,
+            )
+            .await
         {
             Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
             Ok(false) => return StatusCode::UNAUTHORIZED,
@@ -397,7 +515,7 @@ AND uig.user_id = $1))",
             Ok(_) => {}
         };
 
-        match sqlx::query("INSERT INTO course_lecturer(course_id, user_id) SELECT * FROM UNNEST(array_fill($1, ARRAY[array_length($2::int8[], 1)]), $2::int8[])")
+        match sqlx::query("INSERT INTO course_lecturer(course_id, user_id) SELECT $1, u FROM UNNEST($2::int8[]) u")
             .bind(course_id)
             .bind(user_ids)
             .execute(&mut *tx).await {
@@ -410,36 +528,12 @@ AND uig.user_id = $1))",
     }
 
     pub async fn get_groups(
-        auth_session: AuthSession<auth::Backend>,
+        permissions: Permissions,
         UrlPath(id): UrlPath<i64>,
         State(state): State<crate::AppState>,
     ) -> Response {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Course,
-            &id,
-            Operations::READ,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
-        {
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            Ok(false) => {
-                match sqlx::query_scalar::<_, String>(
-                    "SELECT g.name FROM \"group\" AS g JOIN course_group cg ON cg.group_id = g.id \
-WHERE cg.course_id = $2 \
-AND (EXISTS(SELECT 1 FROM course_user cu WHERE cu.course_id = $2 AND cu.user_id = $1) \
-OR EXISTS(SELECT 1 FROM course_group cg2 JOIN user_in_group uig ON uig.group_id = cg2.group_id \
-WHERE cg2.course_id = $2 AND uig.user_id = $1))",
-                )
-                .bind(s_user.user_id)
-                .bind(id).fetch_all(&state.db).await {
-                    Ok(n) => return Json(n).into_response(),
-                    Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-                }
-            },
-            Ok(true) => {}
+        if let Err(code) = check_read(&state, &permissions, id).await {
+            return code.into_response();
         }
 
         match sqlx::query_scalar::<_, String>("SELECT g.name FROM \"group\" AS g JOIN course_group cg ON cg.group_id = g.id WHERE cg.course_id = $1")
@@ -453,24 +547,20 @@ WHERE cg2.course_id = $2 AND uig.user_id = $1))",
     }
 
     pub async fn add_groups(
-        auth_session: AuthSession<auth::Backend>,
+        permissions: Permissions,
         UrlPath(course_id): UrlPath<i64>,
         State(state): State<crate::AppState>,
         Json(group_ids): Json<Vec<i64>>,
     ) -> StatusCode {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Course,
-            &course_id,
-            Operations::UPDATE,
-            s_user.user_id,
-            &state.db,
+        if let Err(code) = check_capability(
+            &state,
+            &permissions,
+            course_id,
+            Capability::ManageEnrollment,
         )
         .await
         {
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
-            Ok(false) => return StatusCode::UNAUTHORIZED,
-            Ok(true) => {}
+            return code;
         }
 
         match sqlx::query("INSERT INTO course_group(course_id, group_id) SELECT * FROM UNNEST(array_fill($1, ARRAY[array_length($2::int8[], 1)]), $2::int8[])")
@@ -485,24 +575,20 @@ WHERE cg2.course_id = $2 AND uig.user_id = $1))",
     }
 
     pub async fn set_groups(
-        auth_session: AuthSession<auth::Backend>,
+        permissions: Permissions,
         UrlPath(course_id): UrlPath<i64>,
         State(state): State<crate::AppState>,
         Json(group_ids): Json<Vec<i64>>,
     ) -> StatusCode {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Course,
-            &course_id,
-            Operations::UPDATE,
-            s_user.user_id,
-            &state.db,
+        if let Err(code) = check_capability(
+            &state,
+            &permissions,
+            course_id,
+            Capability::ManageEnrollment,
         )
         .await
         {
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
-            Ok(false) => return StatusCode::UNAUTHORIZED,
-            Ok(true) => {}
+            return code;
         }
 
         let Ok(mut tx) = state.db.begin().await else {
@@ -518,7 +604,7 @@ WHERE cg2.course_id = $2 AND uig.user_id = $1))",
             Ok(_) => {}
         };
 
-        match sqlx::query("INSERT INTO course_group(course_id, group_id) SELECT * FROM UNNEST(array_fill($1, ARRAY[array_length($2::int8[], 1)]), $2::int8[])")
+        match sqlx::query("INSERT INTO course_group(course_id, group_id) SELECT $1, u FROM UNNEST($2::int8[]) u")
             .bind(course_id)
             .bind(group_ids)
             .execute(&mut *tx).await {
@@ -531,33 +617,21 @@ WHERE cg2.course_id = $2 AND uig.user_id = $1))",
     }
 
     pub async fn get_users(
-        auth_session: AuthSession<auth::Backend>,
+        permissions: Permissions,
         UrlPath(id): UrlPath<i64>,
         State(state): State<crate::AppState>,
     ) -> Response {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Course,
-            &id,
-            Operations::READ,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
+        if let Err(code) = check_capability(&state, &permissions, id, Capability::ViewMembers).await
         {
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
-            Ok(true) => {}
+            return code.into_response();
         }
 
-        match sqlx::query_as::<_, Profile>(
-            "SELECT u.id, u.firstname, u.lastname, u.title, u.email FROM \"user\" u \
-LEFT JOIN course_user cu ON cu.user_id = u.id \
-WHERE cu.course_id = $1 \
-OR u.id IN (SELECT user_id FROM user_in_group uig JOIN course_group cg ON cg.group_id = uig.group_id WHERE cg.course_id = $1)",
+        match sqlx::query_as::<_, CourseMember>(
+            "SELECT u.id, u.firstname, u.lastname, u.title, u.email, cm.role FROM \"user\" u \
+JOIN course_member cm ON cm.user_id = u.id \
+WHERE cm.course_id = $1 ORDER BY cm.role, u.id",
         )
         .bind(id)
-        .bind(Operations::READ)
         .fetch_all(&state.db)
         .await
         {
@@ -567,35 +641,36 @@ OR u.id IN (SELECT user_id FROM user_in_group uig JOIN course_group cg ON cg.gro
         }
     }
 
+    /// Enrolls the users as students for `validity`, those already enrolled stay as they are.
     pub async fn add_users(
-        auth_session: AuthSession<auth::Backend>,
+        permissions: Permissions,
         UrlPath(course_id): UrlPath<i64>,
+        Query(validity): Query<Validity>,
         State(state): State<crate::AppState>,
         Json(user_ids): Json<Vec<i64>>,
     ) -> StatusCode {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Course,
-            &course_id,
-            Operations::UPDATE,
-            s_user.user_id,
-            &state.db,
+        if let Err(code) = check_capability(
+            &state,
+            &permissions,
+            course_id,
+            Capability::ManageEnrollment,
         )
         .await
         {
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
-            Ok(false) => return StatusCode::UNAUTHORIZED,
-            Ok(true) => {}
+            return code;
+        }
+        if !validity.is_valid() {
+            return StatusCode::BAD_REQUEST;
         }
 
         match sqlx::query(
-            "INSERT INTO course_user(user_id, course_id) \
-SELECT * FROM UNNEST($1::int8[], \
-array_fill($2, ARRAY[array_length($1::int8[], 1)]))",
+            "INSERT INTO course_user(user_id, course_id, valid_from, valid_until) \
+SELECT u, $2, $3, $4 FROM UNNEST($1::int8[]) u ON CONFLICT DO NOTHING",
         )
         .bind(user_ids)
         .bind(course_id)
-        .bind(Operations::READ)
+        .bind(validity.valid_from)
+        .bind(validity.valid_until)
         .execute(&state.db)
         .await
         {
@@ -604,37 +679,33 @@ array_fill($2, ARRAY[array_length($1::int8[], 1)]))",
         }
     }
 
+    /// Replaces the enrolled users, those who stay enrolled keep their role.
     pub async fn set_users(
-        auth_session: AuthSession<auth::Backend>,
+        permissions: Permissions,
         UrlPath(course_id): UrlPath<i64>,
         State(state): State<crate::AppState>,
         Json(user_ids): Json<Vec<i64>>,
     ) -> StatusCode {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Course,
-            &course_id,
-            Operations::UPDATE,
-            s_user.user_id,
-            &state.db,
+        if let Err(code) = check_capability(
+            &state,
+            &permissions,
+            course_id,
+            Capability::ManageEnrollment,
         )
         .await
         {
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
-            Ok(false) => return StatusCode::UNAUTHORIZED,
-            Ok(true) => {}
+            return code;
         }
 
         let Ok(mut tx) = state.db.begin().await else {
             return StatusCode::INTERNAL_SERVER_ERROR;
         };
 
-        match sqlx::query(
-            "DELETE FROM course_user WHERE course_id = $1",
-        )
-        .bind(course_id)
-        .execute(&mut *tx)
-        .await
+        match sqlx::query("DELETE FROM course_user WHERE course_id = $1 AND user_id <> ALL($2)")
+            .bind(course_id)
+            .bind(&user_ids)
+            .execute(&mut *tx)
+            .await
         {
             Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
             Ok(_) => {}
@@ -642,11 +713,10 @@ array_fill($2, ARRAY[array_length($1::int8[], 1)]))",
 
         match sqlx::query(
             "INSERT INTO course_user(user_id, course_id) \
-SELECT * FROM UNNEST($1::int8[], \
-array_fill($2::int::bit(16), ARRAY[array_length($1::int8[], 1)]))",
+SELECT u, $2 FROM UNNEST($1::int8[]) u ON CONFLICT DO NOTHING",
         )
+        .bind(&user_ids)
         .bind(course_id)
-        .bind(user_ids)
         .execute(&mut *tx)
         .await
         {
@@ -659,35 +729,71 @@ array_fill($2::int::bit(16), ARRAY[array_length($1::int8[], 1)]))",
     }
 
     pub async fn delete_user(
-        auth_session: AuthSession<auth::Backend>,
+        permissions: Permissions,
         UrlPath((course_id, user_id)): UrlPath<(i64, i64)>,
         State(state): State<crate::AppState>,
     ) -> StatusCode {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Course,
-            &course_id,
-            Operations::UPDATE,
-            s_user.user_id,
-            &state.db,
+        if let Err(code) = check_capability(
+            &state,
+            &permissions,
+            course_id,
+            Capability::ManageEnrollment,
         )
         .await
         {
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
-            Ok(false) => return StatusCode::UNAUTHORIZED,
-            Ok(true) => {}
+            return code;
+        }
+
+        match sqlx::query("DELETE FROM course_user WHERE course_id = $1 AND user_id = $2")
+            .bind(course_id)
+            .bind(user_id)
+            .execute(&state.db)
+            .await
+        {
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Ok(_) => StatusCode::OK,
+        }
+    }
+
+    //PUT /course/{courseId}/users/{userId}
+    /// Enrolls the user with the given role or changes the role they are enrolled with, and when
+    /// the enrollment applies. Lecturers are managed through `/course/{courseId}/lecturers`.
+    pub async fn set_user_role(
+        permissions: Permissions,
+        UrlPath((course_id, user_id)): UrlPath<(i64, i64)>,
+        State(state): State<crate::AppState>,
+        Json(req): Json<CourseRoleRequest>,
+    ) -> StatusCode {
+        if let Err(code) = check_capability(
+            &state,
+            &permissions,
+            course_id,
+            Capability::ManageEnrollment,
+        )
+        .await
+        {
+            return code;
+        }
+        if req.role == CourseRole::Lecturer || !req.validity.is_valid() {
+            return StatusCode::BAD_REQUEST;
         }
 
         match sqlx::query(
-            "DELETE FROM course_user WHERE course_id = $1 AND user_id = $2",
+            "INSERT INTO course_user(course_id, user_id, role, valid_from, valid_until) \
+VALUES ($1, $2, $3, $4, $5) ON CONFLICT (course_id, user_id) DO UPDATE SET role = EXCLUDED.role, \
+valid_from = EXCLUDED.valid_from, valid_until = EXCLUDED.valid_until",
         )
         .bind(course_id)
         .bind(user_id)
+        .bind(req.role)
+        .bind(req.validity.valid_from)
+        .bind(req.validity.valid_until)
         .execute(&state.db)
         .await
         {
+            Ok(_) => StatusCode::OK,
+            Err(sqlx::Error::Database(e)) if e.is_foreign_key_violation() => StatusCode::NOT_FOUND,
             Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
-            Ok(_) => StatusCode::OK
         }
     }
 }
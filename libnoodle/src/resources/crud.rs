@@ -0,0 +1,276 @@
+use std::{fmt::Display, str::FromStr};
+
+use async_trait::async_trait;
+use axum::{
+    Json, Router,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use thiserror::Error;
+
+use super::Type;
+use crate::{
+    AppState,
+    auth::{cache::Permissions, permission::Operations},
+};
+
+const TOTAL_COUNT_HEADER: &str = "x-total-count";
+
+/// Why a request on a resource failed, answered with the matching status code.
+#[derive(Debug, Error)]
+pub enum ResourceError {
+    #[error("SQLX Error")]
+    Sqlx(#[from] sqlx::Error),
+    #[error("malformed parameters")]
+    BadParam,
+    #[error("resource does not exist")]
+    NotFound,
+    #[error("access denied")]
+    AccessDenied,
+}
+
+impl IntoResponse for ResourceError {
+    fn into_response(self) -> Response {
+        match self {
+            Self::Sqlx(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::BadParam => StatusCode::BAD_REQUEST,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::AccessDenied => StatusCode::UNAUTHORIZED,
+        }
+        .into_response()
+    }
+}
+
+/// `Err(AccessDenied)` unless a permission check passed.
+pub fn require(allowed: bool) -> Result<(), ResourceError> {
+    if allowed {
+        Ok(())
+    } else {
+        Err(ResourceError::AccessDenied)
+    }
+}
+
+/// Part of a list, given as `?limit=&offset=`. Without a limit the list goes on to its end.
+#[derive(Deserialize, Default, Clone, Copy, Debug)]
+pub struct Page {
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+impl Page {
+    pub fn validate(self) -> Result<Self, ResourceError> {
+        if self.limit.is_some_and(|l| l < 0) || self.offset.is_some_and(|o| o < 0) {
+            return Err(ResourceError::BadParam);
+        }
+        Ok(self)
+    }
+}
+
+/// [`Resource::Filter`] of resources whose lists can't be narrowed down.
+#[derive(Deserialize, Default, Debug)]
+pub struct NoFilter {}
+
+/// Resources a list is restricted to by the grants of the user.
+#[derive(Debug)]
+pub enum Selection<Id> {
+    /// Those with a grant of their own
+    Only(Vec<Id>),
+    /// All of them through a type-wide grant, except those it is denied on
+    Except(Vec<Id>),
+}
+
+impl<Id> Selection<Id> {
+    /// Condition on the id column that holds for the selected resources, with the ids as `$1`.
+    pub fn condition(&self) -> &'static str {
+        match self {
+            Self::Only(_) => "= ANY($1)",
+            Self::Except(_) => "<> ALL($1)",
+        }
+    }
+
+    pub fn into_ids(self) -> Vec<Id> {
+        match self {
+            Self::Only(ids) | Self::Except(ids) => ids,
+        }
+    }
+}
+
+/// A resource with the usual list, get, update and delete endpoints, and with [`Create`] a create
+/// endpoint, see [`router`]. Implementations supply the queries and the validation, the handlers
+/// check the permissions the same way for all of them: reading and listing needs `READ`, creating
+/// a type-wide `CREATE`, updating and deleting `UPDATE` and `DELETE` on the resource.
+#[async_trait]
+pub trait Resource: Serialize + Send + Sized + 'static {
+    const TYPE: Type;
+    type Id: DeserializeOwned + FromStr + Display + Send + Sync + 'static;
+    /// Body of create and update requests
+    type Description: DeserializeOwned + Send + 'static;
+    /// Query parameters that narrow a list down next to its [`Page`], [`NoFilter`] if there are
+    /// none
+    type Filter: DeserializeOwned + Send + Sync + 'static;
+
+    /// Rejects a description that may not be written with [`ResourceError::BadParam`].
+    fn validate(_desc: &Self::Description) -> Result<(), ResourceError> {
+        Ok(())
+    }
+
+    /// Rejects a page that may not be listed with [`ResourceError::BadParam`], and fills in what
+    /// it leaves open.
+    fn validate_page(page: Page) -> Result<Page, ResourceError> {
+        page.validate()
+    }
+
+    /// The resources in `selection` that match `filter`, ordered by id.
+    async fn list(
+        state: &AppState,
+        selection: Selection<Self::Id>,
+        page: Page,
+        filter: &Self::Filter,
+    ) -> Result<Vec<Self>, sqlx::Error>;
+
+    async fn fetch(state: &AppState, id: &Self::Id) -> Result<Option<Self>, sqlx::Error>;
+
+    /// `None` if the resource does not exist.
+    async fn update(
+        state: &AppState,
+        id: &Self::Id,
+        desc: Self::Description,
+    ) -> Result<Option<Self>, sqlx::Error>;
+
+    /// `false` if the resource does not exist.
+    async fn delete(state: &AppState, id: &Self::Id) -> Result<bool, sqlx::Error>;
+
+    /// The resources the user may read, by default those [`list_permitted`] for `READ`.
+    async fn readable(
+        permissions: &Permissions,
+        state: &AppState,
+        page: Page,
+        filter: &Self::Filter,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        list_permitted::<Self>(permissions, state, Operations::READ, page, filter).await
+    }
+
+    /// How many resources the user may read with `filter` across all pages, sent along with a
+    /// list as `X-Total-Count`. By default `None`, which leaves the header out.
+    async fn count_readable(
+        _permissions: &Permissions,
+        _state: &AppState,
+        _filter: &Self::Filter,
+    ) -> Result<Option<i64>, sqlx::Error> {
+        Ok(None)
+    }
+
+    /// Whether the user may read `id`, by default through a grant.
+    async fn can_read(
+        permissions: &Permissions,
+        _state: &AppState,
+        id: &Self::Id,
+    ) -> Result<bool, sqlx::Error> {
+        permissions.has_id(Self::TYPE, id, Operations::READ).await
+    }
+}
+
+/// A [`Resource`] that is created from its description alone. Others, like files whose contents
+/// are uploaded, bring their own create endpoint.
+#[async_trait]
+pub trait Create: Resource {
+    async fn insert(state: &AppState, desc: Self::Description) -> Result<Self, sqlx::Error>;
+}
+
+/// Resources of `R` the user holds one of `operations` on, through a type-wide grant apart from
+/// the resources it is denied on, or through grants on single ones.
+pub async fn list_permitted<R: Resource>(
+    permissions: &Permissions,
+    state: &AppState,
+    operations: Operations,
+    page: Page,
+    filter: &R::Filter,
+) -> Result<Vec<R>, sqlx::Error> {
+    let selection = if permissions.has_all(R::TYPE, operations).await? {
+        Selection::Except(permissions.denied_ids(R::TYPE, operations).await?)
+    } else {
+        Selection::Only(permissions.permitted_ids(R::TYPE, operations).await?)
+    };
+    R::list(state, selection, page, filter).await
+}
+
+// the handlers are public for resources with endpoints of their own next to these, which mount
+// them one by one instead of through `router`
+
+pub async fn list<R: Resource>(
+    permissions: Permissions,
+    Query(page): Query<Page>,
+    Query(filter): Query<R::Filter>,
+    State(state): State<AppState>,
+) -> Result<Response, ResourceError> {
+    let page = R::validate_page(page)?;
+    let resources = Json(R::readable(&permissions, &state, page, &filter).await?);
+    Ok(
+        match R::count_readable(&permissions, &state, &filter).await? {
+            Some(total) => ([(TOTAL_COUNT_HEADER, total.to_string())], resources).into_response(),
+            None => resources.into_response(),
+        },
+    )
+}
+
+pub async fn get_one<R: Resource>(
+    permissions: Permissions,
+    Path(id): Path<R::Id>,
+    State(state): State<AppState>,
+) -> Result<Json<R>, ResourceError> {
+    require(R::can_read(&permissions, &state, &id).await?)?;
+    R::fetch(&state, &id)
+        .await?
+        .map(Json)
+        .ok_or(ResourceError::NotFound)
+}
+
+pub async fn create<R: Create>(
+    permissions: Permissions,
+    State(state): State<AppState>,
+    Json(desc): Json<R::Description>,
+) -> Result<Json<R>, ResourceError> {
+    require(permissions.can_create(R::TYPE).await?)?;
+    R::validate(&desc)?;
+    Ok(Json(R::insert(&state, desc).await?))
+}
+
+pub async fn update<R: Resource>(
+    permissions: Permissions,
+    Path(id): Path<R::Id>,
+    State(state): State<AppState>,
+    Json(desc): Json<R::Description>,
+) -> Result<Json<R>, ResourceError> {
+    require(permissions.has_id(R::TYPE, &id, Operations::UPDATE).await?)?;
+    R::validate(&desc)?;
+    R::update(&state, &id, desc)
+        .await?
+        .map(Json)
+        .ok_or(ResourceError::NotFound)
+}
+
+pub async fn delete<R: Resource>(
+    permissions: Permissions,
+    Path(id): Path<R::Id>,
+    State(state): State<AppState>,
+) -> Result<StatusCode, ResourceError> {
+    // also covers type-wide grants, unless one on the resource denies them
+    require(permissions.has_id(R::TYPE, &id, Operations::DELETE).await?)?;
+    if R::delete(&state, &id).await? {
+        Ok(StatusCode::OK)
+    } else {
+        Err(ResourceError::NotFound)
+    }
+}
+
+/// Routes listing (`GET`) and creating (`POST`) resources of `R` at `collection`, and getting
+/// (`GET`), updating (`PUT`) and deleting (`DELETE`) one at `item`, whose only parameter is the
+/// id of the resource.
+pub fn router<R: Create>(collection: &str, item: &str) -> Router<AppState> {
+    Router::new()
+        .route(collection, get(list::<R>).post(create::<R>))
+        .route(item, get(get_one::<R>).put(update::<R>).delete(delete::<R>))
+}
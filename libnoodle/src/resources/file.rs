@@ -3,9 +3,30 @@ use std::{
     str::FromStr,
 };
 
+use async_trait::async_trait;
+use const_format::concatcp;
 use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
 use uuid::Uuid;
 
+use crate::{
+    AppState,
+    auth::{
+        cache::Permissions,
+        permission::{EFFECTIVE_OPERATIONS, Operations},
+    },
+    media::{
+        self, MediaStore,
+        policy::Context,
+        variant::{RenderError, Variant},
+    },
+    resources::{
+        self,
+        course::MEMBER_SEES_SECTION,
+        crud::{Page, Resource, ResourceError, Selection},
+    },
+};
+
 pub struct Path(pub std::path::PathBuf);
 use base64::{Engine, engine::general_purpose::STANDARD_NO_PAD};
 
@@ -62,20 +83,334 @@ pub struct FileRow {
     #[sqlx(rename = "type")]
     mime_type: String,
     location: String,
+    size: i64,
+    checksum: Option<Vec<u8>>,
+    owner_id: Option<i64>,
     created_at: chrono::DateTime<chrono::Utc>,
     updated_at: chrono::DateTime<chrono::Utc>,
 }
 
-impl FileRow {
-    pub async fn get_contents_b64(&self, media_path: &str) -> String {
-        let path = Path::from_file_row(media_path, self);
+#[derive(Debug, thiserror::Error)]
+pub enum BlobRefError {
+    #[error("media store operation failed")]
+    Store(#[from] media::Error),
+    #[error("database query failed")]
+    Sqlx(#[from] sqlx::Error),
+}
 
-        let file_contents = tokio::fs::read(&path).await.unwrap();
+/// Serialises reference changes of one blob, so it can't be deleted while a new row starts to use
+/// it.
+async fn lock_blob(conn: &mut sqlx::PgConnection, key: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1))")
+        .bind(key)
+        .execute(conn)
+        .await
+        .map(|_| ())
+}
 
-        tokio::task::spawn_blocking(|| STANDARD_NO_PAD.encode(file_contents))
+async fn read_b64(store: &dyn MediaStore, key: &str) -> Result<String, media::Error> {
+    let file_contents = media::read_to_vec(store, key).await?;
+
+    Ok(
+        tokio::task::spawn_blocking(move || STANDARD_NO_PAD.encode(file_contents))
             .await
-            .unwrap()
+            .unwrap(),
+    )
+}
+
+/// Removes the row `uid` if the blob it references under `location` does not exist.
+/// Returns whether the row was removed.
+pub async fn remove_if_missing(
+    db: &PgPool,
+    store: &dyn MediaStore,
+    uid: Uuid,
+    location: &str,
+) -> Result<bool, BlobRefError> {
+    let mut tx = db.begin().await?;
+    lock_blob(&mut tx, location).await?;
+    if store.exists(location).await? {
+        return Ok(false);
+    }
+    let removed = sqlx::query("DELETE FROM \"file\" WHERE uid = $1 AND location = $2")
+        .bind(uid)
+        .bind(location)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+        > 0;
+    tx.commit().await?;
+    Ok(removed)
+}
+
+/// Deletes the blob stored under `key` once no `file` row references it anymore.
+pub async fn release_blob(
+    db: &PgPool,
+    store: &dyn MediaStore,
+    key: &str,
+) -> Result<(), BlobRefError> {
+    let mut tx = db.begin().await?;
+    lock_blob(&mut tx, key).await?;
+    let referenced =
+        sqlx::query_scalar::<_, i32>("SELECT 1 FROM \"file\" WHERE location = $1 LIMIT 1")
+            .bind(key)
+            .fetch_optional(&mut *tx)
+            .await?
+            .is_some();
+    if !referenced {
+        store.delete(key).await?;
+        for variant in Variant::ALL {
+            store.delete(&variant.key(key)).await?;
+        }
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Holds for files `f` embedded in a course `$1` may read, through a course permission covering the
+/// `$2` operations or as a member of the course who may see the section embedding them. Without
+/// any `$2` operations, as for a token scoped to other ones, neither applies.
+const COURSE_READABLE: &str = concatcp!(
+    "($2::int::bit(16) & (SELECT ",
+    EFFECTIVE_OPERATIONS,
+    " FROM course_permissions p \
+LEFT JOIN current_user_has_role ur ON ur.role_id = p.role_id \
+WHERE (p.user_id = $1 OR ur.user_id = $1) \
+AND (p.resource_id = cs.course_id OR p.resource_id IS NULL))) <> B'0'::bit(16) \
+OR $2::int <> 0 AND EXISTS(SELECT 1 FROM course_member cm WHERE cm.course_id = cs.course_id \
+AND cm.user_id = $1 AND ",
+    MEMBER_SEES_SECTION,
+    ")"
+);
+const IN_READABLE_COURSE: &str = concatcp!(
+    "EXISTS(SELECT 1 FROM file_in_content_element fc \
+JOIN content_element ce ON ce.uid = fc.content_id \
+JOIN content_section cs ON cs.uid = ce.section_id \
+WHERE fc.file_id = f.uid AND (",
+    COURSE_READABLE,
+    "))"
+);
+
+/// Whether the user may read the file `uid`, through a file permission or through access to a
+/// course that embeds it.
+pub async fn user_can_read(
+    permissions: &Permissions,
+    db: &PgPool,
+    uid: Uuid,
+) -> Result<bool, sqlx::Error> {
+    if permissions
+        .has_id(resources::Type::File, uid, Operations::READ)
+        .await?
+    {
+        return Ok(true);
+    }
+    sqlx::query_scalar::<_, i32>(concatcp!(
+        "SELECT 1 FROM \"file\" f WHERE f.uid = $3 AND ",
+        IN_READABLE_COURSE
+    ))
+    .bind(permissions.user_id())
+    .bind(Operations::READ & permissions.allowed_operations(resources::Type::File))
+    .bind(uid)
+    .fetch_optional(db)
+    .await
+    .map(|r| r.is_some())
+}
+
+/// Courses and content elements that embed the file `uid` and whose course `user_id` may read,
+/// as `(course_id, content_id)` pairs.
+pub async fn readable_embeddings(
+    db: &PgPool,
+    uid: Uuid,
+    user_id: i64,
+) -> Result<Vec<(i64, i64)>, sqlx::Error> {
+    sqlx::query_as::<_, (i64, i64)>(concatcp!(
+        "SELECT cs.course_id, ce.uid FROM file_in_content_element fc \
+JOIN content_element ce ON ce.uid = fc.content_id \
+JOIN content_section cs ON cs.uid = ce.section_id \
+WHERE fc.file_id = $3 AND cs.course_id IS NOT NULL AND (",
+        COURSE_READABLE,
+        ") ORDER BY cs.course_id, ce.uid"
+    ))
+    .bind(user_id)
+    .bind(Operations::READ)
+    .bind(uid)
+    .fetch_all(db)
+    .await
+}
+
+impl FileRow {
+    pub async fn get_contents_b64(&self, store: &dyn MediaStore) -> Result<String, media::Error> {
+        read_b64(store, &self.location).await
+    }
+
+    /// Contents of `variant` of the file and their mime type.
+    /// `None` if there are no variants for the type of the file.
+    pub async fn get_variant_b64(
+        &self,
+        store: &dyn MediaStore,
+        variant: Variant,
+    ) -> Result<Option<(String, &'static str)>, RenderError> {
+        let Some((key, mime_type)) =
+            media::variant::ensure(store, &self.location, &self.mime_type, variant).await?
+        else {
+            return Ok(None);
+        };
+        Ok(Some((read_b64(store, &key).await?, mime_type)))
+    }
+
+    /// Entity tag for the stored contents. Changes whenever the file is replaced.
+    pub fn etag(&self) -> String {
+        format!(
+            "\"{}-{:x}\"",
+            self.uid.simple(),
+            self.updated_at.timestamp_micros()
+        )
+    }
+
+    /// Entity tag for `variant` of the stored contents.
+    pub fn variant_etag(&self, variant: Variant) -> String {
+        format!(
+            "\"{}-{:x}-{}\"",
+            self.uid.simple(),
+            self.updated_at.timestamp_micros(),
+            variant.name()
+        )
+    }
+
+    /// Courses with a content element that contains the file `uid`.
+    pub async fn linked_courses(db: &PgPool, uid: Uuid) -> Result<Vec<i64>, sqlx::Error> {
+        sqlx::query_scalar::<_, i64>(
+            "SELECT DISTINCT cs.course_id FROM file_in_content_element fc \
+JOIN content_element ce ON ce.uid = fc.content_id \
+JOIN content_section cs ON cs.uid = ce.section_id \
+WHERE fc.file_id = $1 AND cs.course_id IS NOT NULL",
+        )
+        .bind(uid)
+        .fetch_all(db)
+        .await
+    }
+
+    /// Inserts a new `file` row referencing `blob`.
+    pub async fn insert(
+        db: &PgPool,
+        store: &dyn MediaStore,
+        uid: Uuid,
+        filename: &str,
+        mime_type: &str,
+        owner_id: Option<i64>,
+        blob: &media::Blob,
+    ) -> Result<Self, BlobRefError> {
+        let mut tx = db.begin().await?;
+        lock_blob(&mut tx, &blob.key).await?;
+        // a concurrent release may have removed an identical blob after it was stored
+        if !store.exists(&blob.key).await? {
+            return Err(BlobRefError::Store(media::Error::NotFound));
+        }
+        let row = sqlx::query_as::<_, FileRow>(
+            "INSERT INTO \"file\" (uid, filename, \"type\", location, size, checksum, owner_id) \
+VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING *",
+        )
+        .bind(uid)
+        .bind(filename)
+        .bind(mime_type)
+        .bind(&blob.key)
+        .bind(blob.size as i64)
+        .bind(&blob.checksum[..])
+        .bind(owner_id)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(row)
     }
+
+    /// Points the row at `blob` and releases the blob it referenced before.
+    /// Returns the updated row, or `None` if it does not exist.
+    pub async fn replace_blob(
+        db: &PgPool,
+        store: &dyn MediaStore,
+        uid: Uuid,
+        filename: &str,
+        mime_type: &str,
+        blob: &media::Blob,
+    ) -> Result<Option<Self>, BlobRefError> {
+        let mut tx = db.begin().await?;
+        lock_blob(&mut tx, &blob.key).await?;
+        if !store.exists(&blob.key).await? {
+            return Err(BlobRefError::Store(media::Error::NotFound));
+        }
+        let Some(old_location) = sqlx::query_scalar::<_, String>(
+            "SELECT location FROM \"file\" WHERE uid = $1 FOR UPDATE",
+        )
+        .bind(uid)
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+        let row = sqlx::query_as::<_, FileRow>(
+            "UPDATE \"file\" SET filename = $1, \"type\" = $2, location = $3, size = $4, checksum = $5, \
+updated_at = CURRENT_TIMESTAMP WHERE uid = $6 RETURNING *",
+        )
+        .bind(filename)
+        .bind(mime_type)
+        .bind(&blob.key)
+        .bind(blob.size as i64)
+        .bind(&blob.checksum[..])
+        .bind(uid)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        if old_location != blob.key {
+            release_blob(db, store, &old_location).await?;
+        }
+        Ok(Some(row))
+    }
+}
+
+/// Storage taken up by the files of a user or a course.
+#[derive(Serialize, sqlx::FromRow, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageUsage {
+    /// Total size of the files in bytes
+    pub used: i64,
+    pub file_count: i64,
+}
+
+impl StorageUsage {
+    /// Files uploaded by `owner_id`.
+    pub async fn of_owner(db: &PgPool, owner_id: i64) -> Result<Self, sqlx::Error> {
+        sqlx::query_as::<_, Self>(
+            "SELECT COALESCE(SUM(size), 0)::BIGINT AS used, COUNT(*) AS file_count \
+FROM \"file\" WHERE owner_id = $1",
+        )
+        .bind(owner_id)
+        .fetch_one(db)
+        .await
+    }
+
+    /// Files in the content elements of `course_id`, each counted once.
+    pub async fn of_course(db: &PgPool, course_id: i64) -> Result<Self, sqlx::Error> {
+        sqlx::query_as::<_, Self>(
+            "SELECT COALESCE(SUM(f.size), 0)::BIGINT AS used, COUNT(*) AS file_count \
+FROM \"file\" f WHERE f.uid IN (SELECT fc.file_id FROM file_in_content_element fc \
+JOIN content_element ce ON ce.uid = fc.content_id \
+JOIN content_section cs ON cs.uid = ce.section_id \
+WHERE cs.course_id = $1)",
+        )
+        .bind(course_id)
+        .fetch_one(db)
+        .await
+    }
+}
+
+/// Usage report of `/users/{id}/storage` and `/course/{id}/storage`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageReport {
+    #[serde(flatten)]
+    pub usage: StorageUsage,
+    /// Configured quota in bytes, `null` if unlimited
+    pub quota: Option<u64>,
 }
 
 #[derive(Deserialize)]
@@ -86,164 +421,853 @@ pub struct FileDescription {
     data: String,
 }
 
+/// File info without the contents, returned by the listing and the streaming endpoints and as the
+/// attachments of content elements.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FileMetadata {
+    #[serde(with = "uuid::serde::simple")]
+    uid: Uuid,
+    filename: String,
+    #[serde(rename = "type")]
+    mime_type: String,
+    size: i64,
+    /// Hex encoded SHA-256 of the contents
+    checksum: Option<String>,
+    owner_id: Option<i64>,
+    created_at: chrono::DateTime<chrono::Utc>,
+    last_modified: chrono::DateTime<chrono::Utc>,
+}
+
+impl From<FileRow> for FileMetadata {
+    fn from(row: FileRow) -> Self {
+        Self {
+            uid: row.uid,
+            filename: row.filename,
+            mime_type: row.mime_type,
+            size: row.size,
+            checksum: row.checksum.map(hex::encode),
+            owner_id: row.owner_id,
+            created_at: row.created_at,
+            last_modified: row.updated_at,
+        }
+    }
+}
+
+/// Query parameters of the endpoints that store file contents.
+#[derive(Deserialize)]
+pub struct UploadParams {
+    #[serde(default)]
+    pub context: Context,
+    /// Course the file is uploaded for, its quota applies in addition to the uploader's
+    pub course: Option<i64>,
+}
+
+pub const DEFAULT_PAGE_SIZE: i64 = 50;
+pub const MAX_PAGE_SIZE: i64 = 500;
+
+/// Query parameters of `GET /files` next to the page.
+#[derive(Deserialize)]
+pub struct FileFilter {
+    /// Exact mime type, or a whole top level type like `image/*`
+    #[serde(rename = "type")]
+    pub mime_type: Option<String>,
+    /// Case insensitive part of the filename
+    pub search: Option<String>,
+}
+
+/// Body of `PUT /file/{uid}/metadata`, the contents are replaced through `/file/{uid}/content`.
+#[derive(Deserialize)]
+pub struct MetadataChange {
+    filename: String,
+}
+
+fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+impl FileFilter {
+    fn mime_type_pattern(&self) -> Option<String> {
+        self.mime_type
+            .as_deref()
+            .map(|t| match t.strip_suffix("/*") {
+                Some(top_level) => format!("{}/%", escape_like(top_level)),
+                None => escape_like(t),
+            })
+    }
+
+    fn filename_pattern(&self) -> Option<String> {
+        self.search
+            .as_deref()
+            .map(|search| format!("%{}%", escape_like(search)))
+    }
+}
+
+/// Files `$1` may read, matching the `$3` mime type and `$4` filename patterns
+const LIST_FILTER: &str = concatcp!(
+    "WHERE (($2::int::bit(16) & (SELECT ",
+    EFFECTIVE_OPERATIONS,
+    " FROM file_permissions p \
+LEFT JOIN current_user_has_role ur ON ur.role_id = p.role_id \
+WHERE (p.user_id = $1 OR ur.user_id = $1) \
+AND (p.resource_id = f.uid OR p.resource_id IS NULL))) <> B'0'::bit(16) OR ",
+    IN_READABLE_COURSE,
+    ") AND ($3::text IS NULL OR f.\"type\" LIKE $3) \
+AND ($4::text IS NULL OR f.filename ILIKE $4)"
+);
+
+/// The metadata endpoints of files. Files are created by uploading their contents, so there is no
+/// [`crud::Create`](super::crud::Create).
+#[async_trait]
+impl Resource for FileMetadata {
+    const TYPE: resources::Type = resources::Type::File;
+    type Id = Uuid;
+    type Description = MetadataChange;
+    type Filter = FileFilter;
+
+    fn validate(desc: &MetadataChange) -> Result<(), ResourceError> {
+        if desc.filename.is_empty() {
+            return Err(ResourceError::BadParam);
+        }
+        Ok(())
+    }
+
+    /// Pages of [`DEFAULT_PAGE_SIZE`] files unless a limit of at most [`MAX_PAGE_SIZE`] is given.
+    fn validate_page(page: Page) -> Result<Page, ResourceError> {
+        let limit = page.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+        let offset = page.offset.unwrap_or(0);
+        if !(1..=MAX_PAGE_SIZE).contains(&limit) || offset < 0 {
+            return Err(ResourceError::BadParam);
+        }
+        Ok(Page {
+            limit: Some(limit),
+            offset: Some(offset),
+        })
+    }
+
+    /// Newest first, like [`readable`](Self::readable).
+    async fn list(
+        state: &AppState,
+        selection: Selection<Uuid>,
+        page: Page,
+        filter: &FileFilter,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, FileRow>(&format!(
+            "SELECT f.* FROM \"file\" f WHERE f.uid {} \
+AND ($2::text IS NULL OR f.\"type\" LIKE $2) AND ($3::text IS NULL OR f.filename ILIKE $3) \
+ORDER BY f.created_at DESC, f.uid LIMIT $4 OFFSET $5",
+            selection.condition()
+        ))
+        .bind(selection.into_ids())
+        .bind(filter.mime_type_pattern())
+        .bind(filter.filename_pattern())
+        .bind(page.limit)
+        .bind(page.offset)
+        .fetch_all(&state.db)
+        .await?;
+        Ok(rows.into_iter().map(Into::into).collect())
+    }
+
+    async fn fetch(state: &AppState, uid: &Uuid) -> Result<Option<Self>, sqlx::Error> {
+        Ok(
+            sqlx::query_as::<_, FileRow>("SELECT * FROM \"file\" WHERE uid = $1")
+                .bind(uid)
+                .fetch_optional(&state.db)
+                .await?
+                .map(Into::into),
+        )
+    }
+
+    async fn update(
+        state: &AppState,
+        uid: &Uuid,
+        desc: MetadataChange,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        Ok(sqlx::query_as::<_, FileRow>(
+            "UPDATE \"file\" SET filename = $1 WHERE uid = $2 RETURNING *",
+        )
+        .bind(desc.filename)
+        .bind(uid)
+        .fetch_optional(&state.db)
+        .await?
+        .map(Into::into))
+    }
+
+    /// Removes the row and, unless other rows share it, the blob with its variants.
+    async fn delete(state: &AppState, uid: &Uuid) -> Result<bool, sqlx::Error> {
+        let Some(location) = sqlx::query_scalar::<_, String>(
+            "DELETE FROM \"file\" WHERE uid = $1 RETURNING location",
+        )
+        .bind(uid)
+        .fetch_optional(&state.db)
+        .await?
+        else {
+            return Ok(false);
+        };
+        // the file is gone either way, a blob left behind is reported as orphaned by check-media
+        if let Err(e) = release_blob(&state.db, &*state.media, &location).await {
+            tracing::warn!("releasing the blob {location} of file {uid} failed: {e}");
+        }
+        Ok(true)
+    }
+
+    /// The files the user may read through a file permission or a course that embeds them,
+    /// newest first.
+    async fn readable(
+        permissions: &Permissions,
+        state: &AppState,
+        page: Page,
+        filter: &FileFilter,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, FileRow>(concatcp!(
+            "SELECT f.* FROM \"file\" f ",
+            LIST_FILTER,
+            " ORDER BY f.created_at DESC, f.uid LIMIT $5 OFFSET $6"
+        ))
+        .bind(permissions.user_id())
+        .bind(Operations::READ & permissions.allowed_operations(resources::Type::File))
+        .bind(filter.mime_type_pattern())
+        .bind(filter.filename_pattern())
+        .bind(page.limit)
+        .bind(page.offset)
+        .fetch_all(&state.db)
+        .await?;
+        Ok(rows.into_iter().map(Into::into).collect())
+    }
+
+    async fn count_readable(
+        permissions: &Permissions,
+        state: &AppState,
+        filter: &FileFilter,
+    ) -> Result<Option<i64>, sqlx::Error> {
+        sqlx::query_scalar::<_, i64>(concatcp!("SELECT COUNT(*) FROM \"file\" f ", LIST_FILTER))
+            .bind(permissions.user_id())
+            .bind(Operations::READ & permissions.allowed_operations(resources::Type::File))
+            .bind(filter.mime_type_pattern())
+            .bind(filter.filename_pattern())
+            .fetch_one(&state.db)
+            .await
+            .map(Some)
+    }
+
+    async fn can_read(
+        permissions: &Permissions,
+        state: &AppState,
+        uid: &Uuid,
+    ) -> Result<bool, sqlx::Error> {
+        user_can_read(permissions, &state.db, *uid).await
+    }
+}
+
+/// Inclusive byte range of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub(crate) fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeRequest {
+    Full,
+    Partial(ByteRange),
+    Unsatisfiable,
+}
+
+/// Interprets a `Range` header for a file of `len` bytes.
+/// Only single `bytes=` ranges are honoured, anything else is answered with the whole file.
+pub fn parse_range(header: Option<&str>, len: u64) -> RangeRequest {
+    let Some(spec) = header.and_then(|h| h.trim().strip_prefix("bytes=")) else {
+        return RangeRequest::Full;
+    };
+    if spec.contains(',') {
+        return RangeRequest::Full;
+    }
+    let Some((start, end)) = spec.split_once('-') else {
+        return RangeRequest::Full;
+    };
+    let (start, end) = (start.trim(), end.trim());
+
+    let range = if start.is_empty() {
+        // suffix range: the last `end` bytes
+        match end.parse::<u64>() {
+            Ok(0) => return RangeRequest::Unsatisfiable,
+            Ok(suffix) if len > 0 => ByteRange {
+                start: len.saturating_sub(suffix),
+                end: len - 1,
+            },
+            Ok(_) => return RangeRequest::Unsatisfiable,
+            Err(_) => return RangeRequest::Full,
+        }
+    } else {
+        let Ok(start) = start.parse::<u64>() else {
+            return RangeRequest::Full;
+        };
+        let end = if end.is_empty() {
+            len.saturating_sub(1)
+        } else {
+            match end.parse::<u64>() {
+                Ok(e) => e.min(len.saturating_sub(1)),
+                Err(_) => return RangeRequest::Full,
+            }
+        };
+        if start >= len || end < start {
+            return RangeRequest::Unsatisfiable;
+        }
+        ByteRange { start, end }
+    };
+    RangeRequest::Partial(range)
+}
+
+/// Checks an `If-None-Match` header value against `etag`.
+pub fn etag_matches(header: &str, etag: &str) -> bool {
+    header.split(',').map(str::trim).any(|candidate| {
+        candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+    })
+}
+
 pub mod http {
     // TODO: proper error handling for failed I/O operations
-    // Files are not streamed. They are directly put in memory and written to the file system at once.
-    // This is definetly not optimal (Someone uploads an 1GB file -> Ram usage goes up by 1GB).
-    // If we want to change that, the API spec needs to be adjusted accordingly.
+    // `create`, `update` and `get_by_uid` are the legacy JSON/base64 endpoints and keep the whole
+    // file in memory. Large files should go through `upload`, `replace_content` and `get_content`,
+    // which stream the contents from and to the media store.
     use std::sync::Arc;
 
     use crate::{
-        auth::{self, permission::Operations},
+        auth::{cache::Permissions, permission::Operations},
+        media::{
+            policy::{self, Limit, Quota, QuotaScope, ScreenError, Screened},
+            variant::VariantQueryParam,
+        },
         resources,
     };
 
     use super::*;
     use axum::{
         Json,
-        extract::{Path as UrlPath, State},
-        http::StatusCode,
+        body::Body,
+        extract::{Multipart, Path as UrlPath, Query, State},
+        http::{HeaderMap, HeaderValue, StatusCode, header},
         response::{IntoResponse, Response},
     };
-    use axum_login::AuthSession;
-    use sha1::{Digest, Sha1};
+    use futures_util::{StreamExt, TryStreamExt};
 
-    const TEMP_SUFFIX: &'static str = "tmp";
+    const UPLOAD_FIELD_NAME: &str = "file";
 
-    fn get_temp_file_name(orig_name: &str) -> String {
-        format!("{}.{}", orig_name, TEMP_SUFFIX)
+    fn http_date(time: &chrono::DateTime<chrono::Utc>) -> String {
+        time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
     }
 
-    fn strip_tmp_suffix(temp_file_name: &str) -> &str {
-        &temp_file_name[..temp_file_name.len() - (TEMP_SUFFIX.len() + 1)]
+    fn store_error_response(e: media::Error, limit: &Limit) -> Response {
+        match e {
+            media::Error::Body => StatusCode::BAD_REQUEST.into_response(),
+            media::Error::TooLarge => limit.rejection().into_response(),
+            _ => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
     }
 
-    pub async fn get_all(
+    /// Checks that `course_id` exists and the user may edit its content.
+    async fn check_course(
+        state: &crate::AppState,
+        permissions: &Permissions,
+        course_id: i64,
+    ) -> Result<(), Response> {
+        match sqlx::query_scalar::<_, i32>("SELECT 1 FROM \"course\" WHERE uid = $1")
+            .bind(course_id)
+            .fetch_optional(&state.db)
+            .await
+        {
+            Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR.into_response()),
+            Ok(None) => return Err(StatusCode::NOT_FOUND.into_response()),
+            Ok(Some(_)) => {}
+        }
+        resources::course::http::check_capability(
+            state,
+            permissions,
+            course_id,
+            resources::course::Capability::EditContent,
+        )
+        .await
+        .map(|_| ())
+        .map_err(IntoResponse::into_response)
+    }
+
+    /// Quotas an upload is subject to: the one of `owner_id`, the one of `course_id` and, for a
+    /// replacement, those of the courses the `replaced` file is linked to.
+    /// The size of the `replaced` file does not count towards the quotas it is already part of.
+    async fn quotas(
+        state: &crate::AppState,
+        owner_id: Option<i64>,
+        course_id: Option<i64>,
+        replaced: Option<&FileRow>,
+    ) -> Result<Vec<Quota>, sqlx::Error> {
+        let replaced_size = replaced.map_or(0, |f| f.size);
+        let mut quotas = Vec::new();
+        if let (Some(quota), Some(owner_id)) = (state.upload_policy.user_quota(), owner_id) {
+            let usage = StorageUsage::of_owner(&state.db, owner_id).await?;
+            quotas.push(Quota {
+                scope: QuotaScope::User,
+                quota,
+                used: (usage.used - replaced_size).max(0) as u64,
+            });
+        }
+        if let Some(quota) = state.upload_policy.course_quota() {
+            let linked = match replaced {
+                Some(file) => FileRow::linked_courses(&state.db, file.uid).await?,
+                None => Vec::new(),
+            };
+            let mut courses = linked.clone();
+            if let Some(course_id) = course_id
+                && !courses.contains(&course_id)
+            {
+                courses.push(course_id);
+            }
+            for course_id in courses {
+                let usage = StorageUsage::of_course(&state.db, course_id).await?;
+                let already_counted = if linked.contains(&course_id) {
+                    replaced_size
+                } else {
+                    0
+                };
+                quotas.push(Quota {
+                    scope: QuotaScope::Course,
+                    quota,
+                    used: (usage.used - already_counted).max(0) as u64,
+                });
+            }
+        }
+        Ok(quotas)
+    }
+
+    /// Checks an upload against the policy of `context` and the tightest of its [`quotas`].
+    async fn screen_upload<'a>(
+        state: &crate::AppState,
+        context: Context,
+        declared_type: Option<&str>,
+        body: media::ByteStream<'a>,
+        owner_id: Option<i64>,
+        course_id: Option<i64>,
+        replaced: Option<&FileRow>,
+    ) -> Result<Screened<'a>, Response> {
+        let quota = match quotas(state, owner_id, course_id, replaced).await {
+            Ok(quotas) => quotas.into_iter().min_by_key(Quota::remaining),
+            Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR.into_response()),
+        };
+        match state
+            .upload_policy
+            .screen(context, declared_type, body, quota)
+            .await
+        {
+            Ok(screened) => Ok(screened),
+            Err(ScreenError::Rejected(rejection)) => Err(rejection.into_response()),
+            Err(ScreenError::Store(media::Error::Body)) => {
+                Err(StatusCode::BAD_REQUEST.into_response())
+            }
+            Err(ScreenError::Store(_)) => Err(StatusCode::INTERNAL_SERVER_ERROR.into_response()),
+        }
+    }
+
+    //POST /files/upload
+    pub async fn upload(
         State(state): State<crate::AppState>,
-        auth_session: AuthSession<crate::auth::Backend>,
+        permissions: Permissions,
+        Query(param): Query<UploadParams>,
+        mut multipart: Multipart,
     ) -> Response {
-        let s_user = auth_session.user.unwrap();
-        let files_result = match auth::user_has_permissions_all(
-            resources::Type::File,
-            Operations::READ,
-            s_user.user_id,
+        match permissions.can_create(resources::Type::File).await {
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
+            Ok(true) => {}
+        }
+        if let Some(course_id) = param.course
+            && let Err(response) = check_course(&state, &permissions, course_id).await
+        {
+            return response;
+        }
+
+        let field = loop {
+            match multipart.next_field().await {
+                Ok(Some(field)) if field.name() == Some(UPLOAD_FIELD_NAME) => break field,
+                Ok(Some(_)) => continue,
+                Ok(None) | Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+            }
+        };
+        let filename = match field.file_name() {
+            Some(name) if !name.is_empty() => name.to_owned(),
+            _ => return StatusCode::BAD_REQUEST.into_response(),
+        };
+        let declared_type = field.content_type().map(str::to_owned);
+
+        let screened = match screen_upload(
+            &state,
+            param.context,
+            declared_type.as_deref(),
+            field.map_err(|_| media::Error::Body).boxed(),
+            Some(permissions.user_id()),
+            param.course,
+            None,
+        )
+        .await
+        {
+            Ok(s) => s,
+            Err(response) => return response,
+        };
+        let blob = match state.media.put(screened.body).await {
+            Ok(b) => b,
+            Err(e) => return store_error_response(e, &screened.limit),
+        };
+
+        let file_id = Uuid::new_v4();
+        match FileRow::insert(
             &state.db,
+            &*state.media,
+            file_id,
+            &filename,
+            &screened.mime_type,
+            Some(permissions.user_id()),
+            &blob,
         )
         .await
         {
-            Ok(true) => match sqlx::query_as::<_, FileRow>("SELECT * FROM \"file\"")
-                .fetch_all(&state.db)
-                .await
-            {
-                Ok(f) => f,
-                Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            },
-            Ok(false) => {
-                match sqlx::query_as::<_, FileRow>(
-                    "SELECT f.uid, f.filename, f.type, f.location, f.created_at, f.updated_at \
-FROM \"file\" f \
-JOIN file_permissions fp ON f.id = fp.resource_id \
-WHERE (fp.user_id = $1 OR EXISTS(\
-SELECT 1 FROM user_has_role ur \
-WHERE ur.user_id = $1 AND ur.role_id = fp.role_id)) \
-AND (fp.permission & $2::bit(16)) <> B'0'::bit(16)",
+            Ok(row) => {
+                state.variants.enqueue(&row.location, &row.mime_type);
+                (StatusCode::CREATED, Json(FileMetadata::from(row))).into_response()
+            }
+            Err(_) => {
+                let _ = release_blob(&state.db, &*state.media, &blob.key).await;
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+
+    //GET /file/{uid}/content
+    pub async fn get_content(
+        UrlPath(uid): UrlPath<Uuid>,
+        State(state): State<crate::AppState>,
+        permissions: Permissions,
+        Query(param): Query<VariantQueryParam>,
+        headers: HeaderMap,
+    ) -> Response {
+        match user_can_read(&permissions, &state.db, uid).await {
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
+            Ok(true) => {}
+        };
+
+        let file = match sqlx::query_as::<_, FileRow>("SELECT * FROM \"file\" WHERE uid = $1")
+            .bind(uid)
+            .fetch_optional(&state.db)
+            .await
+        {
+            Ok(Some(file)) => file,
+            Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+
+        let (location, mime_type, etag) = match param.variant {
+            None => (file.location.clone(), file.mime_type.clone(), file.etag()),
+            Some(variant) => {
+                match media::variant::ensure(
+                    &*state.media,
+                    &file.location,
+                    &file.mime_type,
+                    variant,
                 )
-                .fetch_all(&state.db)
                 .await
                 {
-                    Ok(f) => f,
-                    Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+                    Ok(Some((key, mime_type))) => {
+                        (key, mime_type.to_owned(), file.variant_etag(variant))
+                    }
+                    Err(RenderError::Store(_)) => {
+                        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                    }
+                    // not an image, or one that can't be rendered
+                    Ok(None) | Err(_) => {
+                        return StatusCode::NOT_FOUND.into_response();
+                    }
                 }
             }
+        };
+        if let Some(if_none_match) = headers
+            .get(header::IF_NONE_MATCH)
+            .and_then(|h| h.to_str().ok())
+            && etag_matches(if_none_match, &etag)
+        {
+            return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response();
+        }
+
+        // documents that can run scripts are only handed out as downloads, so that an upload
+        // can't script the origin of the API
+        let disposition = format!(
+            "{}; filename=\"{}\"",
+            if policy::is_scriptable(&mime_type) {
+                "attachment"
+            } else {
+                "inline"
+            },
+            file.filename.replace(['"', '\\'], "_")
+        );
+        // let the client download directly from the backend if it supports that
+        match state
+            .media
+            .presigned_url(&location, &mime_type, &disposition)
+            .await
+        {
+            Ok(Some(url)) => {
+                return (
+                    StatusCode::TEMPORARY_REDIRECT,
+                    [(header::LOCATION, url), (header::ETAG, etag)],
+                )
+                    .into_response();
+            }
+            Ok(None) => {}
             Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+
+        // a row without its blob is an inconsistency, not a missing resource
+        let Ok(Some(len)) = state.media.size(&location).await else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
         };
 
-        let mut files = Vec::with_capacity(files_result.len());
-        for file in files_result {
-            let file_contents_encoded = file.get_contents_b64(&state.media_path).await;
+        let range = parse_range(
+            headers.get(header::RANGE).and_then(|h| h.to_str().ok()),
+            len,
+        );
+        let mut response = match range {
+            RangeRequest::Full => {
+                let Ok(contents) = state.media.get(&location, None).await else {
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                };
+                let mut response = Body::from_stream(contents).into_response();
+                response
+                    .headers_mut()
+                    .insert(header::CONTENT_LENGTH, HeaderValue::from(len));
+                response
+            }
+            RangeRequest::Partial(range) => {
+                let Ok(contents) = state.media.get(&location, Some(range)).await else {
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                };
+                let mut response =
+                    (StatusCode::PARTIAL_CONTENT, Body::from_stream(contents)).into_response();
+                let response_headers = response.headers_mut();
+                response_headers.insert(header::CONTENT_LENGTH, HeaderValue::from(range.len()));
+                response_headers.insert(
+                    header::CONTENT_RANGE,
+                    HeaderValue::from_str(&format!("bytes {}-{}/{}", range.start, range.end, len))
+                        .unwrap(),
+                );
+                response
+            }
+            RangeRequest::Unsatisfiable => {
+                return (
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    [(header::CONTENT_RANGE, format!("bytes */{len}"))],
+                )
+                    .into_response();
+            }
+        };
 
-            files.push(File {
-                uid: file.uid,
-                mime_type: file.mime_type,
-                filename: file.filename,
-                data: file_contents_encoded,
-                last_modified: file.updated_at,
-            })
+        let response_headers = response.headers_mut();
+        response_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        response_headers.insert(
+            header::CONTENT_SECURITY_POLICY,
+            HeaderValue::from_static("sandbox"),
+        );
+        response_headers.insert(
+            header::X_CONTENT_TYPE_OPTIONS,
+            HeaderValue::from_static("nosniff"),
+        );
+        response_headers.insert(header::ETAG, HeaderValue::from_str(&etag).unwrap());
+        response_headers.insert(
+            header::LAST_MODIFIED,
+            HeaderValue::from_str(&http_date(&file.updated_at)).unwrap(),
+        );
+        if let Ok(mime_type) = HeaderValue::from_str(&mime_type) {
+            response_headers.insert(header::CONTENT_TYPE, mime_type);
+        }
+        if let Ok(disposition) = HeaderValue::from_str(&disposition) {
+            response_headers.insert(header::CONTENT_DISPOSITION, disposition);
+        }
+        response
+    }
+
+    //PUT /file/{uid}/content
+    pub async fn replace_content(
+        permissions: Permissions,
+        UrlPath(uid): UrlPath<Uuid>,
+        State(state): State<crate::AppState>,
+        Query(param): Query<UploadParams>,
+        headers: HeaderMap,
+        body: Body,
+    ) -> Response {
+        match permissions
+            .has_id(resources::Type::File, &uid, Operations::UPDATE)
+            .await
+        {
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
+            Ok(true) => {}
+        }
+
+        let old_file = match sqlx::query_as::<_, FileRow>("SELECT * FROM \"file\" WHERE uid = $1")
+            .bind(uid)
+            .fetch_optional(&state.db)
+            .await
+        {
+            Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            Ok(Some(file)) => file,
+        };
+        if let Some(course_id) = param.course
+            && let Err(response) = check_course(&state, &permissions, course_id).await
+        {
+            return response;
+        }
+
+        let declared_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|h| h.to_str().ok());
+
+        let screened = match screen_upload(
+            &state,
+            param.context,
+            declared_type,
+            body.into_data_stream()
+                .map_err(|_| media::Error::Body)
+                .boxed(),
+            old_file.owner_id,
+            param.course,
+            Some(&old_file),
+        )
+        .await
+        {
+            Ok(s) => s,
+            Err(response) => return response,
+        };
+        let blob = match state.media.put(screened.body).await {
+            Ok(b) => b,
+            Err(e) => return store_error_response(e, &screened.limit),
+        };
+
+        match FileRow::replace_blob(
+            &state.db,
+            &*state.media,
+            uid,
+            &old_file.filename,
+            &screened.mime_type,
+            &blob,
+        )
+        .await
+        {
+            Ok(Some(row)) => {
+                state.variants.enqueue(&row.location, &row.mime_type);
+                Json(FileMetadata::from(row)).into_response()
+            }
+            Ok(None) => {
+                let _ = release_blob(&state.db, &*state.media, &blob.key).await;
+                StatusCode::NOT_FOUND.into_response()
+            }
+            Err(_) => {
+                let _ = release_blob(&state.db, &*state.media, &blob.key).await;
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
         }
-        Json(files).into_response()
     }
 
     pub async fn create(
         State(state): State<crate::AppState>,
-        auth_session: AuthSession<crate::auth::Backend>,
+        permissions: Permissions,
+        Query(param): Query<UploadParams>,
         Json(file): Json<FileDescription>,
     ) -> Response {
-        let s_user = auth_session.user.unwrap();
-        match auth::can_create(resources::Type::File, s_user.user_id, &state.db).await {
+        match permissions.can_create(resources::Type::File).await {
             Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
             Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
             Ok(true) => {}
         }
+        if let Some(course_id) = param.course
+            && let Err(response) = check_course(&state, &permissions, course_id).await
+        {
+            return response;
+        }
 
-        if file.filename.len() < 1 {
+        if file.filename.is_empty() {
             return StatusCode::BAD_REQUEST.into_response();
         }
-        let current_time = chrono::Utc::now();
-        let mut hasher = Sha1::new();
-        hasher.update(current_time.to_rfc2822());
-        hasher.update(&file.filename);
-        hasher.update(&s_user.user_id.to_le_bytes());
-        let dir_hash = hasher.finalize();
-
-        let mut path = Path::from_hash(&state.media_path, &dir_hash);
-        tokio::fs::create_dir(&path.0.parent().unwrap())
-            .await
-            .unwrap();
-
-        path.0.set_extension(TEMP_SUFFIX);
 
         let shared_contents = Arc::new(file.data);
         let contents = {
             let data_for_decode = Arc::clone(&shared_contents);
-            tokio::task::spawn_blocking(move || {
-                STANDARD_NO_PAD.decode(data_for_decode.as_bytes()).unwrap()
+            match tokio::task::spawn_blocking(move || {
+                STANDARD_NO_PAD.decode(data_for_decode.as_bytes())
             })
             .await
             .unwrap()
+            {
+                Ok(c) => c,
+                Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+            }
         };
 
-        if let Err(_) = tokio::fs::write(&path, contents).await {
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-        }
+        let screened = match screen_upload(
+            &state,
+            param.context,
+            Some(&file.mime_type),
+            media::bytes_stream(contents),
+            Some(permissions.user_id()),
+            param.course,
+            None,
+        )
+        .await
+        {
+            Ok(s) => s,
+            Err(response) => return response,
+        };
+        let blob = match state.media.put(screened.body).await {
+            Ok(b) => b,
+            Err(e) => return store_error_response(e, &screened.limit),
+        };
 
         let file_id = Uuid::new_v4();
-        match sqlx::query_scalar::<_, chrono::DateTime<chrono::Utc>>(
-            "INSERT INTO \"file\" (uid, filename, \"type\", location) VALUES ($1, $2, $3, $4) RETURNING created_at",
+        match FileRow::insert(
+            &state.db,
+            &*state.media,
+            file_id,
+            &file.filename,
+            &screened.mime_type,
+            Some(permissions.user_id()),
+            &blob,
         )
-        .bind(&file_id)
-        .bind(&file.filename)
-        .bind(&file.mime_type)
-        .bind(&path.0.strip_prefix(&state.media_path).unwrap().with_extension("").to_str().unwrap())
-        .fetch_one(&state.db)
         .await
         {
-            Ok(created_at) => {
+            Ok(row) => {
+                state.variants.enqueue(&row.location, &row.mime_type);
                 let data = match Arc::try_unwrap(shared_contents) {
                     Ok(s) => s,
                     Err(arc) => (*arc).clone(),
                 };
-                let path_stripped = strip_tmp_suffix(path.0.to_str().unwrap());
-                tokio::fs::rename(&path, path_stripped).await.unwrap();
-                (StatusCode::CREATED, Json(File{
-                    uid: file_id,
-                    filename: file.filename,
-                    mime_type: file.mime_type,
-                    data,
-                    last_modified: created_at,
-                })).into_response()
-            },
+                (
+                    StatusCode::CREATED,
+                    Json(File {
+                        uid: file_id,
+                        filename: row.filename,
+                        mime_type: row.mime_type,
+                        data,
+                        last_modified: row.created_at,
+                    }),
+                )
+                    .into_response()
+            }
             Err(_) => {
-                tokio::fs::remove_file(path).await.unwrap();
+                let _ = release_blob(&state.db, &*state.media, &blob.key).await;
                 StatusCode::INTERNAL_SERVER_ERROR.into_response()
             }
         }
@@ -252,34 +1276,38 @@ AND (fp.permission & $2::bit(16)) <> B'0'::bit(16)",
     pub async fn get_by_uid(
         UrlPath(uid): UrlPath<Uuid>,
         State(state): State<crate::AppState>,
-        auth_session: AuthSession<crate::auth::Backend>,
+        permissions: Permissions,
+        Query(param): Query<VariantQueryParam>,
     ) -> Response {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::File,
-            &uid,
-            Operations::READ,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
-        {
+        match user_can_read(&permissions, &state.db, uid).await {
             Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
             Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
             Ok(true) => {}
         };
 
         match sqlx::query_as::<_, FileRow>("SELECT * FROM \"file\" WHERE uid = $1")
-            .bind(&uid)
+            .bind(uid)
             .fetch_optional(&state.db)
             .await
         {
             Ok(Some(file)) => {
-                let encoded_contents = file.get_contents_b64(&state.media_path).await;
+                let (encoded_contents, mime_type) = match param.variant {
+                    None => match file.get_contents_b64(&*state.media).await {
+                        Ok(c) => (c, file.mime_type),
+                        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+                    },
+                    Some(variant) => match file.get_variant_b64(&*state.media, variant).await {
+                        Ok(Some((c, mime_type))) => (c, mime_type.to_owned()),
+                        Err(RenderError::Store(_)) => {
+                            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                        }
+                        Ok(None) | Err(_) => return StatusCode::NOT_FOUND.into_response(),
+                    },
+                };
                 Json(File {
                     uid,
                     filename: file.filename,
-                    mime_type: file.mime_type,
+                    mime_type,
                     data: encoded_contents,
                     last_modified: file.updated_at,
                 })
@@ -291,23 +1319,18 @@ AND (fp.permission & $2::bit(16)) <> B'0'::bit(16)",
     }
 
     pub async fn update(
-        auth_session: AuthSession<crate::auth::Backend>,
+        permissions: Permissions,
         UrlPath(uid): UrlPath<Uuid>,
         State(state): State<crate::AppState>,
+        Query(param): Query<UploadParams>,
         Json(new_file): Json<FileDescription>,
     ) -> Response {
-        let s_user = auth_session.user.unwrap();
-        if new_file.filename.len() < 1 {
+        if new_file.filename.is_empty() {
             return StatusCode::BAD_REQUEST.into_response();
         }
-        match auth::user_has_permissions_id(
-            resources::Type::File,
-            &uid,
-            Operations::UPDATE,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
+        match permissions
+            .has_id(resources::Type::File, &uid, Operations::UPDATE)
+            .await
         {
             Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
             Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
@@ -315,7 +1338,7 @@ AND (fp.permission & $2::bit(16)) <> B'0'::bit(16)",
         }
 
         let old_file = match sqlx::query_as::<_, FileRow>("SELECT * FROM \"file\" WHERE uid = $1")
-            .bind(&uid)
+            .bind(uid)
             .fetch_optional(&state.db)
             .await
         {
@@ -323,96 +1346,78 @@ AND (fp.permission & $2::bit(16)) <> B'0'::bit(16)",
             Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
             Ok(Some(file)) => file,
         };
+        if let Some(course_id) = param.course
+            && let Err(response) = check_course(&state, &permissions, course_id).await
+        {
+            return response;
+        }
 
         let shared_contents = Arc::new(new_file.data);
         let new_contents = {
             let data_for_decode = Arc::clone(&shared_contents);
-            tokio::task::spawn_blocking(move || {
-                STANDARD_NO_PAD.decode(data_for_decode.as_bytes()).unwrap()
+            match tokio::task::spawn_blocking(move || {
+                STANDARD_NO_PAD.decode(data_for_decode.as_bytes())
             })
             .await
             .unwrap()
+            {
+                Ok(c) => c,
+                Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+            }
         };
 
-        let mut path = Path::from_relative_path(&state.media_path, &old_file.location);
-        path.0.set_extension(TEMP_SUFFIX);
-
-        tokio::fs::write(&path, new_contents).await.unwrap();
+        let screened = match screen_upload(
+            &state,
+            param.context,
+            Some(&new_file.mime_type),
+            media::bytes_stream(new_contents),
+            old_file.owner_id,
+            param.course,
+            Some(&old_file),
+        )
+        .await
+        {
+            Ok(s) => s,
+            Err(response) => return response,
+        };
+        let blob = match state.media.put(screened.body).await {
+            Ok(b) => b,
+            Err(e) => return store_error_response(e, &screened.limit),
+        };
 
-        match sqlx::query_scalar::<_, chrono::DateTime<chrono::Utc>>(
-            "UPDATE \"file\" SET filename = $1, \"type\" = $2, updated_at = CURRENT_TIMESTAMP WHERE uid = $3 RETURNING updated_at",
+        match FileRow::replace_blob(
+            &state.db,
+            &*state.media,
+            uid,
+            &new_file.filename,
+            &screened.mime_type,
+            &blob,
         )
-        .bind(&new_file.filename)
-        .bind(&new_file.mime_type)
-        .bind(&uid)
-        .fetch_one(&state.db)
         .await
         {
-            Ok(last_modified) => {
+            Ok(Some(row)) => {
+                state.variants.enqueue(&row.location, &row.mime_type);
                 let data = match Arc::try_unwrap(shared_contents) {
                     Ok(s) => s,
                     Err(arc) => (*arc).clone(),
                 };
-                tokio::fs::rename(&path, strip_tmp_suffix(path.0.to_str().unwrap())).await.unwrap();
                 Json(File {
                     uid,
-                    filename: new_file.filename,
-                    mime_type: new_file.mime_type,
+                    filename: row.filename,
+                    mime_type: row.mime_type,
                     data,
-                    last_modified,
-                }).into_response()
-            },
+                    last_modified: row.updated_at,
+                })
+                .into_response()
+            }
+            Ok(None) => {
+                let _ = release_blob(&state.db, &*state.media, &blob.key).await;
+                StatusCode::NOT_FOUND.into_response()
+            }
             Err(_) => {
-                tokio::fs::remove_file(&path).await.unwrap();
+                let _ = release_blob(&state.db, &*state.media, &blob.key).await;
                 StatusCode::INTERNAL_SERVER_ERROR.into_response()
-            },
-        }
-    }
-
-    pub async fn delete(
-        UrlPath(uid): UrlPath<Uuid>,
-        State(state): State<crate::AppState>,
-        auth_session: AuthSession<crate::auth::Backend>,
-    ) -> StatusCode {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::File,
-            &uid,
-            Operations::DELETE,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
-        {
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
-            Ok(false) => return StatusCode::UNAUTHORIZED,
-            Ok(true) => {}
-        }
-
-        let file_to_delete =
-            match sqlx::query_as::<_, FileRow>("SELECT * FROM \"file\" WHERE uid = $1")
-                .bind(&uid)
-                .fetch_optional(&state.db)
-                .await
-            {
-                Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
-                Ok(None) => return StatusCode::NOT_FOUND,
-                Ok(Some(file)) => file,
-            };
-
-        match sqlx::query("DELETE FROM \"file\" WHERE uid = $1")
-            .bind(&uid)
-            .execute(&state.db)
-            .await
-        {
-            Ok(_) => {
-                let path = Path::from_relative_path(&state.media_path, &file_to_delete.location);
-                match tokio::fs::remove_file(&path).await {
-                    Ok(_) => StatusCode::OK,
-                    Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
-                }
             }
-            Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
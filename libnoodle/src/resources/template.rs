@@ -1,6 +1,13 @@
+use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 
+use super::{
+    Type,
+    crud::{Create, NoFilter, Page, Resource, ResourceError, Selection},
+};
+use crate::AppState;
+
 #[derive(Debug, Serialize, Deserialize, Clone, FromRow)]
 #[serde(rename_all = "camelCase")]
 pub struct Template {
@@ -15,195 +22,76 @@ pub struct TemplateDescription {
     pub name: String,
 }
 
-pub mod http {
-    use crate::{
-        auth::{self, permission::Operations},
-        resources,
-    };
-
-    use super::{Template, TemplateDescription};
-    use axum::{
-        Json,
-        extract::{Path as UrlPath, State},
-        http::StatusCode,
-        response::{IntoResponse, Response},
-    };
-    use axum_login::AuthSession;
+#[async_trait]
+impl Resource for Template {
+    const TYPE: Type = Type::Template;
+    type Id = i64;
+    type Description = TemplateDescription;
+    type Filter = NoFilter;
 
-    pub async fn get_all(
-        auth_session: AuthSession<auth::Backend>,
-        State(state): State<crate::AppState>,
-    ) -> Response {
-        let s_user = auth_session.user.unwrap();
-        let templates = match auth::user_has_permissions_all(
-            resources::Type::Template,
-            Operations::READ,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
-        {
-            Ok(true) => match sqlx::query_as::<_, Template>("SELECT uid, name FROM \"template\"")
-                .fetch_all(&state.db)
-                .await
-            {
-                Ok(templates) => templates,
-                Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            },
-            Ok(false) => match sqlx::query_as::<_, Template>(
-                "SELECT t.uid, t.name \
-FROM template t \
-JOIN template_permissions tp ON t.uid = tp.resource_id \
-WHERE (tp.user_id = $1 OR EXISTS(\
-SELECT 1 FROM user_has_role ur \
-WHERE ur.user_id = $1 AND ur.role_id = tp.role_id)) \
-AND (tp.permission & $2::int::bit(16)) <> B'0'::bit(16)",
-            )
-            .bind(s_user.user_id)
-            .bind(Operations::READ)
-            .fetch_all(&state.db)
-            .await
-            {
-                Ok(templates) => templates,
-                Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            },
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-        };
-        Json(templates).into_response()
+    fn validate(desc: &TemplateDescription) -> Result<(), ResourceError> {
+        if desc.name.trim().is_empty() {
+            return Err(ResourceError::BadParam);
+        }
+        Ok(())
     }
 
-    pub async fn get_by_uid(
-        auth_session: AuthSession<auth::Backend>,
-        UrlPath(id): UrlPath<i64>,
-        State(state): State<crate::AppState>,
-    ) -> Response {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Template,
-            &id,
-            Operations::READ,
-            s_user.user_id,
-            &state.db,
-        )
+    async fn list(
+        state: &AppState,
+        selection: Selection<i64>,
+        page: Page,
+        _filter: &NoFilter,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as::<_, Template>(&format!(
+            "SELECT uid, name FROM \"template\" WHERE uid {} ORDER BY uid LIMIT $2 OFFSET $3",
+            selection.condition()
+        ))
+        .bind(selection.into_ids())
+        .bind(page.limit)
+        .bind(page.offset)
+        .fetch_all(&state.db)
         .await
-        {
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
-            Ok(true) => {}
-        };
+    }
 
-        match sqlx::query_as::<_, Template>("SELECT uid, name FROM \"template\" WHERE uid = $1")
+    async fn fetch(state: &AppState, id: &i64) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as::<_, Template>("SELECT uid, name FROM \"template\" WHERE uid = $1")
             .bind(id)
             .fetch_optional(&state.db)
             .await
-        {
-            Ok(Some(tpl)) => Json(tpl).into_response(),
-            Ok(None) => StatusCode::NOT_FOUND.into_response(),
-            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-        }
     }
 
-    pub async fn create(
-        auth_session: AuthSession<auth::Backend>,
-        State(state): State<crate::AppState>,
-        Json(desc): Json<TemplateDescription>,
-    ) -> Response {
-        let s_user = auth_session.user.unwrap();
-        match auth::can_create(resources::Type::Template, s_user.user_id, &state.db).await {
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
-            Ok(true) => {}
-        }
-
-        if desc.name.trim().is_empty() {
-            return StatusCode::BAD_REQUEST.into_response();
-        }
-        match sqlx::query_scalar::<_, i64>(
-            "INSERT INTO \"template\"(name) VALUES ($1) RETURNING uid",
+    async fn update(
+        state: &AppState,
+        id: &i64,
+        desc: TemplateDescription,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as::<_, Template>(
+            "UPDATE \"template\" SET name = $1 WHERE uid = $2 RETURNING uid, name",
         )
-        .bind(&desc.name)
-        .fetch_one(&state.db)
+        .bind(desc.name)
+        .bind(id)
+        .fetch_optional(&state.db)
         .await
-        {
-            Ok(id) => Json(Template {
-                template_id: id,
-                name: desc.name,
-            })
-            .into_response(),
-            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-        }
     }
 
-    pub async fn update(
-        auth_session: AuthSession<auth::Backend>,
-        UrlPath(id): UrlPath<i64>,
-        State(state): State<crate::AppState>,
-        Json(desc): Json<TemplateDescription>,
-    ) -> Response {
-        let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_id(
-            resources::Type::Template,
-            &id,
-            Operations::UPDATE,
-            s_user.user_id,
-            &state.db,
-        )
-        .await
-        {
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
-            Ok(true) => {}
-        }
-
-        if desc.name.trim().is_empty() {
-            return StatusCode::BAD_REQUEST.into_response();
-        }
-        match sqlx::query("UPDATE \"template\" SET name = $1 WHERE uid = $2")
-            .bind(&desc.name)
+    async fn delete(state: &AppState, id: &i64) -> Result<bool, sqlx::Error> {
+        Ok(sqlx::query("DELETE FROM \"template\" WHERE uid = $1")
             .bind(id)
             .execute(&state.db)
-            .await
-        {
-            Ok(res) => {
-                if res.rows_affected() == 0 {
-                    StatusCode::NOT_FOUND.into_response()
-                } else {
-                    Json(Template {
-                        template_id: id,
-                        name: desc.name,
-                    })
-                    .into_response()
-                }
-            }
-            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-        }
+            .await?
+            .rows_affected()
+            > 0)
     }
+}
 
-    pub async fn delete(
-        auth_session: AuthSession<auth::Backend>,
-        UrlPath(id): UrlPath<i64>,
-        State(state): State<crate::AppState>,
-    ) -> StatusCode {
-        let s_user = auth_session.user.unwrap();
-        match auth::can_delete(resources::Type::Template, s_user.user_id, &state.db).await {
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
-            Ok(false) => return StatusCode::UNAUTHORIZED,
-            Ok(true) => {}
-        }
-
-        match sqlx::query("DELETE FROM \"template\" WHERE uid = $1")
-            .bind(id)
-            .execute(&state.db)
-            .await
-        {
-            Ok(r) => {
-                if r.rows_affected() == 0 {
-                    StatusCode::NOT_FOUND
-                } else {
-                    StatusCode::OK
-                }
-            }
-            Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
-        }
+#[async_trait]
+impl Create for Template {
+    async fn insert(state: &AppState, desc: TemplateDescription) -> Result<Self, sqlx::Error> {
+        sqlx::query_as::<_, Template>(
+            "INSERT INTO \"template\"(name) VALUES ($1) RETURNING uid, name",
+        )
+        .bind(desc.name)
+        .fetch_one(&state.db)
+        .await
     }
 }
@@ -1,7 +1,14 @@
+use const_format::concatcp;
 use serde::Deserialize;
 use sqlx::PgPool;
 
-use crate::auth::permission::Operations;
+use crate::{
+    auth::{
+        permission::{EFFECTIVE_OPERATIONS, Operations},
+        token::Scope,
+    },
+    resources,
+};
 
 #[derive(serde::Serialize, serde::Deserialize, sqlx::FromRow)]
 #[serde(rename_all = "camelCase")]
@@ -17,18 +24,19 @@ pub struct Profile {
 impl Profile {
     pub async fn fetch_all(
         db: &PgPool,
-        requesting_user_id: i64,
+        requesting_user: &User,
     ) -> Result<Vec<Profile>, sqlx::Error> {
-        match sqlx::query_as::<_, Self>(
+        match sqlx::query_as::<_, Self>(concatcp!(
             "SELECT u.id, u.firstname, u.lastname, u.title, u.email FROM \"user\" u \
-WHERE EXISTS (\
-SELECT 1 FROM user_permissions up \
-WHERE (up.resource_id = u.id OR up.resource_id IS NULL) \
-AND (up.permission & $2::int::bit(16)) <> B'0'::bit(16) \
-AND (up.user_id = $1 OR up.role_id IN (SELECT role_id FROM user_has_role WHERE user_id = $1)))",
-        )
-        .bind(requesting_user_id)
-        .bind(Operations::READ)
+WHERE ($2::int::bit(16) & (SELECT ",
+            EFFECTIVE_OPERATIONS,
+            " FROM user_permissions p \
+WHERE (p.resource_id = u.id OR p.resource_id IS NULL) \
+AND (p.user_id = $1 OR p.role_id IN (SELECT role_id FROM current_user_has_role WHERE user_id = $1)))) \
+<> B'0'::bit(16)",
+        ))
+        .bind(requesting_user.user_id)
+        .bind(Operations::READ & requesting_user.allowed_operations(resources::Type::User))
         .fetch_all(db)
         .await
         {
@@ -68,6 +76,21 @@ pub struct User {
     pub(crate) title: String,
     pub(crate) email: String,
     pub(crate) password: Vec<u8>,
+    /// Set when logged in with a personal access token, which limits what the user may do
+    pub(crate) scope: Option<Scope>,
+    /// Set when a role requires two-factor authentication the user did not set up yet
+    pub(crate) totp_missing: bool,
+}
+
+impl User {
+    /// Operations the user may use on resources of `resource_type` as far as their login goes,
+    /// their grants decide the rest.
+    pub fn allowed_operations(&self, resource_type: resources::Type) -> Operations {
+        match &self.scope {
+            Some(scope) => scope.operations_on(resource_type),
+            None => Operations::ALL,
+        }
+    }
 }
 
 #[derive(Debug, Clone, serde::Deserialize)]
@@ -83,19 +106,23 @@ pub mod http {
     use crate::{
         auth::{
             self,
+            expiry::Validity,
             permission::{
                 GroupRow, Operations, RoleRow, add_user_to_role_groups_query,
-                add_users_to_groups_query, add_users_to_roles_query,
+                add_users_to_groups_query, add_users_to_roles_query, http::grant,
                 remove_user_from_role_groups_query,
             },
         },
-        resources,
+        resources::{
+            self,
+            file::{StorageReport, StorageUsage},
+        },
     };
 
     use super::{New, Profile, validation};
     use axum::{
         Json,
-        extract::{Path, State},
+        extract::{Path, Query, State},
         http::StatusCode,
         response::{IntoResponse, Response},
     };
@@ -118,7 +145,7 @@ pub mod http {
         Json(user): Json<New>,
     ) -> Response {
         let s_user = auth_session.user.unwrap();
-        match auth::can_create(resources::Type::User, s_user.user_id, &state.db).await {
+        match auth::can_create(resources::Type::User, &s_user, &state.db).await {
             Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
             Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
             Ok(true) => {}
@@ -137,10 +164,9 @@ pub mod http {
                     return (StatusCode::BAD_REQUEST, Json(validation)).into_response();
                 }
 
-                let hashed = tokio::task::spawn_blocking(|| {
-                    bcrypt::hash(user.password, bcrypt::DEFAULT_COST)
-                })
-                .await;
+                let hashed =
+                    tokio::task::spawn_blocking(move || auth::password::hash(&user.password))
+                        .await;
 
                 if let Ok(pw) = hashed {
                     if let Err(_) = pw {
@@ -165,6 +191,18 @@ pub mod http {
                     if let Err(_) | Ok(None) = result {
                         return StatusCode::INTERNAL_SERVER_ERROR.into_response();
                     }
+                    // the account exists either way, the mail can be sent again
+                    if let Ok(Some(user_id)) = result
+                        && let Err(e) = auth::email::send_verification(
+                            &state.db,
+                            &*state.mailer,
+                            user_id,
+                            &user.email,
+                        )
+                        .await
+                    {
+                        tracing::warn!("verification mail to user {user_id} failed: {e}");
+                    }
                     (
                         StatusCode::CREATED,
                         Json(Profile::new(
@@ -188,7 +226,7 @@ pub mod http {
         State(state): State<crate::AppState>,
     ) -> Response {
         let user = auth_session.user.unwrap();
-        match GroupRow::from_user_id(&state.db, user.user_id, user.user_id).await {
+        match GroupRow::from_user_id(&state.db, &user, user.user_id).await {
             Ok(g) => Json(g).into_response(),
             Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
         }
@@ -199,7 +237,7 @@ pub mod http {
         State(state): State<crate::AppState>,
     ) -> Response {
         let user = auth_session.user.unwrap();
-        match RoleRow::from_user_id(&state.db, user.user_id, user.user_id).await {
+        match RoleRow::from_user_id(&state.db, &user, user.user_id).await {
             Ok(r) => Json(r).into_response(),
             Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
         }
@@ -210,7 +248,7 @@ pub mod http {
         State(state): State<crate::AppState>,
     ) -> Response {
         let s_user = auth_session.user.unwrap();
-        match Profile::fetch_all(&state.db, s_user.user_id).await {
+        match Profile::fetch_all(&state.db, &s_user).await {
             Ok(u) => Json(u).into_response(),
             Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
         }
@@ -222,30 +260,18 @@ pub mod http {
         State(state): State<crate::AppState>,
     ) -> Response {
         let s_user = auth_session.user.unwrap();
-        match auth::user_has_permissions_all(
+        // also covers type-wide grants, unless one on the user denies them
+        match auth::user_has_permissions_id(
             resources::Type::User,
+            &id,
             Operations::READ,
-            s_user.user_id,
+            &s_user,
             &state.db,
         )
         .await
         {
             Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            Ok(false) => match auth::user_has_permissions_id(
-                resources::Type::User,
-                &id,
-                Operations::READ,
-                s_user.user_id,
-                &state.db,
-            )
-            .await
-            {
-                Err(_) => {
-                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-                }
-                Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
-                Ok(true) => {}
-            },
+            Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
             Ok(true) => {}
         }
 
@@ -266,6 +292,49 @@ pub mod http {
         }
     }
 
+    //GET /users/{id}/storage
+    pub async fn get_storage(
+        auth_session: AuthSession<crate::auth::Backend>,
+        Path(id): Path<i64>,
+        State(state): State<crate::AppState>,
+    ) -> Response {
+        let s_user = auth_session.user.unwrap();
+        if s_user.user_id != id {
+            match auth::user_has_permissions_id(
+                resources::Type::User,
+                &id,
+                Operations::READ,
+                &s_user,
+                &state.db,
+            )
+            .await
+            {
+                Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+                Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
+                Ok(true) => {}
+            }
+        }
+
+        match sqlx::query_scalar::<_, i32>("SELECT 1 FROM \"user\" WHERE id = $1")
+            .bind(id)
+            .fetch_optional(&state.db)
+            .await
+        {
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+            Ok(Some(_)) => {}
+        }
+
+        match StorageUsage::of_owner(&state.db, id).await {
+            Ok(usage) => Json(StorageReport {
+                usage,
+                quota: state.upload_policy.user_quota(),
+            })
+            .into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
     pub async fn update(
         auth_session: AuthSession<crate::auth::Backend>,
         Path(id): Path<i64>,
@@ -281,7 +350,7 @@ pub mod http {
             resources::Type::User,
             &id,
             Operations::UPDATE,
-            s_user.user_id,
+            &s_user,
             &state.db,
         )
         .await
@@ -332,7 +401,21 @@ pub mod http {
         State(state): State<crate::AppState>,
     ) -> StatusCode {
         let s_user = auth_session.user.unwrap();
-        match auth::can_delete(resources::Type::User, s_user.user_id, &state.db).await {
+        match auth::can_delete(resources::Type::User, &s_user, &state.db).await {
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
+            Ok(false) => return StatusCode::UNAUTHORIZED,
+            Ok(true) => {}
+        }
+        // a deny grant on the user itself still protects them
+        match auth::user_has_permissions_id(
+            resources::Type::User,
+            &id,
+            Operations::DELETE,
+            &s_user,
+            &state.db,
+        )
+        .await
+        {
             Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
             Ok(false) => return StatusCode::UNAUTHORIZED,
             Ok(true) => {}
@@ -370,7 +453,7 @@ pub mod http {
         }
 
         let s_user = auth_session.user.unwrap();
-        match GroupRow::from_user_id(&state.db, s_user.user_id, user_id).await {
+        match GroupRow::from_user_id(&state.db, &s_user, user_id).await {
             Ok(g) => Json(g).into_response(),
             Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
         }
@@ -396,7 +479,7 @@ pub mod http {
                         resources::Type::User,
                         &user_id,
                         Operations::UPDATE,
-                        s_user.user_id,
+                        &s_user,
                         &state.db,
                     )
                     .await
@@ -428,9 +511,8 @@ pub mod http {
                             return StatusCode::INTERNAL_SERVER_ERROR.into_response();
                         }
                     } else {
-                        let mut user_ids = Vec::with_capacity(group_ids.len());
-                        user_ids.fill(user_id);
-                        if let Err(_) = add_users_to_groups_query()
+                        let user_ids = vec![user_id; group_ids.len()];
+                        if let Err(_) = add_users_to_groups_query(Validity::ALWAYS)
                             .bind(&user_ids)
                             .bind(&group_ids)
                             .execute(&mut *transaction)
@@ -453,10 +535,11 @@ pub mod http {
     pub async fn add_to_groups(
         auth_session: AuthSession<crate::auth::Backend>,
         Path(user_id): Path<i64>,
+        Query(validity): Query<Validity>,
         State(state): State<crate::AppState>,
         Json(group_ids): Json<Vec<i64>>,
     ) -> StatusCode {
-        if group_ids.len() < 1 {
+        if group_ids.len() < 1 || !validity.is_valid() {
             return StatusCode::BAD_REQUEST;
         }
         match sqlx::query("SELECT 1 FROM \"user\" WHERE id = $1")
@@ -473,7 +556,7 @@ pub mod http {
             resources::Type::User,
             &user_id,
             Operations::UPDATE,
-            s_user.user_id,
+            &s_user,
             &state.db,
         )
         .await
@@ -483,20 +566,23 @@ pub mod http {
             Ok(true) => {}
         }
         if group_ids.len() == 1 {
-            if let Err(_) =
-                sqlx::query("INSERT INTO \"user_in_group\"(user_id, group_id) VALUES ($1, $2)")
-                    .bind(user_id)
-                    .bind(group_ids[0])
-                    .execute(&state.db)
-                    .await
+            if let Err(_) = sqlx::query(
+                "INSERT INTO \"user_in_group\"(user_id, group_id, valid_from, valid_until) \
+VALUES ($1, $2, $3, $4)",
+            )
+            .bind(user_id)
+            .bind(group_ids[0])
+            .bind(validity.valid_from)
+            .bind(validity.valid_until)
+            .execute(&state.db)
+            .await
             {
                 return StatusCode::INTERNAL_SERVER_ERROR;
             }
             return StatusCode::CREATED;
         }
-        let mut user_ids = Vec::with_capacity(group_ids.len());
-        user_ids.fill(user_id);
-        match add_users_to_groups_query()
+        let user_ids = vec![user_id; group_ids.len()];
+        match add_users_to_groups_query(validity)
             .bind(user_ids)
             .bind(group_ids)
             .execute(&state.db)
@@ -518,7 +604,7 @@ pub mod http {
             resources::Type::User,
             &user_id,
             Operations::UPDATE,
-            s_user.user_id,
+            &s_user,
             &state.db,
         )
         .await
@@ -554,7 +640,7 @@ pub mod http {
             _ => {}
         }
         let s_user = auth_session.user.unwrap();
-        match RoleRow::from_user_id(&state.db, s_user.user_id, user_id).await {
+        match RoleRow::from_user_id(&state.db, &s_user, user_id).await {
             Ok(r) => Json(r).into_response(),
             Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
         }
@@ -580,7 +666,7 @@ pub mod http {
                         resources::Type::User,
                         &user_id,
                         Operations::UPDATE,
-                        s_user.user_id,
+                        &s_user,
                         &state.db,
                     )
                     .await
@@ -589,6 +675,25 @@ pub mod http {
                         Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
                         Ok(true) => {}
                     }
+                    // the roles the user loses or gets are changed, like in `/roles/{id}/users`
+                    let Ok(current) = sqlx::query_scalar::<_, i64>(
+                        "SELECT role_id FROM \"user_has_role\" WHERE user_id = $1",
+                    )
+                    .bind(user_id)
+                    .fetch_all(&state.db)
+                    .await
+                    else {
+                        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                    };
+                    let lost = current.iter().filter(|r| !role_ids.contains(r));
+                    let got = role_ids.iter().filter(|r| !current.contains(r));
+                    for role_id in lost.chain(got) {
+                        if let Err(e) =
+                            RoleRow::check_membership_change(*role_id, &s_user, &state.db).await
+                        {
+                            return grant::error_response(e);
+                        }
+                    }
                     let Ok(mut transaction) = state.db.begin().await else {
                         return StatusCode::INTERNAL_SERVER_ERROR.into_response();
                     };
@@ -626,9 +731,8 @@ pub mod http {
                             return StatusCode::INTERNAL_SERVER_ERROR.into_response();
                         }
                     } else {
-                        let mut user_ids = Vec::with_capacity(role_ids.len());
-                        user_ids.fill(user_id);
-                        if let Err(_) = add_users_to_roles_query()
+                        let user_ids = vec![user_id; role_ids.len()];
+                        if let Err(_) = add_users_to_roles_query(Validity::ALWAYS)
                             .bind(&user_ids)
                             .bind(&role_ids)
                             .execute(&mut *transaction)
@@ -651,10 +755,11 @@ pub mod http {
     pub async fn assign_roles(
         auth_session: AuthSession<crate::auth::Backend>,
         Path(user_id): Path<i64>,
+        Query(validity): Query<Validity>,
         State(state): State<crate::AppState>,
         Json(role_ids): Json<Vec<i64>>,
     ) -> StatusCode {
-        if role_ids.len() < 1 {
+        if role_ids.len() < 1 || !validity.is_valid() {
             return StatusCode::BAD_REQUEST;
         }
         let s_user = auth_session.user.unwrap();
@@ -662,7 +767,7 @@ pub mod http {
             resources::Type::User,
             &user_id,
             Operations::UPDATE,
-            s_user.user_id,
+            &s_user,
             &state.db,
         )
         .await
@@ -671,6 +776,11 @@ pub mod http {
             Ok(false) => return StatusCode::UNAUTHORIZED,
             Ok(true) => {}
         }
+        for role_id in &role_ids {
+            if let Err(e) = RoleRow::check_membership_change(*role_id, &s_user, &state.db).await {
+                return grant::error_response(e).status();
+            }
+        }
 
         match sqlx::query("SELECT 1 FROM \"user\" WHERE id = $1")
             .bind(user_id)
@@ -687,17 +797,22 @@ pub mod http {
         };
 
         if role_ids.len() == 1 {
-            if sqlx::query("INSERT INTO \"user_has_role\"(user_id, role_id) VALUES ($1, $2)")
-                .bind(user_id)
-                .bind(role_ids[0])
-                .execute(&mut *transaction)
-                .await
-                .is_err()
+            if sqlx::query(
+                "INSERT INTO \"user_has_role\"(user_id, role_id, valid_from, valid_until) \
+VALUES ($1, $2, $3, $4)",
+            )
+            .bind(user_id)
+            .bind(role_ids[0])
+            .bind(validity.valid_from)
+            .bind(validity.valid_until)
+            .execute(&mut *transaction)
+            .await
+            .is_err()
             {
                 return StatusCode::INTERNAL_SERVER_ERROR;
             }
-            if sqlx::query("INSERT INTO \"user_in_group\"(user_id, group_id) VALUES ($1, (SELECT \"group\" FROM \"role\" WHERE id = $2))")
-                .bind(user_id).bind(role_ids[0]).execute(&mut *transaction).await.is_err() {
+            if sqlx::query("INSERT INTO \"user_in_group\"(user_id, group_id, valid_from, valid_until) VALUES ($1, (SELECT \"group\" FROM \"role\" WHERE id = $2), $3, $4)")
+                .bind(user_id).bind(role_ids[0]).bind(validity.valid_from).bind(validity.valid_until).execute(&mut *transaction).await.is_err() {
                 return StatusCode::INTERNAL_SERVER_ERROR;
             }
 
@@ -709,7 +824,7 @@ pub mod http {
 
         let user_ids = vec![user_id; role_ids.len()];
 
-        if add_users_to_roles_query()
+        if add_users_to_roles_query(validity)
             .bind(&user_ids)
             .bind(&role_ids)
             .execute(&mut *transaction)
@@ -719,7 +834,7 @@ pub mod http {
             return StatusCode::INTERNAL_SERVER_ERROR;
         }
 
-        if add_user_to_role_groups_query()
+        if add_user_to_role_groups_query(validity)
             .bind(user_id)
             .bind(&role_ids)
             .execute(&mut *transaction)
@@ -747,7 +862,7 @@ pub mod http {
             resources::Type::User,
             &user_id,
             Operations::UPDATE,
-            s_user.user_id,
+            &s_user,
             &state.db,
         )
         .await
@@ -756,6 +871,11 @@ pub mod http {
             Ok(false) => return StatusCode::UNAUTHORIZED,
             Ok(true) => {}
         }
+        for role_id in &role_ids {
+            if let Err(e) = RoleRow::check_membership_change(*role_id, &s_user, &state.db).await {
+                return grant::error_response(e).status();
+            }
+        }
 
         let Ok(mut transaction) = state.db.begin().await else {
             return StatusCode::INTERNAL_SERVER_ERROR;
@@ -788,7 +908,7 @@ pub mod http {
     }
 }
 
-mod validation {
+pub(crate) mod validation {
     use serde::Serialize;
 
     #[derive(Serialize)]
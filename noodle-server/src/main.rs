@@ -1,17 +1,20 @@
-use axum::routing::{delete, post};
+use axum::extract::DefaultBodyLimit;
+use axum::routing::{delete, post, put};
 use axum::{Router, routing::get};
 use axum_login::tower_sessions::{ExpiredDeletion, SessionManagerLayer};
 use axum_login::{AuthManagerLayerBuilder, login_required};
 use dotenv::dotenv;
 use libnoodle::AppState;
-use libnoodle::{auth, resources, user};
+use libnoodle::{auth, mail, media, resources, user};
 use sqlx::PgPool;
 use sqlx::postgres::PgPoolOptions;
 use std::env;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use tokio::task::AbortHandle;
 use tower_http::cors::{Any, CorsLayer};
 use tower_sessions_sqlx_store::PostgresStore;
+use tracing_subscriber::EnvFilter;
 
 async fn migrate_test(db_pool: &PgPool) {
     let exists = sqlx::query_scalar::<_, i32>("SELECT 1 FROM \"user\" WHERE email = $1")
@@ -23,13 +26,13 @@ async fn migrate_test(db_pool: &PgPool) {
 
     if !exists {
         let main_user_id = sqlx::query_scalar::<_, i64>(
-            "INSERT INTO \"user\" (firstname, lastname, title, email, password) VALUES ($1, $2, $3, $4, $5) RETURNING id",
+            "INSERT INTO \"user\" (firstname, lastname, title, email, password, email_verified_at) VALUES ($1, $2, $3, $4, $5, now()) RETURNING id",
         )
         .bind(&env::var("ADMIN_FIRSTNAME").unwrap())
         .bind(&env::var("ADMIN_LASTNAME").unwrap())
         .bind(&env::var("ADMIN_TITLE").unwrap())
         .bind(&env::var("ADMIN_MAIL").unwrap())
-        .bind(&bcrypt::hash(&env::var("ADMIN_PASSWORD").unwrap(), bcrypt::DEFAULT_COST).unwrap())
+        .bind(&auth::password::hash(&env::var("ADMIN_PASSWORD").unwrap()).unwrap())
         .fetch_one(db_pool)
         .await
         .unwrap();
@@ -48,6 +51,11 @@ async fn migrate_test(db_pool: &PgPool) {
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv().ok();
 
+    // `RUST_LOG` picks what is logged, by default everything from info up
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()))
+        .init();
+
     let args: Vec<_> = std::env::args().collect();
 
     let pool_options = PgPoolOptions::new().max_connections(5);
@@ -61,6 +69,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         db_pool = pool_options.connect(&env::var("PG_URL").unwrap()).await?;
     }
 
+    let media = media::from_env();
+
+    // `noodle-server [test] check-media [--repair]` compares the file table with the media store
+    if args.iter().any(|a| a == "check-media") {
+        let repair = args.iter().any(|a| a == "--repair");
+        let grace = media::integrity::grace_from_env();
+        let report = media::integrity::check(&db_pool, &*media, grace, repair).await?;
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
     let session_store = PostgresStore::new(db_pool.clone());
     session_store.migrate().await?;
 
@@ -78,9 +97,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let auth_backend = auth::Backend::new(db_pool.clone()).await;
     let auth_layer = AuthManagerLayerBuilder::new(auth_backend, session_layer).build();
 
+    media::integrity::spawn_from_env(db_pool.clone(), media.clone());
+    auth::expiry::spawn_from_env(db_pool.clone());
+
     let app_state = AppState {
         db: db_pool.clone(),
-        media_path: env::var("MEDIA_PATH").unwrap(),
+        permissions: Arc::new(auth::cache::PermissionCache::from_env()),
+        media: media.clone(),
+        mailer: mail::from_env(),
+        upload_policy: Arc::new(media::policy::UploadPolicy::from_env()),
+        variants: media::variant::Pipeline::spawn(media),
     };
 
     //testing only
@@ -91,8 +117,33 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     use auth::permission;
     let app = Router::new()
         .route("/user", get(user::http::get_self).post(user::http::create))
+        .route("/user/password", put(auth::password::http::change))
         .route("/user/groups", get(user::http::get_self_groups))
         .route("/user/roles", get(user::http::get_self_roles))
+        .route(
+            "/user/sessions",
+            get(auth::session::http::get_own).delete(auth::session::http::revoke_own_others),
+        )
+        .route(
+            "/user/sessions/{id}",
+            delete(auth::session::http::revoke_own),
+        )
+        .route(
+            "/user/tokens",
+            get(auth::token::http::get_own).post(auth::token::http::create),
+        )
+        .route("/user/tokens/{id}", delete(auth::token::http::revoke))
+        .route(
+            "/user/totp",
+            get(auth::totp::http::get_own)
+                .post(auth::totp::http::enroll)
+                .delete(auth::totp::http::disable),
+        )
+        .route("/user/totp/confirm", post(auth::totp::http::confirm))
+        .route(
+            "/user/totp/recovery-codes",
+            post(auth::totp::http::renew_recovery_codes),
+        )
         .route("/users", get(user::http::get_all))
         .route(
             "/users/{id}",
@@ -100,6 +151,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .patch(user::http::update)
                 .delete(user::http::delete),
         )
+        .route("/users/{id}/storage", get(user::http::get_storage))
+        .route(
+            "/users/{id}/sessions",
+            delete(auth::session::http::revoke_user),
+        )
+        .route("/users/{id}/totp", delete(auth::totp::http::reset_user))
+        .route(
+            "/users/{id}/email-verification",
+            post(auth::email::http::resend_verification),
+        )
         .route(
             "/users/{id}/groups",
             get(user::http::get_groups)
@@ -124,6 +185,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .patch(permission::http::role::update)
                 .delete(permission::http::role::delete),
         )
+        .route(
+            "/roles/{id}/totp",
+            get(auth::totp::http::get_role_requirement).put(auth::totp::http::set_role_requirement),
+        )
         .route(
             "/roles/{id}/users",
             get(permission::http::role::get_users)
@@ -141,6 +206,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .patch(permission::http::group::update)
                 .delete(permission::http::group::delete),
         )
+        .route(
+            "/groups/{id}/subtree",
+            get(permission::http::group::get_subtree),
+        )
         .route(
             "/groups/{id}/users",
             get(permission::http::group::get_users)
@@ -148,33 +217,57 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .post(permission::http::group::add_users)
                 .delete(permission::http::group::delete_users),
         )
+        .route(
+            "/grants/{type}",
+            get(permission::http::grant::get_all)
+                .put(permission::http::grant::set)
+                .delete(permission::http::grant::revoke),
+        )
+        .route(
+            "/grants/{type}/explain",
+            get(permission::http::grant::explain),
+        )
         .route(
             "/files",
-            get(resources::file::http::get_all).post(resources::file::http::create),
+            get(resources::crud::list::<resources::file::FileMetadata>)
+                .post(resources::file::http::create),
+        )
+        .route(
+            "/files/upload",
+            post(resources::file::http::upload).layer(DefaultBodyLimit::disable()),
         )
         .route(
             "/file/{uid}",
             get(resources::file::http::get_by_uid)
                 .put(resources::file::http::update)
-                .delete(resources::file::http::delete),
+                .delete(resources::crud::delete::<resources::file::FileMetadata>),
         )
         .route(
-            "/design",
-            get(resources::branding::http::get).post(resources::branding::http::create_default),
+            "/file/{uid}/metadata",
+            get(resources::crud::get_one::<resources::file::FileMetadata>)
+                .put(resources::crud::update::<resources::file::FileMetadata>),
         )
         .route(
-            "/courses",
-            get(resources::course::http::get_all).post(resources::course::http::create),
+            "/file/{uid}/content",
+            get(resources::file::http::get_content)
+                .put(resources::file::http::replace_content)
+                .layer(DefaultBodyLimit::disable()),
+        )
+        .route(
+            "/design",
+            get(resources::branding::http::get).post(resources::branding::http::create_default),
         )
         .route(
             "/courses/manage",
             get(resources::course::http::get_all_management),
         )
         .route(
-            "/course/{id}",
-            get(resources::course::http::get_by_uid)
-                .put(resources::course::http::update)
-                .delete(resources::course::http::delete),
+            "/course/{courseId}/access",
+            get(resources::course::http::get_access),
+        )
+        .route(
+            "/course/{courseId}/storage",
+            get(resources::course::http::get_storage),
         )
         .route(
             "/course/{courseId}/lecturers",
@@ -196,7 +289,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         )
         .route(
             "/course/{courseId}/users/{userId}",
-            delete(resources::course::http::delete_user),
+            put(resources::course::http::set_user_role)
+                .delete(resources::course::http::delete_user),
         )
         .route(
             "/course/{courseId}/sections",
@@ -217,14 +311,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .delete(resources::content_section::http::delete_course_content),
         )
         .route(
-            "/templates",
-            get(resources::template::http::get_all).post(resources::template::http::create),
+            "/course/{courseId}/section/{sectionId}/content/{contentId}/files",
+            get(resources::content_section::http::get_content_files)
+                .post(resources::content_section::http::attach_file)
+                .put(resources::content_section::http::reorder_files),
         )
         .route(
-            "/template/{id}",
-            get(resources::template::http::get_by_uid)
-                .put(resources::template::http::update)
-                .delete(resources::template::http::delete),
+            "/course/{courseId}/section/{sectionId}/content/{contentId}/files/{fileUid}",
+            delete(resources::content_section::http::detach_file),
         )
         .route(
             "/template/{templateId}/sections",
@@ -244,9 +338,37 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .put(resources::content_section::http::update_template_content)
                 .delete(resources::content_section::http::delete_template_content),
         )
+        .merge(resources::crud::router::<resources::course::Course>(
+            "/courses",
+            "/course/{id}",
+        ))
+        .merge(resources::crud::router::<resources::template::Template>(
+            "/templates",
+            "/template/{id}",
+        ))
+        .route("/logout", post(auth::session::http::logout))
+        .route_layer(axum::middleware::from_fn_with_state(
+            app_state.clone(),
+            auth::session::http::track,
+        ))
+        .route_layer(axum::middleware::from_fn(auth::totp::http::enforce))
         .route_layer(login_required!(auth::Backend))
+        .route_layer(axum::middleware::from_fn(auth::token::http::bearer))
         //NOTE: potentially temporary
         .route("/login", post(auth::create_session_handler))
+        .route("/login/totp", post(auth::totp::http::login))
+        .route(
+            "/password-reset",
+            post(auth::email::http::request_password_reset),
+        )
+        .route(
+            "/password-reset/confirm",
+            post(auth::email::http::reset_password),
+        )
+        .route(
+            "/email-verification/confirm",
+            post(auth::email::http::verify),
+        )
         .layer(
             tower::ServiceBuilder::new().layer(auth_layer).layer(
                 CorsLayer::new()
@@ -257,10 +379,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         )
         .with_state(app_state);
 
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal(deletion_task.abort_handle()))
-        .await
-        .unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal(deletion_task.abort_handle()))
+    .await
+    .unwrap();
     Ok(())
 }
 
@@ -1 +1 @@
-INSERT INTO "user"(firstname, lastname, email, password) VALUES ('Admin', 'Istrator', 'admin@noodle.de', '$2b$12$WkOfAro0M..cGQDvlfwfO.Saa6a0FzW.HTe7ZjMLP/HkVEyXMUFsy');
+INSERT INTO "user"(firstname, lastname, email, password, email_verified_at) VALUES ('Admin', 'Istrator', 'admin@noodle.de', '$2b$12$WkOfAro0M..cGQDvlfwfO.Saa6a0FzW.HTe7ZjMLP/HkVEyXMUFsy', now());
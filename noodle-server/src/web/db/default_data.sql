@@ -2,20 +2,20 @@ WITH inserted_group AS (INSERT INTO "group" ("name", shortname, kind, parent) VA
 INSERT INTO "role"(name, permissions, "group")
 VALUES ('admin',
 ('[
-{"subject": "user", "ops": 15, "ids": null},
-{"subject": "role", "ops": 15, "ids": null},
-{"subject": "group", "ops": 15, "ids": null},
-{"subject": "file", "ops": 15, "ids": null},
-{"subject": "course", "ops": 15, "ids": null},
-{"subject": "template", "ops": 15, "ids": null}
+{"subject": "user", "ops": ["create", "read", "update", "delete", "publish", "grade", "enroll", "managePermissions", "export"], "ids": null},
+{"subject": "role", "ops": ["create", "read", "update", "delete", "publish", "grade", "enroll", "managePermissions", "export"], "ids": null},
+{"subject": "group", "ops": ["create", "read", "update", "delete", "publish", "grade", "enroll", "managePermissions", "export"], "ids": null},
+{"subject": "file", "ops": ["create", "read", "update", "delete", "publish", "grade", "enroll", "managePermissions", "export"], "ids": null},
+{"subject": "course", "ops": ["create", "read", "update", "delete", "publish", "grade", "enroll", "managePermissions", "export"], "ids": null},
+{"subject": "template", "ops": ["create", "read", "update", "delete", "publish", "grade", "enroll", "managePermissions", "export"], "ids": null}
 ]'::json),
 (SELECT id FROM inserted_group)
 )
 RETURNING id;
 
-INSERT INTO user_permissions (role_id, permission) VALUES((SELECT id FROM "role" WHERE name = 'admin'), 15::bit(16));
-INSERT INTO role_permissions (role_id, permission) VALUES((SELECT id FROM "role" WHERE name = 'admin'), 15::bit(16));
-INSERT INTO group_permissions (role_id, permission) VALUES((SELECT id FROM "role" WHERE name = 'admin'), 15::bit(16));
-INSERT INTO file_permissions (role_id, permission) VALUES((SELECT id FROM "role" WHERE name = 'admin'), 15::bit(16));
-INSERT INTO course_permissions (role_id, permission) VALUES((SELECT id FROM "role" WHERE name = 'admin'), 15::bit(16));
-INSERT INTO template_permissions (role_id, permission) VALUES((SELECT id FROM "role" WHERE name = 'admin'), 15::bit(16));
+INSERT INTO user_permissions (role_id, permission) VALUES((SELECT id FROM "role" WHERE name = 'admin'), 511::bit(16));
+INSERT INTO role_permissions (role_id, permission) VALUES((SELECT id FROM "role" WHERE name = 'admin'), 511::bit(16));
+INSERT INTO group_permissions (role_id, permission) VALUES((SELECT id FROM "role" WHERE name = 'admin'), 511::bit(16));
+INSERT INTO file_permissions (role_id, permission) VALUES((SELECT id FROM "role" WHERE name = 'admin'), 511::bit(16));
+INSERT INTO course_permissions (role_id, permission) VALUES((SELECT id FROM "role" WHERE name = 'admin'), 511::bit(16));
+INSERT INTO template_permissions (role_id, permission) VALUES((SELECT id FROM "role" WHERE name = 'admin'), 511::bit(16));
@@ -16,8 +16,20 @@ DROP TABLE IF EXISTS "group" CASCADE;
 
 DROP TABLE IF EXISTS "user" CASCADE;
 
+DROP TABLE IF EXISTS "user_session" CASCADE;
+
+DROP TABLE IF EXISTS "access_token" CASCADE;
+
+DROP TABLE IF EXISTS "user_totp" CASCADE;
+
 DROP TYPE IF EXISTS "group_kind" CASCADE;
 
+DROP TYPE IF EXISTS "course_role" CASCADE;
+
+DROP TABLE IF EXISTS "email_token" CASCADE;
+
+DROP TYPE IF EXISTS "email_token_purpose" CASCADE;
+
 DROP TABLE IF EXISTS "course_lecturer" CASCADE;
 
 DROP TABLE IF EXISTS "course_group" CASCADE;
@@ -46,7 +58,10 @@ CREATE TABLE IF NOT EXISTS "user" (
     "lastname" VARCHAR(255),
     "title" VARCHAR(255),
     "email" VARCHAR(255) UNIQUE,
-    "password" CHARACTER(60)
+    -- argon2id, or bcrypt until the user logs in again
+    "password" VARCHAR(255),
+    -- when the user opened the link of the verification mail
+    "email_verified_at" TIMESTAMPTZ
 );
 
 CREATE INDEX ON "user" ("firstname");
@@ -55,6 +70,62 @@ CREATE INDEX ON "user" ("lastname");
 
 CREATE INDEX ON "user" ("email");
 
+-- the login sessions of a user, the session data itself is kept by the session store in
+-- "tower_sessions"."session" under "session_id". That is the value of the session cookie, so only
+-- "id" is handed out
+CREATE TABLE IF NOT EXISTS "user_session" (
+    "id" BIGSERIAL PRIMARY KEY,
+    "session_id" TEXT NOT NULL UNIQUE,
+    "user_id" BIGINT NOT NULL REFERENCES "user"("id") ON DELETE CASCADE,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT now(),
+    "last_seen" TIMESTAMPTZ NOT NULL DEFAULT now(),
+    "ip" TEXT,
+    "user_agent" TEXT
+);
+
+CREATE INDEX ON "user_session" ("user_id");
+
+-- personal access tokens, only their SHA-256 is kept. "scope" narrows down what the grants of the
+-- user allow: {"types": [...] or null, "operations": <bits>}
+CREATE TABLE IF NOT EXISTS "access_token" (
+    "id" BIGSERIAL PRIMARY KEY,
+    "user_id" BIGINT NOT NULL REFERENCES "user"("id") ON DELETE CASCADE,
+    "name" VARCHAR(255) NOT NULL,
+    "token_hash" CHAR(64) NOT NULL UNIQUE,
+    "scope" JSON NOT NULL,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT now(),
+    "expires_at" TIMESTAMPTZ,
+    "last_used" TIMESTAMPTZ
+);
+
+CREATE INDEX ON "access_token" ("user_id");
+
+-- two-factor authentication, "enabled" once a code of the authenticator was confirmed. Codes are
+-- only accepted for time steps after "last_step", "recovery_codes" are the SHA-256 of the unused
+-- recovery codes
+CREATE TABLE IF NOT EXISTS "user_totp" (
+    "user_id" BIGINT PRIMARY KEY REFERENCES "user"("id") ON DELETE CASCADE,
+    "secret" BYTEA NOT NULL,
+    "enabled" BOOLEAN NOT NULL DEFAULT false,
+    "last_step" BIGINT,
+    "recovery_codes" TEXT[] NOT NULL DEFAULT '{}'
+);
+
+CREATE TYPE "email_token_purpose" AS ENUM ('password_reset', 'email_verification');
+
+-- single-use tokens sent by mail, only their SHA-256 is kept. A new one replaces those the user got
+-- for the same purpose before
+CREATE TABLE IF NOT EXISTS "email_token" (
+    "id" BIGSERIAL PRIMARY KEY,
+    "user_id" BIGINT NOT NULL REFERENCES "user"("id") ON DELETE CASCADE,
+    "purpose" "email_token_purpose" NOT NULL,
+    "token_hash" CHAR(64) NOT NULL UNIQUE,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT now(),
+    "expires_at" TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX ON "email_token" ("user_id");
+
 CREATE TYPE "group_kind" AS ENUM ('organization', 'learning', 'contact', 'role');
 
 CREATE TABLE IF NOT EXISTS "group" (
@@ -71,18 +142,26 @@ CREATE TABLE IF NOT EXISTS "role" (
     "id" BIGSERIAL PRIMARY KEY,
     "name" VARCHAR(32) UNIQUE,
     "group" BIGINT REFERENCES "group" ON DELETE SET NULL DEFAULT NULL,
-    "permissions" json
+    "permissions" json,
+    -- members have to log in with two-factor authentication
+    "require_totp" BOOLEAN NOT NULL DEFAULT false
 );
 
 CREATE TABLE IF NOT EXISTS "user_has_role" (
     "user_id" BIGSERIAL REFERENCES "user" ON DELETE CASCADE,
     "role_id" BIGSERIAL REFERENCES "role" ON DELETE CASCADE,
+    "valid_from" TIMESTAMPTZ DEFAULT NULL,
+    "valid_until" TIMESTAMPTZ DEFAULT NULL,
+    CHECK ("valid_from" < "valid_until"),
     PRIMARY KEY ("user_id", "role_id")
 );
 
 CREATE TABLE IF NOT EXISTS "user_in_group" (
     "user_id" BIGSERIAL REFERENCES "user" ON DELETE CASCADE,
     "group_id" BIGSERIAL REFERENCES "group" ON DELETE CASCADE,
+    "valid_from" TIMESTAMPTZ DEFAULT NULL,
+    "valid_until" TIMESTAMPTZ DEFAULT NULL,
+    CHECK ("valid_from" < "valid_until"),
     PRIMARY KEY ("user_id", "group_id")
 );
 
@@ -90,21 +169,33 @@ CREATE TABLE IF NOT EXISTS "user_permissions" ( -- `user` -> CRUD rights for `us
     "user_id" BIGINT REFERENCES "user" ON DELETE CASCADE DEFAULT NULL,
     "role_id" BIGINT REFERENCES "role" ON DELETE CASCADE DEFAULT NULL,
     "resource_id" BIGINT REFERENCES "user" ON DELETE CASCADE DEFAULT NULL,
-    "permission" BIT(16) DEFAULT B'0'
+    "permission" BIT(16) DEFAULT B'0',
+    "deny" BOOLEAN NOT NULL DEFAULT FALSE,
+    "valid_from" TIMESTAMPTZ DEFAULT NULL,
+    "valid_until" TIMESTAMPTZ DEFAULT NULL,
+    CHECK ("valid_from" < "valid_until")
 );
 
 CREATE TABLE IF NOT EXISTS "role_permissions" ( -- `user` -> CRUD rights for `role`
     "user_id" BIGINT REFERENCES "user" ON DELETE CASCADE DEFAULT NULL,
     "role_id" BIGINT REFERENCES "role" ON DELETE CASCADE DEFAULT NULL,
     "resource_id" BIGINT REFERENCES "role" ON DELETE CASCADE DEFAULT NULL,
-    "permission" BIT(16) DEFAULT B'0'
+    "permission" BIT(16) DEFAULT B'0',
+    "deny" BOOLEAN NOT NULL DEFAULT FALSE,
+    "valid_from" TIMESTAMPTZ DEFAULT NULL,
+    "valid_until" TIMESTAMPTZ DEFAULT NULL,
+    CHECK ("valid_from" < "valid_until")
 );
 
 CREATE TABLE IF NOT EXISTS "group_permissions" ( -- `user` -> CRUD rights for `group`
     "user_id" BIGINT REFERENCES "user" ON DELETE CASCADE DEFAULT NULL,
     "role_id" BIGINT REFERENCES "role" ON DELETE CASCADE DEFAULT NULL,
     "resource_id" BIGINT REFERENCES "group" ON DELETE CASCADE DEFAULT NULL,
-    "permission" BIT(16) DEFAULT B'0'
+    "permission" BIT(16) DEFAULT B'0',
+    "deny" BOOLEAN NOT NULL DEFAULT FALSE,
+    "valid_from" TIMESTAMPTZ DEFAULT NULL,
+    "valid_until" TIMESTAMPTZ DEFAULT NULL,
+    CHECK ("valid_from" < "valid_until")
 );
 
 CREATE TABLE IF NOT EXISTS "file" (
@@ -112,6 +203,9 @@ CREATE TABLE IF NOT EXISTS "file" (
     "filename" VARCHAR(255),
     "type" VARCHAR(255),
     "location" VARCHAR(512),
+    "size" BIGINT NOT NULL DEFAULT 0,
+    "checksum" BYTEA DEFAULT NULL, -- SHA-256 of the contents
+    "owner_id" BIGINT REFERENCES "user" ON DELETE SET NULL DEFAULT NULL,
     "created_at" TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
     "updated_at" TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
 );
@@ -122,6 +216,9 @@ CREATE TABLE IF NOT EXISTS "course" (
     "shortname" VARCHAR(128)
 );
 
+--- roles inside a course, from most to least privileged
+CREATE TYPE "course_role" AS ENUM ('lecturer', 'tutor', 'student', 'guest');
+
 CREATE TABLE IF NOT EXISTS "course_lecturer" (
     "course_id" BIGINT NOT NULL REFERENCES "course" ON DELETE CASCADE,
     "user_id" BIGINT NOT NULL REFERENCES "user" ON DELETE CASCADE
@@ -134,7 +231,12 @@ CREATE TABLE IF NOT EXISTS "course_group" (
 
 CREATE TABLE IF NOT EXISTS "course_user" (
     "course_id" BIGINT NOT NULL REFERENCES "course" ON DELETE CASCADE,
-    "user_id" BIGINT NOT NULL REFERENCES "user" ON DELETE CASCADE
+    "user_id" BIGINT NOT NULL REFERENCES "user" ON DELETE CASCADE,
+    "role" "course_role" NOT NULL DEFAULT 'student' CHECK ("role" <> 'lecturer'), -- lecturers are listed in `course_lecturer`
+    "valid_from" TIMESTAMPTZ DEFAULT NULL,
+    "valid_until" TIMESTAMPTZ DEFAULT NULL,
+    CHECK ("valid_from" < "valid_until"),
+    PRIMARY KEY ("course_id", "user_id")
 );
 
 CREATE TABLE IF NOT EXISTS "template" (
@@ -149,6 +251,7 @@ CREATE TABLE IF NOT EXISTS "content_section" (
     "template_id" BIGINT NULL REFERENCES "template" ON DELETE CASCADE DEFAULT NULL,
     "headline" VARCHAR(255),
     "order_index" INTEGER DEFAULT 0,
+    "hidden" BOOLEAN NOT NULL DEFAULT FALSE,
     "created_at" TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
     "updated_at" TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
 );
@@ -170,26 +273,115 @@ CREATE TABLE IF NOT EXISTS "file_in_content_element" (
     "file_id" UUID REFERENCES "file" ON DELETE CASCADE,
     "order_index" INTEGER DEFAULT 0,
     "created_at" TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
-    "updated_at" TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+    "updated_at" TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY ("content_id", "file_id")
 );
 
 CREATE TABLE IF NOT EXISTS "file_permissions" ( -- `user` -> CRUD rights for `group`
     "user_id" BIGINT REFERENCES "user" ON DELETE CASCADE DEFAULT NULL,
     "role_id" BIGINT REFERENCES "role" ON DELETE CASCADE DEFAULT NULL,
     "resource_id" UUID REFERENCES "file" ON DELETE CASCADE DEFAULT NULL,
-    "permission" BIT(16) DEFAULT B'0'
+    "permission" BIT(16) DEFAULT B'0',
+    "deny" BOOLEAN NOT NULL DEFAULT FALSE,
+    "valid_from" TIMESTAMPTZ DEFAULT NULL,
+    "valid_until" TIMESTAMPTZ DEFAULT NULL,
+    CHECK ("valid_from" < "valid_until")
 );
 
 CREATE TABLE IF NOT EXISTS "course_permissions" ( -- `user` -> CRUD rights for `group`
     "user_id" BIGINT REFERENCES "user" ON DELETE CASCADE DEFAULT NULL,
     "role_id" BIGINT REFERENCES "role" ON DELETE CASCADE DEFAULT NULL,
     "resource_id" BIGINT REFERENCES "course" ON DELETE CASCADE DEFAULT NULL,
-    "permission" BIT(16) DEFAULT B'0'
+    "permission" BIT(16) DEFAULT B'0',
+    "deny" BOOLEAN NOT NULL DEFAULT FALSE,
+    "valid_from" TIMESTAMPTZ DEFAULT NULL,
+    "valid_until" TIMESTAMPTZ DEFAULT NULL,
+    CHECK ("valid_from" < "valid_until")
 );
 
 CREATE TABLE IF NOT EXISTS "template_permissions" ( -- `user` -> CRUD rights for `group`
     "user_id" BIGINT REFERENCES "user" ON DELETE CASCADE DEFAULT NULL,
     "role_id" BIGINT REFERENCES "role" ON DELETE CASCADE DEFAULT NULL,
     "resource_id" BIGINT REFERENCES "template" ON DELETE CASCADE DEFAULT NULL,
-    "permission" BIT(16) DEFAULT B'0'
+    "permission" BIT(16) DEFAULT B'0',
+    "deny" BOOLEAN NOT NULL DEFAULT FALSE,
+    "valid_from" TIMESTAMPTZ DEFAULT NULL,
+    "valid_until" TIMESTAMPTZ DEFAULT NULL,
+    CHECK ("valid_from" < "valid_until")
+);
+
+--- whether a grant or membership with the validity window `valid_from` to `valid_until` applies now,
+--- a window without bounds always does
+CREATE OR REPLACE FUNCTION is_current(valid_from TIMESTAMPTZ, valid_until TIMESTAMPTZ) RETURNS BOOLEAN AS $$
+    SELECT (valid_from IS NULL OR valid_from <= now()) AND (valid_until IS NULL OR now() < valid_until)
+$$ LANGUAGE SQL STABLE;
+
+--- the role memberships that apply now
+CREATE OR REPLACE VIEW "current_user_has_role" AS
+SELECT "user_id", "role_id" FROM "user_has_role" WHERE is_current("valid_from", "valid_until");
+
+--- every group a user belongs to, directly or as a member of one of its sub-groups
+CREATE OR REPLACE VIEW "effective_user_in_group" AS
+WITH RECURSIVE "ancestry"("group_id", "ancestor_id") AS (
+    SELECT "id", "id" FROM "group"
+    UNION
+    SELECT a."group_id", g."parent" FROM "ancestry" a
+    JOIN "group" g ON g."id" = a."ancestor_id" WHERE g."parent" IS NOT NULL
+)
+SELECT DISTINCT uig."user_id", a."ancestor_id" AS "group_id" FROM "user_in_group" uig
+JOIN "ancestry" a ON a."group_id" = uig."group_id"
+WHERE is_current(uig."valid_from", uig."valid_until");
+
+--- the role of every member of a course: lecturers, enrolled users with their role and members of
+--- its groups as students, the most privileged one if a user is a member several times
+CREATE OR REPLACE VIEW "course_member" AS
+SELECT DISTINCT ON ("course_id", "user_id") "course_id", "user_id", "role" FROM (
+    SELECT "course_id", "user_id", 'lecturer'::"course_role" AS "role" FROM "course_lecturer"
+    UNION ALL
+    SELECT "course_id", "user_id", "role" FROM "course_user" WHERE is_current("valid_from", "valid_until")
+    UNION ALL
+    SELECT cg."course_id", uig."user_id", 'student'::"course_role" FROM "course_group" cg
+    JOIN "effective_user_in_group" uig ON uig."group_id" = cg."group_id"
+) m ORDER BY "course_id", "user_id", "role";
+
+--- folds the grants that apply to a user into the operations they hold. The grants are applied in
+--- the aggregate's order, see `EFFECTIVE_OPERATIONS`: an allow adds its operations and a deny
+--- takes them away again, so the last grant that mentions an operation decides it
+CREATE OR REPLACE FUNCTION apply_grant(held BIT(16), permission BIT(16), deny BOOLEAN) RETURNS BIT(16) AS $$
+    SELECT CASE WHEN deny THEN held & ~COALESCE(permission, B'0'::bit(16))
+        ELSE held | COALESCE(permission, B'0'::bit(16)) END
+$$ LANGUAGE SQL IMMUTABLE;
+CREATE OR REPLACE AGGREGATE effective_permission(BIT(16), BOOLEAN) (
+    SFUNC = apply_grant,
+    STYPE = BIT(16),
+    INITCOND = '0000000000000000'
+);
+
+--- bumped whenever grants or role memberships change, to invalidate cached permissions
+CREATE TABLE IF NOT EXISTS "permission_generation" (
+    "id" BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK ("id"),
+    "value" BIGINT NOT NULL DEFAULT 0
 );
+INSERT INTO "permission_generation" DEFAULT VALUES ON CONFLICT DO NOTHING;
+
+CREATE OR REPLACE FUNCTION bump_permission_generation() RETURNS TRIGGER AS $$
+BEGIN
+    UPDATE "permission_generation" SET "value" = "value" + 1;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE TRIGGER "user_has_role_changed" AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON "user_has_role"
+    FOR EACH STATEMENT EXECUTE FUNCTION bump_permission_generation();
+CREATE OR REPLACE TRIGGER "user_permissions_changed" AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON "user_permissions"
+    FOR EACH STATEMENT EXECUTE FUNCTION bump_permission_generation();
+CREATE OR REPLACE TRIGGER "role_permissions_changed" AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON "role_permissions"
+    FOR EACH STATEMENT EXECUTE FUNCTION bump_permission_generation();
+CREATE OR REPLACE TRIGGER "group_permissions_changed" AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON "group_permissions"
+    FOR EACH STATEMENT EXECUTE FUNCTION bump_permission_generation();
+CREATE OR REPLACE TRIGGER "file_permissions_changed" AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON "file_permissions"
+    FOR EACH STATEMENT EXECUTE FUNCTION bump_permission_generation();
+CREATE OR REPLACE TRIGGER "course_permissions_changed" AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON "course_permissions"
+    FOR EACH STATEMENT EXECUTE FUNCTION bump_permission_generation();
+CREATE OR REPLACE TRIGGER "template_permissions_changed" AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON "template_permissions"
+    FOR EACH STATEMENT EXECUTE FUNCTION bump_permission_generation();
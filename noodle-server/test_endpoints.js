@@ -39,6 +39,11 @@ const templatesData = [
   }
 ]
 
+const unknownFileUid = "00000000000000000000000000000000"
+
+// every operation, as granted to the admin role
+const allOperations = ["create", "read", "update", "delete", "publish", "grade", "enroll", "managePermissions", "export"]
+
 const sections = [
   {
     sectionId: 1,
@@ -69,6 +74,7 @@ const nologinTests = [
   Test(method.put, "/users/1", {}, 405, null),
   Test(method.post, "/users/1", {}, 405, null),
   Test(method.delete, "/users/1", {}, 401, null),
+  Test(method.get, "/users/1/storage", null, 401, null),
   Test(method.get, "/users/5/groups", null, 401, null),
   Test(method.patch, "/users/5/groups", {}, 405, null),
   Test(method.put, "/users/5/groups", {}, 401, null),
@@ -89,6 +95,7 @@ const nologinTests = [
   Test(method.put, "/roles/4", {}, 405, null),
   Test(method.post, "/roles/4", {}, 405, null),
   Test(method.delete, "/roles/4", {}, 401, null),
+  Test(method.get, "/groups/4/subtree", null, 401, null),
   Test(method.get, "/roles/4/users", null, 401, null),
   Test(method.patch, "/roles/4/users", {}, 405, null),
   Test(method.put, "/roles/4/users", {}, 401, null),
@@ -119,11 +126,25 @@ const nologinTests = [
   Test(method.get, "/course/1", null, 401, null),
   Test(method.put, "/course/1", { name: courses[1].name }, 401, null),
   Test(method.delete, "/course/1", null, 401, null),
+  Test(method.get, "/course/1/storage", null, 401, null),
+  Test(method.get, "/course/1/access", null, 401, null),
+  Test(method.put, "/course/1/users/1", { role: "tutor" }, 401, null),
+  Test(method.get, "/course/1/section/1/content/1/files", null, 401, null),
+  Test(method.get, "/grants/course", null, 401, null),
+  Test(method.put, "/grants/course", { userId: 1, resourceId: "1", operations: ["read"] }, 401, null),
+  Test(method.get, "/grants/course/explain?userId=1&operations=read", null, 401, null),
+  Test(method.post, "/course/1/section/1/content/1/files", { fileUid: unknownFileUid }, 401, null),
+  Test(method.delete, `/course/1/section/1/content/1/files/${unknownFileUid}`, null, 401, null),
   Test(method.get, "/templates", null, 401, null),
   Test(method.post, "/templates", { name: templatesData[0].name }, 401, null),
   Test(method.get, "/template/1", null, 401, null),
   Test(method.put, "/template/1", { name: templatesData[1].name }, 401, null),
-  Test(method.delete, "/template/1", null, 401, null)
+  Test(method.delete, "/template/1", null, 401, null),
+  Test(method.get, `/file/${unknownFileUid}/content`, null, 401, null),
+  Test(method.get, `/file/${unknownFileUid}/content?variant=thumb`, null, 401, null),
+  Test(method.put, `/file/${unknownFileUid}/content`, {}, 401, null),
+  Test(method.get, `/file/${unknownFileUid}/metadata`, null, 401, null),
+  Test(method.put, `/file/${unknownFileUid}/metadata`, { filename: "renamed.txt" }, 401, null)
 ]
 
 const users = [
@@ -193,6 +214,7 @@ const roles = [
 ]
 
 const loggedinTests = [
+  LoginAs(adminMail, adminPassword),
   Test(method.get, "/user", null, 200,
     {
       userId: DONT_CARE,
@@ -223,6 +245,7 @@ const loggedinTests = [
     }),
   Test(method.get, "/user/groups", null, 200, []),
   Test(method.get, "/user/roles", null, 200, []),
+  Test(method.get, `/users/${users[0].userId}/storage`, null, 200, { used: DONT_CARE, fileCount: DONT_CARE, quota: DONT_CARE }),
   Test(method.get, `/users/${users[0].userId}`, null, 200,
     {
       userId: users[0].userId,
@@ -323,6 +346,39 @@ const loggedinTests = [
       email: users[2].email,
     }
   ]),
+  Test(method.get, `/groups/${groups[4].groupId}/subtree`, null, 200, {
+    group: {
+      groupId: groups[4].groupId,
+      name: groups[4].name,
+      shortname: DONT_CARE,
+      kind: groups[4].kind,
+      parent: DONT_CARE,
+      children: []
+    },
+    members: [
+      {
+        userId: users[0].userId,
+        firstname: users[0].firstname,
+        lastname: users[0].lastname,
+        title: DONT_CARE,
+        email: users[0].email,
+      },
+      {
+        userId: users[2].userId,
+        firstname: users[2].firstname,
+        lastname: users[2].lastname,
+        title: DONT_CARE,
+        email: users[2].email,
+      }
+    ]
+  }),
+  Test(method.patch, `/groups/${groups[4].groupId}`, {
+    name: groups[4].name,
+    shortname: "design",
+    kind: groups[4].kind,
+    parent: groups[4].groupId
+  }, 400, null),
+  Test(method.get, "/groups/4242/subtree", null, 404, null),
   Test(method.post, "/roles", {
     name: roles[0].name,
     permissions: roles[0].permissions
@@ -418,6 +474,12 @@ const loggedinTests = [
     courseId: courses[0].courseId,
     name: courses[0].name
   }),
+  Test(method.get, `/course/${courses[0].courseId}/storage`, null, 200, { used: 0, fileCount: 0, quota: DONT_CARE }),
+  Test(method.get, `/course/${courses[0].courseId}/section/${sections[0].sectionId}/content/1/files`, null, 404, null),
+  Test(method.post, `/course/${courses[0].courseId}/section/${sections[0].sectionId}/content/1/files`, { fileUid: unknownFileUid }, 404, null),
+  Test(method.put, `/course/${courses[0].courseId}/section/${sections[0].sectionId}/content/1/files`, [], 404, null),
+  Test(method.post, `/course/${courses[0].courseId}/section/${sections[0].sectionId}/content`, { type: "link", content: { url: "javascript:alert(1)" } }, 400, { error: "invalidUrl", field: "url" }),
+  Test(method.post, `/course/${courses[0].courseId}/section/${sections[0].sectionId}/content`, { type: "quiz", content: { question: "2 + 2?", options: [{ text: "4" }, { text: "5" }] } }, 400, { error: "noCorrectOption" }),
   Test(method.put, `/course/${courses[1].courseId}`, { name: courses[1].name }, 200, {
     courseId: courses[1].courseId,
     name: courses[1].name
@@ -453,28 +515,480 @@ const loggedinTests = [
   }),
   Test(method.delete, `/template/${templatesData[1].templateId}`, null, 200, null),
   Test(method.get, `/template/${templatesData[1].templateId}`, null, 404, null),
-  Test(method.get, "/templates", null, 200, [])
+  Test(method.get, "/templates", null, 200, []),
+  Test(method.get, "/grants/course", null, 200, [{ userId: null, roleId: DONT_CARE, resourceId: null, operations: allOperations, deny: false }]),
+  Test(method.get, "/grants/course?resourceId=abc", null, 400, null),
+  Test(method.put, "/grants/course", { userId: 1, roleId: 1, operations: ["read"] }, 400, null),
+  Test(method.put, "/grants/course", { userId: 1, operations: 1024 }, 400, null),
+  Test(method.put, "/grants/course", { userId: 1, operations: ["fly"] }, 422, null),
+  Test(method.put, "/grants/course", { userId: 1, resourceId: "42", operations: ["read"] }, 404, null),
+  Test(method.put, "/grants/template", { userId: 1, operations: ["read"] }, 201, { userId: 1, roleId: null, resourceId: null, operations: ["read"], deny: false }),
+  Test(method.put, "/grants/template", { userId: 1, operations: ["read", "update"] }, 200, { userId: 1, roleId: null, resourceId: null, operations: ["read", "update"], deny: false }),
+  Test(method.delete, "/grants/template?userId=1", null, 200, null),
+  Test(method.delete, "/grants/template?userId=1", null, 404, null),
+  Test(method.get, "/grants/course/explain?userId=1&operations=create", null, 200, {
+    granted: true,
+    grants: [{ source: "role", scope: "type", roleId: DONT_CARE, roleName: DONT_CARE, resourceId: null, operations: allOperations, deny: false, matches: true }],
+    fallbacks: []
+  }),
+  Test(method.get, "/grants/course/explain?userId=1&operations=0", null, 400, null),
+  Test(method.get, "/grants/course/explain?userId=1&operations=fly", null, 400, null),
+  Test(method.get, "/grants/file/explain?userId=1&resourceId=abc&operations=read", null, 400, null),
+  Test(method.get, "/grants/course/explain?userId=4242&operations=read", null, 404, null),
+  Test(method.get, "/files", null, 200, []),
+  Test(method.get, "/files?type=image/*&search=logo", null, 200, []),
+  Test(method.get, "/files?limit=0", null, 400, null),
+  Test(method.post, "/files?context=avatar", { filename: "notes.txt", type: "text/plain", data: "aGVsbG8" }, 415, { error: "typeNotAllowed", type: "text/plain", allowedTypes: DONT_CARE }),
+  Test(method.post, "/files", { filename: "notes.txt", type: "image/png", data: "aGVsbG8" }, 415, { error: "typeMismatch", declaredType: "image/png", detectedType: "text/plain" })
 ]
 
-async function runTests() {
-  let failedTests = []
+// role permissions decide what the users of a role may do, so editing them changes access
+const roleEditTests = [
+  Test(method.post, "/templates", { name: "Template 2" }, 200, { templateId: 2, name: "Template 2" }),
+  Test(method.patch, `/roles/${roles[0].roleId}`, {
+    name: roles[0].name,
+    permissions: [{ subject: "template", ops: ["read"], ids: ["2"] }]
+  }, 200, DONT_CARE),
+  Test(method.patch, `/roles/${roles[0].roleId}`, {
+    name: roles[0].name,
+    permissions: [{ subject: "file", ops: ["read"], ids: ["notAUuid"] }]
+  }, 400, null),
+  Test(method.get, "/grants/template?roleId=1", null, 200, [{ userId: null, roleId: 1, resourceId: "2", operations: ["read"], deny: false }]),
+  LoginAs(users[2].email, users[2].password),
+  Test(method.get, "/template/2", null, 200, { templateId: 2, name: "Template 2" }),
+  Test(method.patch, `/roles/${roles[0].roleId}`, {
+    name: roles[0].name,
+    permissions: [{ subject: "template", ops: allOperations, ids: null }]
+  }, 401, null),
+  LoginAs(adminMail, adminPassword),
+  Test(method.patch, `/roles/${roles[0].roleId}`, { name: roles[0].name, permissions: [] }, 200, DONT_CARE),
+  LoginAs(users[2].email, users[2].password),
+  Test(method.get, "/template/2", null, 401, null),
+  // the members of a role get its rights, so only those who may update it and hold them change them
+  Test(method.get, `/roles/${roles[0].roleId}/users`, null, 401, null),
+  Test(method.post, `/roles/${roles[0].roleId}/users`, [users[2].userId], 401, null),
+  Test(method.put, `/roles/${roles[0].roleId}/users`, [users[2].userId], 401, null),
+  Test(method.delete, `/roles/${roles[0].roleId}/users`, [users[2].userId], 401, null),
+  Test(method.post, `/users/${users[2].userId}/roles`, [roles[0].roleId], 401, null),
+  Test(method.delete, `/roles/${roles[0].roleId}`, null, 401, null)
+]
 
-  for (const test of nologinTests) {
-    let result = test.run()
-    if (result.failed) {
-      failedTests.push(
-        {
-          expected: result.expected,
-          actual: result.actual,
-          request: test
-        }
-      )
-    }
-  }
+// the members of a sub-group are members of the groups above it, in the courses of those as well
+const subgroupTests = [
+  LoginAs(adminMail, adminPassword),
+  Test(method.delete, `/groups/${groups[4].groupId}/users`, [users[2].userId], 200, DONT_CARE),
+  Test(method.post, "/groups", { name: "Frontend", shortname: "fe", kind: "learning", parent: groups[4].groupId }, 201, {
+    groupId: DONT_CARE,
+    name: "Frontend",
+    shortname: "fe",
+    kind: "learning",
+    parent: groups[4].groupId
+  }),
+  Test(method.post, `/course/${courses[0].courseId}/groups`, [groups[4].groupId], 200, null),
+  // a membership that only starts later doesn't count yet
+  Test(method.post, "/groups/:group/users?validFrom=2999-01-01T00:00:00Z", [users[2].userId], 201, DONT_CARE),
+  Test(method.get, `/groups/${groups[4].groupId}/subtree`, null, 200, {
+    group: DONT_CARE,
+    members: [
+      { userId: users[0].userId, firstname: users[0].firstname, lastname: users[0].lastname, title: DONT_CARE, email: users[0].email }
+    ]
+  }),
+  LoginAs(users[2].email, users[2].password),
+  Test(method.get, `/course/${courses[0].courseId}/access`, null, 401, null),
+  LoginAs(adminMail, adminPassword),
+  Test(method.delete, "/groups/:group/users", [users[2].userId], 200, DONT_CARE),
+  Test(method.post, "/groups/:group/users", [users[2].userId], 201, DONT_CARE),
+  Test(method.get, `/groups/${groups[4].groupId}/subtree`, null, 200, {
+    group: DONT_CARE,
+    members: [
+      { userId: users[0].userId, firstname: users[0].firstname, lastname: users[0].lastname, title: DONT_CARE, email: users[0].email },
+      { userId: users[2].userId, firstname: users[2].firstname, lastname: users[2].lastname, title: DONT_CARE, email: users[2].email }
+    ]
+  }),
+  LoginAs(users[2].email, users[2].password),
+  Test(method.get, `/course/${courses[0].courseId}/access`, null, 200, { role: "student", capabilities: ["viewMembers"] }),
+  Test(method.patch, `/groups/${groups[4].groupId}`, {
+    name: groups[4].name,
+    shortname: "design",
+    kind: groups[4].kind,
+    parent: groups[4].groupId
+  }, 401, null),
+  LoginAs(adminMail, adminPassword),
+  Test(method.put, `/course/${courses[0].courseId}/groups`, [], 200, null),
+  Test(method.delete, "/groups/:group", null, 200, null),
+  Test(method.post, `/groups/${groups[4].groupId}/users`, [users[2].userId], 201, DONT_CARE)
+]
+
+// course roles decide what members may do inside a course, next to the course grants
+const courseRoleTests = [
+  LoginAs(adminMail, adminPassword),
+  Test(method.post, `/course/${courses[0].courseId}/sections`, { headline: "Solutions", hidden: true }, 200, DONT_CARE),
+  Test(method.post, `/course/${courses[0].courseId}/users`, [users[2].userId], 200, null),
+  Test(method.put, `/course/${courses[0].courseId}/users/${users[2].userId}`, { role: "lecturer" }, 400, null),
+  LoginAs(users[2].email, users[2].password),
+  Test(method.get, `/course/${courses[0].courseId}/access`, null, 200, { role: "student", capabilities: ["viewMembers"] }),
+  Test(method.post, `/course/${courses[0].courseId}/sections`, { headline: "By a student" }, 401, null),
+  Test(method.put, `/course/${courses[0].courseId}/users/${users[2].userId}`, { role: "tutor" }, 401, null),
+  LoginAs(adminMail, adminPassword),
+  Test(method.put, `/course/${courses[0].courseId}/users/${users[2].userId}`, { role: "tutor" }, 200, null),
+  LoginAs(users[2].email, users[2].password),
+  Test(method.get, `/course/${courses[0].courseId}/access`, null, 200, { role: "tutor", capabilities: ["seeHidden", "grade", "viewMembers"] }),
+  Test(method.post, `/course/${courses[0].courseId}/sections`, { headline: "By a tutor" }, 401, null),
+  LoginAs(adminMail, adminPassword),
+  Test(method.put, `/course/${courses[0].courseId}/lecturers`, [users[2].userId], 200, null),
+  LoginAs(users[2].email, users[2].password),
+  Test(method.get, `/course/${courses[0].courseId}/access`, null, 200, {
+    role: "lecturer",
+    capabilities: ["editContent", "publish", "seeHidden", "grade", "manageEnrollment", "viewMembers"]
+  }),
+  Test(method.post, `/course/${courses[0].courseId}/sections`, { headline: "By a lecturer" }, 200, DONT_CARE),
+  Test(method.put, `/course/${courses[0].courseId}/lecturers`, [users[2].userId], 401, null),
+  Test(method.put, `/course/${courses[0].courseId}`, { name: "Renamed by a lecturer", shortname: "" }, 401, null)
+]
+
+// every kind of content element, as the editors of a course and its other members see them
+const contentUrl = `/course/${courses[0].courseId}/section/${sections[0].sectionId}/content`
+const contentElements = [
+  { type: "markdown", content: "# Welcome" },
+  { type: "file", content: { caption: "Slides" } },
+  { type: "video", content: { url: "https://example.com/intro.mp4", title: "Intro" } },
+  { type: "link", content: { url: "https://example.com", title: null } },
+  {
+    type: "quiz",
+    content: { question: "2 + 2?", options: [{ text: "4", correct: true }, { text: "5", correct: false }], multipleChoice: false }
+  },
+  { type: "divider" }
+].map((kind, i) => ({ contentId: i + 1, parentSectionId: sections[0].sectionId, orderIndex: i, ...kind, files: [] }))
+const withoutSolutions = contentElements.map(e => e.type != "quiz" ? e : {
+  ...e,
+  content: { ...e.content, options: e.content.options.map(o => ({ text: o.text })) }
+})
+// rows from before there were kinds hold free-form strings
+const legacyElements = [
+  { contentId: 7, parentSectionId: sections[0].sectionId, orderIndex: 6, type: "video", content: { url: "https://example.com/old.mp4", title: null }, files: [] },
+  { contentId: 8, parentSectionId: sections[0].sectionId, orderIndex: 7, type: "markdown", content: "Plain old text", files: [] },
+  { contentId: 9, parentSectionId: sections[0].sectionId, orderIndex: 8, type: "markdown", content: "not a url", files: [] },
+  { contentId: 10, parentSectionId: sections[0].sectionId, orderIndex: 9, type: "file", content: { caption: null }, files: [] }
+]
+
+// the hidden "Solutions" section of courseRoleTests, with one element after those above
+const hiddenContentUrl = `/course/${courses[0].courseId}/section/2/content`
+const hiddenContentId = contentElements.length + legacyElements.length + 1
+
+const contentTests = [
+  LoginAs(adminMail, adminPassword),
+  ...contentElements.map(e => Test(method.post, contentUrl, { type: e.type, content: e.content, orderIndex: e.orderIndex }, 200, e)),
+  Test(method.get, contentUrl, null, 200, contentElements),
+  Sql(`INSERT INTO content_element(section_id, order_index, type, content) VALUES
+    (${sections[0].sectionId}, 6, 'video', ' https://example.com/old.mp4 '),
+    (${sections[0].sectionId}, 7, 'text', 'Plain old text'),
+    (${sections[0].sectionId}, 8, 'link', 'not a url'),
+    (${sections[0].sectionId}, 9, 'image', NULL)`),
+  Test(method.get, contentUrl, null, 200, [...contentElements, ...legacyElements]),
+  Test(method.post, hiddenContentUrl, { type: "markdown", content: "4" }, 200, DONT_CARE),
+  Test(method.get, `${hiddenContentUrl}/${hiddenContentId}/files`, null, 200, []),
+  Test(method.put, `/course/${courses[0].courseId}/lecturers`, [], 200, null),
+  Test(method.put, `/course/${courses[0].courseId}/users/${users[2].userId}`, { role: "student" }, 200, null),
+  LoginAs(users[2].email, users[2].password),
+  Test(method.get, contentUrl, null, 200, [...withoutSolutions, ...legacyElements]),
+  Test(method.get, `${hiddenContentUrl}/${hiddenContentId}/files`, null, 404, null),
+  LoginAs(adminMail, adminPassword),
+  Test(method.put, `/course/${courses[0].courseId}/lecturers`, [users[2].userId], 200, null)
+]
+
+// deny grants take operations away again: one on a resource beats a type-wide allow and a user's
+// own grant beats the ones of their roles, even those on the resource
+const denyTests = [
+  LoginAs(adminMail, adminPassword),
+  Test(method.post, "/templates", { name: "Template 3" }, 200, { templateId: 3, name: "Template 3" }),
+  Test(method.patch, `/roles/${roles[0].roleId}`, {
+    name: roles[0].name,
+    permissions: [{ subject: "template", ops: ["read"] }, { subject: "template", ops: ["read"], ids: ["2"], deny: true }]
+  }, 200, DONT_CARE),
+  Test(method.get, "/grants/template?roleId=1", null, 200, [
+    { userId: null, roleId: 1, resourceId: null, operations: ["read"], deny: false },
+    { userId: null, roleId: 1, resourceId: "2", operations: ["read"], deny: true }
+  ]),
+  LoginAs(users[2].email, users[2].password),
+  Test(method.get, "/templates", null, 200, [{ templateId: 3, name: "Template 3" }]),
+  Test(method.get, "/template/2", null, 401, null),
+  Test(method.get, "/template/3", null, 200, { templateId: 3, name: "Template 3" }),
+  LoginAs(adminMail, adminPassword),
+  Test(method.put, "/grants/template", { userId: users[2].userId, resourceId: "2", operations: ["read"] }, 201, DONT_CARE),
+  Test(method.put, "/grants/template", { userId: users[2].userId, operations: ["read"], deny: true }, 201, {
+    userId: users[2].userId, roleId: null, resourceId: null, operations: ["read"], deny: true
+  }),
+  Test(method.get, `/grants/template/explain?userId=${users[2].userId}&resourceId=2&operations=read`, null, 200, {
+    granted: true,
+    grants: [
+      { source: "role", scope: "type", roleId: 1, roleName: roles[0].name, resourceId: null, operations: ["read"], deny: false, matches: true },
+      { source: "role", scope: "resource", roleId: 1, roleName: roles[0].name, resourceId: "2", operations: ["read"], deny: true, matches: true },
+      { source: "user", scope: "type", roleId: null, roleName: null, resourceId: null, operations: ["read"], deny: true, matches: true },
+      { source: "user", scope: "resource", roleId: null, roleName: null, resourceId: "2", operations: ["read"], deny: false, matches: true }
+    ],
+    fallbacks: []
+  }),
+  LoginAs(users[2].email, users[2].password),
+  Test(method.get, "/template/2", null, 200, { templateId: 2, name: "Template 2" }),
+  Test(method.get, "/template/3", null, 401, null),
+  Test(method.get, "/templates", null, 200, [{ templateId: 2, name: "Template 2" }]),
+  LoginAs(adminMail, adminPassword),
+  Test(method.patch, `/roles/${roles[0].roleId}`, {
+    name: roles[0].name,
+    permissions: [
+      { subject: "template", ops: ["read"] },
+      { subject: "template", ops: ["read"], ids: ["2"], deny: true },
+      { subject: "template", ops: ["read"], ids: ["3"] }
+    ]
+  }, 200, DONT_CARE),
+  LoginAs(users[2].email, users[2].password),
+  Test(method.get, "/template/3", null, 401, null),
+  LoginAs(adminMail, adminPassword),
+  Test(method.delete, `/grants/template?userId=${users[2].userId}&deny=true`, null, 200, null),
+  Test(method.delete, `/grants/template?userId=${users[2].userId}&resourceId=2`, null, 200, null),
+  Test(method.put, "/grants/template", { userId: 1, resourceId: "3", operations: ["delete"], deny: true }, 201, DONT_CARE),
+  Test(method.delete, "/template/3", null, 401, null),
+  // managing the permissions of all templates is enough to lift a deny on yourself again
+  Test(method.delete, "/grants/template?userId=1&resourceId=3&deny=true", null, 200, null),
+  Test(method.delete, "/grants/template?userId=1&resourceId=3&deny=true", null, 404, null),
+  Test(method.patch, `/roles/${roles[0].roleId}`, { name: roles[0].name, permissions: [] }, 200, DONT_CARE)
+]
+
+const validityTests = [
+  LoginAs(adminMail, adminPassword),
+  Test(method.put, "/grants/template", {
+    userId: users[2].userId, resourceId: "2", operations: ["read"], validUntil: "2000-01-01T00:00:00Z"
+  }, 201, {
+    userId: users[2].userId, roleId: null, resourceId: "2", operations: ["read"], deny: false, validUntil: "2000-01-01T00:00:00Z"
+  }),
+  LoginAs(users[2].email, users[2].password),
+  Test(method.get, "/template/2", null, 401, null),
+  LoginAs(adminMail, adminPassword),
+  Test(method.put, "/grants/template", {
+    userId: users[2].userId, resourceId: "2", operations: ["read"], validFrom: "2000-01-01T00:00:00Z", validUntil: "2999-01-01T00:00:00Z"
+  }, 200, {
+    userId: users[2].userId, roleId: null, resourceId: "2", operations: ["read"], deny: false,
+    validFrom: "2000-01-01T00:00:00Z", validUntil: "2999-01-01T00:00:00Z"
+  }),
+  LoginAs(users[2].email, users[2].password),
+  Test(method.get, "/template/2", null, 200, { templateId: 2, name: "Template 2" }),
+  LoginAs(adminMail, adminPassword),
+  Test(method.put, "/grants/template", {
+    userId: users[2].userId, resourceId: "2", operations: ["read"], validFrom: "2999-01-01T00:00:00Z", validUntil: "2000-01-01T00:00:00Z"
+  }, 400, null),
+  Test(method.post, `/users/${users[2].userId}/roles?validFrom=2999-01-01T00:00:00Z&validUntil=2000-01-01T00:00:00Z`, [roles[0].roleId], 400, null),
+  // a role that only starts later is not among the roles or groups of the user yet
+  Test(method.post, `/users/${users[2].userId}/roles?validFrom=2999-01-01T00:00:00Z`, [roles[0].roleId], 201, DONT_CARE),
+  Test(method.get, `/users/${users[2].userId}/roles`, null, 200, []),
+  Test(method.get, `/users/${users[2].userId}/groups`, null, 200, [{
+    groupId: groups[4].groupId,
+    name: groups[4].name,
+    shortname: DONT_CARE,
+    kind: groups[4].kind,
+    parent: DONT_CARE
+  }]),
+  Test(method.delete, `/users/${users[2].userId}/roles`, [roles[0].roleId], 200, DONT_CARE),
+  Test(method.delete, `/grants/template?userId=${users[2].userId}&resourceId=2`, null, 200, null)
+]
+
+// memberships replaced by several at once, and member lists without those that ended or haven't
+// started yet
+const membershipTests = [
+  LoginAs(adminMail, adminPassword),
+  Test(method.post, "/roles", { name: "Tutor", permissions: [] }, 201, DONT_CARE),
+  Test(method.put, `/users/${users[2].userId}/roles`, [roles[0].roleId, 2], 200, [roles[0].roleId, 2]),
+  Test(method.get, `/users/${users[2].userId}/roles`, null, 200, [
+    { roleId: roles[0].roleId, name: roles[0].name, permissions: DONT_CARE, group: roles[0].group },
+    { roleId: 2, name: "Tutor", permissions: [], group: DONT_CARE }
+  ]),
+  Test(method.put, `/users/${users[2].userId}/roles`, [roles[0].roleId], 200, [roles[0].roleId]),
+  Test(method.post, "/roles/2/users?validUntil=2000-01-01T00:00:00Z", [users[2].userId], 201, DONT_CARE),
+  Test(method.get, "/roles/2/users", null, 200, []),
+  Test(method.delete, "/roles/2", null, 200, null),
+  Test(method.post, "/groups", { name: "Archive", shortname: "ar", kind: "learning" }, 201, DONT_CARE),
+  Test(method.put, "/groups/:group/users", [users[0].userId, users[2].userId], 200, DONT_CARE),
+  Test(method.get, "/groups/:group/users", null, 200, [{ userId: users[0].userId, firstname: users[0].firstname, lastname: users[0].lastname, title: DONT_CARE, email: users[0].email }, { userId: users[2].userId, firstname: users[2].firstname, lastname: users[2].lastname, title: DONT_CARE, email: users[2].email }]),
+  Test(method.delete, "/groups/:group/users", [users[0].userId], 200, DONT_CARE),
+  Test(method.post, "/groups/:group/users?validUntil=2000-01-01T00:00:00Z", [users[0].userId], 201, DONT_CARE),
+  Test(method.get, "/groups/:group/users", null, 200, [{ userId: users[2].userId, firstname: users[2].firstname, lastname: users[2].lastname, title: DONT_CARE, email: users[2].email }]),
+  // the role group of the user went with replacing their roles
+  Test(method.put, `/users/${users[2].userId}/groups`, [groups[4].groupId, roles[0].group], 200, DONT_CARE),
+  Test(method.get, `/users/${users[2].userId}/groups`, null, 200, [
+    { groupId: groups[4].groupId, name: groups[4].name, shortname: DONT_CARE, kind: groups[4].kind, parent: DONT_CARE },
+    { groupId: roles[0].group, name: roles[0].name, shortname: DONT_CARE, kind: "role", parent: null }
+  ]),
+  Test(method.delete, "/groups/:group", null, 200, null)
+]
 
-  await login(adminMail, adminPassword)
+// the generic resource endpoints page their lists and check deletes on the resource itself
+const resourceTests = [
+  LoginAs(adminMail, adminPassword),
+  Test(method.post, "/templates", { name: "Template 4" }, 200, { templateId: 4, name: "Template 4" }),
+  Test(method.post, "/templates", { name: " " }, 400, null),
+  Test(method.get, "/templates?limit=1&offset=1", null, 200, [{ templateId: 3, name: "Template 3" }]),
+  Test(method.get, "/templates?limit=0", null, 200, []),
+  Test(method.get, "/templates?limit=-1", null, 400, null),
+  Test(method.get, "/courses?offset=-1", null, 400, null),
+  Sql(`INSERT INTO content_section(uid, template_id, headline, order_index) VALUES (900, 3, 'Intro', 0)`),
+  Test(method.get, "/template/3/section/900", null, 200, {
+    sectionId: 900, parentCourseId: null, templateId: 3, headline: "Intro", orderIndex: 0, hidden: false
+  }),
+  Test(method.get, "/template/4/section/900", null, 404, null),
+  Test(method.put, "/template/3/section/900", { headline: "Basics", orderIndex: 1 }, 200, {
+    sectionId: 900, parentCourseId: null, templateId: 3, headline: "Basics", orderIndex: 1, hidden: false
+  }),
+  Test(method.put, "/template/3/section/900", { headline: " " }, 400, null),
+  Test(method.post, "/template/3/section/900/content", { type: "markdown", content: "# Basics" }, 200, DONT_CARE),
+  Test(method.post, "/template/3/section/900/content", { type: "markdown", content: " " }, 400, DONT_CARE),
+  Test(method.post, "/template/4/section/900/content", { type: "markdown", content: "# Basics" }, 400, null),
+  Test(method.get, "/template/3/section/900/content", null, 200, [
+    { contentId: DONT_CARE, parentSectionId: 900, orderIndex: 0, type: "markdown", content: "# Basics", files: [] }
+  ]),
+  Test(method.put, "/grants/template", { userId: users[2].userId, resourceId: "4", operations: ["delete"] }, 201, DONT_CARE),
+  LoginAs(users[2].email, users[2].password),
+  Test(method.get, "/template/3/sections", null, 401, null),
+  Test(method.post, "/template/3/section/900/content", { type: "markdown", content: "# Basics" }, 401, null),
+  Test(method.delete, "/template/3/section/900", null, 401, null),
+  Test(method.delete, "/template/3", null, 401, null),
+  Test(method.delete, "/template/4", null, 200, null),
+  Test(method.get, "/template/4", null, 401, null),
+  LoginAs(adminMail, adminPassword),
+  Test(method.get, "/template/4", null, 404, null),
+  Test(method.delete, "/template/3/section/900/content", null, 200, null),
+  Test(method.get, "/template/3/section/900/content", null, 200, []),
+  Test(method.delete, "/template/3/section/900", null, 200, null),
+  Test(method.delete, "/template/3/section/900", null, 404, null)
+]
+
+// every login is a session of its own that can be ended from the others
+const mediaTests = [
+  LoginAs(adminMail, adminPassword),
+  Test(method.post, "/files/upload", uploadForm("hello.txt", "text/plain", "hello noodle"), 201, {
+    uid: DONT_CARE, filename: "hello.txt", type: "text/plain", size: 12, checksum: DONT_CARE, ownerId: 1, createdAt: DONT_CARE, lastModified: DONT_CARE
+  }),
+  Test(method.get, "/file/:file/content", null, 200, "hello noodle"),
+  Test(method.get, "/file/:file/content", null, 304, null, { "If-None-Match": ":etag" }),
+  Test(method.get, "/file/:file/content", null, 200, "hello noodle", { "If-None-Match": "\"outdated\"" }),
+  Test(method.get, "/file/:file/content", null, 206, "hello", { "Range": "bytes=0-4" }),
+  Test(method.get, "/file/:file/content", null, 206, "noodle", { "Range": "bytes=6-" }),
+  Test(method.get, "/file/:file/content", null, 416, null, { "Range": "bytes=100-" }),
+  // renaming and deleting go through the same checks as for the other resources
+  Test(method.get, "/file/:file/metadata", null, 200, {
+    uid: DONT_CARE, filename: "hello.txt", type: "text/plain", size: 12, checksum: DONT_CARE, ownerId: 1, createdAt: DONT_CARE, lastModified: DONT_CARE
+  }),
+  Test(method.put, "/file/:file/metadata", { filename: "" }, 400, null),
+  Test(method.put, "/file/:file/metadata", { filename: "greeting.txt" }, 200, {
+    uid: DONT_CARE, filename: "greeting.txt", type: "text/plain", size: 12, checksum: DONT_CARE, ownerId: 1, createdAt: DONT_CARE, lastModified: DONT_CARE
+  }),
+  Test(method.get, "/files?search=greeting", null, 200, [{
+    uid: DONT_CARE, filename: "greeting.txt", type: "text/plain", size: 12, checksum: DONT_CARE, ownerId: 1, createdAt: DONT_CARE, lastModified: DONT_CARE
+  }]),
+  LoginAs(users[2].email, users[2].password),
+  Test(method.get, "/file/:file/metadata", null, 401, null),
+  Test(method.delete, "/file/:file", null, 401, null),
+  LoginAs(adminMail, adminPassword),
+  Test(method.delete, "/file/:file", null, 200, null),
+  Test(method.get, "/file/:file/metadata", null, 404, null),
+  Test(method.delete, "/file/:file", null, 404, null),
+  Test(method.post, "/files/upload", new FormData(), 400, null)
+]
+
+const sessionTests = [
+  LoginAs(adminMail, adminPassword),
+  Test(method.get, "/user/sessions", null, 200, DONT_CARE),
+  Test(method.delete, "/user/sessions", null, 200, null),
+  Test(method.delete, "/user/sessions/999999", null, 404, null),
+  Test(method.delete, `/users/${users[2].userId}/sessions`, null, 200, null),
+  Test(method.post, "/logout", null, 200, null),
+  Test(method.get, "/user/sessions", null, 401, null),
+  Test(method.post, "/logout", null, 401, null),
+  LoginAs(users[2].email, users[2].password),
+  Test(method.delete, "/users/1/sessions", null, 401, null)
+]
+
+const tokenTests = [
+  LoginAs(adminMail, adminPassword),
+  Test(method.post, "/user/tokens", { name: " " }, 400, null),
+  Test(method.post, "/user/tokens", { name: "expired", expiresAt: "2020-01-01T00:00:00Z" }, 400, null),
+  Test(method.get, "/user/tokens", null, 200, DONT_CARE),
+  Test(method.delete, "/user/tokens/999999", null, 404, null),
+  UseToken({ name: "read templates", types: ["template"], operations: ["read"] }),
+  Test(method.get, "/templates", null, 200, DONT_CARE),
+  Test(method.post, "/templates", { name: "Template 5" }, 401, null),
+  Test(method.get, "/users", null, 200, []),
+  Test(method.get, "/user/tokens", null, 401, null),
+  Test(method.post, "/user/tokens", { name: "wider" }, 401, null),
+  Test(method.delete, "/user/sessions", null, 401, null),
+  UseToken("noodle_unknown"),
+  Test(method.get, "/templates", null, 401, null)
+]
 
-  for (const test of loggedinTests) {
+const totpTests = [
+  LoginAs(adminMail, adminPassword),
+  Test(method.get, "/user/totp", null, 200, { enabled: false, required: false, recoveryCodesLeft: 0 }),
+  Test(method.post, "/user/totp/confirm", { code: "000000" }, 400, null),
+  Test(method.post, "/user/totp", null, 201, { secret: DONT_CARE, uri: DONT_CARE }),
+  Test(method.post, "/user/totp/confirm", { code: "000000" }, 400, null),
+  Test(method.post, "/user/totp/recovery-codes", { code: "00000-00000" }, 400, null),
+  Test(method.delete, "/user/totp", { code: "000000" }, 404, null),
+  Test(method.delete, "/users/1/totp", null, 200, null),
+  Test(method.delete, "/users/1/totp", null, 404, null),
+  Test(method.post, "/login/totp", { code: "000000" }, 401, null),
+  Test(method.get, "/roles/1/totp", null, 200, { required: false }),
+  Test(method.get, "/roles/999999/totp", null, 404, null),
+  Test(method.put, "/roles/999999/totp", { required: true }, 404, null),
+  Test(method.put, "/roles/1/totp", { required: true }, 200, null),
+  LoginAs(users[2].email, users[2].password),
+  Test(method.get, "/templates", null, 403, null),
+  Test(method.get, "/user/totp", null, 200, { enabled: false, required: true, recoveryCodesLeft: 0 }),
+  Test(method.delete, "/users/1/totp", null, 403, null),
+  LoginAs(adminMail, adminPassword),
+  Test(method.put, "/roles/1/totp", { required: false }, 200, null),
+  LoginAs(users[2].email, users[2].password),
+  Test(method.get, "/templates", null, 200, DONT_CARE),
+  Test(method.get, "/roles/1/totp", null, 401, null)
+]
+
+const emailTests = [
+  LoginAs(adminMail, adminPassword),
+  Test(method.post, "/password-reset", { email: adminMail }, 202, null),
+  Test(method.post, "/password-reset", { email: "nobody@example.com" }, 202, null),
+  Test(method.post, "/password-reset/confirm", { token: "unknown", password: "weak" }, 400, {
+    tooShort: true, uppercaseMissing: true, lowercaseMissing: false, digitMissing: true, specialMissing: true
+  }),
+  Test(method.post, "/password-reset/confirm", { token: "unknown", password: "Another!Passw0rd" }, 401, null),
+  Test(method.post, "/email-verification/confirm", { token: "unknown" }, 401, null),
+  Test(method.post, "/users/1/email-verification", null, 409, null),
+  Test(method.post, "/users/999999/email-verification", null, 404, null),
+  LoginAs(users[2].email, users[2].password),
+  Test(method.post, "/users/1/email-verification", null, 401, null)
+]
+
+const passwordTests = [
+  // the admin password does not pass the rules for a new one, so its hash is put back at the end
+  Sql(`CREATE TABLE admin_password AS SELECT password FROM "user" WHERE email = '${adminMail}'`),
+  LoginAs(adminMail, adminPassword),
+  Test(method.put, "/user/password", { currentPassword: "wrong", newPassword: "Another!Passw0rd" }, 403, null),
+  Test(method.put, "/user/password", { currentPassword: adminPassword, newPassword: "weak" }, 400, {
+    tooShort: true, uppercaseMissing: true, lowercaseMissing: false, digitMissing: true, specialMissing: true
+  }),
+  Test(method.put, "/user/password", { currentPassword: adminPassword, newPassword: "Another!Passw0rd" }, 200, null),
+  Test(method.get, "/user", null, 200, DONT_CARE),
+  LoginAs(adminMail, "Another!Passw0rd"),
+  Test(method.get, "/user", null, 200, DONT_CARE),
+  Sql(`UPDATE "user" SET password = (SELECT password FROM admin_password) WHERE email = '${adminMail}';
+DROP TABLE admin_password`),
+  LoginAs(adminMail, adminPassword),
+  Test(method.get, "/user", null, 200, DONT_CARE)
+]
+
+// the suites in the order they run, a new one only has to be added here
+const suites = [nologinTests, loggedinTests, roleEditTests, subgroupTests, courseRoleTests, contentTests, denyTests, validityTests, membershipTests, resourceTests, mediaTests, sessionTests, tokenTests, totpTests, emailTests, passwordTests]
+
+async function runTests() {
+  let failedTests = []
+
+  for (const test of suites.flat()) {
     let result = await test.run()
     if (result.failed) {
       failedTests.push(
@@ -487,7 +1001,11 @@ async function runTests() {
     }
   }
 
-  console.log(`Ran ${nologinTests.length + loggedinTests.length} Tests. ${failedTests.length} failed.`)
+  const testCount = suites
+    .flat()
+    .filter(t => t.method != null)
+    .length
+  console.log(`Ran ${testCount} Tests. ${failedTests.length} failed.`)
   for (const test of failedTests) {
     console.log(`Test ${test.request.method} ${test.request.url}
 \texpected:
@@ -502,13 +1020,34 @@ async function runTests() {
 const BASE_URL = "http://localhost:3000"
 
 let sessionCookie = null
+let bearerToken = null
+// the uid of the file and the id of the group a test created last and the ETag of the last response
+// that had one, they replace ":file" and ":group" in the urls and ":etag" in the request headers of
+// the tests after
+let lastFileUid = null
+let lastGroupId = null
+let lastEtag = null
+
+// whether a response body is the expected one, DONT_CARE stands for any value, also in nested
+// objects and arrays
+function matches(expected, actual) {
+  if (expected == DONT_CARE) {
+    return true
+  }
+  if (typeof expected != 'object' || expected == null || typeof actual != 'object' || actual == null) {
+    return _.isEqual(expected, actual)
+  }
+  const expectedKeys = Object.keys(expected)
+  return _.isEqual(expectedKeys, Object.keys(actual)) && expectedKeys.every(k => matches(expected[k], actual[k]))
+}
 
 function Test(
   method,
   url,
   requestBody,
   expectedResponseCode,
-  expectedResponseBody) {
+  expectedResponseBody,
+  requestHeaders = {}) {
 
   return {
     method,
@@ -526,14 +1065,26 @@ function Test(
           "Content-Type": "application/json"
         }
       }
+      for (const [name, value] of Object.entries(requestHeaders)) {
+        requestOpts.headers[name] = value.replace(":etag", lastEtag)
+      }
+
+      if (bearerToken !== null) {
+        requestOpts.headers['Authorization'] = `Bearer ${bearerToken}`
+      }
 
       if (self.requestBody === null) {
         requestOpts.headers['Content-Type'] = undefined
+      } else if (self.requestBody instanceof FormData) {
+        // fetch sets the multipart content type with its boundary
+        delete requestOpts.headers['Content-Type']
       } else {
         requestOpts.body = JSON.stringify(requestBody)
       }
 
-      let response = await fetch(BASE_URL + self.url, requestOpts)
+      const url = self.url.replace(":file", lastFileUid).replace(":group", lastGroupId)
+      let response = await fetch(BASE_URL + url, requestOpts)
+      lastEtag = response.headers.get("etag") ?? lastEtag
       let result = {
         failed: false, expected: {
           responseCode: self.expectedResponseCode,
@@ -546,27 +1097,28 @@ function Test(
       const status = response.status
       let body = null
 
+      const text = await response.text()
       try {
-        body = await response.json()
-      } catch { }
+        body = JSON.parse(text)
+      } catch {
+        // plain text is compared as is where it is expected
+        if (typeof self.expectedResponseBody == 'string') {
+          body = text
+        }
+      }
+      if (status == 201 && body?.uid) {
+        lastFileUid = body.uid
+      }
+      if (status == 201 && body?.groupId) {
+        lastGroupId = body.groupId
+      }
 
       let bodyMatches = true
       let statusMatches = self.expectedResponseCode == DONT_CARE || self.expectedResponseCode == status
 
       if (self.expectedResponseBody != DONT_CARE) {
         if (self.expectedResponseBody != null && body != null) {
-          const expectedKeys = Object.keys(self.expectedResponseBody)
-          const actualKeys = Object.keys(body)
-
-          bodyMatches = _.isEqual(expectedKeys, actualKeys)
-          if (bodyMatches) {
-            for (const k of expectedKeys) {
-              if (self.expectedResponseBody[k] != DONT_CARE && !_.isEqual(self.expectedResponseBody[k], body[k])) {
-                bodyMatches = false
-                break
-              }
-            }
-          }
+          bodyMatches = matches(self.expectedResponseBody, body)
         } else {
           bodyMatches = self.expectedResponseBody == body
         }
@@ -583,6 +1135,63 @@ function Test(
   }
 }
 
+// a multipart form with `contents` as the file of an upload
+function uploadForm(filename, type, contents) {
+  const form = new FormData()
+  form.append("file", new Blob([contents], { type }), filename)
+  return form
+}
+
+// runs statements on the test database, for rows the API can't create or put back
+function Sql(statement) {
+  return {
+    run: async function() {
+      try {
+        await sql.unsafe(statement)
+      } catch (e) {
+        console.log(`statement failed: ${e.message}`)
+      }
+      return { failed: false }
+    }
+  }
+}
+
+// switches the session to another user in the middle of a test list
+function LoginAs(email, password) {
+  return {
+    run: async function() {
+      await login(email, password)
+      return { failed: false }
+    }
+  }
+}
+
+// sends the following requests with an access token instead of the session, either a token
+// created from the given description or the given token as is
+function UseToken(token) {
+  return {
+    run: async function() {
+      if (typeof token != 'string') {
+        const response = await fetch(`${BASE_URL}/user/tokens`, {
+          method: "POST",
+          body: JSON.stringify(token),
+          headers: {
+            "Cookie": sessionCookie,
+            "Content-Type": "application/json"
+          }
+        })
+        if (response.status != 201) {
+          console.log("token creation failed!")
+          process.exit(1)
+        }
+        token = (await response.json()).token
+      }
+      sessionCookie = null
+      bearerToken = token
+      return { failed: false }
+    }
+  }
+}
 
 async function login(email, password) {
   const response = await fetch(`${BASE_URL}/login`, {
@@ -598,6 +1207,7 @@ async function login(email, password) {
     process.exit(1)
   }
   sessionCookie = response.headers.get("set-cookie")
+  bearerToken = null
 }
 
 const sql = new SQL({